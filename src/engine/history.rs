@@ -0,0 +1,296 @@
+// Undo/redo. Every change to the project is an Edit holding both what was there before and after,
+// so it can be applied either way.
+
+use std::{mem, sync::Arc, time::{Duration, Instant}};
+
+use super::{DAWEngine, pattern::{Pattern, TrackEvent}, playlist::Clip, project::Project, instrument::Instrument, plugins::builtin::sampler::sample::Sample};
+
+const HISTORY_LIMIT: usize = 256;
+const GROUP_TIMEOUT: Duration = Duration::from_millis(1000); // typing faster than this is one undo step
+
+#[derive(Clone, Copy)]
+pub struct EventChange {
+    pub row: usize,
+    pub track: usize,
+    pub before: TrackEvent,
+    pub after: TrackEvent,
+}
+
+#[derive(Clone)]
+pub enum Edit {
+    Events { pattern: usize, changes: Vec<EventChange> }, // note/instrument/volume entry, deletes, block operations
+    Pattern { pattern: usize, before: Pattern, after: Pattern }, // resizes and anything else changing the shape
+    AddPattern { pattern: usize, added: Pattern }, // new and cloned patterns, always the last one
+    RemovePattern { pattern: usize, removed: Pattern, clips: Vec<(usize, Clip)> }, // with its clips and where they were in the playlist
+    AddClip { index: usize, clip: Clip },
+    RemoveClip { index: usize, clip: Clip },
+    ReplaceClip { index: usize, before: Clip, after: Clip }, // moved, resized
+    Clips { before: Vec<Clip>, after: Vec<Clip> }, // the whole playlist, e.g. rebuilt from the order list
+    Instrument { index: usize, before: Option<Instrument>, after: Option<Instrument> }, // None when added or removed
+    Sample { index: usize, before: Option<Arc<Sample>>, after: Option<Arc<Sample>> }, // same, the whole sample
+    SampleFrames { index: usize, start: usize, before: Sample, after: Sample }, // data only holds the frames from start that changed
+}
+
+// For tables where only the last entry is ever added or removed
+fn apply_slot<T: Clone>(list: &mut Vec<T>, index: usize, value: &Option<T>) {
+    match value {
+        Some(value) if index < list.len() => list[index] = value.clone(),
+        Some(value) => list.insert(index.min(list.len()), value.clone()),
+        None => if index < list.len() { list.remove(index); },
+    }
+}
+
+impl Edit {
+    fn apply(&self, project: &mut Project, undo: bool) {
+        match self {
+            Edit::Events { pattern, changes } => {
+                let Some(pattern) = project.patterns.get_mut(*pattern) else {
+                    return
+                };
+                // Backwards when undoing, in case a cell was changed twice
+                let mut apply = |change: &EventChange| {
+                    if let Some(event) = pattern.rows.get_mut(change.row).and_then(|row| row.get_mut(change.track)) {
+                        *event = if undo { change.before } else { change.after };
+                    }
+                };
+                if undo {
+                    changes.iter().rev().for_each(&mut apply);
+                } else {
+                    changes.iter().for_each(&mut apply);
+                }
+            },
+            Edit::Pattern { pattern, before, after } => {
+                if let Some(pattern) = project.patterns.get_mut(*pattern) {
+                    // Mute and solo are left out of the history, the tracks keep what they have now
+                    let mixer: Vec<(bool, bool)> = pattern.tracks.iter().map(|info| (info.muted, info.solo)).collect();
+                    *pattern = if undo { before.clone() } else { after.clone() };
+                    for (info, (muted, solo)) in pattern.tracks.iter_mut().zip(mixer) {
+                        info.muted = muted;
+                        info.solo = solo;
+                    }
+                }
+            },
+            Edit::AddPattern { pattern, added } => {
+                if !undo {
+                    project.patterns.insert((*pattern).min(project.patterns.len()), added.clone());
+                } else if *pattern < project.patterns.len() {
+                    project.patterns.remove(*pattern);
+                }
+            },
+            Edit::RemovePattern { pattern, removed, clips } => {
+                let playlist = &mut project.playlist.clips;
+                if undo {
+                    for clip in playlist.iter_mut() {
+                        let Clip::Pattern(clip) = clip;
+                        if clip.pattern_index >= *pattern {
+                            clip.pattern_index += 1;
+                        }
+                    }
+                    project.patterns.insert((*pattern).min(project.patterns.len()), removed.clone());
+                    for (index, clip) in clips {
+                        playlist.insert((*index).min(playlist.len()), clip.clone());
+                    }
+                } else {
+                    if *pattern < project.patterns.len() {
+                        project.patterns.remove(*pattern);
+                    }
+                    for (index, _) in clips.iter().rev() {
+                        if *index < playlist.len() {
+                            playlist.remove(*index);
+                        }
+                    }
+                    for clip in playlist.iter_mut() {
+                        let Clip::Pattern(clip) = clip;
+                        if clip.pattern_index > *pattern {
+                            clip.pattern_index -= 1;
+                        }
+                    }
+                }
+            },
+            Edit::AddClip { index, clip } | Edit::RemoveClip { index, clip } => {
+                let clips = &mut project.playlist.clips;
+                if undo == matches!(self, Edit::AddClip { .. }) {
+                    if *index < clips.len() {
+                        clips.remove(*index);
+                    }
+                } else {
+                    clips.insert((*index).min(clips.len()), clip.clone());
+                }
+            },
+            Edit::ReplaceClip { index, before, after } => {
+                if let Some(clip) = project.playlist.clips.get_mut(*index) {
+                    *clip = if undo { before.clone() } else { after.clone() };
+                }
+            },
+            Edit::Clips { before, after } => {
+                project.playlist.clips = if undo { before.clone() } else { after.clone() };
+            },
+            Edit::Instrument { index, before, after } => apply_slot(&mut project.instruments, *index, if undo { before } else { after }),
+            Edit::Sample { index, before, after } => apply_slot(&mut project.samples, *index, if undo { before } else { after }),
+            Edit::SampleFrames { index, start, before, after } => {
+                let Some(sample) = project.samples.get_mut(*index) else {
+                    return
+                };
+                let (from, to) = if undo { (after, before) } else { (before, after) };
+                // Only copied if the history still holds this version of the sample
+                let sample = Arc::make_mut(sample);
+                let mut data = mem::take(&mut sample.data);
+                data.resize_with(to.data.len(), Vec::new);
+                for (channel, frames) in data.iter_mut().zip(&to.data) {
+                    let start = (*start).min(channel.len());
+                    let end = (start + from.frames()).min(channel.len());
+                    channel.splice(start..end, frames.iter().copied());
+                }
+                *sample = Sample { data, ..to.settings() };
+            },
+        }
+    }
+
+    // Folds a later edit of the same cells into this one, keeping the oldest before
+    fn merge(&mut self, other: &Edit) -> bool {
+        let (Edit::Events { pattern, changes }, Edit::Events { pattern: other_pattern, changes: other_changes }) = (self, other) else {
+            return false
+        };
+        if pattern != other_pattern {
+            return false
+        }
+
+        for change in other_changes {
+            match changes.iter_mut().find(|existing| existing.row == change.row && existing.track == change.track) {
+                Some(existing) => existing.after = change.after,
+                None => changes.push(*change),
+            }
+        }
+        true
+    }
+}
+
+struct Entry {
+    edit: Edit,
+    grouped: bool,
+    time: Instant,
+}
+
+pub struct History {
+    undo: Vec<Entry>,
+    redo: Vec<Edit>,
+    changes: u64, // goes up with every edit, undo and redo
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            undo: Vec::with_capacity(HISTORY_LIMIT),
+            redo: Vec::new(),
+            changes: 0,
+        }
+    }
+
+    // For an edit that's already been applied. Grouped edits coming in quick succession become a single undo step.
+    pub fn push(&mut self, edit: Edit, grouped: bool) {
+        self.redo.clear();
+        self.changes += 1;
+
+        if let Some(last) = self.undo.last_mut() {
+            if grouped && last.grouped && last.time.elapsed() < GROUP_TIMEOUT && last.edit.merge(&edit) {
+                last.time = Instant::now();
+                return
+            }
+        }
+
+        self.push_undo(edit, grouped);
+    }
+
+    // Drops the oldest entry when full
+    fn push_undo(&mut self, edit: Edit, grouped: bool) {
+        if self.undo.len() >= HISTORY_LIMIT {
+            self.undo.remove(0);
+        }
+        self.undo.push(Entry { edit, grouped, time: Instant::now() });
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.changes += 1;
+    }
+
+    // Lets the UI tell when the project has changed without comparing it
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+}
+
+impl DAWEngine {
+    // The project, and the engine's state that goes along with it
+    fn apply(&mut self, edit: &Edit, undo: bool) {
+        let inserted = match edit {
+            Edit::AddPattern { pattern, added } => Some((*pattern, added, !undo)),
+            Edit::RemovePattern { pattern, removed, .. } => Some((*pattern, removed, undo)),
+            _ => None,
+        };
+        if let Some((pattern, contents, insert)) = inserted {
+            if insert {
+                let state = self.new_pattern_state(contents);
+                self.state.patterns.insert(pattern.min(self.state.patterns.len()), state);
+                if self.current_pattern >= pattern {
+                    self.current_pattern += 1;
+                }
+            } else {
+                if pattern < self.state.patterns.len() {
+                    self.pattern_stop(pattern);
+                    self.state.patterns.remove(pattern);
+                }
+                if self.current_pattern >= pattern && self.current_pattern != 0 {
+                    self.current_pattern -= 1;
+                }
+            }
+        }
+        // Samplers let go of the sample first, so it can be changed in place
+        if let Edit::SampleFrames { .. } = edit {
+            self.release_samples();
+        }
+        edit.apply(&mut self.project, undo);
+        self.fit_pattern_states();
+        match edit {
+            Edit::Instrument { .. } => self.sync_instruments(),
+            Edit::Sample { .. } | Edit::SampleFrames { .. } => self.sync_samples(),
+            _ => {}
+        }
+    }
+
+    // Makes a change to the project that can be undone
+    pub fn apply_edit(&mut self, edit: Edit) {
+        self.apply(&edit, false);
+        self.history.push(edit, false);
+    }
+
+    pub fn undo(&mut self) -> bool {
+        let Some(entry) = self.history.undo.pop() else {
+            return false
+        };
+        self.apply(&entry.edit, true);
+        self.history.redo.push(entry.edit);
+        self.history.changes += 1;
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        let Some(edit) = self.history.redo.pop() else {
+            return false
+        };
+        self.apply(&edit, false);
+        self.history.changes += 1;
+        // Not grouped, so typing right after a redo doesn't get merged into it
+        self.history.push_undo(edit, false);
+        true
+    }
+}
@@ -0,0 +1,498 @@
+// The instrument table. Instrument N in a pattern's instrument column is instruments[N-1],
+// which is also the module index its notes are sent to.
+// Each instrument gets a plugin to play it, made again whenever its kind changes.
+
+use super::{
+    DAWEngine,
+    history::Edit,
+    plugins::{
+        interface::Plugin,
+        bridge::{load_plugin, BridgedPlugin},
+        scanner::PluginDatabase,
+        lv2::LV2Plugin,
+        builtin::{
+            envelope::{ClassicEnvelope, ClassicEnvelopePoint},
+            sampler::{plugin::SamplerPlugin, keymap::{Keymap, Zone}},
+            subsynth::synth::SubSynth,
+            midi::plugin::MidiOutPlugin,
+        },
+    },
+};
+
+pub const MAX_INSTRUMENTS: usize = 99; // two digits in the pattern editor, like IT
+
+pub const ENVELOPE_VOLUME: usize = 0;
+pub const ENVELOPE_PANNING: usize = 1;
+pub const ENVELOPE_PITCH: usize = 2;
+pub const ENVELOPE_NAMES: [&str; 3] = ["Volume", "Panning", "Pitch"];
+
+pub const ENVELOPE_MAX_NODES: usize = 25;
+
+#[derive(Clone, PartialEq)]
+pub enum InstrumentKind {
+    None,
+    Sampler,
+    SubSynth,
+    MidiOut,
+    Clap(String), // bundle path, see PluginDescriptor::path
+    Lv2(String), // URI
+}
+
+impl InstrumentKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstrumentKind::None => "None",
+            InstrumentKind::Sampler => "Sampler",
+            InstrumentKind::SubSynth => "SubSynth",
+            InstrumentKind::MidiOut => "MIDI Out",
+            InstrumentKind::Clap(_) => "CLAP",
+            InstrumentKind::Lv2(_) => "LV2",
+        }
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            InstrumentKind::Clap(path) | InstrumentKind::Lv2(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct EnvelopeNode {
+    pub tick: u16,
+    pub value: i8, // 0..=64 for volume, -32..=32 for panning and pitch
+}
+
+// IT style, nodes joined by straight lines
+#[derive(Clone)]
+pub struct Envelope {
+    pub enabled: bool,
+    pub nodes: Vec<EnvelopeNode>, // sorted by tick, the first one is at 0
+    pub loop_range: Option<(usize, usize)>, // node indices
+    pub sustain: Option<(usize, usize)>, // looped while the note is held
+}
+
+impl Envelope {
+    pub fn new(value: i8) -> Self {
+        Self {
+            enabled: false,
+            nodes: vec![EnvelopeNode { tick: 0, value }, EnvelopeNode { tick: 100, value }],
+            loop_range: None,
+            sustain: None,
+        }
+    }
+
+    pub fn range(which: usize) -> (i8, i8) {
+        if which == ENVELOPE_VOLUME { (0, 64) } else { (-32, 32) }
+    }
+
+    // For the sampler: volume 0..1, panning -1..1, and pitch in semitones (IT's pitch envelope is in half semitones)
+    fn to_classic(&self, which: usize, tempo: u16, samplerate: u32) -> Option<ClassicEnvelope> {
+        if !self.enabled || self.nodes.is_empty() {
+            return None
+        }
+        let scale = match which {
+            ENVELOPE_VOLUME => 1.0 / 64.0,
+            ENVELOPE_PANNING => 1.0 / 32.0,
+            ENVELOPE_PITCH => 0.5,
+            _ => return None,
+        };
+
+        let mut envelope = ClassicEnvelope::new(6, tempo, samplerate);
+        envelope.points = self.nodes.iter().map(|node| ClassicEnvelopePoint { tick: node.tick, level: node.value as f32 * scale }).collect();
+        if let Some(range) = self.loop_range {
+            envelope.env_loop = range;
+            envelope.env_loop_enabled = true;
+        }
+        if let Some(range) = self.sustain {
+            envelope.env_sustain = range;
+            envelope.env_sustain_enabled = true;
+        }
+        Some(envelope)
+    }
+}
+
+// Envelopes are played by the sampler, the other kinds have their own
+#[derive(Clone)]
+pub struct Instrument {
+    pub name: String,
+    pub kind: InstrumentKind,
+    pub volume: u8, // velocity for notes with no volume, 0..=127
+    pub midi_channel: Option<u8>, // for MIDI Out, None uses the plugin's channel
+    pub midi_port: Option<String>, // for MIDI Out, None connects to the first port available
+    pub bridged: bool, // CLAP and LV2 only, runs the plugin in a process of its own so a crash doesn't take us down
+    pub envelopes: [Envelope; 3], // see ENVELOPE_*
+    pub keymap: Keymap, // which project samples the sampler plays, an empty one plays the sample with the instrument's number
+}
+
+impl Instrument {
+    pub fn new(name: String, kind: InstrumentKind) -> Self {
+        Self {
+            name,
+            kind,
+            volume: 127,
+            midi_channel: None,
+            midi_port: None,
+            bridged: false,
+            envelopes: [Envelope::new(64), Envelope::new(0), Envelope::new(0)],
+            keymap: Keymap::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kind == InstrumentKind::None && self.name.is_empty()
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self::new(String::new(), InstrumentKind::None)
+    }
+}
+
+// The built-in plugins are kept as themselves, so their settings can be reached
+pub(crate) enum InstrumentPlugin {
+    Sampler(SamplerPlugin),
+    SubSynth(SubSynth),
+    MidiOut(MidiOutPlugin),
+    External(Box<dyn Plugin + Send>), // CLAP or LV2
+}
+
+impl InstrumentPlugin {
+    fn plugin(&mut self) -> &mut dyn Plugin {
+        match self {
+            InstrumentPlugin::Sampler(plugin) => plugin,
+            InstrumentPlugin::SubSynth(plugin) => plugin,
+            InstrumentPlugin::MidiOut(plugin) => plugin,
+            InstrumentPlugin::External(plugin) => plugin.as_mut(),
+        }
+    }
+}
+
+pub(crate) struct InstrumentInstance {
+    kind: InstrumentKind, // what the plugin was made for
+    bridged: bool,
+    plugin: Option<InstrumentPlugin>, // None for InstrumentKind::None, without a path, while pending, or if it failed to load
+    pending: bool, // waiting for InstrumentBuilds::build
+}
+
+impl InstrumentInstance {
+    fn new(kind: &InstrumentKind, bridged: bool, samplerate: u32, channels: u8, sample_size: u32) -> Self {
+        let plugin = match kind {
+            InstrumentKind::None => Ok(None),
+            InstrumentKind::Clap(path) | InstrumentKind::Lv2(path) if path.is_empty() => Ok(None),
+            InstrumentKind::Sampler => SamplerPlugin::new("").map(|plugin| Some(InstrumentPlugin::Sampler(plugin))),
+            InstrumentKind::SubSynth => SubSynth::new("").map(|plugin| Some(InstrumentPlugin::SubSynth(plugin))),
+            InstrumentKind::MidiOut => MidiOutPlugin::new("").map(|plugin| Some(InstrumentPlugin::MidiOut(plugin))),
+            InstrumentKind::Clap(path) | InstrumentKind::Lv2(path) if bridged => {
+                BridgedPlugin::new(path).map(|plugin| Some(InstrumentPlugin::External(Box::new(plugin))))
+            },
+            InstrumentKind::Clap(path) | InstrumentKind::Lv2(path) => load_plugin(path).map(|plugin| Some(InstrumentPlugin::External(plugin))),
+        };
+
+        // Not retried until the kind changes
+        let mut plugin = plugin.unwrap_or_else(|err| {
+            eprintln!("Instrument {}: {}", kind.name(), err);
+            None
+        });
+        if let Some(plugin) = &mut plugin {
+            plugin.plugin().configure(samplerate, channels, sample_size);
+            plugin.plugin().enable();
+        }
+
+        Self { kind: kind.clone(), bridged, plugin, pending: false }
+    }
+
+    // External plugins are loaded from disk or started in another process, which takes too long to do with the engine locked
+    fn is_external(kind: &InstrumentKind) -> bool {
+        kind.path().map_or(false, |path| !path.is_empty())
+    }
+
+    fn restore(&mut self, state: Option<&[u8]>) {
+        if let (Some(plugin), Some(state)) = (&mut self.plugin, state) {
+            if let Err(err) = plugin.plugin().load_state(state) {
+                eprintln!("Instrument {}: {}", self.kind.name(), err);
+            }
+        }
+    }
+}
+
+struct InstrumentBuild {
+    index: usize,
+    kind: InstrumentKind,
+    bridged: bool,
+    state: Option<Vec<u8>>, // restored once it's built
+    instance: Option<InstrumentInstance>,
+}
+
+// Plugins to load and plugins to drop, without the engine locked. See DAWEngine::take_instrument_builds.
+#[derive(Default)]
+pub(crate) struct InstrumentBuilds {
+    builds: Vec<InstrumentBuild>,
+    retired: Vec<InstrumentInstance>, // replaced or removed, dropped along with this
+    samplerate: u32,
+    channels: u8,
+    sample_size: u32,
+}
+
+impl InstrumentBuilds {
+    pub fn needs_install(&self) -> bool {
+        !self.builds.is_empty()
+    }
+
+    pub fn build(&mut self) {
+        for build in self.builds.iter_mut() {
+            let mut instance = InstrumentInstance::new(&build.kind, build.bridged, self.samplerate, self.channels, self.sample_size);
+            instance.restore(build.state.as_deref());
+            build.instance = Some(instance);
+        }
+    }
+}
+
+// Every external instrument that can be picked, as its kind and name. Slow, every new CLAP bundle is loaded to find out what's in it.
+pub fn scan_instrument_plugins() -> Vec<(InstrumentKind, String)> {
+    let mut plugins = Vec::new();
+
+    let mut database = PluginDatabase::load();
+    if let Err(err) = database.scan() {
+        eprintln!("Unable to scan CLAP plugins: {}", err);
+    }
+    plugins.extend(database.instruments().map(|desc| (InstrumentKind::Clap(desc.path.clone()), desc.name.clone())));
+
+    match LV2Plugin::available() {
+        Ok(descriptors) => plugins.extend(descriptors.into_iter()
+            .filter(|desc| desc.is_instrument())
+            .map(|desc| (InstrumentKind::Lv2(desc.path), desc.name))),
+        Err(err) => eprintln!("Unable to scan LV2 plugins: {}", err),
+    }
+
+    plugins
+}
+
+impl DAWEngine {
+    // Makes the plugins match the instrument table, after every edit
+    pub(crate) fn sync_instruments(&mut self) {
+        // Removed instruments keep their state, in case they're brought back
+        for index in self.project.instruments.len()..self.instances.len() {
+            self.store_plugin_state(index);
+        }
+        let removed = self.instances.drain(self.project.instruments.len().min(self.instances.len())..);
+        self.instrument_builds.retired.extend(removed);
+
+        for index in 0..self.project.instruments.len() {
+            let instrument = &self.project.instruments[index];
+            if self.instances.get(index).map_or(true, |instance| instance.kind != instrument.kind || instance.bridged != instrument.bridged) {
+                self.store_plugin_state(index);
+                let instrument = &self.project.instruments[index];
+                let state = self.project.plugin_states.get(index)
+                    .and_then(|states| states.iter().find(|(kind, _)| *kind == instrument.kind))
+                    .map(|(_, state)| state.clone());
+
+                let mut instance = if InstrumentInstance::is_external(&instrument.kind) {
+                    self.instrument_builds.builds.push(InstrumentBuild { index, kind: instrument.kind.clone(), bridged: instrument.bridged, state, instance: None });
+                    InstrumentInstance { kind: instrument.kind.clone(), bridged: instrument.bridged, plugin: None, pending: true }
+                } else {
+                    let mut instance = InstrumentInstance::new(&instrument.kind, instrument.bridged, self.samplerate, self.channels, self.sample_size);
+                    instance.restore(state.as_deref());
+                    instance
+                };
+                // Samplers play the project's samples
+                if let Some(InstrumentPlugin::Sampler(plugin)) = &mut instance.plugin {
+                    plugin.set_samples(&self.project.samples);
+                }
+                if index < self.instances.len() {
+                    let old = std::mem::replace(&mut self.instances[index], instance);
+                    self.instrument_builds.retired.push(old);
+                } else {
+                    self.instances.push(instance);
+                }
+            }
+
+            let instrument = &self.project.instruments[index];
+            match &mut self.instances[index].plugin {
+                Some(InstrumentPlugin::MidiOut(plugin)) => {
+                    plugin.set_channel(index, instrument.midi_channel);
+                    if let Some(port) = &instrument.midi_port {
+                        if let Err(err) = plugin.change_port(port) {
+                            eprintln!("Instrument {}: {}", instrument.kind.name(), err);
+                        }
+                    }
+                },
+                Some(InstrumentPlugin::Sampler(plugin)) => {
+                    let default = [Zone::new(index)];
+                    plugin.set_zones(if instrument.keymap.zones.is_empty() { &default } else { &instrument.keymap.zones });
+                    for (which, envelope) in instrument.envelopes.iter().enumerate() {
+                        plugin.envelopes[which] = envelope.to_classic(which, self.project.tempo, self.samplerate);
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // The ports a MIDI Out instrument can reach and the one it's connected to
+    pub(crate) fn midi_out_ports(&self, index: usize) -> (Vec<String>, Option<String>) {
+        match self.instances.get(index).and_then(|instance| instance.plugin.as_ref()) {
+            Some(InstrumentPlugin::MidiOut(plugin)) => (plugin.get_ports().to_vec(), plugin.port().map(|port| port.to_string())),
+            _ => (Vec::new(), None),
+        }
+    }
+
+    // Hands over the plugins sync_instruments couldn't load, and the ones it replaced, to be handled without the engine locked
+    pub(crate) fn take_instrument_builds(&mut self) -> InstrumentBuilds {
+        let mut builds = std::mem::take(&mut self.instrument_builds);
+        builds.samplerate = self.samplerate;
+        builds.channels = self.channels;
+        builds.sample_size = self.sample_size;
+        builds
+    }
+
+    // Swaps in the plugins that were built, if the instruments still want them. The rest are left in builds to be dropped.
+    pub(crate) fn install_instruments(&mut self, builds: &mut InstrumentBuilds) {
+        for build in builds.builds.drain(..) {
+            let Some(instance) = build.instance else {
+                continue
+            };
+            match self.instances.get_mut(build.index) {
+                Some(current) if current.pending && current.kind == build.kind && current.bridged == build.bridged => *current = instance,
+                _ => builds.retired.push(instance),
+            }
+        }
+        self.sync_instruments();
+    }
+
+    // Keeps the state of the plugin playing instrument index, replacing what was kept for its kind
+    fn store_plugin_state(&mut self, index: usize) {
+        let Some(InstrumentInstance { kind, plugin: Some(plugin), .. }) = self.instances.get_mut(index) else {
+            return
+        };
+        let state = match plugin.plugin().save_state() {
+            Ok(state) => state,
+            Err(err) => {
+                eprintln!("Instrument {}: {}", kind.name(), err);
+                return
+            },
+        };
+
+        if self.project.plugin_states.len() <= index {
+            self.project.plugin_states.resize_with(index + 1, Vec::new);
+        }
+        let states = &mut self.project.plugin_states[index];
+        states.retain(|(stored, _)| stored != kind);
+        states.push((kind.clone(), state));
+    }
+
+    // Before the project is saved, so it has the plugins' current settings. Nothing saves projects yet.
+    #[allow(dead_code)]
+    pub fn store_plugin_states(&mut self) {
+        for index in 0..self.instances.len() {
+            self.store_plugin_state(index);
+        }
+    }
+
+    // Hands the sample table to every sampler, after it changed
+    pub(crate) fn sync_samples(&mut self) {
+        for instance in self.instances.iter_mut() {
+            if let Some(InstrumentPlugin::Sampler(plugin)) = &mut instance.plugin {
+                plugin.set_samples(&self.project.samples);
+            }
+        }
+    }
+
+    // Until the next sync_samples, so the project holds the only reference to each sample
+    pub(crate) fn release_samples(&mut self) {
+        for instance in self.instances.iter_mut() {
+            if let Some(InstrumentPlugin::Sampler(plugin)) = &mut instance.plugin {
+                plugin.set_samples(&[]);
+            }
+        }
+    }
+
+    // Classic envelopes run at the song tempo
+    pub(crate) fn change_instrument_tempo(&mut self, tempo: u16) {
+        for instance in self.instances.iter_mut() {
+            if let Some(InstrumentPlugin::Sampler(plugin)) = &mut instance.plugin {
+                plugin.change_tempo(tempo);
+            }
+        }
+    }
+
+    // Plays this buffer's events on every instrument and mixes them into buf
+    pub(crate) fn process_instruments(&mut self, buf: &mut [f32]) {
+        buf.fill(0.0);
+        // Only allocates if the buffer is bigger than it's ever been
+        self.instrument_input.resize(buf.len(), 0.0);
+        self.instrument_output.resize(buf.len(), 0.0);
+
+        for (index, instance) in self.instances.iter_mut().enumerate() {
+            let Some(plugin) = &mut instance.plugin else {
+                continue
+            };
+            self.instrument_events.clear();
+            self.instrument_events.extend(self.state.event_list.iter().filter(|event| event.module_index == index).copied());
+
+            plugin.plugin().process(&self.instrument_events, &self.instrument_input, &mut self.instrument_output);
+            for (out, value) in buf.iter_mut().zip(&self.instrument_output) {
+                *out += value;
+            }
+        }
+        self.state.event_list.clear();
+    }
+}
+
+// All of these can be undone
+impl DAWEngine {
+    // Returns the index, None if the table is full
+    pub fn add_instrument(&mut self, instrument: Instrument) -> Option<usize> {
+        let index = self.project.instruments.len();
+        if index >= MAX_INSTRUMENTS {
+            return None
+        }
+        // Not the state of an instrument that was removed from here
+        if let Some(states) = self.project.plugin_states.get_mut(index) {
+            states.clear();
+        }
+        self.apply_edit(Edit::Instrument { index, before: None, after: Some(instrument) });
+        Some(index)
+    }
+
+    // A copy that starts with the same plugin settings
+    pub fn clone_instrument(&mut self, index: usize) -> Option<usize> {
+        let instrument = self.project.instruments.get(index)?.clone();
+        self.store_plugin_state(index);
+        let states = self.project.plugin_states.get(index).cloned().unwrap_or_default();
+
+        let new_index = self.project.instruments.len();
+        if new_index >= MAX_INSTRUMENTS {
+            return None
+        }
+        if self.project.plugin_states.len() <= new_index {
+            self.project.plugin_states.resize_with(new_index + 1, Vec::new);
+        }
+        self.project.plugin_states[new_index] = states;
+        self.apply_edit(Edit::Instrument { index: new_index, before: None, after: Some(instrument) });
+        Some(new_index)
+    }
+
+    pub fn set_instrument(&mut self, index: usize, instrument: Instrument) {
+        let Some(before) = self.project.instruments.get(index).cloned() else {
+            return
+        };
+        self.apply_edit(Edit::Instrument { index, before: Some(before), after: Some(instrument) });
+    }
+
+    // Patterns refer to instruments by number, so only the last one is actually removed. The rest are emptied.
+    pub fn remove_instrument(&mut self, index: usize) {
+        let Some(before) = self.project.instruments.get(index).cloned() else {
+            return
+        };
+        let after = if index + 1 == self.project.instruments.len() { None } else { Some(Instrument::default()) };
+        self.apply_edit(Edit::Instrument { index, before: Some(before), after });
+    }
+
+    // Velocity of a note entered with no volume
+    pub(crate) fn default_velocity(&self, instrument: usize) -> u8 {
+        self.project.instruments.get(instrument).map_or(127, |instrument| instrument.volume.min(127))
+    }
+}
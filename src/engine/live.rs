@@ -0,0 +1,220 @@
+// Notes played live, from a MIDI keyboard. They're sent to the selected instrument right away,
+// and written into the playing pattern while recording.
+
+use std::{mem, sync::mpsc::Sender};
+use midir::{MidiInput, MidiInputConnection, Ignore};
+
+use super::{DAWEngine, history::{Edit, EventChange}, sync::SyncMessage, pattern::{Note, TrackEvent}, plugins::interface::{TimedEvent, Event}, allocate_note, free_note};
+
+const CLIENT_NAME: &str = "Project Corrosion MIDI In";
+
+#[derive(Clone, Copy, Debug)]
+pub enum LiveEvent {
+    NoteOn { key: u8, vel: u8 },
+    NoteOff { key: u8 },
+    ControlChange { index: u8, value: u8 },
+    Sync { message: SyncMessage, stamp: u64 }, // clock and MTC, stamp is when it came in, in µs
+
+    // Auditioning from the pattern editor, never recorded. Everything started with the same source
+    // (e.g. the key that was pressed) is stopped together. No instrument means the live one.
+    PreviewOn { source: u32, instrument: Option<usize>, key: u8, vel: u8 },
+    PreviewOff { source: u32 },
+}
+
+impl LiveEvent {
+    // Channels are ignored, everything goes to the selected instrument
+    pub fn from_midi(message: &[u8]) -> Option<LiveEvent> {
+        match message {
+            // Note on with velocity 0 is a note off
+            [status, key, 0] if status & 0xF0 == 0x90 => Some(LiveEvent::NoteOff { key: *key }),
+            [status, key, vel] if status & 0xF0 == 0x90 => Some(LiveEvent::NoteOn { key: *key, vel: *vel }),
+            [status, key, _] if status & 0xF0 == 0x80 => Some(LiveEvent::NoteOff { key: *key }),
+            [status, index, value] if status & 0xF0 == 0xB0 => Some(LiveEvent::ControlChange { index: *index, value: *value }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct LiveNote {
+    key: u8,
+    id: usize,
+    track: Option<usize>, // where it was recorded to
+}
+
+pub fn midi_input_ports() -> Result<Vec<String>, String> {
+    let midi_in = MidiInput::new(CLIENT_NAME).map_err(|err| err.to_string())?;
+    Ok(midi_in.ports().iter().filter_map(|port| midi_in.port_name(port).ok()).collect())
+}
+
+// An open MIDI input port, closed when dropped
+pub struct MidiInputDevice {
+    pub name: String,
+    _connection: MidiInputConnection<()>,
+}
+
+impl MidiInputDevice {
+    // Messages from the port are sent to the engine, see DAWEngine::live_sender()
+    pub fn open(index: usize, sender: Sender<LiveEvent>) -> Result<MidiInputDevice, String> {
+        let mut midi_in = MidiInput::new(CLIENT_NAME).map_err(|err| err.to_string())?;
+        midi_in.ignore(Ignore::ActiveSense); // MTC full frames are SysEx
+
+        let ports = midi_in.ports();
+        let port = ports.get(index).ok_or(format!("There is no MIDI input #{index}"))?;
+        let name = midi_in.port_name(port).map_err(|err| err.to_string())?;
+
+        let connection = midi_in.connect(port, "corrosion-midi-in", move |stamp, message, _| {
+            let sync = SyncMessage::from_midi(message).map(|message| LiveEvent::Sync { message, stamp });
+            if let Some(event) = sync.or_else(|| LiveEvent::from_midi(message)) {
+                let _ = sender.send(event);
+            }
+        }, ()).map_err(|err| err.to_string())?;
+
+        Ok(MidiInputDevice { name, _connection: connection })
+    }
+}
+
+impl DAWEngine {
+    pub fn live_sender(&self) -> Sender<LiveEvent> {
+        self.live_tx.clone()
+    }
+
+    // Called at the start of every buffer, so live notes are as early as they can be
+    pub(crate) fn process_live_events(&mut self) {
+        while let Ok(event) = self.live_rx.try_recv() {
+            let instrument = self.live_instrument;
+
+            match event {
+                LiveEvent::NoteOn { key, vel } => {
+                    // Retriggered without a note off in between
+                    if self.live_notes.iter().any(|note| note.key == key) {
+                        self.live_note_off(key);
+                    }
+
+                    let id = self.start_note(instrument, key, vel);
+                    let track = self.record_note(key, vel);
+                    self.live_notes.push(LiveNote { key, id, track });
+                },
+                LiveEvent::NoteOff { key } => self.live_note_off(key),
+                LiveEvent::ControlChange { index, value } => {
+                    self.state.event_list.push(TimedEvent {
+                        module_index: instrument,
+                        position: 0,
+                        event: Event::ControlChange { index, value },
+                    });
+                },
+                LiveEvent::Sync { message, stamp } => self.sync_receive(message, stamp),
+                LiveEvent::PreviewOn { source, instrument, key, vel } => {
+                    let id = self.start_note(instrument.unwrap_or(self.live_instrument), key, vel);
+                    self.preview_notes.push((source, id));
+                },
+                LiveEvent::PreviewOff { source } => {
+                    while let Some(index) = self.preview_notes.iter().position(|(from, _)| *from == source) {
+                        let (_, id) = self.preview_notes.remove(index);
+                        self.stop_note(id);
+                    }
+                },
+            }
+        }
+    }
+
+    fn live_note_off(&mut self, key: u8) {
+        let Some(index) = self.live_notes.iter().position(|note| note.key == key) else {
+            return
+        };
+        let note = self.live_notes.remove(index);
+        self.stop_note(note.id);
+
+        if let Some(track) = note.track {
+            self.record_event(track, Note::Off, 0, 128);
+        }
+    }
+
+    // Returns the note ID
+    fn start_note(&mut self, instrument: usize, key: u8, vel: u8) -> usize {
+        let id = allocate_note(&mut self.state.notes, key, instrument, self.state.next_note_id);
+        self.state.notes[id].vel = vel;
+        self.state.notes[id].is_on = true;
+        self.state.next_note_id = (id + 1) % self.state.notes.len();
+
+        self.state.event_list.push(TimedEvent {
+            module_index: instrument,
+            position: 0,
+            event: Event::NoteOn { id, key, vel },
+        });
+        id
+    }
+
+    fn stop_note(&mut self, id: usize) {
+        let state = self.state.notes[id];
+        free_note(&mut self.state.notes, id);
+
+        self.state.event_list.push(TimedEvent {
+            module_index: state.instrument,
+            position: 0,
+            event: Event::NoteOff { id, key: state.key, vel: state.vel },
+        });
+    }
+
+    // Writes a note into the playing pattern, on the first track from record_track that isn't holding a recorded note.
+    // Returns the track, or None if we're not recording.
+    fn record_note(&mut self, key: u8, vel: u8) -> Option<usize> {
+        let note = Note::from_repr(key).filter(|_| key < Note::PreviousTrack as u8)?;
+        let tracks = self.project.patterns.get(self.current_pattern)?.rows.first()?.len();
+        if tracks == 0 {
+            return None
+        }
+
+        let track = (0..tracks)
+            .map(|offset| (self.record_track + offset) % tracks)
+            .find(|&track| !self.live_notes.iter().any(|held| held.track == Some(track)))
+            .unwrap_or(self.record_track % tracks);
+
+        let instrument = (self.live_instrument + 1).min(u8::MAX as usize) as u8; // 0 is none in patterns
+        if self.record_event(track, note, instrument, vel) { Some(track) } else { None }
+    }
+
+    // Quantized to the nearest row, the one after the last wraps around like the pattern does.
+    // A note off landing on its own note goes to the next row instead.
+    fn record_event(&mut self, track: usize, note: Note, instrument: u8, volume: u8) -> bool {
+        if !self.recording {
+            return false
+        }
+        let Some(state) = self.state.patterns.get(self.current_pattern) else {
+            return false
+        };
+        if !self.state.playing || !state.playing {
+            return false
+        }
+
+        let pattern = &mut self.project.patterns[self.current_pattern];
+        if pattern.rows.is_empty() {
+            return false
+        }
+        let late = state.ticks_passed as u32 * 2 >= state.row_length; // past the middle of the row
+        let mut row = (state.row as usize + late as usize) % pattern.rows.len();
+        if matches!(note, Note::Off) && !matches!(pattern.rows[row][track].note, Note::None | Note::Off) {
+            row += 1;
+            if row >= pattern.rows.len() || !matches!(pattern.rows[row][track].note, Note::None) {
+                return false
+            }
+        }
+
+        let after = TrackEvent { note, instrument, volume };
+        let before = mem::replace(&mut pattern.rows[row][track], after);
+        self.recorded.push((self.current_pattern, EventChange { row, track, before, after }));
+        true
+    }
+
+    // Notes recorded since the last call, already in the project, as edits for the undo history
+    pub fn take_recorded(&mut self) -> Vec<Edit> {
+        let mut edits: Vec<Edit> = Vec::new();
+        for (pattern, change) in self.recorded.drain(..) {
+            match edits.last_mut() {
+                Some(Edit::Events { pattern: last, changes }) if *last == pattern => changes.push(change),
+                _ => edits.push(Edit::Events { pattern, changes: vec![change] }),
+            }
+        }
+        edits
+    }
+}
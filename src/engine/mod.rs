@@ -6,18 +6,29 @@ pub mod utils;
 pub mod plugins;
 mod test;
 pub mod mixer;
+pub mod live;
+pub mod sync;
+pub mod history;
+pub mod instrument;
+pub mod samples;
 
 use self::{
     playlist::Playlist,
     project::Project,
     state::{PatternState, PlaylistState, State}, test::GoertzelSine,
-    plugins::interface::NoteState
+    plugins::interface::{NoteState, TimedEvent},
+    instrument::{InstrumentInstance, InstrumentBuilds},
+    live::{LiveEvent, LiveNote},
+    sync::SyncState,
+    history::{History, EventChange}
 };
-use std::io::Write;
+use std::{io::Write, sync::mpsc::{self, Sender, Receiver}};
 
 #[allow(dead_code)]
 pub struct DAWEngine {
-    tick_length: u32,
+    tick_length: u32, // of the current tick, in samples
+    exact_tick_length: f64, // ticks are a whole number of samples, the fraction is carried over to the next one
+    tick_remainder: f64,
     samplerate: u32,
     channels: u8,
     sample_size: u32,
@@ -29,6 +40,25 @@ pub struct DAWEngine {
     pub project: Project,
     pub state: State,
 
+    // Live playing, see live.rs
+    pub live_instrument: usize, // module index notes from MIDI input go to
+    pub recording: bool,
+    pub record_track: usize, // first track live notes are recorded into
+    live_tx: Sender<LiveEvent>,
+    live_rx: Receiver<LiveEvent>,
+    live_notes: Vec<LiveNote>,
+    recorded: Vec<(usize, EventChange)>, // pattern, change. Written on the audio thread, taken by the UI for the undo history.
+    preview_notes: Vec<(u32, usize)>, // source, note ID
+
+    pub sync: SyncState, // MIDI clock and MTC, see sync.rs
+    pub history: History, // undo/redo, see history.rs
+
+    instances: Vec<InstrumentInstance>, // plugin of every instrument, see instrument.rs
+    instrument_builds: InstrumentBuilds,
+    instrument_events: Vec<TimedEvent>,
+    instrument_input: Vec<f32>,
+    instrument_output: Vec<f32>,
+
     test_osc: GoertzelSine
 }
 
@@ -74,17 +104,23 @@ fn free_note(note_registry: &mut [NoteState], id: usize) {
 #[allow(dead_code)]
 impl DAWEngine {
     pub fn new(samplerate: u32, channels: u8, sample_size: u32) -> Self {
-        let playlist = Playlist { clips: Vec::new() };
+        let playlist = Playlist { clips: Vec::new(), loop_range: None };
         let project = Project {
             ppq: 96,
             tempo: 125,
 
             playlist,
             patterns: Vec::new(),
+            instruments: Vec::new(),
+            samples: Vec::new(),
+            plugin_states: Vec::new(),
         };
 
+        let (live_tx, live_rx) = mpsc::channel();
         let mut engine = DAWEngine {
             tick_length: 0,
+            exact_tick_length: 0.0,
+            tick_remainder: 0.0,
             samplerate,
             channels,
             sample_size,
@@ -107,6 +143,24 @@ impl DAWEngine {
                 next_note_id: 0,
             },
 
+            live_instrument: 0,
+            recording: false,
+            record_track: 0,
+            live_tx,
+            live_rx,
+            live_notes: Vec::with_capacity(16),
+            recorded: Vec::with_capacity(256),
+            preview_notes: Vec::with_capacity(16),
+
+            sync: SyncState::new(),
+            history: History::new(),
+
+            instances: Vec::with_capacity(instrument::MAX_INSTRUMENTS),
+            instrument_builds: InstrumentBuilds::default(),
+            instrument_events: Vec::with_capacity(sample_size as usize),
+            instrument_input: vec![0.0; sample_size as usize * channels as usize],
+            instrument_output: vec![0.0; sample_size as usize * channels as usize],
+
             test_osc: GoertzelSine::new(440.0, 0.0, 48000)
         };
         engine.set_tempo(125);
@@ -123,8 +177,24 @@ impl DAWEngine {
 
     pub fn set_tempo(&mut self, tempo: u16) {
         self.project.tempo = tempo;
-        self.tick_length = (((60.0 / self.project.tempo as f32) * self.samplerate as f32)
-            / self.project.ppq as f32) as u32;
+        self.set_tick_rate(tempo as f64);
+        self.change_instrument_tempo(tempo);
+    }
+
+    // Following an external clock, which is rarely a whole BPM. The project keeps the nearest one.
+    pub(crate) fn set_exact_tempo(&mut self, tempo: f64) {
+        let rounded = tempo.round().clamp(1.0, u16::MAX as f64) as u16;
+        if rounded != self.project.tempo {
+            self.project.tempo = rounded;
+            self.change_instrument_tempo(rounded);
+        }
+        self.set_tick_rate(tempo);
+    }
+
+    // How fast the transport moves, without changing the project's tempo
+    pub(crate) fn set_tick_rate(&mut self, tempo: f64) {
+        self.exact_tick_length = 60.0 * self.samplerate as f64 / (tempo.max(1.0) * self.project.ppq.max(1) as f64);
+        self.tick_length = ((self.exact_tick_length + self.tick_remainder) as u32).max(1);
     }
 
     fn tick(&mut self, sample_index: usize) {
@@ -134,6 +204,10 @@ impl DAWEngine {
         if self.samples_passed == 0 {
             if self.song_mode {
                 // Playlist
+                self.playlist_tick();
+                for i in 0..self.state.patterns.len() {
+                    self.pattern_tick(i, sample_index)
+                }
                 self.state.playlist.position += 1;
                 print!(
                     "Ticks passed: {}\nBeats passed: {}\r\x1b[A",
@@ -141,9 +215,6 @@ impl DAWEngine {
                     self.state.playlist.position / self.project.ppq as u32
                 );
                 std::io::stdout().flush().unwrap();
-                for i in 0..self.state.patterns.len() {
-                    self.pattern_tick(i, sample_index)
-                }
             } else {
                 // Pattern
                 if !self.state.patterns[self.current_pattern].playing {
@@ -152,12 +223,21 @@ impl DAWEngine {
                 self.pattern_tick(self.current_pattern, sample_index);
             }
         }
+        // After the playlist, which may have looped back
+        self.sync_tick(sample_index);
         self.samples_passed += 1;
         if self.samples_passed >= self.tick_length {
             self.samples_passed = 0;
+            let length = self.exact_tick_length + self.tick_remainder;
+            self.tick_length = (length as u32).max(1);
+            self.tick_remainder = length - self.tick_length as f64;
         }
     }
 
+    pub fn song_mode(&self) -> bool {
+        self.song_mode
+    }
+
     pub fn switch_song_mode(&mut self, song_mode: bool) {
         // Stop all clips
 
@@ -172,14 +252,14 @@ impl DAWEngine {
     }
 
     pub fn process(&mut self, buf: &mut [f32]) {
+        self.sync_begin_block();
+        self.process_live_events();
+
         for sample_index in 0..(buf.len()/self.channels as usize) {
             self.tick(sample_index);
         }
 
-        // TODO audio
-        /* for chunk in buf.chunks_mut(self.channels as usize) {
-            (chunk[0], chunk[1]) = (1.0, -1.0)
-        } */
+        self.process_instruments(buf);
 
         // play test tone
         /* for chunk in buf.chunks_mut(self.channels as usize) {
@@ -28,9 +28,30 @@ pub enum Note {
 
 type Row = Vec<TrackEvent>;
 
+pub const TRACK_MIN_WIDTH: u8 = 11; // note, instrument, volume and the border
+pub const TRACK_MAX_WIDTH: u8 = 32;
+
+// How a track looks and whether it's heard
+#[derive(Clone)]
+pub struct TrackInfo {
+    pub name: String, // empty shows "Track NN"
+    pub muted: bool,
+    pub solo: bool,
+    pub color: Option<u32>, // of the header, None for the default
+    pub width: u8, // in characters, see TRACK_MIN_WIDTH
+}
+
+impl Default for TrackInfo {
+    fn default() -> Self {
+        Self { name: String::new(), muted: false, solo: false, color: None, width: TRACK_MIN_WIDTH }
+    }
+}
+
 #[derive(Clone)]
 pub struct Pattern {
+    pub name: String, // can be empty
     pub rows: Vec<Row>,
+    pub tracks: Vec<TrackInfo>, // one for every event in a row
     pub rpb: u8, // rows per beat
                  // 2022-04-07: RPB has been moved from Project to here, so that multiple patterns in a project can have a unique amount of row precision.
                  // This is similar to adjusting Ticks/Row in traditional trackers such as Impulse Tracker.
@@ -48,7 +69,16 @@ impl Pattern {
         let mut rows: Vec<Row> = Vec::with_capacity(rows_amount as usize);
         rows.resize_with(rows_amount as usize, || row.clone());
 
-        Pattern { rows, rpb: 4 }
+        Pattern { name: String::new(), rows, tracks: vec![TrackInfo::default(); tracks_amount as usize], rpb: 4 }
+    }
+
+    // Muted tracks don't start notes. Any soloed track mutes all the ones that aren't.
+    pub fn track_audible(&self, track: usize) -> bool {
+        let Some(info) = self.tracks.get(track) else {
+            return true
+        };
+        let solo = self.tracks.iter().any(|info| info.solo);
+        !info.muted && (!solo || info.solo)
     }
 }
 
@@ -2,9 +2,11 @@
 
 pub struct Playlist {
     pub clips: Vec<Clip>,
+    pub loop_range: Option<(u32, u32)>, // song mode jumps back to the start when it reaches the end
 }
 
 #[allow(dead_code)]
+#[derive(Clone)]
 pub enum Clip {
     Pattern(PatternClip), // Audio(AudioClip)
 }
@@ -21,6 +23,7 @@ trait ClipInfo {
     }
 }
 
+#[derive(Clone, PartialEq)]
 pub struct PatternClip {
     pub pattern_index: usize,
     pub begin: u32,
@@ -0,0 +1,789 @@
+// Out-of-process plugin hosting
+// The plugin runs in a helper process (this executable, started with BRIDGE_ARG), audio and events are exchanged over shared memory.
+// If the helper crashes or stops responding, the plugin reports PluginError::Crashed and outputs silence.
+
+use std::{
+    env,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering}},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    clap::ClapPlugin,
+    lv2::LV2Plugin,
+    interface::{Plugin, PluginError, Parameter, ParameterKind, PluginDescriptor, TimedEvent, Event},
+};
+
+pub const BRIDGE_ARG: &str = "--plugin-bridge";
+
+const MAX_EVENTS: usize = 1024;
+const MAX_SAMPLES: usize = 16384;
+const DATA_SIZE: usize = 1 << 20; // for anything that isn't audio or events, e.g. the parameter list
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+const WATCH_INTERVAL: Duration = Duration::from_millis(10);
+
+// Request states
+const STATE_IDLE: u32 = 0;
+const STATE_REQUEST: u32 = 1;
+const STATE_DONE: u32 = 2;
+
+// Commands
+const COMMAND_PROCESS: u32 = 0;
+const COMMAND_ENABLE: u32 = 1;
+const COMMAND_DISABLE: u32 = 2;
+const COMMAND_SHOW_GUI: u32 = 3;
+const COMMAND_GET_PARAMS: u32 = 4;
+const COMMAND_SHUTDOWN: u32 = 5;
+const COMMAND_CONFIGURE: u32 = 6;
+const COMMAND_SET_PARAM: u32 = 7;
+const COMMAND_SAVE_STATE: u32 = 8;
+const COMMAND_LOAD_STATE: u32 = 9;
+
+// Event kinds
+const EVENT_NOTE_OFF: u32 = 0;
+const EVENT_NOTE_ON: u32 = 1;
+const EVENT_CONTROL_CHANGE: u32 = 2;
+const EVENT_EXPR_PITCH: u32 = 3;
+const EVENT_EXPR_VOLUME: u32 = 4;
+const EVENT_EXPR_PRESSURE: u32 = 5;
+const EVENT_EXPR_TIMBRE: u32 = 6;
+
+// The other process writes to it too, so only ever touched through atomics
+#[repr(C)]
+struct BridgeHeader {
+    state: AtomicU32,
+    command: AtomicU32,
+    result: AtomicU32, // 0 is success
+    arg: AtomicU32,
+
+    event_count: AtomicU32,
+    input_len: AtomicU32,
+    output_len: AtomicU32,
+    data_len: AtomicU32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BridgeEvent {
+    position: u32,
+    kind: u32,
+    id: u32,
+    key: u8,
+    vel: u8,
+    value: f32,
+}
+
+const EVENTS_OFFSET: usize = std::mem::size_of::<BridgeHeader>();
+const INPUT_OFFSET: usize = EVENTS_OFFSET + MAX_EVENTS * std::mem::size_of::<BridgeEvent>();
+const OUTPUT_OFFSET: usize = INPUT_OFFSET + MAX_SAMPLES * std::mem::size_of::<f32>();
+const DATA_OFFSET: usize = OUTPUT_OFFSET + MAX_SAMPLES * std::mem::size_of::<f32>();
+const SHM_SIZE: usize = DATA_OFFSET + DATA_SIZE;
+
+/*
+    SHARED MEMORY
+*/
+
+struct SharedMemory {
+    ptr: *mut u8,
+    path: PathBuf,
+    owner: bool, // the host removes the file when done
+}
+
+// The memory is only accessed through the request protocol, never concurrently.
+// Everything past the header is read and written through raw pointers, never references, as the other process owns it half the time.
+unsafe impl Send for SharedMemory {}
+
+impl SharedMemory {
+    #[cfg(unix)]
+    fn open(path: PathBuf, create: bool) -> Result<SharedMemory, String> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .open(&path)
+            .map_err(|err| format!("{}", err))?;
+        if create {
+            file.set_len(SHM_SIZE as u64).map_err(|err| format!("{}", err))?;
+        }
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                SHM_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("mmap failed: {}", std::io::Error::last_os_error()))
+        }
+
+        Ok(SharedMemory { ptr: ptr as *mut u8, path, owner: create })
+    }
+
+    #[cfg(not(unix))]
+    fn open(_path: PathBuf, _create: bool) -> Result<SharedMemory, String> {
+        // TODO CreateFileMapping on Windows
+        Err("Plugin bridging is not supported on this platform yet".to_string())
+    }
+
+    fn create() -> Result<SharedMemory, String> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let name = format!("corrosion-bridge-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let dir = PathBuf::from("/dev/shm");
+        let path = if dir.is_dir() { dir.join(name) } else { env::temp_dir().join(name) };
+
+        SharedMemory::open(path, true)
+    }
+
+    fn header(&self) -> &BridgeHeader {
+        unsafe { &*(self.ptr as *const BridgeHeader) }
+    }
+
+    fn state(&self) -> &AtomicU32 {
+        &self.header().state
+    }
+
+    // Header fields other than state, ordered by the acquire/release on state
+    fn get(field: &AtomicU32) -> u32 {
+        field.load(Ordering::Relaxed)
+    }
+
+    fn set(field: &AtomicU32, value: u32) {
+        field.store(value, Ordering::Relaxed)
+    }
+
+    // Copies values in at offset, as many as fit in count
+    fn write<T: Copy>(&self, offset: usize, count: usize, values: &[T]) {
+        let ptr = unsafe { self.ptr.add(offset) as *mut T };
+        for (i, value) in values.iter().take(count).enumerate() {
+            unsafe { ptr.add(i).write_volatile(*value) };
+        }
+    }
+
+    // Copies values out from offset, as many as fit in count
+    fn read<T: Copy>(&self, offset: usize, count: usize, values: &mut [T]) {
+        let ptr = unsafe { self.ptr.add(offset) as *const T };
+        for (i, value) in values.iter_mut().take(count).enumerate() {
+            *value = unsafe { ptr.add(i).read_volatile() };
+        }
+    }
+
+    fn write_events(&self, events: &[TimedEvent]) -> usize {
+        let ptr = unsafe { self.ptr.add(EVENTS_OFFSET) as *mut BridgeEvent };
+        for (i, e) in events.iter().take(MAX_EVENTS).enumerate() {
+            unsafe { ptr.add(i).write_volatile(encode_event(e)) };
+        }
+        events.len().min(MAX_EVENTS)
+    }
+
+    fn read_events(&self, count: usize, events: &mut Vec<TimedEvent>) {
+        let ptr = unsafe { self.ptr.add(EVENTS_OFFSET) as *const BridgeEvent };
+        for i in 0..count.min(MAX_EVENTS) {
+            if let Some(e) = decode_event(&unsafe { ptr.add(i).read_volatile() }) {
+                events.push(e);
+            }
+        }
+    }
+
+    fn write_input(&self, input: &[f32]) {
+        self.write(INPUT_OFFSET, MAX_SAMPLES, input);
+    }
+
+    fn read_input(&self, input: &mut [f32]) {
+        self.read(INPUT_OFFSET, MAX_SAMPLES, input);
+    }
+
+    fn write_output(&self, output: &[f32]) {
+        self.write(OUTPUT_OFFSET, MAX_SAMPLES, output);
+    }
+
+    fn read_output(&self, output: &mut [f32]) {
+        self.read(OUTPUT_OFFSET, MAX_SAMPLES, output);
+    }
+
+    fn write_data(&self, data: &[u8]) {
+        let len = data.len().min(DATA_SIZE);
+        self.write(DATA_OFFSET, DATA_SIZE, &data[..len]);
+        Self::set(&self.header().data_len, len as u32);
+    }
+
+    fn read_data(&self) -> Vec<u8> {
+        let len = (Self::get(&self.header().data_len) as usize).min(DATA_SIZE);
+        let mut data = vec![0; len];
+        self.read(DATA_OFFSET, DATA_SIZE, &mut data);
+        data
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, SHM_SIZE);
+        }
+        if self.owner {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn encode_event(e: &TimedEvent) -> BridgeEvent {
+    let mut out = BridgeEvent { position: e.position, kind: 0, id: 0, key: 0, vel: 0, value: 0.0 };
+
+    match e.event {
+        Event::NoteOff { id, key, vel } => { out.kind = EVENT_NOTE_OFF; out.id = id as u32; out.key = key; out.vel = vel; },
+        Event::NoteOn { id, key, vel } => { out.kind = EVENT_NOTE_ON; out.id = id as u32; out.key = key; out.vel = vel; },
+        Event::ControlChange { index, value } => { out.kind = EVENT_CONTROL_CHANGE; out.key = index; out.vel = value; },
+        Event::ExprPitch { id, target_pitch } => { out.kind = EVENT_EXPR_PITCH; out.id = id as u32; out.value = target_pitch; },
+        Event::ExprVolume { id, target_vol } => { out.kind = EVENT_EXPR_VOLUME; out.id = id as u32; out.vel = target_vol; },
+        Event::ExprPressure { id, pressure } => { out.kind = EVENT_EXPR_PRESSURE; out.id = id as u32; out.vel = pressure; },
+        Event::ExprTimbre { id, timbre } => { out.kind = EVENT_EXPR_TIMBRE; out.id = id as u32; out.vel = timbre; },
+    }
+
+    out
+}
+
+fn decode_event(e: &BridgeEvent) -> Option<TimedEvent> {
+    let event = match e.kind {
+        EVENT_NOTE_OFF => Event::NoteOff { id: e.id as usize, key: e.key, vel: e.vel },
+        EVENT_NOTE_ON => Event::NoteOn { id: e.id as usize, key: e.key, vel: e.vel },
+        EVENT_CONTROL_CHANGE => Event::ControlChange { index: e.key, value: e.vel },
+        EVENT_EXPR_PITCH => Event::ExprPitch { id: e.id as usize, target_pitch: e.value },
+        EVENT_EXPR_VOLUME => Event::ExprVolume { id: e.id as usize, target_vol: e.vel },
+        EVENT_EXPR_PRESSURE => Event::ExprPressure { id: e.id as usize, pressure: e.vel },
+        EVENT_EXPR_TIMBRE => Event::ExprTimbre { id: e.id as usize, timbre: e.vel },
+        _ => return None,
+    };
+
+    Some(TimedEvent { module_index: 0, position: e.position, event })
+}
+
+// Parameters are sent as text, one per line, tab separated
+fn encode_params(params: &[Parameter]) -> String {
+    let mut text = String::new();
+
+    for param in params {
+        let kind = match &param.kind {
+            ParameterKind::Continuous => "c".to_string(),
+            ParameterKind::Stepped => "s".to_string(),
+            ParameterKind::Enum(labels) => format!("e{}", labels.iter().map(|label| label.replace(['\t', '\n', '\x1f'], " ")).collect::<Vec<String>>().join("\x1f")),
+        };
+
+        text.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            param.index,
+            param.name.replace(['\t', '\n'], " "),
+            param.value,
+            param.min,
+            param.max,
+            param.default,
+            param.display.replace(['\t', '\n'], " "),
+            kind,
+            param.automatable as u8,
+            param.read_only as u8,
+        ));
+    }
+
+    text
+}
+
+fn decode_params(text: &str) -> Vec<Parameter> {
+    let mut params = Vec::new();
+
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 10 {
+            continue
+        }
+
+        let kind = match fields[7].split_at(fields[7].len().min(1)) {
+            ("s", _) => ParameterKind::Stepped,
+            ("e", labels) => ParameterKind::Enum(labels.split('\x1f').map(|label| label.to_string()).collect()),
+            _ => ParameterKind::Continuous,
+        };
+
+        if let (Ok(index), Ok(value), Ok(min), Ok(max), Ok(default)) = (fields[0].parse(), fields[2].parse(), fields[3].parse(), fields[4].parse(), fields[5].parse()) {
+            params.push(Parameter {
+                index,
+                name: fields[1].to_string(),
+                value,
+                min,
+                max,
+                default,
+                display: fields[6].to_string(),
+                kind,
+                automatable: fields[8] == "1",
+                read_only: fields[9] == "1",
+            });
+        }
+    }
+
+    params
+}
+
+/// Instantiates the plugin a bridge should host, based on the path
+pub fn load_plugin(path: &str) -> Result<Box<dyn Plugin + Send>, PluginError> {
+    // LV2 URIs may contain #, so the bundle path is everything before the first one
+    let lv2_bundle = path.split_once('#').map_or(path, |(bundle, _)| bundle);
+    if lv2_bundle.trim_end_matches('/').ends_with(".lv2") {
+        return Ok(Box::new(LV2Plugin::new(path)?))
+    }
+
+    let bundle_path = path.rsplit_once('#').map_or(path, |(bundle, _)| bundle);
+    if bundle_path.ends_with(".clap") {
+        Ok(Box::new(ClapPlugin::new(path)?))
+    } else {
+        Err(PluginError::NoSuchPlugin)
+    }
+}
+
+/*
+    HOST SIDE
+*/
+
+// Shared with the thread that owns the helper process, so the audio thread never has to make a system call for it
+#[derive(Default)]
+struct BridgeWatch {
+    exited: AtomicBool,
+    kill: AtomicBool,
+}
+
+// Waits for the helper to exit, or kills it when asked to, and reaps it
+fn watch_child(mut child: Child, watch: Arc<BridgeWatch>) {
+    loop {
+        if watch.kill.load(Ordering::Acquire) {
+            let _ = child.kill();
+            let _ = child.wait();
+            break
+        }
+        match child.try_wait() {
+            Ok(None) => thread::sleep(WATCH_INTERVAL),
+            Ok(Some(_)) | Err(_) => break,
+        }
+    }
+    watch.exited.store(true, Ordering::Release);
+}
+
+pub struct BridgedPlugin {
+    active: bool,
+    error: Option<PluginError>,
+    params: Vec<Parameter>,
+    sample_time: f64, // seconds per sample in a buffer, the deadline for processing one
+    late_since: Option<Instant>, // a process request that missed its deadline and hasn't finished yet
+
+    shm: SharedMemory,
+    watch: Arc<BridgeWatch>,
+}
+
+impl BridgedPlugin {
+    // Sends the request that has been written to shared memory and waits for the helper to finish it.
+    // Returns false if the helper has crashed or timed out, in which case the plugin is marked as crashed.
+    fn request(&mut self, command: u32, arg: u32, timeout: Duration) -> bool {
+        if !self.finish_late(timeout) {
+            return false
+        }
+
+        self.send(command, arg);
+        if self.wait_done(timeout) {
+            self.shm.state().store(STATE_IDLE, Ordering::Release);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn send(&self, command: u32, arg: u32) {
+        let header = self.shm.header();
+        SharedMemory::set(&header.command, command);
+        SharedMemory::set(&header.arg, arg);
+        self.shm.state().store(STATE_REQUEST, Ordering::Release);
+    }
+
+    // A late process request has to be done before the next one is written. Its output is thrown away.
+    fn finish_late(&mut self, timeout: Duration) -> bool {
+        if self.error.is_some() {
+            return false
+        }
+        if self.late_since.is_some() {
+            if !self.wait_done(timeout) {
+                return false
+            }
+            self.shm.state().store(STATE_IDLE, Ordering::Release);
+            self.late_since = None;
+        }
+        true
+    }
+
+    fn fetch_params(&mut self) {
+        self.params.clear();
+        if !self.request(COMMAND_GET_PARAMS, 0, REQUEST_TIMEOUT) {
+            return
+        }
+
+        let data = self.shm.read_data();
+        self.params = decode_params(&String::from_utf8_lossy(&data));
+    }
+
+    // Error message written by the helper when a request fails
+    fn request_error(&self) -> String {
+        String::from_utf8_lossy(&self.shm.read_data()).into_owned()
+    }
+
+    // Some(true) once the request is done, Some(false) if the helper is gone, None while it's still working
+    fn poll(&mut self) -> Option<bool> {
+        if self.shm.state().load(Ordering::Acquire) == STATE_DONE {
+            return Some(true)
+        }
+        if self.watch.exited.load(Ordering::Acquire) {
+            self.error = Some(PluginError::Crashed("Bridge process exited".to_string()));
+            return Some(false)
+        }
+        None
+    }
+
+    // The watch thread kills and reaps it
+    fn stopped_responding(&mut self) {
+        self.watch.kill.store(true, Ordering::Release);
+        self.error = Some(PluginError::Crashed("Bridge process stopped responding".to_string()));
+    }
+
+    fn wait_done(&mut self, timeout: Duration) -> bool {
+        let started = Instant::now();
+
+        loop {
+            if let Some(done) = self.poll() {
+                return done
+            }
+            if started.elapsed() > timeout {
+                self.stopped_responding();
+                return false
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+impl Drop for BridgedPlugin {
+    fn drop(&mut self) {
+        // The watch thread reaps the helper once it has exited
+        if !self.request(COMMAND_SHUTDOWN, 0, REQUEST_TIMEOUT) {
+            self.watch.kill.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Plugin for BridgedPlugin {
+    fn new(path: &str) -> Result<Self, PluginError> {
+        let shm = SharedMemory::create().map_err(PluginError::LoadError)?;
+        let exe = env::current_exe().map_err(|err| PluginError::LoadError(format!("{}", err)))?;
+
+        let child = Command::new(exe)
+            .arg(BRIDGE_ARG)
+            .arg(&shm.path)
+            .arg(path)
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|err| PluginError::LoadError(format!("Unable to start bridge process: {}", err)))?;
+
+        let watch = Arc::new(BridgeWatch::default());
+        let child_watch = watch.clone();
+        thread::Builder::new()
+            .name("plugin bridge watch".to_string())
+            .spawn(move || watch_child(child, child_watch))
+            .map_err(|err| PluginError::LoadError(format!("{}", err)))?;
+
+        let mut this = BridgedPlugin { active: false, error: None, params: Vec::new(), sample_time: 0.0, late_since: None, shm, watch };
+
+        // The helper reports back once the plugin is loaded
+        if !this.wait_done(STARTUP_TIMEOUT) {
+            return Err(PluginError::LoadError(format!("{}", this.error.take().unwrap())))
+        }
+        this.shm.state().store(STATE_IDLE, Ordering::Release);
+
+        if SharedMemory::get(&this.shm.header().result) != 0 {
+            let message = String::from_utf8_lossy(&this.shm.read_data()).into_owned();
+            return Err(PluginError::LoadError(message))
+        }
+
+        Ok(this)
+    }
+
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> {
+        // Anything external can be bridged
+        let mut descriptors = ClapPlugin::available()?;
+        descriptors.extend(LV2Plugin::available()?);
+        Ok(descriptors)
+    }
+
+    fn configure(&mut self, samplerate: u32, channels: u8, sample_size: u32) {
+        self.sample_time = 1.0 / (samplerate.max(1) as f64 * channels.max(1) as f64);
+
+        let mut data = Vec::with_capacity(9);
+        data.extend_from_slice(&samplerate.to_le_bytes());
+        data.push(channels);
+        data.extend_from_slice(&sample_size.to_le_bytes());
+        self.shm.write_data(&data);
+
+        self.request(COMMAND_CONFIGURE, 0, REQUEST_TIMEOUT);
+    }
+
+    // Runs on the audio thread, so it never waits longer than the buffer lasts.
+    // A late buffer is played as silence, and the next one is only sent once the helper has caught up.
+    fn process(&mut self, events: &[TimedEvent], input: &[f32], output: &mut [f32]) {
+        output.fill(0.0);
+        if self.error.is_some() || output.len() > MAX_SAMPLES {
+            return
+        }
+
+        if let Some(since) = self.late_since {
+            match self.poll() {
+                Some(true) => {
+                    self.shm.state().store(STATE_IDLE, Ordering::Release);
+                    self.late_since = None;
+                },
+                Some(false) => return,
+                None => {
+                    if since.elapsed() > REQUEST_TIMEOUT {
+                        self.stopped_responding();
+                    }
+                    return
+                },
+            }
+        }
+
+        let event_count = self.shm.write_events(events);
+        self.shm.write_input(input);
+
+        let header = self.shm.header();
+        SharedMemory::set(&header.event_count, event_count as u32);
+        SharedMemory::set(&header.input_len, input.len().min(MAX_SAMPLES) as u32);
+        SharedMemory::set(&header.output_len, output.len() as u32);
+
+        let deadline = Instant::now() + Duration::from_secs_f64(output.len() as f64 * self.sample_time);
+        self.send(COMMAND_PROCESS, 0);
+        loop {
+            match self.poll() {
+                Some(true) => {
+                    self.shm.read_output(output);
+                    self.shm.state().store(STATE_IDLE, Ordering::Release);
+                    return
+                },
+                Some(false) => return,
+                None if Instant::now() >= deadline => {
+                    self.late_since = Some(Instant::now());
+                    return
+                },
+                None => thread::yield_now(),
+            }
+        }
+    }
+
+    fn show_gui(&mut self, shown: bool) {
+        self.request(COMMAND_SHOW_GUI, shown as u32, REQUEST_TIMEOUT);
+    }
+
+    fn get_params(&self) -> Vec<Parameter> {
+        // Cached, since fetching them requires a request to the bridge
+        self.params.clone()
+    }
+
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError> {
+        let mut data = Vec::with_capacity(16);
+        data.extend_from_slice(&(index as u64).to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+        self.shm.write_data(&data);
+
+        if !self.request(COMMAND_SET_PARAM, 0, REQUEST_TIMEOUT) {
+            return Err(PluginError::Crashed(format!("{}", self.error.as_ref().unwrap())))
+        }
+        if SharedMemory::get(&self.shm.header().result) != 0 {
+            return Err(PluginError::NoSuchParameter(index))
+        }
+
+        self.fetch_params();
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError> {
+        if !self.request(COMMAND_SAVE_STATE, 0, REQUEST_TIMEOUT) {
+            return Err(PluginError::Crashed(format!("{}", self.error.as_ref().unwrap())))
+        }
+        if SharedMemory::get(&self.shm.header().result) != 0 {
+            return Err(PluginError::StateError(self.request_error()))
+        }
+
+        Ok(self.shm.read_data())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        if state.len() > DATA_SIZE {
+            return Err(PluginError::StateError("State is too large to be bridged".to_string()))
+        }
+        self.shm.write_data(state);
+
+        if !self.request(COMMAND_LOAD_STATE, 0, REQUEST_TIMEOUT) {
+            return Err(PluginError::Crashed(format!("{}", self.error.as_ref().unwrap())))
+        }
+        if SharedMemory::get(&self.shm.header().result) != 0 {
+            return Err(PluginError::StateError(self.request_error()))
+        }
+
+        self.fetch_params();
+        Ok(())
+    }
+
+    fn enable(&mut self) {
+        self.active = self.request(COMMAND_ENABLE, 0, REQUEST_TIMEOUT);
+        self.fetch_params();
+    }
+
+    fn disable(&mut self) {
+        self.request(COMMAND_DISABLE, 0, REQUEST_TIMEOUT);
+        self.active = false;
+    }
+
+    fn active(&self) -> bool {
+        self.active && self.error.is_none()
+    }
+
+    fn error(&self) -> Option<&PluginError> {
+        self.error.as_ref()
+    }
+}
+
+/*
+    HELPER SIDE
+*/
+
+/// Entry point of the bridge helper process. Returns the exit code.
+pub fn bridge_child(shm_path: &str, plugin_path: &str) -> i32 {
+    let shm = match SharedMemory::open(PathBuf::from(shm_path), false) {
+        Ok(shm) => shm,
+        Err(err) => {
+            eprintln!("Bridge: {}", err);
+            return 1
+        }
+    };
+
+    let mut plugin = match load_plugin(plugin_path) {
+        Ok(plugin) => {
+            SharedMemory::set(&shm.header().result, 0);
+            plugin
+        },
+        Err(err) => {
+            shm.write_data(format!("{}", err).as_bytes());
+            SharedMemory::set(&shm.header().result, 1);
+            shm.state().store(STATE_DONE, Ordering::Release);
+            return 1
+        }
+    };
+    shm.state().store(STATE_DONE, Ordering::Release);
+
+    #[cfg(unix)]
+    let parent = unsafe { libc::getppid() };
+    let mut events: Vec<TimedEvent> = Vec::with_capacity(MAX_EVENTS);
+    let mut input: Vec<f32> = Vec::with_capacity(MAX_SAMPLES);
+    let mut output: Vec<f32> = Vec::with_capacity(MAX_SAMPLES);
+    let mut idle_since = Instant::now();
+
+    loop {
+        if shm.state().load(Ordering::Acquire) != STATE_REQUEST {
+            // Don't outlive the host
+            #[cfg(unix)]
+            if unsafe { libc::getppid() } != parent {
+                return 0
+            }
+
+            // Spin while requests keep coming in, sleep when idle
+            if idle_since.elapsed() > Duration::from_millis(100) {
+                thread::sleep(Duration::from_millis(1));
+            } else {
+                thread::yield_now();
+            }
+            continue
+        }
+        idle_since = Instant::now();
+
+        let header = shm.header();
+        let fail = || SharedMemory::set(&header.result, 1);
+        SharedMemory::set(&header.result, 0);
+        match SharedMemory::get(&header.command) {
+            COMMAND_PROCESS => {
+                events.clear();
+                shm.read_events(SharedMemory::get(&header.event_count) as usize, &mut events);
+
+                input.clear();
+                input.resize((SharedMemory::get(&header.input_len) as usize).min(MAX_SAMPLES), 0.0);
+                shm.read_input(&mut input);
+                output.clear();
+                output.resize((SharedMemory::get(&header.output_len) as usize).min(MAX_SAMPLES), 0.0);
+
+                plugin.process(&events, &input, &mut output);
+                shm.write_output(&output);
+            },
+            COMMAND_ENABLE => plugin.enable(),
+            COMMAND_DISABLE => plugin.disable(),
+            COMMAND_SHOW_GUI => plugin.show_gui(SharedMemory::get(&header.arg) != 0),
+            COMMAND_GET_PARAMS => shm.write_data(encode_params(&plugin.get_params()).as_bytes()),
+            COMMAND_CONFIGURE => {
+                let data = shm.read_data();
+                if data.len() == 9 {
+                    let samplerate = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    let sample_size = u32::from_le_bytes([data[5], data[6], data[7], data[8]]);
+                    plugin.configure(samplerate, data[4], sample_size);
+                }
+            },
+            COMMAND_SET_PARAM => {
+                let data = shm.read_data();
+                let result = if data.len() == 16 {
+                    let index = u64::from_le_bytes(data[0..8].try_into().unwrap()) as usize;
+                    let value = f64::from_le_bytes(data[8..16].try_into().unwrap());
+                    plugin.set_param(index, value)
+                } else {
+                    Err(PluginError::NoSuchParameter(0))
+                };
+                if let Err(err) = result {
+                    shm.write_data(format!("{}", err).as_bytes());
+                    fail();
+                }
+            },
+            COMMAND_SAVE_STATE => match plugin.save_state() {
+                Ok(state) if state.len() <= DATA_SIZE => shm.write_data(&state),
+                Ok(_) => {
+                    shm.write_data(b"State is too large to be bridged");
+                    fail();
+                },
+                Err(err) => {
+                    shm.write_data(format!("{}", err).as_bytes());
+                    fail();
+                }
+            },
+            COMMAND_LOAD_STATE => {
+                let state = shm.read_data();
+                if let Err(err) = plugin.load_state(&state) {
+                    shm.write_data(format!("{}", err).as_bytes());
+                    fail();
+                }
+            },
+            COMMAND_SHUTDOWN => {
+                drop(plugin);
+                shm.state().store(STATE_DONE, Ordering::Release);
+                return 0
+            },
+            _ => fail(),
+        }
+
+        shm.state().store(STATE_DONE, Ordering::Release);
+    }
+}
@@ -1,20 +1,55 @@
-// Ye Olde ADSR envelope
+// Ye Olde ADSR envelope, with delay and hold stages. Stage times are in seconds.
 
-// TODO fix stage times, so they are in seconds
-// As of now, decay_time of 1.0 takes 9.210182 seconds to decay from 1 to 0
+// Shape of a stage, as the fraction of the way travelled against the fraction of time passed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    Exponential, // fast at first then slowing down, like an analog envelope
+    Custom(f32), // 0 is linear, positive bends like Exponential, negative the other way
+}
 
-/* const T60: f32 = 10.0f32.powf(-60.0/20.0);
-const T80: f32 = 10.0f32.powf(-80.0/20.0); */
+impl Curve {
+    fn apply(self, t: f32) -> f32 {
+        let k = match self {
+            Curve::Linear => return t,
+            Curve::Exponential => 5.0,
+            Curve::Custom(k) => k,
+        };
+        if k.abs() < 0.001 {
+            return t
+        }
+        (1.0 - (-k * t).exp()) / (1.0 - (-k).exp())
+    }
+}
+
+// What triggering a voice that's still sounding does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retrigger {
+    Reset, // start over from zero
+    Legato, // keep going if held, attack from the current level if released
+}
 
 pub struct Adsr {
-    attack_time: f32,
-    decay_time: f32,
-    sustain_level: f32,
-    release_time: f32,
+    pub delay_time: f32,
+    pub attack_time: f32,
+    pub hold_time: f32,
+    pub decay_time: f32,
+    pub sustain_level: f32,
+    pub release_time: f32,
+
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
+    pub retrigger: Retrigger,
+    pub velocity_sensitivity: f32, // 0 ignores velocity, 1 scales the whole envelope by it
+
     sample_rate: f32,
-    smoothing_factor: f32,
     state: EnvelopeState,
+    position: u32, // samples into the current stage
+    length: u32, // of the current stage, in samples
+    start: f32, // level the current stage started from
     value: f32,
+    scale: f32, // from velocity
 }
 
 impl Adsr {
@@ -26,114 +61,175 @@ impl Adsr {
         sample_rate: f32,
     ) -> Self {
         Self {
+            delay_time: 0.0,
             attack_time,
+            hold_time: 0.0,
             decay_time,
             sustain_level,
             release_time,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Exponential,
+            release_curve: Curve::Exponential,
+            retrigger: Retrigger::Reset,
+            velocity_sensitivity: 0.0,
             sample_rate,
             state: EnvelopeState::Idle,
+            position: 0,
+            length: 0,
+            start: 0.0,
             value: 0.0,
-            smoothing_factor: 1.0,
+            scale: 1.0,
         }
     }
 
-    pub fn trigger(&mut self) {
-        self.state = EnvelopeState::Attack;
-        self.smoothing_factor = (1.0 / self.attack_time) / self.sample_rate;
+    // velocity is 0..1
+    pub fn trigger(&mut self, velocity: f32) {
+        let held = !matches!(self.state, EnvelopeState::Idle | EnvelopeState::Release);
+        if self.retrigger == Retrigger::Legato && held {
+            return
+        }
+
+        self.scale = 1.0 - self.velocity_sensitivity.clamp(0.0, 1.0) * (1.0 - velocity.clamp(0.0, 1.0));
+        if self.retrigger == Retrigger::Legato && self.state == EnvelopeState::Release {
+            self.enter(EnvelopeState::Attack);
+        } else {
+            self.value = 0.0;
+            self.enter(EnvelopeState::Delay);
+        }
     }
 
     pub fn release(&mut self) {
-        self.state = EnvelopeState::Release;
-        self.smoothing_factor = (1.0 / self.release_time) / self.sample_rate;
+        if self.state != EnvelopeState::Idle {
+            self.enter(EnvelopeState::Release);
+        }
     }
 
     pub fn is_idle(&self) -> bool {
         self.state == EnvelopeState::Idle
     }
 
+    fn enter(&mut self, state: EnvelopeState) {
+        let seconds = match state {
+            EnvelopeState::Delay => self.delay_time,
+            EnvelopeState::Attack => self.attack_time,
+            EnvelopeState::Hold => self.hold_time,
+            EnvelopeState::Decay => self.decay_time,
+            EnvelopeState::Release => self.release_time,
+            EnvelopeState::Sustain | EnvelopeState::Idle => 0.0,
+        };
+        self.state = state;
+        self.position = 0;
+        self.length = (seconds.max(0.0) * self.sample_rate).round() as u32;
+        self.start = self.value;
+    }
+
     pub fn process(&mut self) -> f32 {
-        match self.state {
-            EnvelopeState::Idle => {
-                self.value = 0.0;
-            }
-            EnvelopeState::Attack => {
-                self.value += (1.0 - self.value) * self.smoothing_factor;
-                if self.value >= 0.999 {
+        // Move on from finished stages, ones with no length take no time
+        while self.position >= self.length {
+            match self.state {
+                EnvelopeState::Delay => self.enter(EnvelopeState::Attack),
+                EnvelopeState::Attack => {
                     self.value = 1.0;
-                    self.state = EnvelopeState::Decay;
-                    self.smoothing_factor = (1.0 / self.decay_time) / self.sample_rate;
+                    self.enter(EnvelopeState::Hold);
                 }
-            }
-            EnvelopeState::Decay => {
-                self.value += (self.sustain_level - self.value) * self.smoothing_factor;
-                if self.value <= self.sustain_level {
+                EnvelopeState::Hold => self.enter(EnvelopeState::Decay),
+                EnvelopeState::Decay => {
                     self.value = self.sustain_level;
-                    self.state = EnvelopeState::Sustain;
+                    self.enter(EnvelopeState::Sustain);
+                    break
                 }
+                EnvelopeState::Release => {
+                    self.value = 0.0;
+                    self.enter(EnvelopeState::Idle);
+                    break
+                }
+                EnvelopeState::Sustain | EnvelopeState::Idle => break,
             }
+        }
+
+        let (target, curve) = match self.state {
+            EnvelopeState::Attack => (1.0, self.attack_curve),
+            EnvelopeState::Decay => (self.sustain_level, self.decay_curve),
+            EnvelopeState::Release => (0.0, self.release_curve),
             EnvelopeState::Sustain => {
                 self.value = self.sustain_level;
+                return self.value * self.scale
             }
-            EnvelopeState::Release => {
-                self.value += (0.0 - self.value) * self.smoothing_factor;
-                if self.value <= 0.0 {
-                    self.value = 0.0;
-                    self.state = EnvelopeState::Idle;
-                }
+            EnvelopeState::Idle => {
+                self.value = 0.0;
+                return 0.0
             }
-        }
-        self.value
+            EnvelopeState::Delay | EnvelopeState::Hold => {
+                self.position += 1;
+                return self.value * self.scale
+            }
+        };
+
+        // Lands exactly on the target at the end of the stage
+        self.position += 1;
+        let t = self.position as f32 / self.length as f32;
+        self.value = self.start + (target - self.start) * curve.apply(t);
+        self.value * self.scale
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum EnvelopeState {
     Idle,
+    Delay,
     Attack,
+    Hold,
     Decay,
     Sustain,
     Release,
 }
 
-// "Classic" envelope, exists there solely for compatibility with old tracker files
-struct ClassicEnvelopePoint {
-    tick: u8,
-    level: f32,
+// "Classic" envelope, exists there solely for compatibility with old tracker files.
+// Advances once per tick like IT/XM do, at tempo * 2 / 5 ticks per second, and interpolates in between.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClassicEnvelopePoint {
+    pub tick: u16,
+    pub level: f32,
 }
 
-struct ClassicEnvelope {
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClassicEnvelope {
     pub points: Vec<ClassicEnvelopePoint>,
     pub env_loop: (usize, usize),
     pub env_sustain: (usize, usize),
     pub env_loop_enabled: bool,
     pub env_sustain_enabled: bool,
+    pub carry: bool, // a new note continues where the previous one was instead of restarting
+    pub fadeout: f32, // fraction of the volume lost per tick after release, 0 for none
 
-    pub tickrate: u8,
-    pub tempo: u8,
+    pub tickrate: u8, // speed (ticks per row), doesn't affect how fast the envelope runs
+    pub tempo: u16,
     pub samplerate: u32,
 
-    samples_passed: u16,
-    current_tick: u8,
-    tick_length: u16,
+    samples_passed: u32,
+    current_tick: u16,
+    tick_length: u32,
+    fade: f32,
 
     triggered: bool,
     playing: bool,
 }
 
 impl ClassicEnvelope {
-    pub fn new(tickrate: u8, tempo: u8, samplerate: u32) -> Self {
-        let tick_length = ((60.0 / tempo as f32) * samplerate as f32) as u16;
-
+    pub fn new(tickrate: u8, tempo: u16, samplerate: u32) -> Self {
         Self {
             points: Vec::new(),
             env_loop: (0, 0), // Envelope loop begin and end
             env_sustain: (0, 0), // Sustain loop begin and end
+            carry: false,
+            fadeout: 0.0,
             tickrate,
             tempo,
             samplerate,
             samples_passed: 0,
             current_tick: 0,
-            tick_length,
+            tick_length: Self::tick_length(tempo, samplerate),
+            fade: 1.0,
             triggered: false,
             playing: false,
             env_loop_enabled: false,
@@ -141,43 +237,115 @@ impl ClassicEnvelope {
         }
     }
 
-    pub fn change_tempo(&mut self, tempo: u8) {
+    // Approximates an AHDSR envelope (times in seconds) with points, for instruments imported from SFZ/SF2.
+    // Ticks run at the IT rate for 125 BPM, 50 per second.
+    pub fn from_adsr(attack: f32, hold: f32, decay: f32, sustain: f32, release: f32, samplerate: u32) -> Self {
+        const TICKS_PER_SECOND: f32 = 125.0 * 2.0 / 5.0;
+
+        let mut env = Self::new(6, 125, samplerate);
+        let mut tick: u32 = 0;
+        let mut add_point = |env: &mut Self, seconds: f32, level: f32| {
+            // Points must be at increasing ticks
+            let ticks = ((seconds * TICKS_PER_SECOND).round() as u32).max(1);
+            tick = (tick + ticks).min(u16::MAX as u32);
+            if env.points.last().map_or(true, |last| (last.tick as u32) < tick) {
+                env.points.push(ClassicEnvelopePoint { tick: tick as u16, level });
+            }
+        };
+
+        env.points.push(ClassicEnvelopePoint { tick: 0, level: if attack > 0.0 { 0.0 } else { 1.0 } });
+        if attack > 0.0 {
+            add_point(&mut env, attack, 1.0);
+        }
+        if hold > 0.0 {
+            add_point(&mut env, hold, 1.0);
+        }
+        add_point(&mut env, decay, sustain);
+
+        let sustain_point = env.points.len() - 1;
+        env.env_sustain = (sustain_point, sustain_point);
+        env.env_sustain_enabled = true;
+        add_point(&mut env, release, 0.0);
+
+        env
+    }
+
+    // In samples, IT and XM run at tempo * 2 / 5 ticks per second
+    fn tick_length(tempo: u16, samplerate: u32) -> u32 {
+        ((samplerate as u64 * 5) / (tempo.max(1) as u64 * 2)).max(1) as u32
+    }
+
+    pub fn change_tempo(&mut self, tempo: u16) {
         self.tempo = tempo;
-        self.tick_length = ((60.0 / tempo as f32) * self.samplerate as f32) as u16;
+        self.tick_length = Self::tick_length(tempo, self.samplerate);
+        self.samples_passed = self.samples_passed.min(self.tick_length - 1);
+    }
+
+    pub fn change_samplerate(&mut self, samplerate: u32) {
+        self.samplerate = samplerate;
+        self.change_tempo(self.tempo);
+    }
+
+    // Where the envelope goes after the current tick, following the sustain loop while held and the loop otherwise
+    fn next_tick(&self) -> u16 {
+        let tick = self.current_tick.saturating_add(1);
+
+        let active_loop = if self.env_sustain_enabled && self.triggered {
+            Some(self.env_sustain)
+        } else if self.env_loop_enabled {
+            Some(self.env_loop)
+        } else {
+            None
+        };
+        if let Some((start, end)) = active_loop {
+            if let (Some(start), Some(end)) = (self.points.get(start), self.points.get(end)) {
+                // Only jump back if we were inside the loop, a loop ending before the current tick is left alone
+                if self.current_tick <= end.tick && tick > end.tick {
+                    return start.tick
+                }
+            }
+        }
+
+        // Stay at the last point
+        match self.points.last() {
+            Some(last) if tick > last.tick => self.current_tick.max(last.tick),
+            _ => tick,
+        }
     }
 
     pub fn process(&mut self) -> f32 {
+        if self.points.is_empty() {
+            return 0.0
+        }
         if !self.playing {
             return self.points[0].level;
         }
 
+        // Interpolate towards the next tick, so steps between points don't zipper
+        let next_tick = self.next_tick();
+        let ratio = self.samples_passed as f32 / self.tick_length as f32;
+        let current = self.value(self.current_tick);
+        let value = current + (self.value(next_tick) - current) * ratio;
+
+        self.samples_passed += 1;
         if self.samples_passed >= self.tick_length {
-            self.current_tick += 1;
+            self.samples_passed = 0;
+            self.current_tick = next_tick;
 
-            // Stop envelope when it's at the last point
-            if self.current_tick > self.points[self.points.len()-1].tick {
-                self.playing = false;
-                self.current_tick = 0;
-            }
-            
-            if self.env_sustain_enabled && self.triggered {
-                // Jump to the beginning of the loop
-                if self.current_tick > self.points[self.env_sustain.1].tick {
-                    self.current_tick = self.points[self.env_sustain.0].tick
-                }
-            } else if self.env_loop_enabled {
-                // Jump to the beginning of the loop
-                if self.current_tick > self.points[self.env_loop.1].tick {
-                    self.current_tick = self.points[self.env_loop.0].tick
-                }
+            if !self.triggered {
+                self.fade = (self.fade - self.fadeout).max(0.0);
             }
         }
-        self.value(self.current_tick)
+
+        value
     }
 
-    fn value(&self, tick: u8) -> f32 {
-        if tick <= self.points[0].tick {
-            return self.points[0].level;
+    fn value(&self, tick: u16) -> f32 {
+        let Some(first) = self.points.first() else {
+            return 0.0
+        };
+        if tick <= first.tick {
+            return first.level;
         }
 
         for i in 1..self.points.len() {
@@ -185,9 +353,9 @@ impl ClassicEnvelope {
             let next_point = &self.points[i];
 
             if tick < next_point.tick {
-                let tick_diff = next_point.tick - prev_point.tick;
+                let tick_diff = next_point.tick.saturating_sub(prev_point.tick).max(1);
                 let level_diff = next_point.level - prev_point.level;
-                let tick_ratio = (tick - prev_point.tick) as f32 / tick_diff as f32;
+                let tick_ratio = tick.saturating_sub(prev_point.tick) as f32 / tick_diff as f32;
 
                 return prev_point.level + (level_diff * tick_ratio);
             }
@@ -196,12 +364,272 @@ impl ClassicEnvelope {
         return self.points[self.points.len() - 1].level;
     }
 
-    fn trigger(&mut self) {
+    // Multiplier from the fade-out, only goes down after release
+    pub fn fade(&self) -> f32 {
+        self.fade
+    }
+
+    // Whether the envelope reached its last point with no loop left to take it back
+    pub fn finished(&self) -> bool {
+        let Some(last) = self.points.last() else {
+            return true
+        };
+        let looping = (self.env_sustain_enabled && self.triggered) || self.env_loop_enabled;
+        !self.playing || (!looping && self.current_tick >= last.tick)
+    }
+
+    pub fn trigger(&mut self) {
+        if !(self.carry && self.playing) {
+            self.current_tick = 0;
+            self.samples_passed = 0;
+        }
+        self.fade = 1.0;
         self.playing = true;
         self.triggered = true;
     }
 
-    fn release(&mut self) {
+    // Carries on from another envelope's position, if carry is on. Call before trigger().
+    pub fn carry_from(&mut self, other: &ClassicEnvelope) {
+        if self.carry && other.playing {
+            self.current_tick = other.current_tick;
+            self.samples_passed = other.samples_passed.min(self.tick_length - 1);
+            self.playing = true;
+        }
+    }
+
+    pub fn release(&mut self) {
         self.triggered = false;
     }
 }
+
+#[cfg(test)]
+mod adsr_tests {
+    use super::*;
+
+    const SAMPLE_RATES: [f32; 3] = [44100.0, 48000.0, 96000.0];
+
+    // Returns the last value
+    fn run(env: &mut Adsr, samples: u32) -> f32 {
+        (0..samples).map(|_| env.process()).last().unwrap_or(0.0)
+    }
+
+    fn samples(seconds: f32, sample_rate: f32) -> u32 {
+        (seconds * sample_rate).round() as u32
+    }
+
+    #[test]
+    fn stage_durations() {
+        for sample_rate in SAMPLE_RATES {
+            let (attack, decay, release) = (samples(0.01, sample_rate), samples(0.02, sample_rate), samples(0.03, sample_rate));
+            let mut env = Adsr::new(0.01, 0.02, 0.5, 0.03, sample_rate);
+            env.trigger(1.0);
+
+            assert!(run(&mut env, attack - 1) < 1.0);
+            assert_eq!(env.process(), 1.0);
+            assert!(run(&mut env, decay - 1) > 0.5);
+            assert_eq!(env.process(), 0.5);
+            assert_eq!(run(&mut env, 1000), 0.5);
+
+            env.release();
+            assert!(run(&mut env, release - 1) > 0.0);
+            assert_eq!(env.process(), 0.0);
+            env.process();
+            assert!(env.is_idle());
+        }
+    }
+
+    #[test]
+    fn delay_and_hold() {
+        for sample_rate in SAMPLE_RATES {
+            let (delay, attack, hold) = (samples(0.005, sample_rate), samples(0.01, sample_rate), samples(0.015, sample_rate));
+            let mut env = Adsr::new(0.01, 0.02, 0.5, 0.03, sample_rate);
+            env.delay_time = 0.005;
+            env.hold_time = 0.015;
+            env.trigger(1.0);
+
+            assert_eq!(run(&mut env, delay), 0.0);
+            assert!(env.process() > 0.0);
+            assert_eq!(run(&mut env, attack - 1), 1.0);
+            assert_eq!(run(&mut env, hold), 1.0);
+            assert!(env.process() < 1.0);
+        }
+    }
+
+    #[test]
+    fn retrigger_reset_starts_over() {
+        for sample_rate in SAMPLE_RATES {
+            let attack = samples(0.01, sample_rate);
+            let mut env = Adsr::new(0.01, 0.02, 0.5, 0.03, sample_rate);
+            env.trigger(1.0);
+            run(&mut env, attack * 2);
+            env.release();
+            run(&mut env, 10);
+
+            env.trigger(1.0);
+            assert_eq!(env.process(), 1.0 / attack as f32);
+        }
+    }
+
+    #[test]
+    fn retrigger_legato() {
+        for sample_rate in SAMPLE_RATES {
+            let attack = samples(0.01, sample_rate);
+            let mut env = Adsr::new(0.01, 0.02, 0.5, 0.03, sample_rate);
+            env.retrigger = Retrigger::Legato;
+            env.trigger(1.0);
+
+            // Held, the envelope carries on
+            run(&mut env, attack / 2);
+            let level = env.process();
+            env.trigger(1.0);
+            assert!(env.process() > level);
+            assert_eq!(run(&mut env, attack - attack / 2 - 2), 1.0); // still peaks on time
+
+            // Released, it attacks from where it was
+            run(&mut env, samples(0.1, sample_rate));
+            env.release();
+            let level = run(&mut env, 10);
+            env.trigger(1.0);
+            let next = env.process();
+            assert!(next > level && next < 1.0);
+        }
+    }
+
+    #[test]
+    fn velocity_scaling() {
+        for sample_rate in SAMPLE_RATES {
+            let sustained = |sensitivity: f32, velocity: f32| {
+                let mut env = Adsr::new(0.01, 0.02, 0.5, 0.03, sample_rate);
+                env.velocity_sensitivity = sensitivity;
+                env.trigger(velocity);
+                run(&mut env, samples(0.1, sample_rate))
+            };
+            assert_eq!(sustained(0.0, 0.25), 0.5);
+            assert_eq!(sustained(1.0, 0.5), 0.25);
+            assert_eq!(sustained(0.5, 0.0), 0.25);
+            assert_eq!(sustained(1.0, 1.0), 0.5);
+        }
+    }
+}
+
+#[cfg(test)]
+mod classic_tests {
+    use super::*;
+
+    // Points at the given ticks, 2 samples per tick at 125 BPM
+    fn envelope(points: &[(u16, f32)]) -> ClassicEnvelope {
+        let mut env = ClassicEnvelope::new(6, 125, 100);
+        env.points = points.iter().map(|&(tick, level)| ClassicEnvelopePoint { tick, level }).collect();
+        env
+    }
+
+    fn run_ticks(env: &mut ClassicEnvelope, ticks: u32) {
+        for _ in 0..ticks * env.tick_length {
+            env.process();
+        }
+    }
+
+    #[test]
+    fn tick_length_at_125_bpm() {
+        // 50 ticks per second
+        for (samplerate, length) in [(44100, 882), (48000, 960)] {
+            let mut env = ClassicEnvelope::new(6, 125, samplerate);
+            env.points = vec![ClassicEnvelopePoint { tick: 0, level: 0.0 }, ClassicEnvelopePoint { tick: 1, level: 1.0 }];
+            assert_eq!(env.tick_length, length);
+
+            env.trigger();
+            let values: Vec<f32> = (0..length + 1).map(|_| env.process()).collect();
+            assert_eq!(values[0], 0.0);
+            assert!((values[length as usize / 2] - 0.5).abs() < 1e-6);
+            assert!(values[length as usize - 1] < 1.0);
+            assert_eq!(values[length as usize], 1.0);
+        }
+    }
+
+    #[test]
+    fn tick_length_follows_tempo_and_samplerate() {
+        let mut env = ClassicEnvelope::new(6, 125, 44100);
+        env.change_tempo(250);
+        assert_eq!(env.tick_length, 441);
+        env.change_samplerate(48000);
+        assert_eq!(env.tick_length, 480);
+    }
+
+    #[test]
+    fn loop_goes_back_to_start() {
+        let mut env = envelope(&[(0, 0.0), (2, 1.0), (4, 0.5)]);
+        env.env_loop = (0, 1);
+        env.env_loop_enabled = true;
+        env.trigger();
+
+        let mut ticks = Vec::new();
+        for _ in 0..6 {
+            ticks.push(env.current_tick);
+            run_ticks(&mut env, 1);
+        }
+        assert_eq!(ticks, [0, 1, 2, 0, 1, 2]);
+
+        // Still looping after release
+        env.release();
+        run_ticks(&mut env, 5);
+        assert!(env.current_tick <= 2);
+        assert!(!env.finished());
+    }
+
+    #[test]
+    fn sustain_loop_holds_until_release() {
+        let mut env = envelope(&[(0, 1.0), (1, 0.5), (3, 0.0)]);
+        env.env_sustain = (1, 1);
+        env.env_sustain_enabled = true;
+        env.trigger();
+
+        run_ticks(&mut env, 10);
+        assert_eq!(env.current_tick, 1);
+        assert_eq!(env.process(), 0.5);
+        assert!(!env.finished());
+
+        env.release();
+        run_ticks(&mut env, 3);
+        assert_eq!(env.current_tick, 3);
+        assert_eq!(env.process(), 0.0);
+        assert!(env.finished());
+    }
+
+    #[test]
+    fn carry_continues_from_previous_note() {
+        let mut first = envelope(&[(0, 0.0), (10, 1.0)]);
+        first.carry = true;
+        let mut second = first.clone();
+        first.trigger();
+        run_ticks(&mut first, 4);
+
+        second.carry_from(&first);
+        second.trigger();
+        assert_eq!(second.current_tick, 4);
+
+        // Without carry the new note starts over
+        let mut third = envelope(&[(0, 0.0), (10, 1.0)]);
+        third.carry_from(&first);
+        third.trigger();
+        assert_eq!(third.current_tick, 0);
+    }
+
+    #[test]
+    fn fadeout_only_after_release() {
+        let mut env = envelope(&[(0, 1.0)]);
+        env.fadeout = 0.25;
+        env.trigger();
+        run_ticks(&mut env, 4);
+        assert_eq!(env.fade(), 1.0);
+
+        env.release();
+        run_ticks(&mut env, 2);
+        assert_eq!(env.fade(), 0.5);
+        run_ticks(&mut env, 4);
+        assert_eq!(env.fade(), 0.0);
+
+        // A new note brings the volume back
+        env.trigger();
+        assert_eq!(env.fade(), 1.0);
+    }
+}
@@ -1 +1,2 @@
-pub(crate) mod plugin;
\ No newline at end of file
+pub(crate) mod plugin;
+pub(crate) mod output;
\ No newline at end of file
@@ -0,0 +1,152 @@
+// Sends MIDI at the right time from its own thread, and keeps the port connected as devices come and go.
+// process() runs ahead of what's being heard, so messages are queued with the time they should go out at.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use midir::{MidiOutput, MidiOutputConnection};
+
+pub const CLIENT_NAME: &str = "Project Corrosion MIDI Out";
+const CONNECTION_NAME: &str = "corrosion-midi";
+const POLL_INTERVAL: Duration = Duration::from_millis(500); // how often to look for ports appearing or disappearing
+const MAX_MESSAGE_LEN: usize = 10; // an MTC full frame, the longest message we send
+
+// Sent from the audio thread, so it's kept out of the heap
+#[derive(Clone, Copy)]
+pub struct Message {
+    bytes: [u8; MAX_MESSAGE_LEN],
+    len: u8,
+}
+
+impl Message {
+    // Anything past MAX_MESSAGE_LEN is cut off
+    pub fn new(message: &[u8]) -> Self {
+        let len = message.len().min(MAX_MESSAGE_LEN);
+        let mut bytes = [0; MAX_MESSAGE_LEN];
+        bytes[..len].copy_from_slice(&message[..len]);
+        Self { bytes, len: len as u8 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+pub enum Command {
+    Send(Instant, Message), // when, message
+    Connect(String), // port name
+}
+
+pub enum Status {
+    Ports(Vec<String>),
+    Connected(String),
+    Disconnected(String),
+    Error(String),
+}
+
+pub fn port_names(midi_out: &MidiOutput) -> Vec<String> {
+    midi_out.ports().iter().filter_map(|port| midi_out.port_name(port).ok()).collect()
+}
+
+// Connects to a port by name
+pub fn connect(name: &str) -> Result<MidiOutputConnection, String> {
+    let midi_out = MidiOutput::new(CLIENT_NAME).map_err(|err| err.to_string())?;
+    let ports = midi_out.ports();
+    let port = ports.iter()
+        .find(|port| midi_out.port_name(port).map_or(false, |port_name| port_name == name))
+        .ok_or(format!("There is no MIDI port named {name}"))?;
+    midi_out.connect(port, CONNECTION_NAME).map_err(|err| err.to_string())
+}
+
+// wanted is the port to keep connected to, None takes the first one that shows up
+pub fn spawn(wanted: Option<String>) -> Result<(Sender<Command>, Receiver<Status>), String> {
+    let lister = MidiOutput::new(CLIENT_NAME).map_err(|err| err.to_string())?;
+    let (command_tx, command_rx) = mpsc::channel();
+    let (status_tx, status_rx) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("midi-out".to_string())
+        .spawn(move || run(lister, wanted, command_rx, status_tx))
+        .map_err(|err| err.to_string())?;
+
+    Ok((command_tx, status_rx))
+}
+
+fn run(lister: MidiOutput, mut wanted: Option<String>, commands: Receiver<Command>, status: Sender<Status>) {
+    let mut connection: Option<(String, MidiOutputConnection)> = None;
+    let mut queue: VecDeque<(Instant, Message)> = VecDeque::new();
+    let mut ports: Vec<String> = Vec::new();
+    let mut last_poll: Option<Instant> = None;
+
+    loop {
+        let now = Instant::now();
+        let timeout = match queue.front() {
+            Some((time, _)) => time.saturating_duration_since(now).min(POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        };
+
+        match commands.recv_timeout(timeout) {
+            Ok(Command::Send(time, message)) => {
+                // Keep the queue in time order, messages for the same time stay in the order they came in
+                let index = queue.iter().rposition(|(queued, _)| *queued <= time).map_or(0, |i| i + 1);
+                queue.insert(index, (time, message));
+            },
+            Ok(Command::Connect(name)) => {
+                connection = None;
+                wanted = Some(name);
+                last_poll = None; // connect right away
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break, // the plugin is gone
+        }
+
+        let now = Instant::now();
+        while queue.front().map_or(false, |(time, _)| *time <= now) {
+            let (_, message) = queue.pop_front().unwrap();
+            if let Some((_, conn)) = &mut connection {
+                if let Err(err) = conn.send(message.as_bytes()) {
+                    let _ = status.send(Status::Error(err.to_string()));
+                }
+            }
+        }
+
+        if last_poll.map_or(true, |time| time.elapsed() >= POLL_INTERVAL) {
+            last_poll = Some(now);
+
+            let current = port_names(&lister);
+            if current != ports {
+                ports = current;
+                let _ = status.send(Status::Ports(ports.clone()));
+            }
+
+            // Unplugged
+            if let Some((name, _)) = &connection {
+                if !ports.contains(name) {
+                    let _ = status.send(Status::Disconnected(name.clone()));
+                    connection = None;
+                    queue.clear();
+                }
+            }
+
+            // Plugged (back) in
+            if connection.is_none() {
+                let port = match &wanted {
+                    Some(name) => ports.iter().find(|port| *port == name),
+                    None => ports.first(),
+                };
+                if let Some(name) = port.cloned() {
+                    match connect(&name) {
+                        Ok(conn) => {
+                            let _ = status.send(Status::Connected(name.clone()));
+                            wanted = Some(name.clone());
+                            connection = Some((name, conn));
+                        },
+                        Err(err) => { let _ = status.send(Status::Error(err)); },
+                    }
+                }
+            }
+        }
+    }
+}
@@ -6,83 +6,337 @@ const PROGRAM_CHANGE: u8 = 0xC0;
 const CHANNEL_AFTERTOUCH: u8 = 0xD0;
 const PITCH_BEND: u8 = 0xE0;
 
-use midir::{MidiOutputPort, MidiOutputConnection};
-use crate::engine::{plugins::interface::{Plugin, Event, Parameter, PluginError, TimedEvent, NoteState}};
+const CC_COUNT: usize = 120; // CCs above 119 are channel mode messages
+const CC_VOLUME: u8 = 7;
+const CC_TIMBRE: u8 = 74; // MPE's third dimension
+const STATE_VERSION: u8 = 1;
+
+// Settings, after the CCs
+const MPE_ENABLED: usize = CC_COUNT;
+const MPE_BEND_RANGE: usize = CC_COUNT + 1;
+const BEND_RANGE: usize = CC_COUNT + 2;
+const CHANNEL: usize = CC_COUNT + 3;
+const MPE_ZONE: usize = CC_COUNT + 4;
+const MPE_MEMBERS: usize = CC_COUNT + 5;
+const LATENCY: usize = CC_COUNT + 6;
+const PARAM_COUNT: usize = CC_COUNT + 7;
+
+const MAX_LATENCY: f32 = 500.0; // ms, either way
+
+use std::sync::mpsc::{Sender, Receiver};
+use std::time::{Duration, Instant};
+
+use crate::engine::{plugins::interface::{Plugin, Event, Parameter, ParameterKind, PluginError, TimedEvent, PluginDescriptor}};
+use super::output::{self, Command, Message, Status};
+
+#[derive(Clone, Copy)]
+struct ActiveNote {
+    id: usize,
+    channel: u8,
+    key: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MpeZone {
+    Lower, // manager on channel 1, members counting up from channel 2
+    Upper, // manager on channel 16, members counting down from channel 15
+}
 
 pub struct MidiOutPlugin {
     pub mpe_enabled: bool,
     pub mpe_bend_range: u8,
+    pub mpe_zone: MpeZone,
+    pub mpe_members: u8, // member channels in the zone, 1..=15
+    pub bend_range: u8, // in semitones, for pitch expressions without MPE
+    pub channel: u8, // 0..=15, for instruments without a channel of their own and the CC parameters
+    pub channel_map: Vec<Option<u8>>, // channel of each instrument, by module index
+
+    pub latency: f32, // ms, added to when messages go out so they line up with the audio; negative sends them early
 
-    ports: Vec<MidiOutputPort>,
-    state: Vec<NoteState>,
-    midi_conn: midir::MidiOutputConnection,
+    ports: Vec<String>,
+    port: Option<String>, // connected to
+    wanted: Option<String>, // last asked for, None until a port is picked
+    error: Option<PluginError>,
+    output: Sender<Command>,
+    status: Receiver<Status>,
+    send_at: Instant, // when the message being sent should go out
+    block_start: Instant,
+    notes: Vec<ActiveNote>, // oldest first
+    channel_released: [u64; 16], // when each channel last had a note released, for member allocation
+    event_counter: u64,
+    mpe_config_sent: bool, // the MPE Configuration Message has to be resent when the zone changes
+
+    // We cannot get MIDI CCs from the device, we can only remember what we've sent
+    cc_values: [Option<u8>; CC_COUNT],
+    samplerate: u32,
+    channels: u8,
+    sample_size: u32,
 }
 
 impl MidiOutPlugin {
-    pub fn get_ports(&self) -> &[MidiOutputPort] {
+    // Port names, kept up to date as devices are plugged in and out
+    pub fn get_ports(&self) -> &[String] {
         &self.ports
     }
 
-    pub fn change_port(&mut self, index: usize) {
-        // TODO error handling
-        let midi_out = midir::MidiOutput::new("Project Corrosion MIDI Out").unwrap();
-        let ports = midi_out.ports();
-        self.midi_conn = midi_out.connect(&ports[index], "corrosion-midi").unwrap();
-        self.ports = ports;
+    pub fn port(&self) -> Option<&str> {
+        self.port.as_deref()
+    }
+
+    // Connects to a port by name, it doesn't have to be plugged in yet. If it goes away, it's reconnected when it comes back.
+    pub fn change_port(&mut self, name: &str) -> Result<(), PluginError> {
+        if self.wanted.as_deref() == Some(name) {
+            return Ok(())
+        }
+        self.output.send(Command::Connect(name.to_string())).map_err(|_| PluginError::DeviceError("MIDI output thread has stopped".to_string()))?;
+        self.wanted = Some(name.to_string());
+        Ok(())
+    }
+
+    // Picks up port changes and errors from the output thread
+    fn update_status(&mut self) {
+        while let Ok(status) = self.status.try_recv() {
+            match status {
+                Status::Ports(ports) => self.ports = ports,
+                Status::Connected(name) => {
+                    self.port = Some(name);
+                    self.error = None;
+                    // A new device doesn't know our MPE zone
+                    self.mpe_config_sent = false;
+                },
+                Status::Disconnected(name) => {
+                    self.port = None;
+                    self.notes.clear();
+                    self.error = Some(PluginError::DeviceError(format!("MIDI port {name} has been disconnected")));
+                },
+                Status::Error(err) => self.error = Some(PluginError::DeviceError(err)),
+            }
+        }
+    }
+
+    // None makes the instrument use the default channel
+    pub fn set_channel(&mut self, module_index: usize, channel: Option<u8>) {
+        if self.channel_map.len() <= module_index {
+            self.channel_map.resize(module_index + 1, None);
+        }
+        self.channel_map[module_index] = channel.map(|channel| channel.min(15));
+    }
+
+    pub fn channel_for(&self, module_index: usize) -> u8 {
+        self.channel_map.get(module_index).copied().flatten().unwrap_or(self.channel)
+    }
+
+    // Queued to go out at send_at, errors come back through the status channel
+    fn send(&mut self, message: &[u8]) {
+        let _ = self.output.send(Command::Send(self.send_at, Message::new(message)));
+    }
+
+    // Audio rendered now is heard after the current buffer, so MIDI is delayed by that much plus the latency setting
+    fn event_time(&self, position: u32) -> Instant {
+        let offset = (self.sample_size + position) as f64 / self.samplerate as f64 + self.latency as f64 / 1000.0;
+        if offset > 0.0 {
+            self.block_start + Duration::from_secs_f64(offset)
+        } else {
+            self.block_start.checked_sub(Duration::from_secs_f64(-offset)).unwrap_or(self.block_start)
+        }
+    }
+
+    // 14-bit pitch bend as LSB and MSB, centered at 8192
+    fn pitch_bend(semitones: f32, range: u8) -> [u8; 2] {
+        let bend = (8192.0 + semitones / range.max(1) as f32 * 8192.0).round().clamp(0.0, 16383.0) as u16;
+        [(bend & 0x7F) as u8, (bend >> 7) as u8]
+    }
+
+    pub fn manager_channel(&self) -> u8 {
+        match self.mpe_zone {
+            MpeZone::Lower => 0,
+            MpeZone::Upper => 15,
+        }
+    }
+
+    pub fn member_channels(&self) -> Vec<u8> {
+        let members = self.mpe_members.clamp(1, 15);
+        match self.mpe_zone {
+            MpeZone::Lower => (1..=members).collect(),
+            MpeZone::Upper => (15 - members..15).rev().collect(),
+        }
+    }
+
+    fn send_rpn(&mut self, channel: u8, rpn: u8, value: u8) {
+        self.send(&[CONTROL_CHANGE | channel, 101, 0]);
+        self.send(&[CONTROL_CHANGE | channel, 100, rpn]);
+        self.send(&[CONTROL_CHANGE | channel, 6, value]);
+        self.send(&[CONTROL_CHANGE | channel, 38, 0]);
+        // Null RPN, so stray data entry doesn't change anything
+        self.send(&[CONTROL_CHANGE | channel, 101, 127]);
+        self.send(&[CONTROL_CHANGE | channel, 100, 127]);
+    }
+
+    // MPE Configuration Message (RPN 6 on the manager channel), then the member pitch bend range (RPN 0)
+    fn send_mpe_config(&mut self) {
+        let manager = self.manager_channel();
+        let members = self.member_channels();
+        self.send_rpn(manager, 6, members.len() as u8);
+        self.send_rpn(members[0], 0, self.mpe_bend_range);
+        self.mpe_config_sent = true;
+    }
+
+    // A member channel for a new note: the one free the longest, or the oldest note's if they're all taken
+    fn allocate_member(&mut self) -> u8 {
+        let members = self.member_channels();
+        let free = members.iter().copied()
+            .filter(|&channel| !self.notes.iter().any(|note| note.channel == channel))
+            .min_by_key(|&channel| self.channel_released[channel as usize]);
+        if let Some(channel) = free {
+            return channel
+        }
+
+        let oldest = self.notes.iter().position(|note| members.contains(&note.channel)).unwrap_or(0);
+        let note = self.notes.remove(oldest);
+        self.send(&[NOTE_OFF | note.channel, note.key, 0]);
+        note.channel
+    }
+
+    // Plain MIDI sends each instrument's notes to its channel, MPE gives every note a member channel of its own
+    fn process_event(&mut self, e: &TimedEvent) {
+        self.event_counter += 1;
+        let note = |notes: &[ActiveNote], id: usize| notes.iter().find(|note| note.id == id).copied();
+
+        match e.event {
+            Event::NoteOn { id, key, vel } => {
+                self.notes.retain(|note| note.id != id);
+                let channel = if self.mpe_enabled {
+                    let channel = self.allocate_member();
+                    // The channel may still be bent or pressed from the last note
+                    self.send(&[PITCH_BEND | channel, 0, 64]);
+                    self.send(&[CHANNEL_AFTERTOUCH | channel, 0]);
+                    channel
+                } else {
+                    self.channel_for(e.module_index)
+                };
+                self.notes.push(ActiveNote { id, channel, key });
+                self.send(&[NOTE_ON | channel, key, vel]);
+            },
+            Event::NoteOff { id, key, vel } => {
+                // Goes where the note went, even if the channel has been changed since
+                let channel = match self.notes.iter().position(|note| note.id == id) {
+                    Some(index) => self.notes.remove(index).channel,
+                    None if self.mpe_enabled => return,
+                    None => self.channel_for(e.module_index),
+                };
+                self.channel_released[channel as usize] = self.event_counter;
+                self.send(&[NOTE_OFF | channel, key, vel]);
+            },
+            // Zone-wide in MPE
+            Event::ControlChange { index, value } => {
+                let channel = if self.mpe_enabled { self.manager_channel() } else { self.channel_for(e.module_index) };
+                self.send(&[CONTROL_CHANGE | channel, index, value]);
+            },
+            // Bends the whole channel without MPE, there's nothing per-note
+            Event::ExprPitch { id, target_pitch } => {
+                if let Some(note) = note(&self.notes, id) {
+                    let range = if self.mpe_enabled { self.mpe_bend_range } else { self.bend_range };
+                    let [lsb, msb] = Self::pitch_bend(target_pitch, range);
+                    self.send(&[PITCH_BEND | note.channel, lsb, msb]);
+                }
+            },
+            Event::ExprVolume { id, target_vol } => {
+                if let Some(note) = note(&self.notes, id) {
+                    self.send(&[CONTROL_CHANGE | note.channel, CC_VOLUME, target_vol.min(127)]);
+                }
+            },
+            Event::ExprPressure { id, pressure } => {
+                if let Some(note) = note(&self.notes, id) {
+                    if self.mpe_enabled {
+                        self.send(&[CHANNEL_AFTERTOUCH | note.channel, pressure.min(127)]);
+                    } else {
+                        self.send(&[NOTE_AFTERTOUCH | note.channel, note.key, pressure.min(127)]);
+                    }
+                }
+            },
+            Event::ExprTimbre { id, timbre } => {
+                if let Some(note) = note(&self.notes, id) {
+                    self.send(&[CONTROL_CHANGE | note.channel, CC_TIMBRE, timbre.min(127)]);
+                }
+            },
+        }
+    }
+
+    // Where the CC parameters go
+    fn cc_channel(&self) -> u8 {
+        if self.mpe_enabled { self.manager_channel() } else { self.channel }
     }
 }
 
 impl Plugin for MidiOutPlugin {
+    // path is the name of the port to connect to, or empty for the first one available
     fn new(path: &str) -> Result<MidiOutPlugin, PluginError> {
-        let midi_out = match midir::MidiOutput::new("Project Corrosion MIDI Out") {
-            Ok(output) => output,
-            Err(err) => { return Err(PluginError::InitError(format!("{}", err))) },
-        };
-
-        let ports = midi_out.ports();
-        let midi_conn: MidiOutputConnection;
-        if ports.len() != 0 {
-            midi_conn = match midi_out.connect(&ports[0], "corrosion-midi") {
-                Ok(conn) => conn,
-                Err(err) => { return Err(PluginError::InitError(format!("{}", err))) },
-            };
-        } else {
-            return Err(PluginError::InitError("There are no available MIDI ports".to_string()))
-        };
+        let wanted = if path.is_empty() { None } else { Some(path.to_string()) };
+        let (output, status) = output::spawn(wanted.clone()).map_err(PluginError::InitError)?;
 
         Ok(MidiOutPlugin {
             mpe_enabled: true,
-            mpe_bend_range: 24, // two octaves
-            ports,
-            state: Vec::with_capacity(16),
-            midi_conn,
+            mpe_bend_range: 48, // MPE's default for member channels
+            mpe_zone: MpeZone::Lower,
+            mpe_members: 15,
+            bend_range: 2,
+            channel: 0,
+            channel_map: Vec::new(),
+            latency: 0.0,
+
+            ports: Vec::new(),
+            port: None,
+            wanted,
+            error: None,
+            output,
+            status,
+            send_at: Instant::now(),
+            block_start: Instant::now(),
+            notes: Vec::with_capacity(16),
+            channel_released: [0; 16],
+            event_counter: 0,
+            mpe_config_sent: false,
+
+            cc_values: [None; CC_COUNT],
+            samplerate: 48000,
+            channels: 2,
+            sample_size: 0,
         })
     }
 
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> {
+        Ok(vec![PluginDescriptor {
+            path: String::new(),
+            id: "corrosion.midi-out".to_string(),
+            name: "MIDI Out".to_string(),
+            vendor: "Polyzium Productions".to_string(),
+            version: "0.0.0".to_string(),
+            features: vec!["note-effect".to_string()],
+            audio_inputs: Vec::new(),
+            audio_outputs: Vec::new(),
+            note_inputs: 1,
+            note_outputs: 0,
+        }])
+    }
+
+    fn configure(&mut self, samplerate: u32, channels: u8, sample_size: u32) {
+        self.samplerate = samplerate;
+        self.channels = channels;
+        self.sample_size = sample_size;
+    }
+
     fn process(&mut self, events: &[TimedEvent], _input: &[f32], _output: &mut [f32]) {
+        self.update_status();
+        self.block_start = Instant::now();
+
+        if self.port.is_some() && self.mpe_enabled && !self.mpe_config_sent {
+            self.send_at = self.event_time(0);
+            self.send_mpe_config();
+        }
+
         for e in events {
-            if self.mpe_enabled {
-                // Note ID = channel ID, because MPE has 1 note per channel.
-                // MIDI has 16 channels per port, so 16 note expressive polyphony is guaranteed.
-                match e.event {
-                    Event::NoteOff { id, key, vel } => { self.midi_conn.send(&[NOTE_OFF | id as u8, key, vel]); },
-                    Event::NoteOn { id, key, vel } => { self.midi_conn.send(&[NOTE_ON | id as u8, key, vel]); },
-                    Event::ControlChange {..} => { println!("MidiOutPlugin: TODO: received ControlChange, but don't know to which channel to send!") }, // TODO figure this out. Probably all channels?
-                    Event::ExprPitch { id, target_pitch } => {
-                        let midi_pitch = (target_pitch) as u16;
-                        let u14 = (midi_pitch << 2) & 0x3FFF;
-
-                        let msb = (u14 >> 7) as u8;
-                        let lsb = (u14 & 0x7F) as u8;
-
-                        self.midi_conn.send(&[PITCH_BEND | id as u8, msb, lsb]);
-                    },
-                    // CC #7 is volume
-                    Event::ExprVolume { id, target_vol } => { self.midi_conn.send(&[CONTROL_CHANGE | id as u8, 7, target_vol]); },
-                }
-            } else {
-                todo!("Non-MPE MIDI is not implemented yet.")
-            }
+            self.send_at = self.event_time(e.position);
+            self.process_event(e);
         }
     }
 
@@ -95,21 +349,136 @@ impl Plugin for MidiOutPlugin {
     }
 
     fn get_params(&self) -> Vec<Parameter> {
-        let mut params: Vec<Parameter> = Vec::with_capacity(119); // a MIDI channel has 119 CCs
+        let mut params: Vec<Parameter> = Vec::with_capacity(PARAM_COUNT);
 
-        for cc in 0..=119 {
+        for cc in 0..CC_COUNT {
             params.push(Parameter {
                 index: cc,
                 name: format!("Continuous Controller #{cc}"),
-                value: 255, // we cannot get MIDI CCs in real time, we can only remember them. TODO I guess?
-                min: 0,
-                max: 127,
+                value: self.cc_values[cc].unwrap_or(0) as f64,
+                min: 0.0,
+                max: 127.0,
+                default: 0.0,
+                display: match self.cc_values[cc] {
+                    Some(value) => value.to_string(),
+                    None => "?".to_string(), // never sent
+                },
+                kind: ParameterKind::Stepped,
+                automatable: true,
+                read_only: false,
             });
         }
 
+        let setting = |index: usize, name: &str, value: u8, min: f64, max: f64, default: f64, kind: ParameterKind| Parameter {
+            index,
+            name: name.to_string(),
+            value: value as f64,
+            min,
+            max,
+            default,
+            display: match &kind {
+                ParameterKind::Enum(values) => values[value as usize].clone(),
+                _ if index == CHANNEL => (value + 1).to_string(),
+                _ if index == MPE_MEMBERS => value.to_string(),
+                _ => format!("{value} semitones"),
+            },
+            kind,
+            automatable: false,
+            read_only: false,
+        };
+        params.push(setting(MPE_ENABLED, "MPE", self.mpe_enabled as u8, 0.0, 1.0, 1.0, ParameterKind::Enum(vec!["Off".to_string(), "On".to_string()])));
+        params.push(setting(MPE_BEND_RANGE, "MPE Pitch Bend Range", self.mpe_bend_range, 1.0, 96.0, 48.0, ParameterKind::Stepped));
+        params.push(setting(BEND_RANGE, "Pitch Bend Range", self.bend_range, 1.0, 96.0, 2.0, ParameterKind::Stepped));
+        params.push(setting(CHANNEL, "Channel", self.channel, 0.0, 15.0, 0.0, ParameterKind::Stepped));
+        params.push(setting(MPE_ZONE, "MPE Zone", self.mpe_zone as u8, 0.0, 1.0, 0.0, ParameterKind::Enum(vec!["Lower".to_string(), "Upper".to_string()])));
+        params.push(setting(MPE_MEMBERS, "MPE Member Channels", self.mpe_members, 1.0, 15.0, 15.0, ParameterKind::Stepped));
+        params.push(Parameter {
+            index: LATENCY,
+            name: "Latency".to_string(),
+            value: self.latency as f64,
+            min: -MAX_LATENCY as f64,
+            max: MAX_LATENCY as f64,
+            default: 0.0,
+            display: format!("{:.1} ms", self.latency),
+            kind: ParameterKind::Continuous,
+            automatable: false,
+            read_only: false,
+        });
+
         params
     }
 
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError> {
+        match index {
+            MPE_ENABLED => self.mpe_enabled = value >= 0.5,
+            MPE_BEND_RANGE => self.mpe_bend_range = value.round().clamp(1.0, 96.0) as u8,
+            BEND_RANGE => self.bend_range = value.round().clamp(1.0, 96.0) as u8,
+            CHANNEL => self.channel = value.round().clamp(0.0, 15.0) as u8,
+            MPE_ZONE => self.mpe_zone = if value >= 0.5 { MpeZone::Upper } else { MpeZone::Lower },
+            MPE_MEMBERS => self.mpe_members = value.round().clamp(1.0, 15.0) as u8,
+            LATENCY => self.latency = (value as f32).clamp(-MAX_LATENCY, MAX_LATENCY),
+            _ if index < CC_COUNT => {
+                self.send_at = Instant::now();
+                let value = value.round().clamp(0.0, 127.0) as u8;
+                self.cc_values[index] = Some(value);
+                let channel = self.cc_channel();
+                self.send(&[CONTROL_CHANGE | channel, index as u8, value]);
+            },
+            _ => return Err(PluginError::NoSuchParameter(index)),
+        }
+        if matches!(index, MPE_ENABLED | MPE_BEND_RANGE | MPE_ZONE | MPE_MEMBERS) {
+            self.mpe_config_sent = false;
+        }
+
+        Ok(())
+    }
+
+    // Layout: version, MPE enabled, MPE bend range, bend range, channel, the channel map (count, then 255 for none),
+    // MPE zone, MPE member count, latency, then the CC values (255 for never sent).
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError> {
+        let mut state = vec![STATE_VERSION, self.mpe_enabled as u8, self.mpe_bend_range, self.bend_range, self.channel];
+        state.extend((self.channel_map.len() as u32).to_le_bytes());
+        state.extend(self.channel_map.iter().map(|channel| channel.unwrap_or(255)));
+        state.extend([self.mpe_zone as u8, self.mpe_members]);
+        state.extend(self.latency.to_le_bytes());
+        state.extend(self.cc_values.iter().map(|value| value.unwrap_or(255)));
+
+        Ok(state)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        let invalid = || PluginError::StateError("Unknown MIDI Out state".to_string());
+        if state.len() < 9 || state[0] != STATE_VERSION {
+            return Err(invalid())
+        }
+
+        let count = u32::from_le_bytes([state[5], state[6], state[7], state[8]]) as usize;
+        let at = 9 + count;
+        if state.len() != at + 6 + CC_COUNT {
+            return Err(invalid())
+        }
+
+        // Clamped like set_param() does, out of range values would go out as broken RPNs
+        self.mpe_enabled = state[1] != 0;
+        self.mpe_bend_range = state[2].clamp(1, 96);
+        self.bend_range = state[3].clamp(1, 96);
+        self.channel = state[4].min(15);
+        self.channel_map = state[9..at].iter().map(|&channel| if channel <= 15 { Some(channel) } else { None }).collect();
+        self.mpe_zone = if state[at] == 1 { MpeZone::Upper } else { MpeZone::Lower };
+        self.mpe_members = state[at + 1].clamp(1, 15);
+        self.latency = f32::from_le_bytes([state[at + 2], state[at + 3], state[at + 4], state[at + 5]]).clamp(-MAX_LATENCY, MAX_LATENCY);
+        self.mpe_config_sent = false;
+        for (cc, &value) in state[at + 6..].iter().enumerate() {
+            if value <= 127 {
+                self.set_param(cc, value as f64)?;
+            } else {
+                self.cc_values[cc] = None;
+            }
+        }
+
+        Ok(())
+    }
+
     fn enable(&mut self) {
         // no-op
     }
@@ -121,4 +490,8 @@ impl Plugin for MidiOutPlugin {
     fn active(&self) -> bool {
         return true
     }
+
+    fn error(&self) -> Option<&PluginError> {
+        self.error.as_ref()
+    }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
-mod envelope;
+pub(crate) mod envelope;
 pub(crate) mod subsynth;
+pub(crate) mod sampler;
 pub(crate) mod midi;
\ No newline at end of file
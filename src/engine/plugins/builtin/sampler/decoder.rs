@@ -0,0 +1,501 @@
+// WAV, AIFF and FLAC decoders. Everything gets converted to f32 in -1..1.
+
+use std::{fs, path::Path};
+
+use super::sample::{Sample, LoopMode};
+
+pub fn load_sample(path: &str) -> Result<Sample, String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let name = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let mut sample = if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+        decode_wav(&data)
+    } else if data.starts_with(b"FORM") && matches!(data.get(8..12), Some(b"AIFF") | Some(b"AIFC")) {
+        decode_aiff(&data)
+    } else if data.starts_with(b"fLaC") {
+        decode_flac(&data)
+    } else {
+        Err("Unknown file format".to_string())
+    }.map_err(|err| format!("{}: {}", path, err))?;
+
+    sample.name = name;
+    sample.path = path.to_string();
+    Ok(sample)
+}
+
+fn u16_le(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_le(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn u16_be(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn u32_be(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Int, // signed
+    UInt, // unsigned, 8-bit WAV only
+    Float,
+}
+
+// Converts interleaved PCM into one Vec per channel
+fn deinterleave(data: &[u8], channels: usize, bits: usize, encoding: Encoding, big_endian: bool) -> Result<Vec<Vec<f32>>, String> {
+    let bytes = bits.div_ceil(8);
+    if channels == 0 || bytes == 0 || bytes > 8 {
+        return Err(format!("Unsupported format: {channels} channels, {bits} bits"))
+    }
+    let frames = data.len() / (bytes * channels);
+    let mut out = vec![Vec::with_capacity(frames); channels];
+
+    for frame in 0..frames {
+        for (ch, channel) in out.iter_mut().enumerate() {
+            let at = (frame * channels + ch) * bytes;
+            let mut raw = [0u8; 8];
+            // Little endian into raw, whatever the source is
+            for i in 0..bytes {
+                raw[i] = if big_endian { data[at + bytes - 1 - i] } else { data[at + i] };
+            }
+            let value = u64::from_le_bytes(raw);
+
+            let sample = match encoding {
+                Encoding::Float if bytes == 4 => f32::from_bits(value as u32),
+                Encoding::Float if bytes == 8 => f64::from_bits(value) as f32,
+                Encoding::Float => return Err(format!("Unsupported float size {bits}")),
+                Encoding::UInt => (value as f32 - 128.0) / 128.0,
+                Encoding::Int => {
+                    // Sign extend from the stored size
+                    let shift = 64 - bytes * 8;
+                    let signed = ((value << shift) as i64) >> shift;
+                    signed as f32 / (1u64 << (bytes * 8 - 1)) as f32
+                }
+            };
+            channel.push(sample);
+        }
+    }
+
+    Ok(out)
+}
+
+/*
+    WAV
+*/
+
+fn decode_wav(data: &[u8]) -> Result<Sample, String> {
+    let mut format: Option<(u16, usize, u32, usize)> = None; // tag, channels, samplerate, bits
+    let mut pcm: Option<&[u8]> = None;
+    let mut sample = Sample::default();
+
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let id = &data[at..at + 4];
+        let size = u32_le(data, at + 4) as usize;
+        let body = &data[at + 8..(at + 8 + size).min(data.len())];
+
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let mut tag = u16_le(body, 0);
+                // WAVE_FORMAT_EXTENSIBLE, the real format is in the GUID
+                if tag == 0xFFFE && body.len() >= 26 {
+                    tag = u16_le(body, 24);
+                }
+                format = Some((tag, u16_le(body, 2) as usize, u32_le(body, 4), u16_le(body, 14) as usize));
+            },
+            b"data" => pcm = Some(body),
+            b"smpl" if body.len() >= 36 => {
+                sample.base_note = u32_le(body, 12).min(127) as u8;
+                // Pitch fraction is a fraction of a semitone upwards
+                let fraction = u32_le(body, 16) as f64 / 4294967296.0;
+                sample.finetune = -(fraction * 100.0).round() as i8;
+
+                let loops = u32_le(body, 28) as usize;
+                if loops > 0 && body.len() >= 36 + 24 {
+                    let kind = u32_le(body, 36 + 4);
+                    sample.loop_start = u32_le(body, 36 + 8) as usize;
+                    sample.loop_end = u32_le(body, 36 + 12) as usize + 1; // inclusive in the file
+                    sample.loop_mode = if kind == 1 { LoopMode::PingPong } else { LoopMode::Forward };
+                }
+            },
+            _ => (),
+        }
+
+        at += 8 + size + (size & 1);
+    }
+
+    let (tag, channels, samplerate, bits) = format.ok_or("No fmt chunk")?;
+    let encoding = match (tag, bits) {
+        (1, 8) => Encoding::UInt,
+        (1, _) => Encoding::Int,
+        (3, _) => Encoding::Float,
+        _ => return Err(format!("Unsupported WAV format {tag}")),
+    };
+
+    sample.samplerate = samplerate;
+    sample.data = deinterleave(pcm.ok_or("No data chunk")?, channels, bits, encoding, false)?;
+    sample.validate_loops();
+    Ok(sample)
+}
+
+/*
+    AIFF
+*/
+
+// 80-bit IEEE 754 extended precision, only used for the sample rate
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let exponent = (u16_be(bytes, 0) & 0x7FFF) as i32;
+    let mantissa = u64::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7], bytes[8], bytes[9]]);
+    if exponent == 0 && mantissa == 0 {
+        return 0.0
+    }
+    mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+fn decode_aiff(data: &[u8]) -> Result<Sample, String> {
+    let is_aifc = &data[8..12] == b"AIFC";
+    let mut format: Option<(usize, usize, f64, Encoding, bool)> = None; // channels, bits, samplerate, encoding, big endian
+    let mut pcm: Option<&[u8]> = None;
+    let mut markers: Vec<(u16, u32)> = Vec::new();
+    let mut loops: Vec<(u16, u16, u16)> = Vec::new(); // play mode, begin marker, end marker
+    let mut sample = Sample::default();
+
+    let mut at = 12;
+    while at + 8 <= data.len() {
+        let id = &data[at..at + 4];
+        let size = u32_be(data, at + 4) as usize;
+        let body = &data[at + 8..(at + 8 + size).min(data.len())];
+
+        match id {
+            b"COMM" if body.len() >= 18 => {
+                let (mut encoding, mut big_endian) = (Encoding::Int, true);
+                if is_aifc && body.len() >= 22 {
+                    match &body[18..22] {
+                        b"NONE" | b"twos" => (),
+                        b"sowt" => big_endian = false,
+                        b"fl32" | b"FL32" | b"fl64" | b"FL64" => encoding = Encoding::Float,
+                        other => return Err(format!("Unsupported AIFC compression {}", String::from_utf8_lossy(other))),
+                    }
+                }
+                format = Some((u16_be(body, 0) as usize, u16_be(body, 6) as usize, extended_to_f64(&body[8..18]), encoding, big_endian));
+            },
+            b"SSND" if body.len() >= 8 => {
+                let offset = u32_be(body, 0) as usize;
+                pcm = Some(&body[(8 + offset).min(body.len())..]);
+            },
+            b"MARK" if body.len() >= 2 => {
+                let count = u16_be(body, 0);
+                let mut pos = 2;
+                for _ in 0..count {
+                    if pos + 7 > body.len() {
+                        break
+                    }
+                    markers.push((u16_be(body, pos), u32_be(body, pos + 2)));
+                    // Pascal string, padded so the whole thing is even
+                    let name_len = body[pos + 6] as usize;
+                    pos += 7 + name_len + ((name_len + 1) & 1);
+                }
+            },
+            b"INST" if body.len() >= 20 => {
+                sample.base_note = (body[0] as i8).clamp(0, 127) as u8;
+                sample.finetune = body[1] as i8;
+                loops.push((u16_be(body, 8), u16_be(body, 10), u16_be(body, 12))); // sustain loop
+                loops.push((u16_be(body, 14), u16_be(body, 16), u16_be(body, 18))); // release loop
+            },
+            _ => (),
+        }
+
+        at += 8 + size + (size & 1);
+    }
+
+    let marker = |id: u16| markers.iter().find(|(marker, _)| *marker == id).map(|(_, position)| *position as usize);
+    let play_mode = |mode: u16| match mode {
+        1 => LoopMode::Forward,
+        2 => LoopMode::PingPong,
+        _ => LoopMode::Off,
+    };
+    // The sustain loop is only used while the note is held, same as ours. The release loop is the regular loop.
+    if let Some(&(mode, begin, end)) = loops.first() {
+        if let (Some(start), Some(end)) = (marker(begin), marker(end)) {
+            sample.sustain_mode = play_mode(mode);
+            sample.sustain_start = start;
+            sample.sustain_end = end;
+        }
+    }
+    if let Some(&(mode, begin, end)) = loops.get(1) {
+        if let (Some(start), Some(end)) = (marker(begin), marker(end)) {
+            sample.loop_mode = play_mode(mode);
+            sample.loop_start = start;
+            sample.loop_end = end;
+        }
+    }
+
+    let (channels, bits, samplerate, encoding, big_endian) = format.ok_or("No COMM chunk")?;
+    sample.samplerate = samplerate.round() as u32;
+    sample.data = deinterleave(pcm.ok_or("No SSND chunk")?, channels, bits, encoding, big_endian)?;
+    sample.validate_loops();
+    Ok(sample)
+}
+
+/*
+    FLAC
+*/
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize, // in bits
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, count: u32) -> Result<u64, String> {
+        if self.pos + count as usize > self.data.len() * 8 {
+            return Err("Unexpected end of FLAC stream".to_string())
+        }
+
+        let mut value: u64 = 0;
+        for _ in 0..count {
+            let bit = (self.data[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+            value = (value << 1) | bit as u64;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn signed(&mut self, count: u32) -> Result<i64, String> {
+        if count == 0 {
+            return Ok(0)
+        }
+        let value = self.bits(count)?;
+        let shift = 64 - count;
+        Ok(((value << shift) as i64) >> shift)
+    }
+
+    fn unary(&mut self) -> Result<u64, String> {
+        let mut zeros = 0;
+        while self.bits(1)? == 0 {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+fn decode_residual(reader: &mut BitReader, block_size: usize, order: usize, out: &mut Vec<i64>) -> Result<(), String> {
+    let (param_bits, escape) = match reader.bits(2)? {
+        0 => (4, 15),
+        1 => (5, 31),
+        method => return Err(format!("Unknown residual coding method {method}")),
+    };
+    let partition_order = reader.bits(4)?;
+    let partitions = 1usize << partition_order;
+
+    for partition in 0..partitions {
+        let count = (block_size >> partition_order) - if partition == 0 { order } else { 0 };
+        let param = reader.bits(param_bits)? as u32;
+
+        if param == escape {
+            let bits = reader.bits(5)? as u32;
+            for _ in 0..count {
+                out.push(reader.signed(bits)?);
+            }
+        } else {
+            for _ in 0..count {
+                let value = (reader.unary()? << param) | reader.bits(param)?;
+                out.push((value >> 1) as i64 ^ -((value & 1) as i64));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn decode_subframe(reader: &mut BitReader, block_size: usize, bits: u32) -> Result<Vec<i64>, String> {
+    reader.bits(1)?; // padding
+    let kind = reader.bits(6)?;
+    let wasted = if reader.bits(1)? == 1 { reader.unary()? as u32 + 1 } else { 0 };
+    let bits = bits - wasted;
+
+    let mut samples: Vec<i64> = Vec::with_capacity(block_size);
+    match kind {
+        0 => {
+            let value = reader.signed(bits)?;
+            samples.resize(block_size, value);
+        },
+        1 => {
+            for _ in 0..block_size {
+                samples.push(reader.signed(bits)?);
+            }
+        },
+        8..=12 => {
+            let order = (kind - 8) as usize;
+            for _ in 0..order {
+                samples.push(reader.signed(bits)?);
+            }
+            decode_residual(reader, block_size, order, &mut samples)?;
+
+            for i in order..block_size {
+                let s = &samples;
+                let prediction = match order {
+                    0 => 0,
+                    1 => s[i - 1],
+                    2 => 2 * s[i - 1] - s[i - 2],
+                    3 => 3 * s[i - 1] - 3 * s[i - 2] + s[i - 3],
+                    _ => 4 * s[i - 1] - 6 * s[i - 2] + 4 * s[i - 3] - s[i - 4],
+                };
+                samples[i] += prediction;
+            }
+        },
+        32..=63 => {
+            let order = (kind - 31) as usize;
+            for _ in 0..order {
+                samples.push(reader.signed(bits)?);
+            }
+            let precision = reader.bits(4)? as u32 + 1;
+            let shift = reader.signed(5)?.max(0);
+            let mut coefs = Vec::with_capacity(order);
+            for _ in 0..order {
+                coefs.push(reader.signed(precision)?);
+            }
+            decode_residual(reader, block_size, order, &mut samples)?;
+
+            for i in order..block_size {
+                let prediction: i64 = coefs.iter().enumerate().map(|(j, coef)| coef * samples[i - 1 - j]).sum();
+                samples[i] += prediction >> shift;
+            }
+        },
+        _ => return Err(format!("Unknown FLAC subframe type {kind}")),
+    }
+
+    if wasted > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= wasted;
+        }
+    }
+    Ok(samples)
+}
+
+fn decode_flac(data: &[u8]) -> Result<Sample, String> {
+    let mut at = 4;
+    let mut info: Option<(u32, usize, u32)> = None; // samplerate, channels, bits
+
+    // Metadata blocks
+    loop {
+        if at + 4 > data.len() {
+            return Err("Truncated FLAC metadata".to_string())
+        }
+        let header = data[at];
+        let size = (data[at + 1] as usize) << 16 | (data[at + 2] as usize) << 8 | data[at + 3] as usize;
+        let body = &data[at + 4..(at + 4 + size).min(data.len())];
+
+        if header & 0x7F == 0 && body.len() >= 18 {
+            let mut reader = BitReader { data: &body[10..], pos: 0 };
+            let samplerate = reader.bits(20)? as u32;
+            let channels = reader.bits(3)? as usize + 1;
+            let bits = reader.bits(5)? as u32 + 1;
+            info = Some((samplerate, channels, bits));
+        }
+
+        at += 4 + size;
+        if header & 0x80 != 0 {
+            break
+        }
+    }
+    let (samplerate, channels, stream_bits) = info.ok_or("No STREAMINFO block")?;
+
+    let mut pcm: Vec<Vec<i64>> = vec![Vec::new(); channels];
+    let mut reader = BitReader { data, pos: at * 8 };
+
+    while reader.pos / 8 + 2 < data.len() {
+        if reader.bits(14)? != 0x3FFE {
+            return Err("Lost FLAC frame sync".to_string())
+        }
+        reader.bits(2)?; // reserved, blocking strategy
+        let block_size_code = reader.bits(4)?;
+        let samplerate_code = reader.bits(4)?;
+        let assignment = reader.bits(4)?;
+        let bits = match reader.bits(3)? {
+            0 => stream_bits,
+            1 => 8,
+            2 => 12,
+            4 => 16,
+            5 => 20,
+            6 => 24,
+            7 => 32,
+            code => return Err(format!("Reserved FLAC sample size {code}")),
+        };
+        reader.bits(1)?;
+
+        // Frame or sample number, UTF-8 style
+        let first = reader.bits(8)?;
+        let extra = (first as u8).leading_ones().saturating_sub(1);
+        for _ in 0..extra {
+            reader.bits(8)?;
+        }
+
+        let block_size = match block_size_code {
+            1 => 192,
+            2..=5 => 576 << (block_size_code - 2),
+            6 => reader.bits(8)? as usize + 1,
+            7 => reader.bits(16)? as usize + 1,
+            8..=15 => 256 << (block_size_code - 8),
+            _ => return Err("Reserved FLAC block size".to_string()),
+        };
+        match samplerate_code {
+            12 => { reader.bits(8)?; },
+            13 | 14 => { reader.bits(16)?; },
+            _ => (),
+        }
+        reader.bits(8)?; // CRC-8
+
+        let frame_channels = if assignment < 8 { assignment as usize + 1 } else { 2 };
+        let mut subframes = Vec::with_capacity(frame_channels);
+        for ch in 0..frame_channels {
+            // The side channel has an extra bit
+            let side = match assignment {
+                8 => ch == 1,
+                9 => ch == 0,
+                10 => ch == 1,
+                _ => false,
+            };
+            subframes.push(decode_subframe(&mut reader, block_size, bits + side as u32)?);
+        }
+
+        // Undo the stereo decorrelation
+        if assignment >= 8 {
+            let (a, b) = (&subframes[0], &subframes[1]);
+            let (left, right): (Vec<i64>, Vec<i64>) = match assignment {
+                8 => (0..block_size).map(|i| (a[i], a[i] - b[i])).unzip(),
+                9 => (0..block_size).map(|i| (a[i] + b[i], b[i])).unzip(),
+                _ => (0..block_size).map(|i| {
+                    let mid = (a[i] << 1) | (b[i] & 1);
+                    ((mid + b[i]) >> 1, (mid - b[i]) >> 1)
+                }).unzip(),
+            };
+            subframes = vec![left, right];
+        }
+
+        for (ch, subframe) in subframes.into_iter().enumerate().take(channels) {
+            pcm[ch].extend(subframe);
+        }
+
+        reader.align();
+        reader.bits(16)?; // CRC-16
+    }
+
+    let scale = (1u64 << (stream_bits - 1)) as f32;
+    let mut sample = Sample {
+        samplerate,
+        data: pcm.into_iter().map(|channel| channel.into_iter().map(|value| value as f32 / scale).collect()).collect(),
+        ..Sample::default()
+    };
+    sample.validate_loops();
+    Ok(sample)
+}
@@ -0,0 +1,89 @@
+// Destructive edits, for the sample editor. Ranges are in frames, the end is exclusive.
+
+use super::sample::{Sample, Interpolation, SincTable};
+
+impl Sample {
+    fn clamp_range(&self, (start, end): (usize, usize)) -> (usize, usize) {
+        let frames = self.frames();
+        (start.min(frames), end.min(frames).max(start.min(frames)))
+    }
+
+    // Loudest peak up to full scale
+    pub fn normalize(&mut self, range: (usize, usize)) {
+        let (start, end) = self.clamp_range(range);
+        let peak = self.data.iter()
+            .flat_map(|channel| channel[start..end].iter())
+            .fold(0.0f32, |peak, value| peak.max(value.abs()));
+        if peak < 1e-6 {
+            return
+        }
+        for channel in &mut self.data {
+            for value in &mut channel[start..end] {
+                *value /= peak;
+            }
+        }
+    }
+
+    pub fn fade_in(&mut self, range: (usize, usize)) {
+        self.fade(range, false);
+    }
+
+    pub fn fade_out(&mut self, range: (usize, usize)) {
+        self.fade(range, true);
+    }
+
+    // Linear
+    fn fade(&mut self, range: (usize, usize), out: bool) {
+        let (start, end) = self.clamp_range(range);
+        let length = (end - start).max(1) as f32;
+        for channel in &mut self.data {
+            for (i, value) in channel[start..end].iter_mut().enumerate() {
+                let gain = i as f32 / length;
+                *value *= if out { 1.0 - gain } else { gain };
+            }
+        }
+    }
+
+    pub fn reverse(&mut self, range: (usize, usize)) {
+        let (start, end) = self.clamp_range(range);
+        for channel in &mut self.data {
+            channel[start..end].reverse();
+        }
+    }
+
+    // Keeps only the range, loops move along with it
+    pub fn trim(&mut self, range: (usize, usize)) {
+        let (start, end) = self.clamp_range(range);
+        if start == end {
+            return
+        }
+        for channel in &mut self.data {
+            channel.truncate(end);
+            channel.drain(..start);
+        }
+
+        for point in [&mut self.loop_start, &mut self.loop_end, &mut self.sustain_start, &mut self.sustain_end] {
+            *point = point.saturating_sub(start);
+        }
+        self.validate_loops();
+    }
+
+    // Changes the sample rate, keeping the pitch. Loops are scaled to match.
+    pub fn resample(&mut self, samplerate: u32, interpolation: Interpolation, sinc: &SincTable) {
+        if samplerate == 0 || samplerate == self.samplerate || self.frames() == 0 {
+            return
+        }
+        let ratio = self.samplerate as f64 / samplerate as f64; // source frames per new frame
+        let frames = (self.frames() as f64 / ratio).round() as usize;
+
+        self.data = (0..self.data.len())
+            .map(|channel| (0..frames).map(|i| self.read(channel, i as f64 * ratio, interpolation, None, sinc)).collect())
+            .collect();
+
+        for point in [&mut self.loop_start, &mut self.loop_end, &mut self.sustain_start, &mut self.sustain_end] {
+            *point = (*point as f64 / ratio).round() as usize;
+        }
+        self.samplerate = samplerate;
+        self.validate_loops();
+    }
+}
@@ -0,0 +1,121 @@
+// Which samples a note plays. Zones cover a key and velocity range; every matching zone plays,
+// except zones sharing a round-robin group, which take turns.
+
+use super::sample::{StateReader, put_u32};
+use super::super::envelope::ClassicEnvelope;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub sample: usize,
+    pub key_low: u8,
+    pub key_high: u8,
+    pub vel_low: u8,
+    pub vel_high: u8,
+    pub group: u32, // round-robin group, 0 for none
+    pub transpose: i8, // in semitones, IT keymaps can map a key to another note
+    pub envelope: Option<ClassicEnvelope>, // volume envelope replacing the sampler's, e.g. an SFZ region's
+}
+
+impl Zone {
+    pub fn new(sample: usize) -> Self {
+        Self {
+            sample,
+            key_low: 0,
+            key_high: 127,
+            vel_low: 0,
+            vel_high: 127,
+            group: 0,
+            transpose: 0,
+            envelope: None,
+        }
+    }
+
+    pub fn contains(&self, key: u8, vel: u8) -> bool {
+        (self.key_low..=self.key_high).contains(&key) && (self.vel_low..=self.vel_high).contains(&vel)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Keymap {
+    pub zones: Vec<Zone>,
+    round_robin: Vec<(u32, usize)>, // group, times played
+}
+
+impl Keymap {
+    // Indices of the zones to play for a note
+    pub fn lookup(&mut self, key: u8, vel: u8) -> Vec<usize> {
+        let mut out = Vec::new();
+        let mut groups: Vec<u32> = Vec::new();
+
+        for (index, zone) in self.zones.iter().enumerate().filter(|(_, zone)| zone.contains(key, vel)) {
+            if zone.group == 0 {
+                out.push(index);
+            } else if !groups.contains(&zone.group) {
+                groups.push(zone.group);
+            }
+        }
+
+        for group in groups {
+            let candidates: Vec<usize> = (0..self.zones.len()).filter(|&index| self.zones[index].group == group && self.zones[index].contains(key, vel)).collect();
+
+            let counter = match self.round_robin.iter_mut().find(|(g, _)| *g == group) {
+                Some((_, counter)) => counter,
+                None => {
+                    self.round_robin.push((group, 0));
+                    &mut self.round_robin.last_mut().unwrap().1
+                }
+            };
+            out.push(candidates[*counter % candidates.len()]);
+            *counter += 1;
+        }
+
+        out
+    }
+
+    // Adds a zone covering everything, returns its index
+    pub fn add_zone(&mut self, sample: usize) -> usize {
+        self.zones.push(Zone::new(sample));
+        self.zones.len() - 1
+    }
+
+    pub fn remove_zone(&mut self, index: usize) {
+        if index < self.zones.len() {
+            self.zones.remove(index);
+        }
+    }
+
+    // Fixes up sample indices after a sample is removed
+    pub fn remove_sample(&mut self, sample: usize) {
+        self.zones.retain(|zone| zone.sample != sample);
+        for zone in self.zones.iter_mut().filter(|zone| zone.sample > sample) {
+            zone.sample -= 1;
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        put_u32(out, self.zones.len() as u32);
+        for zone in &self.zones {
+            put_u32(out, zone.sample as u32);
+            out.extend_from_slice(&[zone.key_low, zone.key_high, zone.vel_low, zone.vel_high]);
+            put_u32(out, zone.group);
+            out.push(zone.transpose as u8);
+        }
+    }
+
+    pub fn read_from(reader: &mut StateReader) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        for _ in 0..reader.u32()? {
+            keymap.zones.push(Zone {
+                sample: reader.u32()? as usize,
+                key_low: reader.u8()?,
+                key_high: reader.u8()?,
+                vel_low: reader.u8()?,
+                vel_high: reader.u8()?,
+                group: reader.u32()?,
+                transpose: reader.u8()? as i8,
+                envelope: None, // read by the sampler, see SamplerPlugin::load_state()
+            });
+        }
+        Ok(keymap)
+    }
+}
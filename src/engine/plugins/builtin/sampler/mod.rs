@@ -0,0 +1,7 @@
+pub(crate) mod decoder;
+pub(crate) mod sample;
+pub(crate) mod keymap;
+pub(crate) mod plugin;
+pub(crate) mod edit;
+mod sfz;
+mod sf2;
\ No newline at end of file
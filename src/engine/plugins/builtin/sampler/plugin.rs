@@ -0,0 +1,744 @@
+// Tracker-style sample player
+
+const STATE_VERSION: u8 = 1;
+const MAX_VOICES: usize = 64;
+const DECLICK_TIME: f32 = 0.01; // seconds, fade applied when a note without a volume envelope is released
+const EXPR_SMOOTHING: f32 = 0.005;
+
+use std::sync::Arc;
+
+use crate::engine::plugins::interface::{Plugin, Event, Parameter, ParameterKind, PluginError, TimedEvent, PluginDescriptor};
+use super::super::envelope::{ClassicEnvelope, ClassicEnvelopePoint};
+use super::decoder::load_sample;
+use super::sfz::load_sfz;
+use super::sf2::load_sf2;
+use super::keymap::{Keymap, Zone};
+use super::sample::{Sample, LoopMode, Interpolation, SincTable, StateReader, put_u32, put_f32};
+
+/*
+    PARAMETERS
+*/
+
+const INTERPOLATION: usize = 0;
+const VOLUME: usize = 1;
+// These apply to the selected sample
+const SAMPLE_VOLUME: usize = 2;
+const SAMPLE_PAN: usize = 3;
+const BASE_NOTE: usize = 4;
+const FINETUNE: usize = 5;
+const LOOP_MODE: usize = 6;
+const LOOP_START: usize = 7;
+const LOOP_END: usize = 8;
+const SUSTAIN_MODE: usize = 9;
+const SUSTAIN_START: usize = 10;
+const SUSTAIN_END: usize = 11;
+const SELECTED_SAMPLE: usize = 12;
+const SELECTED_ZONE: usize = 13;
+// These apply to the selected zone
+const ZONE_SAMPLE: usize = 14;
+const ZONE_KEY_LOW: usize = 15;
+const ZONE_KEY_HIGH: usize = 16;
+const ZONE_VEL_LOW: usize = 17;
+const ZONE_VEL_HIGH: usize = 18;
+const ZONE_GROUP: usize = 19;
+const ZONE_TRANSPOSE: usize = 20;
+const PARAM_COUNT: usize = 21;
+
+const PARAM_NAMES: [&str; PARAM_COUNT] = [
+    "Interpolation",
+    "Volume",
+    "Sample Volume",
+    "Sample Pan",
+    "Base Note",
+    "Finetune",
+    "Loop",
+    "Loop Start",
+    "Loop End",
+    "Sustain Loop",
+    "Sustain Loop Start",
+    "Sustain Loop End",
+    "Selected Sample",
+    "Selected Zone",
+    "Zone Sample",
+    "Zone Lowest Key",
+    "Zone Highest Key",
+    "Zone Lowest Velocity",
+    "Zone Highest Velocity",
+    "Zone Round-robin Group",
+    "Zone Transpose",
+];
+
+// Envelopes, in the order they're stored in the voice
+pub const VOLUME_ENVELOPE: usize = 0;
+pub const PAN_ENVELOPE: usize = 1;
+pub const PITCH_ENVELOPE: usize = 2;
+
+struct Voice {
+    id: usize,
+    key: u8,
+    sample: usize,
+    transpose: i8,
+    velocity: f32,
+    released: bool,
+    done: bool,
+    age: u64,
+
+    position: f64, // in frames of the sample
+    forward: bool, // ping-pong direction
+    fade: f32, // declick after release
+
+    pitch: f32,
+    target_pitch: f32,
+    volume: f32,
+    target_volume: f32,
+
+    envelopes: [Option<ClassicEnvelope>; 3],
+}
+
+pub struct SamplerPlugin {
+    pub samples: Vec<Arc<Sample>>,
+    pub interpolation: Interpolation,
+    pub volume: f32,
+    // Volume (0..1), pan (-1..1) and pitch (semitones) envelopes, as imported from IT/XM
+    pub envelopes: [Option<ClassicEnvelope>; 3],
+    pub keymap: Keymap, // an empty keymap plays the first sample on every key
+    pub selected_sample: usize, // the one the sample parameters refer to
+    pub selected_zone: usize,
+
+    voices: Vec<Voice>,
+    sinc: SincTable,
+    note_counter: u64,
+    order: Vec<usize>, // events of the block by position, kept so process() doesn't allocate
+
+    active: bool,
+    samplerate: u32,
+    channels: u8,
+}
+
+impl SamplerPlugin {
+    // As set by configure(), for anything built to match it like imported envelopes
+    pub fn samplerate(&self) -> u32 {
+        self.samplerate
+    }
+
+    // Envelopes tick with the song tempo, including the ones already playing
+    pub fn change_tempo(&mut self, tempo: u16) {
+        let zones = self.keymap.zones.iter_mut().filter_map(|zone| zone.envelope.as_mut());
+        let voices = self.voices.iter_mut().flat_map(|voice| voice.envelopes.iter_mut().flatten());
+        for envelope in self.envelopes.iter_mut().flatten().chain(zones).chain(voices) {
+            envelope.change_tempo(tempo);
+        }
+    }
+
+    fn selected(&self) -> Option<&Sample> {
+        self.samples.get(self.selected_sample).map(|sample| &**sample)
+    }
+
+    fn selected_zone(&self) -> Option<&Zone> {
+        self.keymap.zones.get(self.selected_zone)
+    }
+
+    // Replaces the whole sample table, e.g. with the project's. The audio is shared, not copied. Playing notes are cut.
+    pub fn set_samples(&mut self, samples: &[Arc<Sample>]) {
+        self.samples.clear();
+        self.samples.extend_from_slice(samples);
+        self.voices.clear();
+        self.selected_sample = self.selected_sample.min(self.samples.len().saturating_sub(1));
+    }
+
+    pub fn add_sample(&mut self, sample: Sample) -> usize {
+        self.samples.push(Arc::new(sample));
+        self.samples.len() - 1
+    }
+
+    pub fn remove_sample(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            return
+        }
+        self.samples.remove(index);
+        self.keymap.remove_sample(index);
+        self.voices.clear();
+        self.selected_sample = self.selected_sample.min(self.samples.len().saturating_sub(1));
+        self.selected_zone = self.selected_zone.min(self.keymap.zones.len().saturating_sub(1));
+    }
+
+    // Replaces the zones, e.g. with the instrument's keymap. Left alone if they're the same, to keep the round-robin turns.
+    pub fn set_zones(&mut self, zones: &[Zone]) {
+        if self.keymap.zones != zones {
+            self.keymap.zones = zones.to_vec();
+            self.selected_zone = self.selected_zone.min(self.keymap.zones.len().saturating_sub(1));
+        }
+    }
+
+    fn note_on(&mut self, id: usize, key: u8, vel: u8) {
+        if self.keymap.zones.is_empty() {
+            if !self.samples.is_empty() {
+                self.start_voice(id, key, vel, 0, 0, None);
+            }
+            return
+        }
+
+        for zone in self.keymap.lookup(key, vel) {
+            let Zone { sample, transpose, .. } = self.keymap.zones[zone];
+            if sample < self.samples.len() {
+                self.start_voice(id, key, vel, sample, transpose, Some(zone));
+            }
+        }
+    }
+
+    fn start_voice(&mut self, id: usize, key: u8, vel: u8, sample: usize, transpose: i8, zone: Option<usize>) {
+        if self.voices.len() >= MAX_VOICES {
+            let steal = self.voices.iter().enumerate()
+                .min_by_key(|(_, voice)| (!voice.released, voice.age))
+                .map(|(i, _)| i)
+                .unwrap_or(0);
+            self.voices.swap_remove(steal);
+        }
+
+        // Carried envelopes pick up from the newest voice
+        let mut envelopes = self.envelopes.clone();
+        if let Some(envelope) = zone.and_then(|zone| self.keymap.zones[zone].envelope.as_ref()) {
+            envelopes[VOLUME_ENVELOPE] = Some(envelope.clone());
+        }
+        let newest = self.voices.iter().max_by_key(|voice| voice.age);
+        for (i, envelope) in envelopes.iter_mut().enumerate() {
+            if let Some(envelope) = envelope {
+                if let Some(previous) = newest.and_then(|voice| voice.envelopes[i].as_ref()) {
+                    envelope.carry_from(previous);
+                }
+                envelope.trigger();
+            }
+        }
+
+        self.note_counter += 1;
+        self.voices.push(Voice {
+            id,
+            key,
+            sample,
+            transpose,
+            velocity: vel as f32 / 127.0,
+            released: false,
+            done: false,
+            age: self.note_counter,
+
+            position: 0.0,
+            forward: true,
+            fade: 1.0,
+
+            pitch: 0.0,
+            target_pitch: 0.0,
+            volume: 1.0,
+            target_volume: 1.0,
+
+            envelopes,
+        });
+    }
+
+    fn note_off(&mut self, id: usize) {
+        for voice in self.voices.iter_mut().filter(|voice| voice.id == id && !voice.released) {
+            voice.released = true;
+            for envelope in voice.envelopes.iter_mut().flatten() {
+                envelope.release();
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::NoteOn { id, key, vel } => self.note_on(id, key, vel),
+            Event::NoteOff { id, .. } => self.note_off(id),
+            Event::ControlChange { index: 7, value } => self.volume = value as f32 / 127.0,
+            Event::ControlChange { .. } => (),
+            Event::ExprPitch { id, target_pitch } => {
+                for voice in self.voices.iter_mut().filter(|voice| voice.id == id) {
+                    voice.target_pitch = target_pitch;
+                }
+            },
+            Event::ExprVolume { id, target_vol } => {
+                for voice in self.voices.iter_mut().filter(|voice| voice.id == id) {
+                    voice.target_volume = target_vol.min(127) as f32 / 127.0;
+                }
+            },
+            Event::ExprPressure { .. } | Event::ExprTimbre { .. } => (),
+        }
+    }
+
+    fn render_frame(&mut self) -> (f32, f32) {
+        let samplerate = self.samplerate as f32;
+        let smoothing = 1.0 - (-1.0 / (EXPR_SMOOTHING * samplerate)).exp();
+        let declick = 1.0 / (DECLICK_TIME * samplerate);
+
+        let (mut left, mut right) = (0.0, 0.0);
+        for voice in self.voices.iter_mut() {
+            let Some(sample) = self.samples.get(voice.sample) else {
+                voice.done = true;
+                continue
+            };
+            let frames = sample.frames();
+
+            voice.pitch += (voice.target_pitch - voice.pitch) * smoothing;
+            voice.volume += (voice.target_volume - voice.volume) * smoothing;
+
+            let mut env = [1.0, 0.0, 0.0];
+            for (i, envelope) in voice.envelopes.iter_mut().enumerate() {
+                if let Some(envelope) = envelope {
+                    env[i] = envelope.process();
+                }
+            }
+
+            let region = sample.active_loop(voice.released);
+            let (l, r) = (
+                sample.read(0, voice.position, self.interpolation, region, &self.sinc),
+                sample.read(1, voice.position, self.interpolation, region, &self.sinc),
+            );
+
+            let fadeout = voice.envelopes[VOLUME_ENVELOPE].as_ref().map_or(1.0, |envelope| envelope.fade());
+            let gain = env[VOLUME_ENVELOPE] * fadeout * voice.velocity * voice.volume * sample.volume * voice.fade;
+            let pan = (sample.pan + env[PAN_ENVELOPE]).clamp(-1.0, 1.0);
+            left += l * gain * (1.0 - pan).min(1.0);
+            right += r * gain * (1.0 + pan).min(1.0);
+
+            // Advance
+            let semitones = voice.key as f32 + voice.transpose as f32 - sample.base_note as f32 + sample.finetune as f32 / 100.0 + voice.pitch + env[PITCH_ENVELOPE];
+            let step = sample.samplerate as f64 / self.samplerate as f64 * 2f64.powf(semitones as f64 / 12.0);
+
+            match region {
+                Some((start, end, mode)) => {
+                    let (start, end) = (start as f64, end as f64);
+                    if mode == LoopMode::Forward {
+                        voice.forward = true;
+                    }
+
+                    if voice.forward {
+                        voice.position += step;
+                        if voice.position >= end {
+                            match mode {
+                                LoopMode::PingPong => {
+                                    voice.position = (end - (voice.position - end)).max(start);
+                                    voice.forward = false;
+                                },
+                                _ => voice.position = start + (voice.position - end) % (end - start),
+                            }
+                        }
+                    } else {
+                        voice.position -= step;
+                        if voice.position < start {
+                            voice.position = (start + (start - voice.position)).min(end);
+                            voice.forward = true;
+                        }
+                    }
+                },
+                None => {
+                    // Left a ping-pong loop going backwards
+                    voice.forward = true;
+                    voice.position += step;
+                    if voice.position >= frames as f64 {
+                        voice.done = true;
+                    }
+                },
+            }
+
+            if voice.released {
+                if let Some(envelope) = &voice.envelopes[VOLUME_ENVELOPE] {
+                    if fadeout <= 0.0 || (envelope.finished() && env[VOLUME_ENVELOPE] <= 0.0) {
+                        voice.done = true;
+                    }
+                } else if sample.sustain_mode == LoopMode::Off {
+                    // Nothing to release into, so just stop without clicking
+                    voice.fade -= declick;
+                    if voice.fade <= 0.0 {
+                        voice.done = true;
+                    }
+                }
+            }
+        }
+
+        self.voices.retain(|voice| !voice.done);
+
+        (left * self.volume, right * self.volume)
+    }
+
+    fn keymap_param(&self, index: usize, name: &str, zone: Option<&Zone>, last_sample: f64) -> Parameter {
+        let last_zone = self.keymap.zones.len().saturating_sub(1) as f64;
+
+        let (value, min, max, default, display) = match (index, zone) {
+            (SELECTED_SAMPLE, _) => (self.selected_sample as f64, 0.0, last_sample, 0.0,
+                self.selected().map_or("-".to_string(), |sample| format!("{}: {}", self.selected_sample, sample.name))),
+            (SELECTED_ZONE, _) => (self.selected_zone as f64, 0.0, last_zone, 0.0,
+                if zone.is_some() { self.selected_zone.to_string() } else { "-".to_string() }),
+            (_, None) => (0.0, 0.0, 0.0, 0.0, "-".to_string()),
+
+            (ZONE_SAMPLE, Some(z)) => (z.sample as f64, 0.0, last_sample, 0.0, z.sample.to_string()),
+            (ZONE_KEY_LOW, Some(z)) => (z.key_low as f64, 0.0, 127.0, 0.0, z.key_low.to_string()),
+            (ZONE_KEY_HIGH, Some(z)) => (z.key_high as f64, 0.0, 127.0, 127.0, z.key_high.to_string()),
+            (ZONE_VEL_LOW, Some(z)) => (z.vel_low as f64, 0.0, 127.0, 0.0, z.vel_low.to_string()),
+            (ZONE_VEL_HIGH, Some(z)) => (z.vel_high as f64, 0.0, 127.0, 127.0, z.vel_high.to_string()),
+            (ZONE_GROUP, Some(z)) => (z.group as f64, 0.0, 255.0, 0.0, if z.group == 0 { "Off".to_string() } else { z.group.to_string() }),
+            (_, Some(z)) => (z.transpose as f64, -127.0, 127.0, 0.0, format!("{:+} st", z.transpose)),
+        };
+
+        Parameter {
+            index,
+            name: name.to_string(),
+            value,
+            min,
+            max,
+            default,
+            display,
+            kind: ParameterKind::Stepped,
+            automatable: false,
+            read_only: index > SELECTED_ZONE && zone.is_none(),
+        }
+    }
+
+    fn write_envelope(out: &mut Vec<u8>, envelope: &Option<ClassicEnvelope>) {
+        let Some(envelope) = envelope else {
+            out.push(0);
+            return
+        };
+
+        out.push(1);
+        out.push(envelope.tickrate);
+        put_u32(out, envelope.tempo as u32);
+        out.extend_from_slice(&[envelope.env_loop_enabled as u8, envelope.env_sustain_enabled as u8, envelope.carry as u8]);
+        put_f32(out, envelope.fadeout);
+        for index in [envelope.env_loop.0, envelope.env_loop.1, envelope.env_sustain.0, envelope.env_sustain.1] {
+            put_u32(out, index as u32);
+        }
+        put_u32(out, envelope.points.len() as u32);
+        for point in &envelope.points {
+            put_u32(out, point.tick as u32);
+            put_f32(out, point.level);
+        }
+    }
+
+    fn read_envelope(&self, reader: &mut StateReader) -> Result<Option<ClassicEnvelope>, String> {
+        if reader.u8()? == 0 {
+            return Ok(None)
+        }
+
+        let tickrate = reader.u8()?;
+        let tempo = reader.u32()?.min(u16::MAX as u32) as u16;
+        let mut envelope = ClassicEnvelope::new(tickrate, tempo, self.samplerate);
+        envelope.env_loop_enabled = reader.u8()? != 0;
+        envelope.env_sustain_enabled = reader.u8()? != 0;
+        envelope.carry = reader.u8()? != 0;
+        envelope.fadeout = reader.f32()?;
+        envelope.env_loop = (reader.u32()? as usize, reader.u32()? as usize);
+        envelope.env_sustain = (reader.u32()? as usize, reader.u32()? as usize);
+        for _ in 0..reader.u32()? {
+            let tick = reader.u32()?.min(u16::MAX as u32) as u16;
+            envelope.points.push(ClassicEnvelopePoint { tick, level: reader.f32()? });
+        }
+
+        // Loop points must be valid point indices
+        let last = envelope.points.len().saturating_sub(1);
+        envelope.env_loop = (envelope.env_loop.0.min(last), envelope.env_loop.1.min(last));
+        envelope.env_sustain = (envelope.env_sustain.0.min(last), envelope.env_sustain.1.min(last));
+
+        Ok(if envelope.points.is_empty() { None } else { Some(envelope) })
+    }
+}
+
+impl Plugin for SamplerPlugin {
+    // path is a sample to load, or empty for an empty sampler
+    fn new(path: &str) -> Result<SamplerPlugin, PluginError> {
+        let mut sampler = SamplerPlugin {
+            samples: Vec::new(),
+            interpolation: Interpolation::Cubic,
+            volume: 1.0,
+            envelopes: [None, None, None],
+            keymap: Keymap::default(),
+            selected_sample: 0,
+            selected_zone: 0,
+
+            voices: Vec::with_capacity(MAX_VOICES),
+            sinc: SincTable::new(),
+            note_counter: 0,
+            order: Vec::with_capacity(1024),
+
+            active: false,
+            samplerate: 48000,
+            channels: 2,
+        };
+
+        // Instruments (SFZ, SF2 with an optional #bank:program) or a single sample
+        let lower = path.to_lowercase();
+        if lower.ends_with(".sfz") {
+            load_sfz(path, &mut sampler).map_err(PluginError::LoadError)?;
+        } else if lower.ends_with(".sf2") || lower.rsplit_once('#').map_or(false, |(file, _)| file.ends_with(".sf2")) {
+            load_sf2(path, &mut sampler).map_err(PluginError::LoadError)?;
+        } else if !path.is_empty() {
+            sampler.add_sample(load_sample(path).map_err(PluginError::LoadError)?);
+        }
+
+        Ok(sampler)
+    }
+
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> {
+        Ok(vec![PluginDescriptor {
+            path: String::new(),
+            id: "corrosion.sampler".to_string(),
+            name: "Sampler".to_string(),
+            vendor: "Polyzium Productions".to_string(),
+            version: "0.0.0".to_string(),
+            features: vec!["instrument".to_string(), "sampler".to_string()],
+            audio_inputs: Vec::new(),
+            audio_outputs: vec![2],
+            note_inputs: 1,
+            note_outputs: 0,
+        }])
+    }
+
+    fn configure(&mut self, samplerate: u32, channels: u8, _sample_size: u32) {
+        self.samplerate = samplerate;
+        self.channels = channels;
+
+        for envelope in self.envelopes.iter_mut().flatten() {
+            envelope.change_samplerate(samplerate);
+        }
+        for envelope in self.keymap.zones.iter_mut().filter_map(|zone| zone.envelope.as_mut()) {
+            envelope.change_samplerate(samplerate);
+        }
+        self.voices.clear();
+    }
+
+    fn process(&mut self, events: &[TimedEvent], _input: &[f32], output: &mut [f32]) {
+        output.fill(0.0);
+        if !self.active {
+            return
+        }
+
+        let channels = self.channels as usize;
+        let frames = output.len() / channels;
+
+        let mut order = std::mem::take(&mut self.order);
+        order.clear();
+        order.extend(0..events.len());
+        order.sort_unstable_by_key(|&i| (events[i].position, i)); // stable sorts allocate
+        let mut next_event = 0;
+
+        for frame in 0..frames {
+            while next_event < order.len() && events[order[next_event]].position as usize <= frame {
+                self.handle_event(&events[order[next_event]].event);
+                next_event += 1;
+            }
+
+            let (left, right) = self.render_frame();
+            let out = &mut output[frame * channels..(frame + 1) * channels];
+            match channels {
+                1 => out[0] = (left + right) * 0.5,
+                _ => {
+                    out[0] = left;
+                    out[1] = right;
+                }
+            }
+        }
+
+        for &i in &order[next_event..] {
+            self.handle_event(&events[i].event);
+        }
+        self.order = order;
+    }
+
+    /* fn note_states(&self) -> &[NoteState] {
+        &self.state
+    } */
+
+    fn show_gui(&mut self, _shown: bool) {
+        // no GUI, samples are edited in the sample editor
+    }
+
+    fn get_params(&self) -> Vec<Parameter> {
+        let enum_kind = |names: Vec<&str>| ParameterKind::Enum(names.iter().map(|name| name.to_string()).collect());
+        let loop_modes = || enum_kind(LoopMode::ALL.iter().map(LoopMode::name).collect());
+        let sample = self.selected();
+        let frames = sample.map_or(0, Sample::frames) as f64;
+        let last_sample = self.samples.len().saturating_sub(1) as f64;
+        let zone = self.selected_zone();
+
+        let mut params = Vec::with_capacity(PARAM_COUNT);
+        for (index, name) in PARAM_NAMES.iter().enumerate() {
+            if index >= SELECTED_SAMPLE {
+                params.push(self.keymap_param(index, name, zone, last_sample));
+                continue
+            }
+
+            // Sample parameters are read-only when there's no sample
+            let read_only = index >= SAMPLE_VOLUME && sample.is_none();
+
+            let (value, min, max, default, kind, display) = match (index, sample) {
+                (INTERPOLATION, _) => (
+                    Interpolation::ALL.iter().position(|mode| *mode == self.interpolation).unwrap_or(0) as f64, 0.0, 3.0, 2.0,
+                    enum_kind(Interpolation::ALL.iter().map(Interpolation::name).collect()), self.interpolation.name().to_string(),
+                ),
+                (VOLUME, _) => (self.volume as f64, 0.0, 1.0, 1.0, ParameterKind::Continuous, format!("{:.0}%", self.volume * 100.0)),
+                (_, None) => (0.0, 0.0, 0.0, 0.0, ParameterKind::Continuous, "-".to_string()),
+
+                (SAMPLE_VOLUME, Some(s)) => (s.volume as f64, 0.0, 1.0, 1.0, ParameterKind::Continuous, format!("{:.0}%", s.volume * 100.0)),
+                (SAMPLE_PAN, Some(s)) => (s.pan as f64, -1.0, 1.0, 0.0, ParameterKind::Continuous, format!("{:+.0}", s.pan * 100.0)),
+                (BASE_NOTE, Some(s)) => (s.base_note as f64, 0.0, 127.0, 60.0, ParameterKind::Stepped, format!("{}", s.base_note)),
+                (FINETUNE, Some(s)) => (s.finetune as f64, -100.0, 100.0, 0.0, ParameterKind::Stepped, format!("{:+} ct", s.finetune)),
+                (LOOP_MODE, Some(s)) => (s.loop_mode as usize as f64, 0.0, 2.0, 0.0, loop_modes(), s.loop_mode.name().to_string()),
+                (LOOP_START, Some(s)) => (s.loop_start as f64, 0.0, frames, 0.0, ParameterKind::Stepped, s.loop_start.to_string()),
+                (LOOP_END, Some(s)) => (s.loop_end as f64, 0.0, frames, frames, ParameterKind::Stepped, s.loop_end.to_string()),
+                (SUSTAIN_MODE, Some(s)) => (s.sustain_mode as usize as f64, 0.0, 2.0, 0.0, loop_modes(), s.sustain_mode.name().to_string()),
+                (SUSTAIN_START, Some(s)) => (s.sustain_start as f64, 0.0, frames, 0.0, ParameterKind::Stepped, s.sustain_start.to_string()),
+                (_, Some(s)) => (s.sustain_end as f64, 0.0, frames, frames, ParameterKind::Stepped, s.sustain_end.to_string()),
+            };
+
+            params.push(Parameter {
+                index,
+                name: name.to_string(),
+                value,
+                min,
+                max,
+                default,
+                display,
+                kind,
+                automatable: matches!(index, VOLUME | SAMPLE_VOLUME | SAMPLE_PAN | FINETUNE),
+                read_only,
+            });
+        }
+
+        params
+    }
+
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError> {
+        match index {
+            INTERPOLATION => self.interpolation = Interpolation::from_index(value.round().max(0.0) as usize),
+            VOLUME => self.volume = value.clamp(0.0, 1.0) as f32,
+            SAMPLE_VOLUME..=SUSTAIN_END => {
+                let Some(sample) = self.samples.get_mut(self.selected_sample).map(Arc::make_mut) else {
+                    return Err(PluginError::NoSuchParameter(index))
+                };
+                let frames = sample.frames();
+                let position = value.round().clamp(0.0, frames as f64) as usize;
+
+                match index {
+                    SAMPLE_VOLUME => sample.volume = value.clamp(0.0, 1.0) as f32,
+                    SAMPLE_PAN => sample.pan = value.clamp(-1.0, 1.0) as f32,
+                    BASE_NOTE => sample.base_note = value.round().clamp(0.0, 127.0) as u8,
+                    FINETUNE => sample.finetune = value.round().clamp(-100.0, 100.0) as i8,
+                    LOOP_MODE => sample.loop_mode = LoopMode::from_index(value.round().max(0.0) as usize),
+                    LOOP_START => sample.loop_start = position,
+                    LOOP_END => sample.loop_end = position,
+                    SUSTAIN_MODE => sample.sustain_mode = LoopMode::from_index(value.round().max(0.0) as usize),
+                    SUSTAIN_START => sample.sustain_start = position,
+                    _ => sample.sustain_end = position,
+                }
+
+                // Turning a loop on without sensible points covers the whole sample
+                if index == LOOP_MODE && sample.loop_start >= sample.loop_end {
+                    (sample.loop_start, sample.loop_end) = (0, frames);
+                }
+                if index == SUSTAIN_MODE && sample.sustain_start >= sample.sustain_end {
+                    (sample.sustain_start, sample.sustain_end) = (0, frames);
+                }
+                sample.validate_loops();
+            },
+            SELECTED_SAMPLE => self.selected_sample = (value.round().max(0.0) as usize).min(self.samples.len().saturating_sub(1)),
+            SELECTED_ZONE => self.selected_zone = (value.round().max(0.0) as usize).min(self.keymap.zones.len().saturating_sub(1)),
+            ZONE_SAMPLE..=ZONE_TRANSPOSE => {
+                let last_sample = self.samples.len().saturating_sub(1);
+                let Some(zone) = self.keymap.zones.get_mut(self.selected_zone) else {
+                    return Err(PluginError::NoSuchParameter(index))
+                };
+                let byte = value.round().clamp(0.0, 127.0) as u8;
+
+                match index {
+                    ZONE_SAMPLE => zone.sample = (value.round().max(0.0) as usize).min(last_sample),
+                    ZONE_KEY_LOW => zone.key_low = byte,
+                    ZONE_KEY_HIGH => zone.key_high = byte,
+                    ZONE_VEL_LOW => zone.vel_low = byte,
+                    ZONE_VEL_HIGH => zone.vel_high = byte,
+                    ZONE_GROUP => zone.group = value.round().clamp(0.0, u32::MAX as f64) as u32,
+                    _ => zone.transpose = value.round().clamp(-127.0, 127.0) as i8,
+                }
+
+                // Keep the ranges the right way around
+                if zone.key_low > zone.key_high {
+                    if index == ZONE_KEY_LOW { zone.key_high = zone.key_low } else { zone.key_low = zone.key_high }
+                }
+                if zone.vel_low > zone.vel_high {
+                    if index == ZONE_VEL_LOW { zone.vel_high = zone.vel_low } else { zone.vel_low = zone.vel_high }
+                }
+            },
+            _ => return Err(PluginError::NoSuchParameter(index)),
+        }
+
+        Ok(())
+    }
+
+    // Layout: version, interpolation, volume, the three envelopes, the samples with their audio, the keymap,
+    // then each zone's volume envelope
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError> {
+        let mut state = vec![STATE_VERSION, self.interpolation as u8];
+        put_f32(&mut state, self.volume);
+        for envelope in &self.envelopes {
+            Self::write_envelope(&mut state, envelope);
+        }
+
+        put_u32(&mut state, self.samples.len() as u32);
+        for sample in &self.samples {
+            sample.write(&mut state);
+        }
+        self.keymap.write(&mut state);
+        for zone in &self.keymap.zones {
+            Self::write_envelope(&mut state, &zone.envelope);
+        }
+
+        Ok(state)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        if state.first() != Some(&STATE_VERSION) {
+            return Err(PluginError::StateError("Unknown Sampler state".to_string()))
+        }
+
+        let mut reader = StateReader { data: &state[1..] };
+        let mut load = || -> Result<(), String> {
+            let interpolation = Interpolation::from_index(reader.u8()? as usize);
+            let volume = reader.f32()?;
+            let envelopes = [self.read_envelope(&mut reader)?, self.read_envelope(&mut reader)?, self.read_envelope(&mut reader)?];
+
+            let mut samples = Vec::new();
+            for _ in 0..reader.u32()? {
+                samples.push(Arc::new(Sample::read_from(&mut reader)?));
+            }
+            let mut keymap = Keymap::read_from(&mut reader)?;
+            for zone in keymap.zones.iter_mut() {
+                zone.envelope = self.read_envelope(&mut reader)?;
+            }
+
+            self.interpolation = interpolation;
+            self.volume = volume;
+            self.envelopes = envelopes;
+            self.samples = samples;
+            self.keymap = keymap;
+            self.selected_sample = 0;
+            self.selected_zone = 0;
+            self.voices.clear();
+            Ok(())
+        };
+
+        load().map_err(PluginError::StateError)
+    }
+
+    fn enable(&mut self) {
+        self.active = true;
+    }
+
+    fn disable(&mut self) {
+        self.active = false;
+        self.voices.clear();
+    }
+
+    fn active(&self) -> bool {
+        self.active
+    }
+
+    fn error(&self) -> Option<&PluginError> {
+        None
+    }
+}
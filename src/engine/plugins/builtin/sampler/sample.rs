@@ -0,0 +1,334 @@
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopMode {
+    Off,
+    Forward,
+    PingPong,
+}
+
+impl LoopMode {
+    pub const ALL: [LoopMode; 3] = [LoopMode::Off, LoopMode::Forward, LoopMode::PingPong];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoopMode::Off => "Off",
+            LoopMode::Forward => "Forward",
+            LoopMode::PingPong => "Ping-pong",
+        }
+    }
+
+    pub fn from_index(index: usize) -> LoopMode {
+        LoopMode::ALL[index.min(LoopMode::ALL.len() - 1)]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Linear,
+    Cubic,
+    Sinc,
+}
+
+impl Interpolation {
+    pub const ALL: [Interpolation; 4] = [Interpolation::Nearest, Interpolation::Linear, Interpolation::Cubic, Interpolation::Sinc];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interpolation::Nearest => "Nearest",
+            Interpolation::Linear => "Linear",
+            Interpolation::Cubic => "Cubic",
+            Interpolation::Sinc => "Sinc",
+        }
+    }
+
+    pub fn from_index(index: usize) -> Interpolation {
+        Interpolation::ALL[index.min(Interpolation::ALL.len() - 1)]
+    }
+}
+
+#[derive(Clone)]
+pub struct Sample {
+    pub name: String,
+    pub path: String, // where it was loaded from, the data itself is saved with the project
+    pub samplerate: u32,
+    pub data: Vec<Vec<f32>>, // one Vec per channel
+
+    pub base_note: u8, // MIDI key that plays the sample at its own rate
+    pub finetune: i8, // in cents
+    pub volume: f32,
+    pub pan: f32, // -1..1
+
+    // Loop ends are exclusive
+    pub loop_mode: LoopMode,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    // Only used while the note is held
+    pub sustain_mode: LoopMode,
+    pub sustain_start: usize,
+    pub sustain_end: usize,
+}
+
+impl Default for Sample {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            path: String::new(),
+            samplerate: 48000,
+            data: Vec::new(),
+
+            base_note: 60, // C-5 in tracker terms
+            finetune: 0,
+            volume: 1.0,
+            pan: 0.0,
+
+            loop_mode: LoopMode::Off,
+            loop_start: 0,
+            loop_end: 0,
+            sustain_mode: LoopMode::Off,
+            sustain_start: 0,
+            sustain_end: 0,
+        }
+    }
+}
+
+impl Sample {
+    pub fn frames(&self) -> usize {
+        self.data.first().map_or(0, |channel| channel.len())
+    }
+
+    // Everything but the audio: name, rate, tuning and loops
+    pub fn settings(&self) -> Sample {
+        Sample { name: self.name.clone(), path: self.path.clone(), data: Vec::new(), ..*self }
+    }
+
+    // A slot left by removing a sample, see DAWEngine::remove_sample()
+    pub fn is_empty(&self) -> bool {
+        self.frames() == 0 && self.name.is_empty()
+    }
+
+    // Clamps loops into the sample, and turns off the ones that make no sense
+    pub fn validate_loops(&mut self) {
+        let frames = self.frames();
+        self.loop_end = self.loop_end.min(frames);
+        self.sustain_end = self.sustain_end.min(frames);
+        if self.loop_start >= self.loop_end {
+            self.loop_mode = LoopMode::Off;
+        }
+        if self.sustain_start >= self.sustain_end {
+            self.sustain_mode = LoopMode::Off;
+        }
+    }
+
+    // The loop region in effect, depending on whether the note is still held
+    pub fn active_loop(&self, released: bool) -> Option<(usize, usize, LoopMode)> {
+        if !released && self.sustain_mode != LoopMode::Off {
+            Some((self.sustain_start, self.sustain_end, self.sustain_mode))
+        } else if self.loop_mode != LoopMode::Off {
+            Some((self.loop_start, self.loop_end, self.loop_mode))
+        } else {
+            None
+        }
+    }
+
+    // Sample at a whole frame index, following the loop past its end so interpolation doesn't click
+    fn at(&self, channel: &[f32], index: i64, region: Option<(usize, usize, LoopMode)>) -> f32 {
+        let index = match region {
+            Some((start, end, mode)) if index >= end as i64 => {
+                let (start, end) = (start as i64, end as i64);
+                let over = (index - end) % (end - start);
+                match mode {
+                    LoopMode::PingPong => end - 1 - over,
+                    _ => start + over,
+                }
+            },
+            _ => index,
+        };
+
+        if index < 0 || index >= channel.len() as i64 {
+            0.0
+        } else {
+            channel[index as usize]
+        }
+    }
+
+    pub fn read(&self, channel: usize, position: f64, interpolation: Interpolation, region: Option<(usize, usize, LoopMode)>, sinc: &SincTable) -> f32 {
+        let Some(data) = self.data.get(channel.min(self.data.len().saturating_sub(1))) else {
+            return 0.0
+        };
+        let index = position.floor() as i64;
+        let frac = (position - position.floor()) as f32;
+        let at = |offset: i64| self.at(data, index + offset, region);
+
+        match interpolation {
+            Interpolation::Nearest => if frac < 0.5 { at(0) } else { at(1) },
+            Interpolation::Linear => at(0) + (at(1) - at(0)) * frac,
+            Interpolation::Cubic => {
+                // Catmull-Rom
+                let (y0, y1, y2, y3) = (at(-1), at(0), at(1), at(2));
+                let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+                let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c = -0.5 * y0 + 0.5 * y2;
+                ((a * frac + b) * frac + c) * frac + y1
+            },
+            Interpolation::Sinc => {
+                let kernel = sinc.kernel(frac);
+                let mut sum = 0.0;
+                for (tap, weight) in kernel.iter().enumerate() {
+                    sum += at(tap as i64 - SINC_HALF as i64 + 1) * weight;
+                }
+                sum
+            },
+        }
+    }
+}
+
+/*
+    SINC
+*/
+
+const SINC_HALF: usize = 8; // taps on each side
+const SINC_TAPS: usize = SINC_HALF * 2;
+const SINC_PHASES: usize = 256;
+
+// Blackman-windowed sinc kernels for every fractional position
+// TODO lower the cutoff when pitching up, it aliases as is
+pub struct SincTable {
+    table: Vec<f32>,
+}
+
+impl SincTable {
+    pub fn new() -> Self {
+        let mut table = vec![0.0; SINC_PHASES * SINC_TAPS];
+
+        for phase in 0..SINC_PHASES {
+            let frac = phase as f32 / SINC_PHASES as f32;
+            let row = &mut table[phase * SINC_TAPS..(phase + 1) * SINC_TAPS];
+
+            for (tap, weight) in row.iter_mut().enumerate() {
+                let x = tap as f32 - SINC_HALF as f32 + 1.0 - frac;
+                let sinc = if x.abs() < 1e-6 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                let n = x / SINC_HALF as f32;
+                let window = if n.abs() >= 1.0 { 0.0 } else { 0.42 + 0.5 * (PI * n).cos() + 0.08 * (2.0 * PI * n).cos() };
+                *weight = sinc * window;
+            }
+
+            // Unity gain at DC
+            let sum: f32 = row.iter().sum();
+            for weight in row.iter_mut() {
+                *weight /= sum;
+            }
+        }
+
+        Self { table }
+    }
+
+    fn kernel(&self, frac: f32) -> &[f32] {
+        let phase = ((frac * SINC_PHASES as f32) as usize).min(SINC_PHASES - 1);
+        &self.table[phase * SINC_TAPS..(phase + 1) * SINC_TAPS]
+    }
+}
+
+/*
+    STATE
+*/
+
+pub fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_f32(out: &mut Vec<u8>, value: f32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_str(out: &mut Vec<u8>, value: &str) {
+    put_u32(out, value.len() as u32);
+    out.extend_from_slice(value.as_bytes());
+}
+
+pub struct StateReader<'a> {
+    pub data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("State is truncated".to_string())
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+impl Sample {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        put_str(out, &self.name);
+        put_str(out, &self.path);
+        put_u32(out, self.samplerate);
+        out.extend_from_slice(&[self.base_note, self.finetune as u8]);
+        put_f32(out, self.volume);
+        put_f32(out, self.pan);
+        for (mode, start, end) in [(self.loop_mode, self.loop_start, self.loop_end), (self.sustain_mode, self.sustain_start, self.sustain_end)] {
+            out.push(mode as u8);
+            put_u32(out, start as u32);
+            put_u32(out, end as u32);
+        }
+
+        put_u32(out, self.data.len() as u32);
+        put_u32(out, self.frames() as u32);
+        for channel in &self.data {
+            for &value in channel {
+                put_f32(out, value);
+            }
+        }
+    }
+
+    pub fn read_from(reader: &mut StateReader) -> Result<Sample, String> {
+        let mut sample = Sample {
+            name: reader.string()?,
+            path: reader.string()?,
+            samplerate: reader.u32()?,
+            base_note: reader.u8()?,
+            finetune: reader.u8()? as i8,
+            volume: reader.f32()?,
+            pan: reader.f32()?,
+            ..Sample::default()
+        };
+        sample.loop_mode = LoopMode::from_index(reader.u8()? as usize);
+        sample.loop_start = reader.u32()? as usize;
+        sample.loop_end = reader.u32()? as usize;
+        sample.sustain_mode = LoopMode::from_index(reader.u8()? as usize);
+        sample.sustain_start = reader.u32()? as usize;
+        sample.sustain_end = reader.u32()? as usize;
+
+        let channels = reader.u32()? as usize;
+        let frames = reader.u32()? as usize;
+        for _ in 0..channels {
+            let bytes = reader.bytes(frames * 4)?;
+            sample.data.push(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect());
+        }
+
+        sample.validate_loops();
+        Ok(sample)
+    }
+}
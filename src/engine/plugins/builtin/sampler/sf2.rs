@@ -0,0 +1,327 @@
+// SoundFont 2 loader. Turns one preset's zones into the sampler's keymap.
+// Modulators are ignored, and so is most of what isn't sample playback (filters, LFOs, effects sends).
+
+use std::fs;
+
+use super::super::envelope::ClassicEnvelope;
+use super::keymap::Zone;
+use super::plugin::{SamplerPlugin, VOLUME_ENVELOPE};
+use super::sample::{Sample, LoopMode};
+
+// Generators we use
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const START_LOOP_OFFSET: u16 = 2;
+const END_LOOP_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const PAN: u16 = 17;
+const ATTACK_VOL_ENV: u16 = 34;
+const HOLD_VOL_ENV: u16 = 35;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const START_LOOP_COARSE_OFFSET: u16 = 45;
+const INITIAL_ATTENUATION: u16 = 48;
+const END_LOOP_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+// Generators which are ranges rather than amounts, and aren't added up between preset and instrument
+const RANGES: [u16; 2] = [KEY_RANGE, VEL_RANGE];
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn name_at(data: &[u8], at: usize) -> String {
+    let raw = &data[at..at + 20];
+    let end = raw.iter().position(|&byte| byte == 0).unwrap_or(20);
+    String::from_utf8_lossy(&raw[..end]).trim().to_string()
+}
+
+// Generator values: the raw 16 bits, ranges are low byte and high byte
+type Generators = Vec<(u16, u16)>;
+
+fn gen(generators: &Generators, oper: u16) -> Option<u16> {
+    generators.iter().rev().find(|(op, _)| *op == oper).map(|(_, amount)| *amount)
+}
+
+fn gen_i(generators: &Generators, oper: u16, default: i32) -> i32 {
+    gen(generators, oper).map_or(default, |amount| amount as i16 as i32)
+}
+
+fn range(generators: &Generators, oper: u16) -> (u8, u8) {
+    gen(generators, oper).map_or((0, 127), |amount| ((amount & 0xFF) as u8, (amount >> 8) as u8))
+}
+
+fn timecents_to_seconds(timecents: i32) -> f32 {
+    if timecents <= -12000 { 0.0 } else { 2f32.powf(timecents as f32 / 1200.0) }
+}
+
+struct Chunks<'a> {
+    smpl: &'a [u8],
+    sm24: Option<&'a [u8]>,
+    phdr: &'a [u8],
+    pbag: &'a [u8],
+    pgen: &'a [u8],
+    inst: &'a [u8],
+    ibag: &'a [u8],
+    igen: &'a [u8],
+    shdr: &'a [u8],
+}
+
+fn find_chunks(data: &[u8]) -> Result<Chunks<'_>, String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+        return Err("Not a SoundFont 2 file".to_string())
+    }
+
+    let mut found: Vec<([u8; 4], &[u8])> = Vec::new();
+    // Chunks inside LISTs are all we need, so a flat walk with descending into LISTs is enough
+    fn walk<'a>(data: &'a [u8], found: &mut Vec<([u8; 4], &'a [u8])>) {
+        let mut at = 0;
+        while at + 8 <= data.len() {
+            let id = [data[at], data[at + 1], data[at + 2], data[at + 3]];
+            let size = u32_at(data, at + 4) as usize;
+            let body = &data[at + 8..(at + 8 + size).min(data.len())];
+            if &id == b"LIST" && body.len() >= 4 {
+                walk(&body[4..], found);
+            } else {
+                found.push((id, body));
+            }
+            at += 8 + size + (size & 1);
+        }
+    }
+    walk(&data[12..], &mut found);
+
+    let chunk = |id: &[u8; 4]| found.iter().find(|(found_id, _)| found_id == id).map(|(_, body)| *body);
+    let required = |id: &[u8; 4]| chunk(id).ok_or_else(|| format!("Missing {} chunk", String::from_utf8_lossy(id)));
+
+    Ok(Chunks {
+        smpl: required(b"smpl")?,
+        sm24: chunk(b"sm24"),
+        phdr: required(b"phdr")?,
+        pbag: required(b"pbag")?,
+        pgen: required(b"pgen")?,
+        inst: required(b"inst")?,
+        ibag: required(b"ibag")?,
+        igen: required(b"igen")?,
+        shdr: required(b"shdr")?,
+    })
+}
+
+// Generators of each zone in a bag range, the first being the global zone if it lacks the terminal generator
+fn zones(bags: &[u8], gens: &[u8], first_bag: usize, end_bag: usize) -> Vec<Generators> {
+    let mut out = Vec::new();
+    for bag in first_bag..end_bag {
+        if (bag + 1) * 4 + 2 > bags.len() {
+            break
+        }
+        let (first_gen, end_gen) = (u16_at(bags, bag * 4) as usize, u16_at(bags, (bag + 1) * 4) as usize);
+        let mut generators = Generators::new();
+        for gen in first_gen..end_gen {
+            if gen * 4 + 4 > gens.len() {
+                break
+            }
+            generators.push((u16_at(gens, gen * 4), u16_at(gens, gen * 4 + 2)));
+        }
+        out.push(generators);
+    }
+    out
+}
+
+// Splits off the global zone, which is the first one if it doesn't end with the terminal generator
+fn split_global(mut zones: Vec<Generators>, terminal: u16) -> (Generators, Vec<Generators>) {
+    let has_global = zones.first().map_or(false, |zone| zone.last().map_or(true, |(op, _)| *op != terminal));
+    if has_global {
+        let global = zones.remove(0);
+        (global, zones)
+    } else {
+        (Generators::new(), zones)
+    }
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> Option<(u8, u8)> {
+    let (low, high) = (a.0.max(b.0), a.1.min(b.1));
+    if low <= high { Some((low, high)) } else { None }
+}
+
+// path is the .sf2 file, optionally followed by #bank:program. Without it the first preset is loaded.
+pub fn load_sf2(path: &str, sampler: &mut SamplerPlugin) -> Result<(), String> {
+    let (file, wanted) = match path.rsplit_once('#') {
+        Some((file, preset)) => {
+            let (bank, program) = preset.split_once(':').ok_or("Expected #bank:program")?;
+            (file, Some((bank.parse::<u16>().map_err(|err| err.to_string())?, program.parse::<u16>().map_err(|err| err.to_string())?)))
+        },
+        None => (path, None),
+    };
+    let data = fs::read(file).map_err(|err| format!("{}: {}", file, err))?;
+    let chunks = find_chunks(&data)?;
+
+    // Presets, the last record is a terminator
+    let preset_count = (chunks.phdr.len() / 38).saturating_sub(1);
+    let mut presets: Vec<(usize, u16, u16)> = (0..preset_count)
+        .map(|i| (i, u16_at(chunks.phdr, i * 38 + 22), u16_at(chunks.phdr, i * 38 + 20))) // index, bank, program
+        .collect();
+    presets.sort_by_key(|(_, bank, program)| (*bank, *program));
+    let preset = match wanted {
+        Some((bank, program)) => presets.iter().find(|(_, b, p)| *b == bank && *p == program).ok_or(format!("No preset {bank}:{program}"))?.0,
+        None => presets.first().ok_or("No presets")?.0,
+    };
+
+    let preset_name = name_at(chunks.phdr, preset * 38);
+    let (first_bag, end_bag) = (u16_at(chunks.phdr, preset * 38 + 24) as usize, u16_at(chunks.phdr, (preset + 1) * 38 + 24) as usize);
+    let (preset_global, preset_zones) = split_global(zones(chunks.pbag, chunks.pgen, first_bag, end_bag), INSTRUMENT);
+
+    let instrument_count = (chunks.inst.len() / 22).saturating_sub(1);
+    let sample_count = (chunks.shdr.len() / 46).saturating_sub(1);
+    let mut made: Vec<((usize, i32, i32, i32, i32), usize)> = Vec::new(); // sample ID and offsets, sample index
+
+    for preset_zone in &preset_zones {
+        let Some(instrument) = gen(preset_zone, INSTRUMENT).map(|i| i as usize) else { continue };
+        if instrument >= instrument_count {
+            continue
+        }
+
+        // Preset generators add to the instrument's, a zone's override the global zone's
+        let mut preset_gens = preset_global.clone();
+        preset_gens.extend(preset_zone.iter().copied());
+
+        let (first_bag, end_bag) = (u16_at(chunks.inst, instrument * 22 + 20) as usize, u16_at(chunks.inst, (instrument + 1) * 22 + 20) as usize);
+        let (inst_global, inst_zones) = split_global(zones(chunks.ibag, chunks.igen, first_bag, end_bag), SAMPLE_ID);
+
+        for inst_zone in &inst_zones {
+            let Some(sample_id) = gen(inst_zone, SAMPLE_ID).map(|id| id as usize) else { continue };
+            if sample_id >= sample_count {
+                continue
+            }
+            let mut gens = inst_global.clone();
+            gens.extend(inst_zone.iter().copied());
+
+            let (Some(keys), Some(vels)) = (
+                intersect(range(&gens, KEY_RANGE), range(&preset_gens, KEY_RANGE)),
+                intersect(range(&gens, VEL_RANGE), range(&preset_gens, VEL_RANGE)),
+            ) else {
+                continue
+            };
+            let amount = |oper: u16, default: i32| gen_i(&gens, oper, default) + if RANGES.contains(&oper) { 0 } else { gen_i(&preset_gens, oper, 0) };
+
+            // Sample header
+            let header = sample_id * 46;
+            let mut start = u32_at(chunks.shdr, header + 20) as i64;
+            let mut end = u32_at(chunks.shdr, header + 24) as i64;
+            let mut loop_start = u32_at(chunks.shdr, header + 28) as i64;
+            let mut loop_end = u32_at(chunks.shdr, header + 32) as i64;
+            let samplerate = u32_at(chunks.shdr, header + 36);
+            let original_pitch = chunks.shdr[header + 40];
+            let pitch_correction = chunks.shdr[header + 41] as i8 as i32;
+
+            // Offsets are only allowed at the instrument level
+            let offsets = (
+                gen_i(&gens, START_OFFSET, 0) + gen_i(&gens, START_COARSE_OFFSET, 0) * 32768,
+                gen_i(&gens, END_OFFSET, 0) + gen_i(&gens, END_COARSE_OFFSET, 0) * 32768,
+                gen_i(&gens, START_LOOP_OFFSET, 0) + gen_i(&gens, START_LOOP_COARSE_OFFSET, 0) * 32768,
+                gen_i(&gens, END_LOOP_OFFSET, 0) + gen_i(&gens, END_LOOP_COARSE_OFFSET, 0) * 32768,
+            );
+            start += offsets.0 as i64;
+            end += offsets.1 as i64;
+            loop_start += offsets.2 as i64;
+            loop_end += offsets.3 as i64;
+
+            let total = (chunks.smpl.len() / 2) as i64;
+            let (start, end) = (start.clamp(0, total), end.clamp(0, total));
+            if start >= end {
+                continue
+            }
+
+            let key = (sample_id, offsets.0, offsets.1, offsets.2, offsets.3);
+            let root = gen(&gens, OVERRIDING_ROOT_KEY).filter(|&root| root <= 127).map_or(original_pitch, |root| root as u8);
+            let finetune = (pitch_correction + amount(FINE_TUNE, 0)).clamp(-100, 100) as i8;
+            let loop_mode = match amount(SAMPLE_MODES, 0) & 3 {
+                1 => (LoopMode::Forward, LoopMode::Off),
+                3 => (LoopMode::Off, LoopMode::Forward), // loops while held, then plays to the end
+                _ => (LoopMode::Off, LoopMode::Off),
+            };
+            let volume = 10f32.powf(-(amount(INITIAL_ATTENUATION, 0).max(0) as f32) / 200.0);
+            let pan = (amount(PAN, 0) as f32 / 500.0).clamp(-1.0, 1.0);
+
+            let index = match made.iter().find(|(made_key, index)| {
+                let existing = &sampler.samples[*index];
+                *made_key == key && existing.base_note == root && existing.finetune == finetune && existing.volume == volume
+                    && existing.pan == pan && (existing.loop_mode, existing.sustain_mode) == loop_mode
+            }) {
+                Some((_, index)) => *index,
+                None => {
+                    // 16-bit data, plus the low 8 bits in sm24 for 24-bit fonts
+                    let data: Vec<f32> = (start..end).map(|i| {
+                        let i = i as usize;
+                        let high = i16::from_le_bytes([chunks.smpl[i * 2], chunks.smpl[i * 2 + 1]]) as i32;
+                        match chunks.sm24 {
+                            Some(sm24) if i < sm24.len() => ((high << 8) | sm24[i] as i32) as f32 / 8388608.0,
+                            _ => high as f32 / 32768.0,
+                        }
+                    }).collect();
+
+                    let frames = data.len();
+                    let loop_start = (loop_start - start).clamp(0, frames as i64) as usize;
+                    let loop_end = (loop_end - start).clamp(0, frames as i64) as usize;
+
+                    let mut sample = Sample {
+                        name: name_at(chunks.shdr, header),
+                        path: path.to_string(),
+                        samplerate,
+                        data: vec![data],
+                        base_note: root,
+                        finetune,
+                        volume,
+                        pan,
+                        loop_mode: loop_mode.0,
+                        loop_start,
+                        loop_end,
+                        sustain_mode: loop_mode.1,
+                        sustain_start: loop_start,
+                        sustain_end: loop_end,
+                    };
+                    sample.validate_loops();
+
+                    let index = sampler.add_sample(sample);
+                    made.push((key, index));
+                    index
+                }
+            };
+
+            // Each zone has its own volume envelope
+            let seconds = |oper: u16| timecents_to_seconds(amount(oper, -12000));
+            // Sustain is an attenuation in centibels
+            let sustain = 10f32.powf(-(amount(SUSTAIN_VOL_ENV, 0).clamp(0, 1440) as f32) / 200.0);
+            let envelope = ClassicEnvelope::from_adsr(seconds(ATTACK_VOL_ENV), seconds(HOLD_VOL_ENV), seconds(DECAY_VOL_ENV), sustain, seconds(RELEASE_VOL_ENV), sampler.samplerate());
+
+            sampler.keymap.zones.push(Zone {
+                key_low: keys.0,
+                key_high: keys.1,
+                vel_low: vels.0,
+                vel_high: vels.1,
+                transpose: amount(COARSE_TUNE, 0).clamp(-127, 127) as i8,
+                envelope: Some(envelope),
+                ..Zone::new(index)
+            });
+        }
+    }
+
+    if sampler.keymap.zones.is_empty() {
+        return Err(format!("Preset {} has no playable zones", preset_name))
+    }
+    sampler.envelopes[VOLUME_ENVELOPE] = None; // the zones have their own
+
+    Ok(())
+}
@@ -0,0 +1,309 @@
+// SFZ loader. Supports the opcodes that matter for playback: key and velocity ranges, tuning,
+// volume, pan, loops, round-robin sequences and the amplitude envelope.
+
+use std::{fs, path::{Path, PathBuf}};
+
+use super::super::envelope::ClassicEnvelope;
+use super::decoder::load_sample;
+use super::keymap::Zone;
+use super::plugin::{SamplerPlugin, VOLUME_ENVELOPE};
+use super::sample::LoopMode;
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+type Opcodes = Vec<(String, String)>;
+
+fn get<'a>(opcodes: &'a Opcodes, name: &str) -> Option<&'a str> {
+    // Later opcodes override earlier ones
+    opcodes.iter().rev().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+}
+
+fn get_f32(opcodes: &Opcodes, name: &str) -> Option<f32> {
+    get(opcodes, name).and_then(|value| value.trim().parse().ok())
+}
+
+// Key numbers or note names like c4, c#4 or db-1. c4 is 60.
+pub fn parse_key(value: &str) -> Option<u8> {
+    let value = value.trim().to_lowercase();
+    if let Ok(key) = value.parse::<i32>() {
+        return Some(key.clamp(0, 127) as u8)
+    }
+
+    let mut chars = value.chars();
+    let mut semitone: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let mut rest: String = chars.collect();
+    if let Some(stripped) = rest.strip_prefix('#') {
+        semitone += 1;
+        rest = stripped.to_string();
+    } else if let Some(stripped) = rest.strip_prefix('b') {
+        semitone -= 1;
+        rest = stripped.to_string();
+    }
+
+    let octave: i32 = rest.parse().ok()?;
+    Some(((octave + 1) * 12 + semitone).clamp(0, 127) as u8)
+}
+
+// Removes comments, and handles #define and #include
+fn preprocess(path: &Path, depth: usize, defines: &mut Vec<(String, String)>) -> Result<String, String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err("#include nested too deep".to_string())
+    }
+    let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    // Block comments first, then line comments
+    let mut text = String::with_capacity(source.len());
+    let mut rest = source.as_str();
+    while let Some(start) = rest.find("/*") {
+        text.push_str(&rest[..start]);
+        rest = match rest[start..].find("*/") {
+            Some(end) => &rest[start + end + 2..],
+            None => "",
+        };
+    }
+    text.push_str(rest);
+
+    let mut out = String::with_capacity(text.len());
+    for line in text.lines() {
+        let line = match line.find("//") {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let trimmed = line.trim();
+
+        if let Some(define) = trimmed.strip_prefix("#define") {
+            let mut parts = define.trim().splitn(2, char::is_whitespace);
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                defines.push((name.to_string(), value.trim().to_string()));
+            }
+            continue
+        }
+        if let Some(include) = trimmed.strip_prefix("#include") {
+            let file = include.trim().trim_matches('"').replace('\\', "/");
+            let dir = path.parent().unwrap_or(Path::new("."));
+            out.push_str(&preprocess(&dir.join(file), depth + 1, defines)?);
+            out.push('\n');
+            continue
+        }
+
+        // Longest names first, so $FOO doesn't eat $FOOBAR
+        let mut line = line.to_string();
+        let mut sorted: Vec<&(String, String)> = defines.iter().collect();
+        sorted.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        for (name, value) in sorted {
+            line = line.replace(name.as_str(), value);
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Header {
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+    Other,
+}
+
+// Returns the opcodes of every region, with the ones inherited from its headers in front
+fn parse(text: &str) -> Vec<Opcodes> {
+    let mut control: Opcodes = Vec::new();
+    let mut global: Opcodes = Vec::new();
+    let mut master: Opcodes = Vec::new();
+    let mut group: Opcodes = Vec::new();
+    let mut regions: Vec<Opcodes> = Vec::new();
+    let mut header = Header::Other;
+
+    let mut rest = text;
+    loop {
+        let (body, next) = match rest.find('<') {
+            Some(start) => (&rest[..start], Some(start)),
+            None => (rest, None),
+        };
+
+        // Opcodes are name=value, values can have spaces in them (sample paths)
+        let mut opcodes: Opcodes = Vec::new();
+        for word in body.split_whitespace() {
+            match word.split_once('=') {
+                Some((name, value)) => opcodes.push((name.to_string(), value.to_string())),
+                None => if let Some((_, value)) = opcodes.last_mut() {
+                    value.push(' ');
+                    value.push_str(word);
+                },
+            }
+        }
+        match header {
+            Header::Control => control.extend(opcodes),
+            Header::Global => global.extend(opcodes),
+            Header::Master => master.extend(opcodes),
+            Header::Group => group.extend(opcodes),
+            Header::Region => if let Some(region) = regions.last_mut() { region.extend(opcodes) },
+            Header::Other => (),
+        }
+
+        let Some(start) = next else { break };
+        let Some(end) = rest[start..].find('>') else { break };
+        let name = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+
+        header = match name {
+            "control" => { control.clear(); Header::Control },
+            "global" => { global.clear(); master.clear(); group.clear(); Header::Global },
+            "master" => { master.clear(); group.clear(); Header::Master },
+            "group" => { group.clear(); Header::Group },
+            "region" => {
+                let mut region = control.clone();
+                region.extend(global.iter().cloned());
+                region.extend(master.iter().cloned());
+                region.extend(group.iter().cloned());
+                regions.push(region);
+                Header::Region
+            },
+            _ => Header::Other, // <curve>, <effect>, <midi>...
+        };
+    }
+
+    regions
+}
+
+pub fn load_sfz(path: &str, sampler: &mut SamplerPlugin) -> Result<(), String> {
+    let path = Path::new(path);
+    let dir = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    let regions = parse(&preprocess(path, 0, &mut Vec::new())?);
+
+    let mut loaded: Vec<(PathBuf, usize)> = Vec::new(); // file, sample index
+    let mut sequences: Vec<((u8, u8, u8, u8), u32)> = Vec::new(); // ranges, round-robin group
+    let mut zones: Vec<(Zone, u32)> = Vec::new(); // with their sequence position
+
+    for region in &regions {
+        // Release triggers aren't supported, they'd play on note on
+        if matches!(get(region, "trigger"), Some("release") | Some("release_key")) {
+            continue
+        }
+        let Some(file) = get(region, "sample") else { continue };
+        if file.starts_with('*') {
+            continue // generators like *sine
+        }
+
+        let file = format!("{}{}", get(region, "default_path").unwrap_or(""), file).replace('\\', "/");
+        let file = dir.join(file);
+
+        // Samples shared by regions are loaded once, unless their settings differ
+        let base_note = get(region, "pitch_keycenter").and_then(parse_key)
+            .or_else(|| get(region, "key").and_then(parse_key))
+            .unwrap_or(60);
+        let mut sample = match loaded.iter().find(|(loaded_file, _)| *loaded_file == file) {
+            Some((_, index)) => (*sampler.samples[*index]).clone(),
+            None => load_sample(&file.to_string_lossy())?,
+        };
+        sample.base_note = base_note;
+        sample.finetune = get_f32(region, "tune").unwrap_or(0.0).clamp(-100.0, 100.0) as i8;
+        sample.volume = 10f32.powf(get_f32(region, "volume").unwrap_or(0.0) / 20.0).min(4.0);
+        sample.pan = (get_f32(region, "pan").unwrap_or(0.0) / 100.0).clamp(-1.0, 1.0);
+
+        // Loop ends are inclusive in SFZ
+        let loop_start = get_f32(region, "loop_start").or_else(|| get_f32(region, "loopstart")).map(|start| start as usize);
+        let loop_end = get_f32(region, "loop_end").or_else(|| get_f32(region, "loopend")).map(|end| end as usize + 1);
+        let (start, end) = (loop_start.unwrap_or(sample.loop_start), loop_end.unwrap_or(sample.loop_end));
+        match get(region, "loop_mode").or_else(|| get(region, "loopmode")) {
+            Some("no_loop") | Some("one_shot") => {
+                sample.loop_mode = LoopMode::Off;
+                sample.sustain_mode = LoopMode::Off;
+            },
+            Some("loop_continuous") => {
+                sample.loop_mode = LoopMode::Forward;
+                (sample.loop_start, sample.loop_end) = (start, end);
+            },
+            Some("loop_sustain") => {
+                sample.loop_mode = LoopMode::Off;
+                sample.sustain_mode = LoopMode::Forward;
+                (sample.sustain_start, sample.sustain_end) = (start, end);
+            },
+            _ => (), // whatever the file says
+        }
+        if sample.loop_mode != LoopMode::Off && sample.loop_start >= sample.loop_end {
+            (sample.loop_start, sample.loop_end) = (0, sample.frames());
+        }
+        if sample.sustain_mode != LoopMode::Off && sample.sustain_start >= sample.sustain_end {
+            (sample.sustain_start, sample.sustain_end) = (0, sample.frames());
+        }
+        sample.validate_loops();
+
+        let index = match sampler.samples.iter().position(|existing| {
+            existing.path == sample.path && existing.base_note == sample.base_note && existing.finetune == sample.finetune
+                && existing.volume == sample.volume && existing.pan == sample.pan && existing.loop_mode == sample.loop_mode
+                && existing.sustain_mode == sample.sustain_mode && (existing.loop_start, existing.loop_end) == (sample.loop_start, sample.loop_end)
+                && (existing.sustain_start, existing.sustain_end) == (sample.sustain_start, sample.sustain_end)
+        }) {
+            Some(index) => index,
+            None => sampler.add_sample(sample),
+        };
+        if !loaded.iter().any(|(loaded_file, _)| *loaded_file == file) {
+            loaded.push((file, index));
+        }
+
+        let mut zone = Zone::new(index);
+        if let Some(key) = get(region, "key").and_then(parse_key) {
+            (zone.key_low, zone.key_high) = (key, key);
+        }
+        if let Some(key) = get(region, "lokey").and_then(parse_key) {
+            zone.key_low = key;
+        }
+        if let Some(key) = get(region, "hikey").and_then(parse_key) {
+            zone.key_high = key;
+        }
+        zone.vel_low = get_f32(region, "lovel").unwrap_or(0.0).clamp(0.0, 127.0) as u8;
+        zone.vel_high = get_f32(region, "hivel").unwrap_or(127.0).clamp(0.0, 127.0) as u8;
+        zone.transpose = get_f32(region, "transpose").unwrap_or(0.0).clamp(-127.0, 127.0) as i8;
+
+        // Regions of a sequence over the same ranges share a round-robin group
+        let sequence_position = get_f32(region, "seq_position").unwrap_or(1.0) as u32;
+        if get_f32(region, "seq_length").unwrap_or(1.0) > 1.0 {
+            let ranges = (zone.key_low, zone.key_high, zone.vel_low, zone.vel_high);
+            zone.group = match sequences.iter().find(|(existing, _)| *existing == ranges) {
+                Some((_, group)) => *group,
+                None => {
+                    sequences.push((ranges, sequences.len() as u32 + 1));
+                    sequences.len() as u32
+                }
+            };
+        }
+
+        // Each region has its own amplitude envelope
+        let seconds = |name: &str, default: f32| get_f32(region, name).unwrap_or(default).max(0.0);
+        let (attack, hold, decay, release) = (seconds("ampeg_attack", 0.0), seconds("ampeg_hold", 0.0), seconds("ampeg_decay", 0.0), seconds("ampeg_release", 0.001));
+        let sustain = get_f32(region, "ampeg_sustain").unwrap_or(100.0).clamp(0.0, 100.0) / 100.0;
+
+        // The defaults don't need an envelope, the sampler declicks on its own
+        if attack > 0.0 || hold > 0.0 || sustain < 1.0 || release > 0.02 {
+            zone.envelope = Some(ClassicEnvelope::from_adsr(attack, hold, decay, sustain, release, sampler.samplerate()));
+        }
+
+        zones.push((zone, sequence_position));
+    }
+
+    if zones.is_empty() {
+        return Err("No playable regions".to_string())
+    }
+
+    // Round-robin goes through the zones in order
+    zones.sort_by_key(|(zone, position)| (zone.group, *position));
+    sampler.keymap.zones.extend(zones.into_iter().map(|(zone, _)| zone));
+    sampler.envelopes[VOLUME_ENVELOPE] = None; // the regions have their own
+
+    Ok(())
+}
@@ -1,2 +1,2 @@
 mod oscillator;
-mod synth;
\ No newline at end of file
+pub(crate) mod synth;
\ No newline at end of file
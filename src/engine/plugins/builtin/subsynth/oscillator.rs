@@ -0,0 +1,115 @@
+// Band-limited oscillators, using PolyBLEP for the saw and square and PolyBLAMP for the triangle
+
+use std::f32::consts::TAU;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+    Noise,
+}
+
+impl Waveform {
+    pub const ALL: [Waveform; 5] = [Waveform::Saw, Waveform::Square, Waveform::Triangle, Waveform::Sine, Waveform::Noise];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Waveform::Saw => "Saw",
+            Waveform::Square => "Square",
+            Waveform::Triangle => "Triangle",
+            Waveform::Sine => "Sine",
+            Waveform::Noise => "Noise",
+        }
+    }
+
+    pub fn from_index(index: usize) -> Waveform {
+        Waveform::ALL[index.min(Waveform::ALL.len() - 1)]
+    }
+}
+
+// Residual of a band-limited step, t is the phase and dt the phase increment
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+// Residual of a band-limited ramp (the integral of the above), for slope changes
+fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt - 1.0;
+        -t * t * t / 3.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt + 1.0;
+        t * t * t / 3.0
+    } else {
+        0.0
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Oscillator {
+    pub waveform: Waveform,
+    pub pulse_width: f32, // 0..1, square only
+    phase: f32,
+    noise_state: u32,
+}
+
+impl Oscillator {
+    pub fn new(waveform: Waveform, seed: u32) -> Self {
+        Self {
+            waveform,
+            pulse_width: 0.5,
+            phase: 0.0,
+            noise_state: seed.max(1), // xorshift gets stuck at 0
+        }
+    }
+
+    pub fn reset(&mut self, phase: f32) {
+        self.phase = phase.rem_euclid(1.0);
+    }
+
+    // Random number in -1..1, also used for the noise waveform
+    pub fn random(&mut self) -> f32 {
+        self.noise_state ^= self.noise_state << 13;
+        self.noise_state ^= self.noise_state >> 17;
+        self.noise_state ^= self.noise_state << 5;
+        (self.noise_state as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    pub fn process(&mut self, freq: f32, samplerate: f32) -> f32 {
+        let dt = (freq / samplerate).clamp(0.0, 0.5);
+        let t = self.phase;
+
+        let value = match self.waveform {
+            Waveform::Saw => 2.0 * t - 1.0 - poly_blep(t, dt),
+            Waveform::Square => {
+                let pw = self.pulse_width.clamp(0.01, 0.99);
+                let naive = if t < pw { 1.0 } else { -1.0 };
+                naive + poly_blep(t, dt) - poly_blep((t + 1.0 - pw) % 1.0, dt)
+            },
+            Waveform::Triangle => {
+                // Corners at 0 (peak) and 0.5 (trough), the slope changes by 8 at each
+                let naive = 2.0 * (2.0 * t - 1.0).abs() - 1.0;
+                naive + 8.0 * dt * (poly_blamp((t + 0.5) % 1.0, dt) - poly_blamp(t, dt))
+            },
+            Waveform::Sine => (t * TAU).sin(),
+            Waveform::Noise => self.random(),
+        };
+
+        self.phase += dt;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+
+        value
+    }
+}
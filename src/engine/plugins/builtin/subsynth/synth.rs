@@ -0,0 +1,554 @@
+// Polyphonic subtractive synthesizer: two unison oscillators, a state variable filter, two ADSRs and an LFO
+
+const STATE_VERSION: u8 = 1;
+const MAX_VOICES: usize = 16;
+const MAX_UNISON: usize = 8;
+const EXPR_SMOOTHING: f32 = 0.005; // seconds, so pitch and volume expressions don't zipper
+
+use std::f32::consts::PI;
+
+use crate::engine::plugins::interface::{Plugin, Event, Parameter, ParameterKind, PluginError, TimedEvent, PluginDescriptor};
+use super::super::envelope::Adsr;
+use super::oscillator::{Oscillator, Waveform};
+
+/*
+    PARAMETERS
+*/
+
+const OSC1_WAVE: usize = 0;
+const OSC2_WAVE: usize = 1;
+const OSC2_SEMITONES: usize = 2;
+const OSC2_DETUNE: usize = 3;
+const OSC_MIX: usize = 4;
+const PULSE_WIDTH: usize = 5;
+const UNISON_VOICES: usize = 6;
+const UNISON_DETUNE: usize = 7;
+const UNISON_SPREAD: usize = 8;
+const FILTER_MODE: usize = 9;
+const CUTOFF: usize = 10;
+const RESONANCE: usize = 11;
+const FILTER_ENV_AMOUNT: usize = 12;
+const KEY_TRACKING: usize = 13;
+const AMP_ATTACK: usize = 14;
+const AMP_DECAY: usize = 15;
+const AMP_SUSTAIN: usize = 16;
+const AMP_RELEASE: usize = 17;
+const FILTER_ATTACK: usize = 18;
+const FILTER_DECAY: usize = 19;
+const FILTER_SUSTAIN: usize = 20;
+const FILTER_RELEASE: usize = 21;
+const LFO_WAVE: usize = 22;
+const LFO_RATE: usize = 23;
+const LFO_PITCH: usize = 24;
+const LFO_CUTOFF: usize = 25;
+const LFO_VOLUME: usize = 26;
+const VOLUME: usize = 27;
+const PARAM_COUNT: usize = 28;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Waveform,
+    FilterMode,
+    Semitones,
+    Cents,
+    Percent,
+    Hz,
+    Seconds,
+    Octaves,
+    Voices,
+}
+
+struct ParamInfo {
+    name: &'static str,
+    min: f64,
+    max: f64,
+    default: f64,
+    unit: Unit,
+}
+
+const fn param(name: &'static str, min: f64, max: f64, default: f64, unit: Unit) -> ParamInfo {
+    ParamInfo { name, min, max, default, unit }
+}
+
+const PARAMS: [ParamInfo; PARAM_COUNT] = [
+    param("Osc 1 Waveform", 0.0, 4.0, 0.0, Unit::Waveform),
+    param("Osc 2 Waveform", 0.0, 4.0, 1.0, Unit::Waveform),
+    param("Osc 2 Semitones", -24.0, 24.0, 0.0, Unit::Semitones),
+    param("Osc 2 Detune", -100.0, 100.0, 7.0, Unit::Cents),
+    param("Osc Mix", 0.0, 1.0, 0.5, Unit::Percent),
+    param("Pulse Width", 0.01, 0.99, 0.5, Unit::Percent),
+    param("Unison Voices", 1.0, MAX_UNISON as f64, 1.0, Unit::Voices),
+    param("Unison Detune", 0.0, 100.0, 15.0, Unit::Cents),
+    param("Unison Spread", 0.0, 1.0, 0.5, Unit::Percent),
+    param("Filter Mode", 0.0, 3.0, 0.0, Unit::FilterMode),
+    param("Cutoff", 20.0, 20000.0, 8000.0, Unit::Hz),
+    param("Resonance", 0.0, 1.0, 0.2, Unit::Percent),
+    param("Filter Envelope", -8.0, 8.0, 2.0, Unit::Octaves),
+    param("Key Tracking", 0.0, 1.0, 0.5, Unit::Percent),
+    param("Amp Attack", 0.001, 10.0, 0.005, Unit::Seconds),
+    param("Amp Decay", 0.001, 10.0, 0.3, Unit::Seconds),
+    param("Amp Sustain", 0.0, 1.0, 0.8, Unit::Percent),
+    param("Amp Release", 0.001, 10.0, 0.2, Unit::Seconds),
+    param("Filter Attack", 0.001, 10.0, 0.005, Unit::Seconds),
+    param("Filter Decay", 0.001, 10.0, 0.5, Unit::Seconds),
+    param("Filter Sustain", 0.0, 1.0, 0.0, Unit::Percent),
+    param("Filter Release", 0.001, 10.0, 0.3, Unit::Seconds),
+    param("LFO Waveform", 0.0, 4.0, 3.0, Unit::Waveform),
+    param("LFO Rate", 0.01, 20.0, 5.0, Unit::Hz),
+    param("LFO to Pitch", 0.0, 12.0, 0.0, Unit::Semitones),
+    param("LFO to Cutoff", 0.0, 4.0, 0.0, Unit::Octaves),
+    param("LFO to Volume", 0.0, 1.0, 0.0, Unit::Percent),
+    param("Volume", 0.0, 1.0, 0.7, Unit::Percent),
+];
+
+const FILTER_MODES: [&str; 4] = ["Low Pass", "High Pass", "Band Pass", "Notch"];
+
+/*
+    FILTER
+*/
+
+// Trapezoidal state variable filter, stays stable when the cutoff is modulated
+#[derive(Default, Clone, Copy)]
+struct Svf {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl Svf {
+    fn process(&mut self, input: f32, cutoff: f32, resonance: f32, mode: usize, samplerate: f32) -> f32 {
+        let g = (PI * cutoff / samplerate).tan();
+        let k = 2.0 - 1.98 * resonance;
+
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let v3 = input - self.ic2eq;
+        let v1 = a1 * self.ic1eq + a2 * v3;
+        let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        match mode {
+            0 => v2,
+            1 => input - k * v1 - v2,
+            2 => v1,
+            _ => input - k * v1,
+        }
+    }
+}
+
+/*
+    VOICES
+*/
+
+struct Voice {
+    id: usize,
+    key: u8,
+    released: bool,
+    age: u64, // for voice stealing
+
+    // Expressions, current values follow the targets
+    pitch: f32,
+    target_pitch: f32,
+    volume: f32,
+    target_volume: f32,
+
+    // Fixed size so starting a note doesn't allocate, only the first `unison` are used
+    unison: usize,
+    osc1: [Oscillator; MAX_UNISON],
+    osc2: [Oscillator; MAX_UNISON],
+    filters: [Svf; 2],
+    amp_env: Adsr,
+    filter_env: Adsr,
+}
+
+impl Voice {
+    fn is_idle(&self) -> bool {
+        self.amp_env.is_idle()
+    }
+}
+
+pub struct SubSynth {
+    params: [f64; PARAM_COUNT],
+    voices: Vec<Voice>,
+    lfo: Oscillator,
+    mod_wheel: f32, // CC #1, adds to the LFO amounts
+    channel_volume: f32, // CC #7
+    note_counter: u64,
+    seed: u32,
+    order: Vec<usize>, // events of the block by position, kept so process() doesn't allocate
+
+    active: bool,
+    samplerate: u32,
+    channels: u8,
+}
+
+impl SubSynth {
+    fn param(&self, index: usize) -> f32 {
+        self.params[index] as f32
+    }
+
+    fn waveform(&self, index: usize) -> Waveform {
+        Waveform::from_index(self.params[index].round() as usize)
+    }
+
+    fn note_on(&mut self, id: usize, key: u8, vel: u8) {
+        let samplerate = self.samplerate as f32;
+
+        // Steal the oldest voice if we're out of them, preferring released ones
+        if self.voices.len() >= MAX_VOICES {
+            let steal = self.voices.iter().enumerate()
+                .min_by_key(|(_, voice)| (!voice.released, voice.age))
+                .map(|(i, _)| i)
+                .unwrap_or(0);
+            self.voices.swap_remove(steal);
+        }
+
+        let unison = self.params[UNISON_VOICES].round().clamp(1.0, MAX_UNISON as f64) as usize;
+        let mut osc1 = [Oscillator::new(self.waveform(OSC1_WAVE), 1); MAX_UNISON];
+        let mut osc2 = [Oscillator::new(self.waveform(OSC2_WAVE), 1); MAX_UNISON];
+        for i in 0..unison {
+            self.seed = self.seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            let mut a = Oscillator::new(self.waveform(OSC1_WAVE), self.seed);
+            let mut b = Oscillator::new(self.waveform(OSC2_WAVE), self.seed ^ 0x5555_5555);
+            // Random phases when stacking, otherwise the voices would phase
+            if unison > 1 {
+                let phase = a.random();
+                a.reset(phase);
+                let phase = b.random();
+                b.reset(phase);
+            }
+            a.pulse_width = self.param(PULSE_WIDTH);
+            b.pulse_width = self.param(PULSE_WIDTH);
+            osc1[i] = a;
+            osc2[i] = b;
+        }
+
+        let mut amp_env = Adsr::new(self.param(AMP_ATTACK), self.param(AMP_DECAY), self.param(AMP_SUSTAIN), self.param(AMP_RELEASE), samplerate);
+        let mut filter_env = Adsr::new(self.param(FILTER_ATTACK), self.param(FILTER_DECAY), self.param(FILTER_SUSTAIN), self.param(FILTER_RELEASE), samplerate);
+        // Velocity only affects loudness
+        amp_env.velocity_sensitivity = 1.0;
+        amp_env.trigger(vel as f32 / 127.0);
+        filter_env.trigger(vel as f32 / 127.0);
+
+        self.note_counter += 1;
+        self.voices.push(Voice {
+            id,
+            key,
+            released: false,
+            age: self.note_counter,
+
+            pitch: 0.0,
+            target_pitch: 0.0,
+            volume: 1.0,
+            target_volume: 1.0,
+
+            unison,
+            osc1,
+            osc2,
+            filters: [Svf::default(); 2],
+            amp_env,
+            filter_env,
+        });
+    }
+
+    fn note_off(&mut self, id: usize) {
+        for voice in self.voices.iter_mut().filter(|voice| voice.id == id && !voice.released) {
+            voice.released = true;
+            voice.amp_env.release();
+            voice.filter_env.release();
+        }
+    }
+
+    fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::NoteOn { id, key, vel } => self.note_on(id, key, vel),
+            Event::NoteOff { id, .. } => self.note_off(id),
+            Event::ControlChange { index, value } => match index {
+                1 => self.mod_wheel = value as f32 / 127.0,
+                7 => self.channel_volume = value as f32 / 127.0,
+                _ => (),
+            },
+            Event::ExprPitch { id, target_pitch } => {
+                for voice in self.voices.iter_mut().filter(|voice| voice.id == id) {
+                    voice.target_pitch = target_pitch;
+                }
+            },
+            Event::ExprVolume { id, target_vol } => {
+                for voice in self.voices.iter_mut().filter(|voice| voice.id == id) {
+                    voice.target_volume = target_vol.min(127) as f32 / 127.0;
+                }
+            },
+            Event::ExprPressure { .. } | Event::ExprTimbre { .. } => (),
+        }
+    }
+
+    // Renders one stereo frame
+    fn render_frame(&mut self) -> (f32, f32) {
+        let samplerate = self.samplerate as f32;
+        let smoothing = 1.0 - (-1.0 / (EXPR_SMOOTHING * samplerate)).exp();
+
+        let lfo = self.lfo.process(self.param(LFO_RATE), samplerate);
+        let lfo_pitch = lfo * (self.param(LFO_PITCH) + self.mod_wheel);
+        let lfo_cutoff = lfo * self.param(LFO_CUTOFF);
+        let lfo_volume = 1.0 - (lfo * 0.5 + 0.5) * self.param(LFO_VOLUME);
+
+        let osc2_offset = self.param(OSC2_SEMITONES) + self.param(OSC2_DETUNE) / 100.0;
+        let mix = self.param(OSC_MIX);
+        let unison_detune = self.param(UNISON_DETUNE) / 100.0;
+        let spread = self.param(UNISON_SPREAD);
+        let filter_mode = self.params[FILTER_MODE].round() as usize;
+        let cutoff = self.param(CUTOFF);
+        let resonance = self.param(RESONANCE);
+        let env_amount = self.param(FILTER_ENV_AMOUNT);
+        let key_tracking = self.param(KEY_TRACKING);
+        let max_cutoff = samplerate * 0.45;
+
+        let (mut left, mut right) = (0.0, 0.0);
+        for voice in self.voices.iter_mut() {
+            voice.pitch += (voice.target_pitch - voice.pitch) * smoothing;
+            voice.volume += (voice.target_volume - voice.volume) * smoothing;
+
+            let note = voice.key as f32 + voice.pitch + lfo_pitch;
+            let unison = voice.unison;
+            let unison_gain = 1.0 / (unison as f32).sqrt();
+
+            let (mut voice_l, mut voice_r) = (0.0, 0.0);
+            for i in 0..unison {
+                // Detune and pan spread evenly from -1 to 1
+                let position = if unison > 1 { i as f32 / (unison - 1) as f32 * 2.0 - 1.0 } else { 0.0 };
+                let detuned = note + position * unison_detune * 0.5;
+
+                let a = voice.osc1[i].process(440.0 * 2f32.powf((detuned - 69.0) / 12.0), samplerate);
+                let b = voice.osc2[i].process(440.0 * 2f32.powf((detuned + osc2_offset - 69.0) / 12.0), samplerate);
+                let sample = (a * (1.0 - mix) + b * mix) * unison_gain;
+
+                let pan = position * spread;
+                voice_l += sample * (1.0 - pan).min(1.0);
+                voice_r += sample * (1.0 + pan).min(1.0);
+            }
+
+            let filter_env = voice.filter_env.process();
+            let octaves = filter_env * env_amount + lfo_cutoff + key_tracking * (voice.key as f32 - 60.0) / 12.0;
+            let voice_cutoff = (cutoff * 2f32.powf(octaves)).clamp(20.0, max_cutoff);
+            voice_l = voice.filters[0].process(voice_l, voice_cutoff, resonance, filter_mode, samplerate);
+            voice_r = voice.filters[1].process(voice_r, voice_cutoff, resonance, filter_mode, samplerate);
+
+            let gain = voice.amp_env.process() * voice.volume;
+            left += voice_l * gain;
+            right += voice_r * gain;
+        }
+
+        self.voices.retain(|voice| !voice.is_idle());
+
+        let volume = self.param(VOLUME) * self.channel_volume * lfo_volume;
+        (left * volume, right * volume)
+    }
+}
+
+impl Plugin for SubSynth {
+    fn new(_path: &str) -> Result<SubSynth, PluginError> {
+        let mut params = [0.0; PARAM_COUNT];
+        for (i, info) in PARAMS.iter().enumerate() {
+            params[i] = info.default;
+        }
+
+        Ok(SubSynth {
+            params,
+            voices: Vec::with_capacity(MAX_VOICES),
+            lfo: Oscillator::new(Waveform::from_index(PARAMS[LFO_WAVE].default as usize), 1),
+            mod_wheel: 0.0,
+            channel_volume: 1.0,
+            note_counter: 0,
+            seed: 0x1234_5678,
+            order: Vec::with_capacity(1024),
+
+            active: false,
+            samplerate: 48000,
+            channels: 2,
+        })
+    }
+
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> {
+        Ok(vec![PluginDescriptor {
+            path: String::new(),
+            id: "corrosion.subsynth".to_string(),
+            name: "SubSynth".to_string(),
+            vendor: "Polyzium Productions".to_string(),
+            version: "0.0.0".to_string(),
+            features: vec!["instrument".to_string(), "synthesizer".to_string()],
+            audio_inputs: Vec::new(),
+            audio_outputs: vec![2],
+            note_inputs: 1,
+            note_outputs: 0,
+        }])
+    }
+
+    fn configure(&mut self, samplerate: u32, channels: u8, _sample_size: u32) {
+        self.samplerate = samplerate;
+        self.channels = channels;
+        // Envelopes are made with the sample rate baked in
+        self.voices.clear();
+    }
+
+    fn process(&mut self, events: &[TimedEvent], _input: &[f32], output: &mut [f32]) {
+        output.fill(0.0);
+        if !self.active {
+            return
+        }
+
+        let channels = self.channels as usize;
+        let frames = output.len() / channels;
+
+        let mut order = std::mem::take(&mut self.order);
+        order.clear();
+        order.extend(0..events.len());
+        order.sort_unstable_by_key(|&i| (events[i].position, i)); // stable sorts allocate
+        let mut next_event = 0;
+
+        for frame in 0..frames {
+            while next_event < order.len() && events[order[next_event]].position as usize <= frame {
+                self.handle_event(&events[order[next_event]].event);
+                next_event += 1;
+            }
+
+            let (left, right) = self.render_frame();
+            let out = &mut output[frame * channels..(frame + 1) * channels];
+            match channels {
+                1 => out[0] = (left + right) * 0.5,
+                _ => {
+                    out[0] = left;
+                    out[1] = right;
+                }
+            }
+        }
+
+        // Events past the end of the buffer
+        for &i in &order[next_event..] {
+            self.handle_event(&events[i].event);
+        }
+        self.order = order;
+    }
+
+    /* fn note_states(&self) -> &[NoteState] {
+        &self.state
+    } */
+
+    fn show_gui(&mut self, _shown: bool) {
+        // no GUI, the parameters are edited in the instrument editor
+    }
+
+    fn get_params(&self) -> Vec<Parameter> {
+        PARAMS.iter().enumerate().map(|(index, info)| {
+            let value = self.params[index];
+            let (kind, display) = match info.unit {
+                Unit::Waveform => (
+                    ParameterKind::Enum(Waveform::ALL.iter().map(|wave| wave.name().to_string()).collect()),
+                    Waveform::from_index(value.round() as usize).name().to_string(),
+                ),
+                Unit::FilterMode => (
+                    ParameterKind::Enum(FILTER_MODES.iter().map(|mode| mode.to_string()).collect()),
+                    FILTER_MODES[(value.round() as usize).min(FILTER_MODES.len() - 1)].to_string(),
+                ),
+                Unit::Semitones => (ParameterKind::Stepped, format!("{:+} st", value.round() as i64)),
+                Unit::Voices => (ParameterKind::Stepped, format!("{}", value.round() as i64)),
+                Unit::Cents => (ParameterKind::Continuous, format!("{:+.1} ct", value)),
+                Unit::Percent => (ParameterKind::Continuous, format!("{:.0}%", value * 100.0)),
+                Unit::Hz => (ParameterKind::Continuous, format!("{:.2} Hz", value)),
+                Unit::Seconds => (ParameterKind::Continuous, format!("{:.3} s", value)),
+                Unit::Octaves => (ParameterKind::Continuous, format!("{:+.2} oct", value)),
+            };
+
+            Parameter {
+                index,
+                name: info.name.to_string(),
+                value,
+                min: info.min,
+                max: info.max,
+                default: info.default,
+                display,
+                kind,
+                automatable: true,
+                read_only: false,
+            }
+        }).collect()
+    }
+
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError> {
+        let info = match PARAMS.get(index) {
+            Some(info) => info,
+            None => return Err(PluginError::NoSuchParameter(index)),
+        };
+
+        let mut value = value.clamp(info.min, info.max);
+        if matches!(info.unit, Unit::Waveform | Unit::FilterMode | Unit::Semitones | Unit::Voices) {
+            value = value.round();
+        }
+        self.params[index] = value;
+
+        // Oscillators take their settings at note on, except for these
+        match index {
+            OSC1_WAVE | OSC2_WAVE | PULSE_WIDTH => {
+                let (wave1, wave2) = (self.waveform(OSC1_WAVE), self.waveform(OSC2_WAVE));
+                let pulse_width = self.param(PULSE_WIDTH);
+                for voice in self.voices.iter_mut() {
+                    for osc in voice.osc1.iter_mut() {
+                        osc.waveform = wave1;
+                        osc.pulse_width = pulse_width;
+                    }
+                    for osc in voice.osc2.iter_mut() {
+                        osc.waveform = wave2;
+                        osc.pulse_width = pulse_width;
+                    }
+                }
+            },
+            LFO_WAVE => self.lfo.waveform = self.waveform(LFO_WAVE),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    // Layout: version, parameter count, then the parameters as little endian f64s
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError> {
+        let mut state = vec![STATE_VERSION, PARAM_COUNT as u8];
+        for value in self.params {
+            state.extend_from_slice(&value.to_le_bytes());
+        }
+
+        Ok(state)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        if state.len() < 2 || state[0] != STATE_VERSION || state.len() != 2 + state[1] as usize * 8 {
+            return Err(PluginError::StateError("Unknown SubSynth state".to_string()))
+        }
+
+        // Parameters added later keep their defaults
+        for (index, bytes) in state[2..].chunks_exact(8).enumerate().take(PARAM_COUNT) {
+            let mut value = [0u8; 8];
+            value.copy_from_slice(bytes);
+            self.set_param(index, f64::from_le_bytes(value))?;
+        }
+
+        Ok(())
+    }
+
+    fn enable(&mut self) {
+        self.active = true;
+    }
+
+    fn disable(&mut self) {
+        self.active = false;
+        self.voices.clear();
+    }
+
+    fn active(&self) -> bool {
+        self.active
+    }
+
+    fn error(&self) -> Option<&PluginError> {
+        None
+    }
+}
@@ -1,14 +1,28 @@
-use std::sync::mpsc;
+use std::ffi::{CStr, CString, c_void};
+use std::os::raw::c_char;
+use std::ptr;
 
-use clap_sys::{plugin::clap_plugin, entry::{clap_plugin_entry, self}};
-use libloading::{Library, Symbol};
+use clap_sys::{
+    plugin::{clap_plugin, clap_plugin_descriptor},
+    entry::clap_plugin_entry,
+    host::clap_host,
+    plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID},
+    ext::{
+        audio_ports::{clap_plugin_audio_ports, clap_audio_port_info, CLAP_EXT_AUDIO_PORTS},
+        note_ports::{clap_plugin_note_ports, CLAP_EXT_NOTE_PORTS},
+        params::{clap_plugin_params, clap_param_info, CLAP_EXT_PARAMS, CLAP_PARAM_IS_STEPPED, CLAP_PARAM_IS_AUTOMATABLE, CLAP_PARAM_IS_READONLY, CLAP_PARAM_IS_HIDDEN},
+        state::{clap_plugin_state, CLAP_EXT_STATE},
+    },
+    events::*,
+    audio_buffer::clap_audio_buffer,
+    process::clap_process,
+    stream::{clap_istream, clap_ostream},
+    version::{CLAP_VERSION, clap_version_is_compatible},
+};
+use libloading::Library;
 
-use super::interface::{Plugin, PluginError, Event, Parameter};
-
-struct ClapPlugin {
-    active: bool,
-    lib: Library
-}
+use super::interface::{Plugin, PluginError, Parameter, ParameterKind, PluginDescriptor, TimedEvent, Event};
+use super::scanner::PluginDatabase;
 
 macro_rules! plugin_load_error {
     ($err:ident) => {
@@ -16,61 +30,790 @@ macro_rules! plugin_load_error {
     };
 }
 
-impl Plugin for ClapPlugin {
-    fn new(path: &str) -> Result<Self, PluginError> {
-        let lib: libloading::Library;
-        let clap_entry: Symbol<clap_plugin_entry>;
+// A loaded and initialized CLAP bundle. Deinitializes the entry when dropped.
+pub(crate) struct ClapBundle {
+    path: String,
+    entry: *const clap_plugin_entry,
+    lib: Library,
+}
+
+impl ClapBundle {
+    pub fn load(path: &str) -> Result<ClapBundle, PluginError> {
+        let lib: Library;
+        let entry: *const clap_plugin_entry;
 
         unsafe {
-            match libloading::Library::new(path) {
+            match Library::new(path) {
                 Ok(loaded_lib) => lib = loaded_lib,
                 Err(err) => plugin_load_error!(err)
             }
 
-            /* match lib.get(b"clap_entry") {
-                Ok(entry) => clap_entry = *entry,
-                Err(err) => return Err(plugin_load_error!(err)),
-            } */
+            // clap_entry is a struct, not a function
+            match lib.get::<*const clap_plugin_entry>(b"clap_entry\0") {
+                Ok(symbol) => entry = *symbol,
+                Err(err) => plugin_load_error!(err),
+            }
+        }
+
+        if entry.is_null() {
+            return Err(PluginError::LoadError("clap_entry is null".to_string()))
         }
 
-        let mut this = ClapPlugin { lib, active: false };
         unsafe {
-            match this.lib.get::<clap_plugin_entry>(b"clap_entry") {
-                Ok(symbol) => clap_entry = symbol,
-                Err(err) => plugin_load_error!(err),
+            if !clap_version_is_compatible((*entry).clap_version) {
+                return Err(PluginError::LoadError(format!(
+                    "Incompatible CLAP version {}.{}.{}",
+                    (*entry).clap_version.major, (*entry).clap_version.minor, (*entry).clap_version.revision
+                )))
+            }
+
+            let c_path = CString::new(path).unwrap_or_default();
+            match (*entry).init {
+                Some(init) => if !init(c_path.as_ptr()) {
+                    return Err(PluginError::InitError("clap_entry.init() failed".to_string()))
+                },
+                None => return Err(PluginError::LoadError("clap_entry.init is null".to_string())),
             }
         }
 
-        // OK. Now what?
+        Ok(ClapBundle { path: path.to_string(), entry, lib })
+    }
 
-        todo!()
+    fn factory(&self) -> Option<&clap_plugin_factory> {
+        unsafe {
+            let get_factory = (*self.entry).get_factory?;
+            let factory = get_factory(CLAP_PLUGIN_FACTORY_ID.as_ptr()) as *const clap_plugin_factory;
+
+            factory.as_ref()
+        }
     }
 
-    fn process(&mut self, events: &[super::interface::TimedEvent], input: &[f32], output: &mut [f32]) {
-        todo!()
+    fn raw_descriptors(&self) -> Vec<&clap_plugin_descriptor> {
+        let mut descriptors = Vec::new();
+        let factory = match self.factory() {
+            Some(factory) => factory,
+            None => return descriptors,
+        };
+
+        unsafe {
+            let (Some(get_plugin_count), Some(get_plugin_descriptor)) = (factory.get_plugin_count, factory.get_plugin_descriptor) else {
+                return descriptors
+            };
+
+            for i in 0..get_plugin_count(factory) {
+                if let Some(desc) = get_plugin_descriptor(factory, i).as_ref() {
+                    descriptors.push(desc)
+                }
+            }
+        }
+
+        descriptors
+    }
+
+    // Creates and initializes a plugin instance. The host must outlive it.
+    pub fn create_plugin(&self, host: *const clap_host, id: &str) -> Result<*const clap_plugin, PluginError> {
+        let factory = match self.factory() {
+            Some(factory) => factory,
+            None => return Err(PluginError::LoadError("Bundle has no plugin factory".to_string())),
+        };
+        let c_id = CString::new(id).unwrap_or_default();
+
+        unsafe {
+            let plugin = match factory.create_plugin {
+                Some(create_plugin) => create_plugin(factory, host, c_id.as_ptr()),
+                None => ptr::null(),
+            };
+            if plugin.is_null() {
+                return Err(PluginError::NoSuchPlugin)
+            }
+
+            match (*plugin).init {
+                Some(init) if init(plugin) => Ok(plugin),
+                _ => {
+                    if let Some(destroy) = (*plugin).destroy {
+                        destroy(plugin)
+                    }
+                    Err(PluginError::InitError(format!("{id} failed to initialize")))
+                }
+            }
+        }
+    }
+
+    // Describes every plugin in the bundle, including its ports.
+    // This instantiates each plugin, so it should only be called from the scanner process.
+    pub fn describe(&self) -> Vec<PluginDescriptor> {
+        let host = new_host();
+        let mut descriptors = Vec::new();
+
+        for desc in self.raw_descriptors() {
+            let id = unsafe { string_from_ptr(desc.id) };
+            let mut descriptor = PluginDescriptor {
+                path: format!("{}#{}", self.path, id),
+                id: id.clone(),
+                name: unsafe { string_from_ptr(desc.name) },
+                vendor: unsafe { string_from_ptr(desc.vendor) },
+                version: unsafe { string_from_ptr(desc.version) },
+                features: Vec::new(),
+                audio_inputs: Vec::new(),
+                audio_outputs: Vec::new(),
+                note_inputs: 0,
+                note_outputs: 0,
+            };
+
+            unsafe {
+                let mut feature = desc.features;
+                while !feature.is_null() && !(*feature).is_null() {
+                    descriptor.features.push(string_from_ptr(*feature));
+                    feature = feature.add(1);
+                }
+            }
+
+            if let Ok(plugin) = self.create_plugin(&*host, &id) {
+                unsafe {
+                    if let Some(audio_ports) = (get_extension(plugin, CLAP_EXT_AUDIO_PORTS) as *const clap_plugin_audio_ports).as_ref() {
+                        descriptor.audio_inputs = audio_port_channels(plugin, audio_ports, true);
+                        descriptor.audio_outputs = audio_port_channels(plugin, audio_ports, false);
+                    }
+                    if let Some(note_ports) = (get_extension(plugin, CLAP_EXT_NOTE_PORTS) as *const clap_plugin_note_ports).as_ref() {
+                        if let Some(count) = note_ports.count {
+                            descriptor.note_inputs = count(plugin, true);
+                            descriptor.note_outputs = count(plugin, false);
+                        }
+                    }
+
+                    if let Some(destroy) = (*plugin).destroy {
+                        destroy(plugin)
+                    }
+                }
+            }
+
+            descriptors.push(descriptor);
+        }
+
+        descriptors
+    }
+}
+
+impl Drop for ClapBundle {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(deinit) = (*self.entry).deinit {
+                deinit()
+            }
+        }
+    }
+}
+
+unsafe fn string_from_ptr(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new()
+    }
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+unsafe fn get_extension(plugin: *const clap_plugin, id: &CStr) -> *const c_void {
+    match (*plugin).get_extension {
+        Some(get_extension) => get_extension(plugin, id.as_ptr()),
+        None => ptr::null(),
+    }
+}
+
+unsafe fn audio_port_channels(plugin: *const clap_plugin, audio_ports: &clap_plugin_audio_ports, is_input: bool) -> Vec<u32> {
+    let mut channels = Vec::new();
+    let (Some(count), Some(get)) = (audio_ports.count, audio_ports.get) else {
+        return channels
+    };
+
+    for i in 0..count(plugin, is_input) {
+        let mut info: clap_audio_port_info = std::mem::zeroed();
+        if get(plugin, i, is_input, &mut info) {
+            channels.push(info.channel_count)
+        }
+    }
+
+    channels
+}
+
+/*
+    HOST
+*/
+
+unsafe extern "C" fn host_get_extension(_host: *const clap_host, _extension_id: *const c_char) -> *const c_void {
+    // TODO host extensions
+    ptr::null()
+}
+
+unsafe extern "C" fn host_request_noop(_host: *const clap_host) {
+    // no-op
+}
+
+pub(crate) fn new_host() -> Box<clap_host> {
+    Box::new(clap_host {
+        clap_version: CLAP_VERSION,
+        host_data: ptr::null_mut(),
+        name: b"Project Corrosion\0".as_ptr() as *const c_char,
+        vendor: b"Polyzium Productions\0".as_ptr() as *const c_char,
+        url: b"\0".as_ptr() as *const c_char,
+        version: b"0.0.0\0".as_ptr() as *const c_char,
+        get_extension: Some(host_get_extension),
+        request_restart: Some(host_request_noop),
+        request_process: Some(host_request_noop),
+        request_callback: Some(host_request_noop),
+    })
+}
+
+/*
+    EVENTS
+*/
+
+// Every event type we send to plugins. The header is always the first field.
+enum ClapEvent {
+    Note(clap_event_note),
+    Expression(clap_event_note_expression),
+    Param(clap_event_param_value),
+    Midi(clap_event_midi),
+}
+
+impl ClapEvent {
+    fn header(&self) -> &clap_event_header {
+        match self {
+            ClapEvent::Note(e) => &e.header,
+            ClapEvent::Expression(e) => &e.header,
+            ClapEvent::Param(e) => &e.header,
+            ClapEvent::Midi(e) => &e.header,
+        }
+    }
+}
+
+fn event_header<T>(time: u32, type_: clap_event_type) -> clap_event_header {
+    clap_event_header {
+        size: std::mem::size_of::<T>() as u32,
+        time,
+        space_id: CLAP_CORE_EVENT_SPACE_ID,
+        type_,
+        flags: 0,
+    }
+}
+
+fn param_event(id: usize, value: f64) -> ClapEvent {
+    ClapEvent::Param(clap_event_param_value {
+        header: event_header::<clap_event_param_value>(0, CLAP_EVENT_PARAM_VALUE),
+        param_id: id as u32,
+        cookie: ptr::null_mut(),
+        note_id: -1,
+        port_index: -1,
+        channel: -1,
+        key: -1,
+        value,
+    })
+}
+
+fn convert_event(e: &TimedEvent, keys: &mut [i16]) -> ClapEvent {
+    let note = |type_, id: usize, key: u8, vel: u8| ClapEvent::Note(clap_event_note {
+        header: event_header::<clap_event_note>(e.position, type_),
+        note_id: id as i32,
+        port_index: 0,
+        channel: 0,
+        key: key as i16,
+        velocity: vel as f64 / 127.0,
+    });
+    let expression = |expression_id, id: usize, key: i16, value| ClapEvent::Expression(clap_event_note_expression {
+        header: event_header::<clap_event_note_expression>(e.position, CLAP_EVENT_NOTE_EXPRESSION),
+        expression_id,
+        note_id: id as i32,
+        port_index: 0,
+        channel: 0,
+        key,
+        value,
+    });
+
+    match e.event {
+        Event::NoteOn { id, key, vel } => {
+            keys[id % keys.len()] = key as i16;
+            note(CLAP_EVENT_NOTE_ON, id, key, vel)
+        },
+        Event::NoteOff { id, key, vel } => note(CLAP_EVENT_NOTE_OFF, id, key, vel),
+        Event::ControlChange { index, value } => ClapEvent::Midi(clap_event_midi {
+            header: event_header::<clap_event_midi>(e.position, CLAP_EVENT_MIDI),
+            port_index: 0,
+            data: [0xB0, index, value],
+        }),
+        Event::ExprPitch { id, target_pitch } => expression(CLAP_NOTE_EXPRESSION_TUNING, id, keys[id % keys.len()], target_pitch as f64),
+        // CLAP volume is linear gain in 0..=4
+        Event::ExprVolume { id, target_vol } => expression(CLAP_NOTE_EXPRESSION_VOLUME, id, keys[id % keys.len()], target_vol as f64 / 127.0),
+        Event::ExprPressure { id, pressure } => expression(CLAP_NOTE_EXPRESSION_PRESSURE, id, keys[id % keys.len()], pressure as f64 / 127.0),
+        Event::ExprTimbre { id, timbre } => expression(CLAP_NOTE_EXPRESSION_BRIGHTNESS, id, keys[id % keys.len()], timbre as f64 / 127.0),
+    }
+}
+
+unsafe extern "C" fn input_events_size(list: *const clap_input_events) -> u32 {
+    let events = &*((*list).ctx as *const Vec<ClapEvent>);
+    events.len() as u32
+}
+
+unsafe extern "C" fn input_events_get(list: *const clap_input_events, index: u32) -> *const clap_event_header {
+    let events = &*((*list).ctx as *const Vec<ClapEvent>);
+    match events.get(index as usize) {
+        Some(e) => e.header(),
+        None => ptr::null(),
+    }
+}
+
+unsafe extern "C" fn output_events_try_push(_list: *const clap_output_events, _event: *const clap_event_header) -> bool {
+    // TODO parameter changes made by the plugin itself
+    true
+}
+
+/*
+    STREAMS
+*/
+
+unsafe extern "C" fn ostream_write(stream: *const clap_ostream, buffer: *const c_void, size: u64) -> i64 {
+    let data = &mut *((*stream).ctx as *mut Vec<u8>);
+    data.extend_from_slice(std::slice::from_raw_parts(buffer as *const u8, size as usize));
+    size as i64
+}
+
+unsafe extern "C" fn istream_read(stream: *const clap_istream, buffer: *mut c_void, size: u64) -> i64 {
+    let data = &mut *((*stream).ctx as *mut &[u8]);
+    let len = data.len().min(size as usize);
+    std::ptr::copy_nonoverlapping(data.as_ptr(), buffer as *mut u8, len);
+    *data = &data[len..];
+    len as i64
+}
+
+/*
+    PLUGIN
+*/
+
+// Largest number of steps for which a stepped parameter is checked for being an enum
+const MAX_ENUM_STEPS: f64 = 32.0;
+
+pub(crate) struct ClapPlugin {
+    active: bool,
+    processing: bool,
+    plugin: *const clap_plugin,
+    // The plugin keeps a pointer to the host, so it's boxed and must be dropped after the plugin
+    host: Box<clap_host>,
+    bundle: ClapBundle,
+
+    samplerate: u32,
+    channels: u8,
+    sample_size: u32,
+    steady_time: i64,
+
+    in_events: Vec<ClapEvent>,
+    pending_params: Vec<ClapEvent>, // set_param() changes, sent with the next process() or flush
+    note_keys: Vec<i16>, // key of each note ID, expressions need it
+
+    // Deinterleaved audio, one buffer per channel per port, allocated in configure()
+    inputs: Vec<Vec<Vec<f32>>>,
+    outputs: Vec<Vec<Vec<f32>>>,
+    // What process() hands the plugin, pointing into the buffers above
+    input_ptrs: Vec<Vec<*const f32>>,
+    output_ptrs: Vec<Vec<*const f32>>,
+    audio_inputs: Vec<clap_audio_buffer>,
+    audio_outputs: Vec<clap_audio_buffer>,
+}
+
+// The plugin instance is only ever touched while the DAWEngine mutex is held
+unsafe impl Send for ClapPlugin {}
+
+impl Drop for ClapPlugin {
+    fn drop(&mut self) {
+        self.disable();
+        unsafe {
+            if let Some(destroy) = (*self.plugin).destroy {
+                destroy(self.plugin)
+            }
+        }
+    }
+}
+
+impl ClapPlugin {
+    fn params_ext(&self) -> Option<&clap_plugin_params> {
+        unsafe { (get_extension(self.plugin, CLAP_EXT_PARAMS) as *const clap_plugin_params).as_ref() }
+    }
+
+    fn state_ext(&self) -> Option<&clap_plugin_state> {
+        unsafe { (get_extension(self.plugin, CLAP_EXT_STATE) as *const clap_plugin_state).as_ref() }
+    }
+
+    fn value_to_text(&self, params: &clap_plugin_params, id: u32, value: f64) -> Option<String> {
+        let mut display = [0 as c_char; 64];
+        unsafe {
+            if params.value_to_text?(self.plugin, id, value, display.as_mut_ptr(), display.len() as u32) {
+                Some(string_from_ptr(display.as_ptr()))
+            } else {
+                None
+            }
+        }
+    }
+
+    // Sends pending parameter changes to the plugin outside of process()
+    fn flush_params(&mut self) {
+        if self.pending_params.is_empty() {
+            return
+        }
+        let flush = match self.params_ext().and_then(|params| params.flush) {
+            Some(flush) => flush,
+            None => return,
+        };
+
+        let in_events = clap_input_events {
+            ctx: &self.pending_params as *const Vec<ClapEvent> as *mut c_void,
+            size: Some(input_events_size),
+            get: Some(input_events_get),
+        };
+        let out_events = clap_output_events { ctx: ptr::null_mut(), try_push: Some(output_events_try_push) };
+
+        unsafe { flush(self.plugin, &in_events, &out_events) };
+        self.pending_params.clear();
+    }
+
+    // Sized for the configured block, so process() doesn't allocate
+    fn allocate_buffers(&mut self) {
+        let frames = self.sample_size as usize;
+        self.inputs = self.port_channels(true).iter().map(|&ch| vec![vec![0.0; frames]; ch as usize]).collect();
+        self.outputs = self.port_channels(false).iter().map(|&ch| vec![vec![0.0; frames]; ch as usize]).collect();
+
+        self.input_ptrs = self.inputs.iter().map(|port| port.iter().map(|ch| ch.as_ptr()).collect()).collect();
+        self.output_ptrs = self.outputs.iter_mut().map(|port| port.iter_mut().map(|ch| ch.as_mut_ptr() as *const f32).collect()).collect();
+        let audio_buffer = |ptrs: &Vec<*const f32>| clap_audio_buffer {
+            data32: ptrs.as_ptr(),
+            data64: ptr::null(),
+            channel_count: ptrs.len() as u32,
+            latency: 0,
+            constant_mask: 0,
+        };
+        self.audio_inputs = self.input_ptrs.iter().map(audio_buffer).collect();
+        self.audio_outputs = self.output_ptrs.iter().map(audio_buffer).collect();
+    }
+
+    // Only the one parameter, get_params() also works out enums which takes a while
+    fn param_info(&self, id: u32) -> Option<clap_param_info> {
+        let params = self.params_ext()?;
+        let (count, get_info) = (params.count?, params.get_info?);
+
+        unsafe {
+            (0..count(self.plugin)).find_map(|i| {
+                let mut info: clap_param_info = std::mem::zeroed();
+                (get_info(self.plugin, i, &mut info) && info.id == id).then_some(info)
+            })
+        }
+    }
+
+    fn port_channels(&self, is_input: bool) -> Vec<u32> {
+        unsafe {
+            match (get_extension(self.plugin, CLAP_EXT_AUDIO_PORTS) as *const clap_plugin_audio_ports).as_ref() {
+                Some(audio_ports) => audio_port_channels(self.plugin, audio_ports, is_input),
+                None => Vec::new(),
+            }
+        }
+    }
+}
+
+impl Plugin for ClapPlugin {
+    // path is either the bundle path, or "bundle path#plugin id" for bundles containing multiple plugins
+    fn new(path: &str) -> Result<Self, PluginError> {
+        let (bundle_path, id) = match path.rsplit_once('#') {
+            Some((bundle_path, id)) => (bundle_path, Some(id)),
+            None => (path, None),
+        };
+
+        if PluginDatabase::load().is_blacklisted(bundle_path) {
+            return Err(PluginError::Blacklisted(bundle_path.to_string()))
+        }
+
+        let bundle = ClapBundle::load(bundle_path)?;
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => match bundle.raw_descriptors().first() {
+                Some(desc) => unsafe { string_from_ptr(desc.id) },
+                None => return Err(PluginError::NoSuchPlugin),
+            },
+        };
+
+        let host = new_host();
+        let plugin = bundle.create_plugin(&*host, &id)?;
+
+        Ok(ClapPlugin {
+            active: false,
+            processing: false,
+            plugin,
+            host,
+            bundle,
+
+            samplerate: 48000,
+            channels: 2,
+            sample_size: 512,
+            steady_time: 0,
+
+            in_events: Vec::with_capacity(1024),
+            pending_params: Vec::with_capacity(64),
+            note_keys: vec![-1; 256],
+
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            input_ptrs: Vec::new(),
+            output_ptrs: Vec::new(),
+            audio_inputs: Vec::new(),
+            audio_outputs: Vec::new(),
+        })
+    }
+
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> {
+        let mut database = PluginDatabase::load();
+        database.scan()?;
+
+        Ok(database.plugins)
+    }
+
+    fn configure(&mut self, samplerate: u32, channels: u8, sample_size: u32) {
+        let was_active = self.active;
+        if was_active {
+            self.disable();
+        }
+
+        self.samplerate = samplerate;
+        self.channels = channels;
+        self.sample_size = sample_size;
+        self.allocate_buffers();
+
+        if was_active {
+            self.enable();
+        }
+    }
+
+    fn process(&mut self, events: &[TimedEvent], input: &[f32], output: &mut [f32]) {
+        output.fill(0.0);
+        if !self.processing {
+            return
+        }
+
+        let channels = self.channels as usize;
+        let frames = (output.len() / channels).min(self.sample_size as usize);
+
+        // Events, parameter changes go first
+        self.in_events.clear();
+        self.in_events.append(&mut self.pending_params);
+        for e in events {
+            let converted = convert_event(e, &mut self.note_keys);
+            self.in_events.push(converted);
+        }
+        // CLAP requires events to be sorted by time
+        self.in_events.sort_by_key(|e| e.header().time);
+
+        // Deinterleave the input into the main port
+        if let Some(port) = self.inputs.first_mut() {
+            for (ch, buffer) in port.iter_mut().enumerate() {
+                for frame in 0..frames {
+                    buffer[frame] = if ch < channels { input.get(frame * channels + ch).copied().unwrap_or(0.0) } else { 0.0 };
+                }
+            }
+        }
+
+        let in_events = clap_input_events {
+            ctx: &self.in_events as *const Vec<ClapEvent> as *mut c_void,
+            size: Some(input_events_size),
+            get: Some(input_events_get),
+        };
+        let out_events = clap_output_events { ctx: ptr::null_mut(), try_push: Some(output_events_try_push) };
+
+        let process = clap_process {
+            steady_time: self.steady_time,
+            frames_count: frames as u32,
+            transport: ptr::null(), // TODO transport
+            audio_inputs: self.audio_inputs.as_ptr(),
+            audio_outputs: self.audio_outputs.as_mut_ptr(),
+            audio_inputs_count: self.audio_inputs.len() as u32,
+            audio_outputs_count: self.audio_outputs.len() as u32,
+            in_events: &in_events,
+            out_events: &out_events,
+        };
+
+        unsafe {
+            if let Some(process_fn) = (*self.plugin).process {
+                process_fn(self.plugin, &process);
+            }
+        }
+        self.steady_time += frames as i64;
+
+        // Interleave the main output port
+        if let Some(port) = self.outputs.first() {
+            for (ch, buffer) in port.iter().enumerate().take(channels) {
+                for frame in 0..frames {
+                    output[frame * channels + ch] = buffer[frame];
+                }
+            }
+            // Mono plugins go to every channel
+            if port.len() == 1 {
+                for frame in 0..frames {
+                    for ch in 1..channels {
+                        output[frame * channels + ch] = port[0][frame];
+                    }
+                }
+            }
+        }
     }
 
     /* fn note_states(&self) -> &[super::interface::NoteState] {
         todo!()
     } */
 
-    fn show_gui(&mut self, shown: bool) {
-        todo!()
+    fn show_gui(&mut self, _shown: bool) {
+        // TODO GUI extension
     }
 
     fn get_params(&self) -> Vec<Parameter> {
-        todo!()
+        let mut out = Vec::new();
+        let params = match self.params_ext() {
+            Some(params) => params,
+            None => return out,
+        };
+        let (Some(count), Some(get_info), Some(get_value)) = (params.count, params.get_info, params.get_value) else {
+            return out
+        };
+
+        unsafe {
+            for i in 0..count(self.plugin) {
+                let mut info: clap_param_info = std::mem::zeroed();
+                if !get_info(self.plugin, i, &mut info) || info.flags & CLAP_PARAM_IS_HIDDEN != 0 {
+                    continue
+                }
+
+                let mut value = info.default_value;
+                get_value(self.plugin, info.id, &mut value);
+
+                let mut kind = ParameterKind::Continuous;
+                if info.flags & CLAP_PARAM_IS_STEPPED != 0 {
+                    kind = ParameterKind::Stepped;
+
+                    // A stepped parameter with non-numeric labels is really an enum
+                    if info.max_value - info.min_value <= MAX_ENUM_STEPS {
+                        let labels: Vec<String> = (info.min_value as i64..=info.max_value as i64)
+                            .map(|step| self.value_to_text(params, info.id, step as f64).unwrap_or_default())
+                            .collect();
+                        if labels.iter().all(|label| !label.is_empty() && label.trim().parse::<f64>().is_err()) {
+                            kind = ParameterKind::Enum(labels);
+                        }
+                    }
+                }
+
+                out.push(Parameter {
+                    index: info.id as usize,
+                    name: string_from_ptr(info.name.as_ptr()),
+                    value,
+                    min: info.min_value,
+                    max: info.max_value,
+                    default: info.default_value,
+                    display: self.value_to_text(params, info.id, value).unwrap_or_else(|| format!("{:.3}", value)),
+                    kind,
+                    automatable: info.flags & CLAP_PARAM_IS_AUTOMATABLE != 0,
+                    read_only: info.flags & CLAP_PARAM_IS_READONLY != 0,
+                });
+            }
+        }
+
+        out
+    }
+
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError> {
+        let info = match self.param_info(index as u32) {
+            Some(info) if info.flags & (CLAP_PARAM_IS_HIDDEN | CLAP_PARAM_IS_READONLY) == 0 => info,
+            _ => return Err(PluginError::NoSuchParameter(index)),
+        };
+
+        self.pending_params.push(param_event(index, value.clamp(info.min_value, info.max_value)));
+        // When processing, the change is sent with the next block
+        if !self.processing {
+            self.flush_params();
+        }
+
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError> {
+        let save = match self.state_ext().and_then(|state| state.save) {
+            Some(save) => save,
+            None => return Ok(Vec::new()), // stateless plugin
+        };
+
+        let mut data: Vec<u8> = Vec::new();
+        let stream = clap_ostream { ctx: &mut data as *mut Vec<u8> as *mut c_void, write: Some(ostream_write) };
+
+        if unsafe { save(self.plugin, &stream) } {
+            Ok(data)
+        } else {
+            Err(PluginError::StateError("clap_plugin_state.save() failed".to_string()))
+        }
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        let load = match self.state_ext().and_then(|state| state.load) {
+            Some(load) => load,
+            None => return Ok(()),
+        };
+
+        let mut remaining = state;
+        let stream = clap_istream { ctx: &mut remaining as *mut &[u8] as *mut c_void, read: Some(istream_read) };
+
+        if unsafe { load(self.plugin, &stream) } {
+            Ok(())
+        } else {
+            Err(PluginError::StateError("clap_plugin_state.load() failed".to_string()))
+        }
     }
 
     fn enable(&mut self) {
-        todo!()
+        if self.active {
+            return
+        }
+
+        unsafe {
+            let activate = match (*self.plugin).activate {
+                Some(activate) => activate,
+                None => return,
+            };
+            if !activate(self.plugin, self.samplerate as f64, 1, self.sample_size) {
+                eprintln!("ClapPlugin: activate() failed");
+                return
+            }
+            self.active = true;
+
+            // Called from the UI thread for now, which CLAP doesn't like, but the engine hasn't got the plugin yet
+            self.processing = match (*self.plugin).start_processing {
+                Some(start_processing) => start_processing(self.plugin),
+                None => true,
+            };
+        }
     }
 
     fn disable(&mut self) {
-        todo!()
+        if !self.active {
+            return
+        }
+
+        unsafe {
+            if self.processing {
+                if let Some(stop_processing) = (*self.plugin).stop_processing {
+                    stop_processing(self.plugin)
+                }
+                self.processing = false;
+            }
+            if let Some(deactivate) = (*self.plugin).deactivate {
+                deactivate(self.plugin)
+            }
+        }
+        self.active = false;
+        self.flush_params();
     }
 
     fn active(&self) -> bool {
         self.active
     }
-}
\ No newline at end of file
+
+    fn error(&self) -> Option<&PluginError> {
+        None
+    }
+}
@@ -1,16 +1,38 @@
-use std::{sync::mpsc};
-
 // use super::builtin::midi::plugin::MidiOutPlugin;
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParameterKind {
+    Continuous,
+    Stepped, // only whole numbers between min and max
+    Enum(Vec<String>), // value is an index into the list
+}
+
 // Describes a parameter
+#[derive(Clone, Debug)]
 pub struct Parameter {
-    pub index: usize,
+    pub index: usize, // the plugin's own ID, e.g. clap_id for CLAP
     pub name: String,
-    pub value: u8, // Max is 127 per MIDI standard
-    pub min: u8,
-    pub max: u8,
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    pub display: String, // value formatted by the plugin, e.g. "440 Hz"
+    pub kind: ParameterKind,
+    pub automatable: bool,
+    pub read_only: bool,
 }
 
+impl Parameter {
+    // Value mapped to 0.0..=1.0, for sliders
+    pub fn normalized(&self) -> f64 {
+        if self.max <= self.min {
+            return 0.0
+        }
+        ((self.value - self.min) / (self.max - self.min)).clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct TimedEvent {
     pub module_index: usize,
 
@@ -18,6 +40,7 @@ pub struct TimedEvent {
     pub event: Event
 }
 
+#[derive(Clone, Copy)]
 pub enum Event {
     NoteOff{id: usize, key: u8, vel: u8},
     NoteOn{id: usize, key: u8, vel: u8},
@@ -25,7 +48,9 @@ pub enum Event {
 
     // polyphonic expressions (for MPE and CLAP)
     ExprPitch{id: usize, target_pitch: f32}, // target pitch in semitones relative to the currently playing note
-    ExprVolume{id: usize, target_vol: u8} // 0..=127
+    ExprVolume{id: usize, target_vol: u8}, // 0..=127
+    ExprPressure{id: usize, pressure: u8}, // 0..=127, aftertouch
+    ExprTimbre{id: usize, timbre: u8} // 0..=127, MPE's CC #74 or CLAP's brightness
 }
 
 #[derive(Clone, Copy)]
@@ -39,11 +64,39 @@ pub struct NoteState {
     pub is_on: bool,
 }
 
+// Describes a plugin that can be inserted, as found by the plugin scanner
+#[derive(Clone, Debug)]
+pub struct PluginDescriptor {
+    pub path: String, // pass this to Plugin::new
+    pub id: String,
+    pub name: String,
+    pub vendor: String,
+    pub version: String,
+    pub features: Vec<String>,
+
+    pub audio_inputs: Vec<u32>, // channel count of each audio port
+    pub audio_outputs: Vec<u32>,
+    pub note_inputs: u32,
+    pub note_outputs: u32,
+}
+
+impl PluginDescriptor {
+    pub fn is_instrument(&self) -> bool {
+        self.features.iter().any(|feature| feature == "instrument")
+    }
+}
+
 #[derive(Debug)]
 pub enum PluginError {
     NoSuchPlugin, // Builtins only, there isn't such a plugin
     LoadError(String), // Failed to load an external (VST/CLAP/etc) plugin
-    InitError(String) // Plugin loaded, but failed to initialize
+    InitError(String), // Plugin loaded, but failed to initialize
+    ScanError(String), // Unable to scan for plugins or to read/write the plugin database
+    Blacklisted(String), // Plugin crashed while being scanned, refusing to load it
+    Crashed(String), // Bridged plugin's process crashed or stopped responding
+    NoSuchParameter(usize),
+    StateError(String), // Plugin refused to save or load its state
+    DeviceError(String) // A device the plugin talks to is unavailable, e.g. a MIDI port was unplugged
 }
 
 impl std::fmt::Display for PluginError {
@@ -52,18 +105,37 @@ impl std::fmt::Display for PluginError {
             PluginError::NoSuchPlugin => write!(f, "No such plugin"),
             PluginError::LoadError(desc) => write!(f, "Unable to load plugin: {desc}"),
             PluginError::InitError(desc) => write!(f, "Unable to initialize plugin: {desc}"),
+            PluginError::ScanError(desc) => write!(f, "Unable to scan plugins: {desc}"),
+            PluginError::NoSuchParameter(index) => write!(f, "No such parameter: {index}"),
+            PluginError::StateError(desc) => write!(f, "Unable to save or load plugin state: {desc}"),
+            PluginError::Crashed(desc) => write!(f, "Plugin crashed: {desc}"),
+            PluginError::DeviceError(desc) => write!(f, "Device error: {desc}"),
+            PluginError::Blacklisted(path) => write!(f, "Plugin {path} is blacklisted, as it has crashed during scanning"),
         }
     }
 }
 
 pub trait Plugin {
     fn new(path: &str) -> Result<Self, PluginError> where Self: Sized;
+    // Lists the plugins of this kind that can be passed to new()
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> where Self: Sized;
+    // Must be called before enable()
+    fn configure(&mut self, samplerate: u32, channels: u8, sample_size: u32);
+    // input and output are interleaved, with the channel count passed to configure()
     fn process(&mut self, events: &[TimedEvent], input: &[f32], output: &mut [f32]);
     // fn note_states(&self) -> &[NoteState];
     fn show_gui(&mut self, shown: bool);
     fn get_params(&self) -> Vec<Parameter>;
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError>;
+
+    // Opaque state blob, stored in the project
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError>;
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError>;
 
     fn enable(&mut self);
     fn disable(&mut self);
     fn active(&self) -> bool;
+    // Set if the plugin has failed in a way it can't recover from, e.g. a bridged plugin crashed,
+    // or while a device it needs is gone
+    fn error(&self) -> Option<&PluginError>;
 }
\ No newline at end of file
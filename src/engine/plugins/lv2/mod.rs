@@ -0,0 +1,1086 @@
+mod sys;
+mod turtle;
+
+use std::env;
+use std::ffi::{CStr, CString, c_void};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Mutex;
+
+use libloading::Library;
+
+use super::interface::{Plugin, PluginError, Parameter, ParameterKind, PluginDescriptor, TimedEvent, Event};
+use sys::*;
+use turtle::{Graph, Node, LV2, RDF, RDFS};
+
+const ATOM: &str = "http://lv2plug.in/ns/ext/atom#";
+const DOAP_NAME: &str = "http://usefulinc.com/ns/doap#name";
+const DOAP_MAINTAINER: &str = "http://usefulinc.com/ns/doap#maintainer";
+const FOAF_NAME: &str = "http://xmlns.com/foaf/0.1/name";
+
+const ATOM_BUFFER_SIZE: usize = 8192; // bytes per atom port
+const STATE_MAGIC: &[u8] = b"LV2S";
+
+// Features we provide, plugins requiring anything else are refused
+const SUPPORTED_FEATURES: [&str; 7] = [
+    LV2_URID__map,
+    LV2_URID__unmap,
+    LV2_WORKER__schedule,
+    LV2_OPTIONS__options,
+    LV2_BUF_SIZE__boundedBlockLength,
+    LV2_CORE__inPlaceBroken, // our buffers never alias anyway
+    LV2_CORE__isLive,
+];
+
+pub fn lv2_search_paths() -> Vec<PathBuf> {
+    if let Some(lv2_path) = env::var_os("LV2_PATH") {
+        return env::split_paths(&lv2_path).collect()
+    }
+
+    let mut paths: Vec<PathBuf> = Vec::new();
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(home) = env::var_os("HOME") {
+            paths.push(Path::new(&home).join(".lv2"));
+        }
+        paths.push(PathBuf::from("/usr/local/lib/lv2"));
+        paths.push(PathBuf::from("/usr/lib/lv2"));
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if let Some(home) = env::var_os("HOME") {
+            paths.push(Path::new(&home).join("Library/Audio/Plug-Ins/LV2"));
+        }
+        paths.push(PathBuf::from("/Library/Audio/Plug-Ins/LV2"));
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        if let Some(appdata) = env::var_os("APPDATA") {
+            paths.push(Path::new(&appdata).join("LV2"));
+        }
+        if let Some(common) = env::var_os("COMMONPROGRAMFILES") {
+            paths.push(Path::new(&common).join("LV2"));
+        }
+    }
+
+    paths
+}
+
+/*
+    BUNDLE DESCRIPTIONS
+*/
+
+#[derive(Clone, Copy, PartialEq)]
+enum PortKind {
+    Audio,
+    Control,
+    Atom,
+    Cv,
+}
+
+struct PortInfo {
+    index: u32,
+    name: String,
+    kind: PortKind,
+    is_input: bool,
+    optional: bool,
+    midi: bool, // atom port which supports MIDI events
+
+    default: f32,
+    min: f32,
+    max: f32,
+    integer: bool,
+    toggled: bool,
+    sample_rate: bool, // min and max are multiplied by the sample rate
+    scale_points: Vec<(f32, String)>, // only for enumeration ports, sorted by value
+}
+
+struct PluginInfo {
+    uri: String,
+    name: String,
+    vendor: String,
+    version: String,
+    bundle: PathBuf,
+    binary: PathBuf,
+    instrument: bool,
+    required_features: Vec<String>,
+    ports: Vec<PortInfo>,
+}
+
+impl PluginInfo {
+    fn descriptor(&self) -> PluginDescriptor {
+        let count = |kind: PortKind, is_input: bool| self.ports.iter().filter(|port| port.kind == kind && port.is_input == is_input).count() as u32;
+        // Audio ports are all mono in LV2, they're exposed as a single port with that many channels
+        let audio_port = |channels: u32| if channels > 0 { vec![channels] } else { Vec::new() };
+
+        PluginDescriptor {
+            path: format!("{}#{}", self.bundle.display(), self.uri),
+            id: self.uri.clone(),
+            name: self.name.clone(),
+            vendor: self.vendor.clone(),
+            version: self.version.clone(),
+            features: vec![if self.instrument { "instrument" } else { "audio-effect" }.to_string()],
+            audio_inputs: audio_port(count(PortKind::Audio, true)),
+            audio_outputs: audio_port(count(PortKind::Audio, false)),
+            note_inputs: self.ports.iter().filter(|port| port.midi && port.is_input).count() as u32,
+            note_outputs: self.ports.iter().filter(|port| port.midi && !port.is_input).count() as u32,
+        }
+    }
+}
+
+fn literal(graph: &Graph, subject: &Node, predicate: &str) -> Option<String> {
+    graph.object(subject, predicate).and_then(|node| node.as_str()).map(|s| s.to_string())
+}
+
+fn read_port(graph: &Graph, port: &Node) -> Option<PortInfo> {
+    let lv2 = |name: &str| format!("{LV2}{name}");
+
+    let kind = if graph.has_type(port, &lv2("AudioPort")) {
+        PortKind::Audio
+    } else if graph.has_type(port, &lv2("ControlPort")) {
+        PortKind::Control
+    } else if graph.has_type(port, &lv2("CVPort")) {
+        PortKind::Cv
+    } else if graph.has_type(port, &format!("{ATOM}AtomPort")) {
+        PortKind::Atom
+    } else {
+        return None
+    };
+
+    let port_property = lv2("portProperty");
+    let properties: Vec<&str> = graph.objects(port, &port_property).filter_map(|node| node.as_str()).collect();
+    let has_property = |name: &str| properties.iter().any(|property| *property == lv2(name));
+
+    let min = graph.object(port, &lv2("minimum")).and_then(Node::as_f32).unwrap_or(0.0);
+    let max = graph.object(port, &lv2("maximum")).and_then(Node::as_f32).unwrap_or(1.0);
+
+    let mut scale_points: Vec<(f32, String)> = Vec::new();
+    if has_property("enumeration") {
+        for point in graph.objects(port, &lv2("scalePoint")) {
+            if let (Some(value), Some(label)) = (graph.object(point, &format!("{RDF}value")).and_then(Node::as_f32), literal(graph, point, &format!("{RDFS}label"))) {
+                scale_points.push((value, label));
+            }
+        }
+        scale_points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+
+    Some(PortInfo {
+        index: graph.object(port, &lv2("index")).and_then(Node::as_f32)? as u32,
+        name: literal(graph, port, &lv2("name"))
+            .or_else(|| literal(graph, port, &lv2("symbol")))
+            .unwrap_or_default(),
+        kind,
+        is_input: graph.has_type(port, &lv2("InputPort")),
+        optional: has_property("connectionOptional"),
+        midi: graph.objects(port, &format!("{ATOM}supports")).any(|node| node.as_str() == Some(LV2_MIDI__MidiEvent)),
+
+        default: graph.object(port, &lv2("default")).and_then(Node::as_f32).unwrap_or(min),
+        min,
+        max,
+        integer: has_property("integer"),
+        toggled: has_property("toggled"),
+        sample_rate: has_property("sampleRate"),
+        scale_points,
+    })
+}
+
+// Reads the plugins described by a bundle's manifest and the files it refers to. The binaries aren't loaded.
+fn read_bundle(bundle: &Path) -> Result<Vec<PluginInfo>, PluginError> {
+    let mut graph = Graph::new();
+    graph.load(&bundle.join("manifest.ttl")).map_err(PluginError::LoadError)?;
+
+    let plugin_type = Node::Iri(format!("{LV2}Plugin"));
+    let type_predicate = format!("{RDF}type");
+    let see_also = format!("{RDFS}seeAlso");
+
+    let plugin_nodes: Vec<Node> = graph.subjects(&type_predicate, &plugin_type).cloned().collect();
+    let mut data_files: Vec<String> = Vec::new();
+    for plugin in &plugin_nodes {
+        for file in graph.objects(plugin, &see_also).filter_map(|node| node.as_str()) {
+            if !data_files.iter().any(|loaded| loaded == file) {
+                data_files.push(file.to_string());
+            }
+        }
+    }
+    for file in &data_files {
+        if let Some(path) = turtle::iri_to_path(file) {
+            graph.load(Path::new(&path)).map_err(PluginError::LoadError)?;
+        }
+    }
+
+    let mut plugins = Vec::new();
+    for plugin in &plugin_nodes {
+        let Some(uri) = plugin.as_str() else { continue };
+        let Some(binary) = graph.object(plugin, &format!("{LV2}binary")).and_then(|node| node.as_str()).and_then(turtle::iri_to_path) else {
+            continue
+        };
+
+        let mut ports: Vec<PortInfo> = graph.objects(plugin, &format!("{LV2}port")).filter_map(|port| read_port(&graph, port)).collect();
+        ports.sort_by_key(|port| port.index);
+
+        let version = match (literal(&graph, plugin, &format!("{LV2}minorVersion")), literal(&graph, plugin, &format!("{LV2}microVersion"))) {
+            (Some(minor), Some(micro)) => format!("0.{minor}.{micro}"),
+            _ => String::new(),
+        };
+
+        plugins.push(PluginInfo {
+            uri: uri.to_string(),
+            name: literal(&graph, plugin, DOAP_NAME).unwrap_or_else(|| uri.to_string()),
+            vendor: graph.object(plugin, DOAP_MAINTAINER)
+                .and_then(|maintainer| literal(&graph, maintainer, FOAF_NAME))
+                .unwrap_or_default(),
+            version,
+            bundle: bundle.to_path_buf(),
+            binary: PathBuf::from(binary),
+            instrument: graph.has_type(plugin, &format!("{LV2}InstrumentPlugin")),
+            required_features: graph.objects(plugin, &format!("{LV2}requiredFeature"))
+                .filter_map(|node| node.as_str())
+                .map(|feature| feature.to_string())
+                .collect(),
+            ports,
+        });
+    }
+
+    Ok(plugins)
+}
+
+/*
+    HOST FEATURES
+*/
+
+// URIDs are indices into this list, plus one
+struct UridMap {
+    uris: Mutex<Vec<CString>>,
+}
+
+impl UridMap {
+    fn map(&self, uri: &str) -> LV2_URID {
+        let mut uris = self.uris.lock().unwrap();
+        if let Some(index) = uris.iter().position(|mapped| mapped.as_bytes() == uri.as_bytes()) {
+            return index as LV2_URID + 1
+        }
+        uris.push(CString::new(uri).unwrap_or_default());
+        uris.len() as LV2_URID
+    }
+
+    fn unmap(&self, urid: LV2_URID) -> Option<String> {
+        let uris = self.uris.lock().unwrap();
+        uris.get((urid as usize).wrapping_sub(1)).map(|uri| uri.to_string_lossy().into_owned())
+    }
+}
+
+unsafe extern "C" fn urid_map(handle: *mut c_void, uri: *const c_char) -> LV2_URID {
+    if uri.is_null() {
+        return 0
+    }
+    let map = &*(handle as *const UridMap);
+    map.map(&CStr::from_ptr(uri).to_string_lossy())
+}
+
+unsafe extern "C" fn urid_unmap(handle: *mut c_void, urid: LV2_URID) -> *const c_char {
+    let map = &*(handle as *const UridMap);
+    let uris = map.uris.lock().unwrap();
+    match uris.get((urid as usize).wrapping_sub(1)) {
+        // The string's buffer is on the heap and never freed while the plugin lives
+        Some(uri) => uri.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+// Work is done synchronously right after run(), as we don't have a non-realtime thread for plugins yet
+#[derive(Default)]
+struct Worker {
+    requests: Vec<Vec<u8>>,
+    responses: Vec<Vec<u8>>,
+}
+
+unsafe fn copy_data(size: u32, data: *const c_void) -> Vec<u8> {
+    if data.is_null() || size == 0 {
+        return Vec::new()
+    }
+    std::slice::from_raw_parts(data as *const u8, size as usize).to_vec()
+}
+
+unsafe extern "C" fn worker_schedule(handle: *mut c_void, size: u32, data: *const c_void) -> u32 {
+    let worker = &mut *(handle as *mut Worker);
+    if size as usize > ATOM_BUFFER_SIZE {
+        return LV2_WORKER_ERR_NO_SPACE
+    }
+    worker.requests.push(copy_data(size, data));
+    LV2_WORKER_SUCCESS
+}
+
+unsafe extern "C" fn worker_respond(handle: *mut c_void, size: u32, data: *const c_void) -> u32 {
+    let worker = &mut *(handle as *mut Worker);
+    worker.responses.push(copy_data(size, data));
+    LV2_WORKER_SUCCESS
+}
+
+#[repr(C)]
+struct OptionValues {
+    min_block_length: i32,
+    max_block_length: i32,
+    sample_rate: f32,
+}
+
+// Everything the plugin may keep a pointer to. All of it is boxed or heap allocated, so it's fine to move this around.
+struct HostFeatures {
+    urids: Box<UridMap>,
+    map: Box<LV2_URID_Map>,
+    unmap: Box<LV2_URID_Unmap>,
+    worker: Box<Worker>,
+    schedule: Box<LV2_Worker_Schedule>,
+    option_values: Box<OptionValues>,
+    options: Vec<LV2_Options_Option>,
+    uris: Vec<CString>,
+    features: Vec<LV2_Feature>,
+    pointers: Vec<*const LV2_Feature>,
+}
+
+impl HostFeatures {
+    fn new() -> HostFeatures {
+        let mut urids = Box::new(UridMap { uris: Mutex::new(Vec::new()) });
+        let urids_ptr = &mut *urids as *mut UridMap as *mut c_void;
+        let mut worker = Box::<Worker>::default();
+        let worker_ptr = &mut *worker as *mut Worker as *mut c_void;
+
+        let mut host = HostFeatures {
+            map: Box::new(LV2_URID_Map { handle: urids_ptr, map: urid_map }),
+            unmap: Box::new(LV2_URID_Unmap { handle: urids_ptr, unmap: urid_unmap }),
+            urids,
+            schedule: Box::new(LV2_Worker_Schedule { handle: worker_ptr, schedule_work: worker_schedule }),
+            worker,
+            option_values: Box::new(OptionValues { min_block_length: 1, max_block_length: 512, sample_rate: 48000.0 }),
+            options: Vec::new(),
+            uris: Vec::new(),
+            features: Vec::new(),
+            pointers: Vec::new(),
+        };
+
+        let (int, float) = (host.urids.map(LV2_ATOM__Int), host.urids.map(LV2_ATOM__Float));
+        let values = &*host.option_values;
+        host.options = vec![
+            LV2_Options_Option { context: LV2_OPTIONS_INSTANCE, subject: 0, key: host.urids.map(LV2_BUF_SIZE__minBlockLength), size: 4, type_: int, value: &values.min_block_length as *const i32 as *const c_void },
+            LV2_Options_Option { context: LV2_OPTIONS_INSTANCE, subject: 0, key: host.urids.map(LV2_BUF_SIZE__maxBlockLength), size: 4, type_: int, value: &values.max_block_length as *const i32 as *const c_void },
+            LV2_Options_Option { context: LV2_OPTIONS_INSTANCE, subject: 0, key: host.urids.map(LV2_PARAMETERS__sampleRate), size: 4, type_: float, value: &values.sample_rate as *const f32 as *const c_void },
+            // terminator
+            LV2_Options_Option { context: LV2_OPTIONS_INSTANCE, subject: 0, key: 0, size: 0, type_: 0, value: ptr::null() },
+        ];
+
+        let data: [(&str, *mut c_void); 6] = [
+            (LV2_URID__map, &mut *host.map as *mut LV2_URID_Map as *mut c_void),
+            (LV2_URID__unmap, &mut *host.unmap as *mut LV2_URID_Unmap as *mut c_void),
+            (LV2_WORKER__schedule, &mut *host.schedule as *mut LV2_Worker_Schedule as *mut c_void),
+            (LV2_OPTIONS__options, host.options.as_mut_ptr() as *mut c_void),
+            (LV2_BUF_SIZE__boundedBlockLength, ptr::null_mut()),
+            (LV2_CORE__isLive, ptr::null_mut()),
+        ];
+        for (uri, _) in &data {
+            host.uris.push(CString::new(*uri).unwrap());
+        }
+        host.features = data.iter().zip(&host.uris).map(|((_, data), uri)| LV2_Feature { uri: uri.as_ptr(), data: *data }).collect();
+        host.pointers = host.features.iter().map(|feature| feature as *const LV2_Feature).collect();
+        host.pointers.push(ptr::null());
+
+        host
+    }
+}
+
+/*
+    STATE
+*/
+
+struct StateProperty {
+    key: String,
+    type_: String,
+    flags: u32,
+    value: Vec<u8>,
+}
+
+struct StateStore<'a> {
+    urids: &'a UridMap,
+    properties: Vec<StateProperty>,
+}
+
+struct StateRetrieve {
+    properties: Vec<(LV2_URID, LV2_URID, u32, Vec<u8>)>, // key, type, flags, value
+}
+
+unsafe extern "C" fn state_store(handle: *mut c_void, key: u32, value: *const c_void, size: usize, type_: u32, flags: u32) -> u32 {
+    let store = &mut *(handle as *mut StateStore);
+    let (Some(key), Some(type_)) = (store.urids.unmap(key), store.urids.unmap(type_)) else {
+        return LV2_STATE_ERR_NO_PROPERTY
+    };
+
+    let value = if value.is_null() { Vec::new() } else { std::slice::from_raw_parts(value as *const u8, size).to_vec() };
+    store.properties.retain(|property| property.key != key);
+    store.properties.push(StateProperty { key, type_, flags, value });
+    LV2_STATE_SUCCESS
+}
+
+unsafe extern "C" fn state_retrieve(handle: *mut c_void, key: u32, size: *mut usize, type_: *mut u32, flags: *mut u32) -> *const c_void {
+    let retrieve = &*(handle as *const StateRetrieve);
+    match retrieve.properties.iter().find(|property| property.0 == key) {
+        Some((_, property_type, property_flags, value)) => {
+            if !size.is_null() { *size = value.len() }
+            if !type_.is_null() { *type_ = *property_type }
+            if !flags.is_null() { *flags = *property_flags }
+            value.as_ptr() as *const c_void
+        },
+        None => ptr::null(),
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PluginError> {
+        if self.data.len() < len {
+            return Err(PluginError::StateError("LV2 state is truncated".to_string()))
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, PluginError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn sized(&mut self) -> Result<&'a [u8], PluginError> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn string(&mut self) -> Result<String, PluginError> {
+        Ok(String::from_utf8_lossy(self.sized()?).into_owned())
+    }
+}
+
+/*
+    ATOMS
+*/
+
+// Atom buffers are u64s, as atoms must be aligned to 8 bytes
+fn atom_bytes(buffer: &mut [u64]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 8) }
+}
+
+// Writes MIDI messages into a sequence atom. Messages that don't fit are dropped.
+fn write_midi_sequence(buffer: &mut [u64], sequence_type: LV2_URID, midi_type: LV2_URID, messages: &[(u32, [u8; 3])]) {
+    let bytes = atom_bytes(buffer);
+    let header_size = std::mem::size_of::<LV2_Atom_Sequence>();
+    let event_size = std::mem::size_of::<LV2_Atom_Event>() + 8; // 3 bytes of MIDI padded to 8
+
+    let mut offset = header_size;
+    for (frames, message) in messages {
+        if offset + event_size > bytes.len() {
+            break
+        }
+        bytes[offset..offset + 8].copy_from_slice(&(*frames as i64).to_ne_bytes());
+        // Program change and channel pressure have a single data byte
+        let length: u32 = if matches!(message[0] & 0xF0, 0xC0 | 0xD0) { 2 } else { 3 };
+        bytes[offset + 8..offset + 12].copy_from_slice(&length.to_ne_bytes());
+        bytes[offset + 12..offset + 16].copy_from_slice(&midi_type.to_ne_bytes());
+        bytes[offset + 16..offset + 24].fill(0);
+        bytes[offset + 16..offset + 19].copy_from_slice(message);
+        offset += event_size;
+    }
+
+    // The atom size doesn't include the atom header itself
+    let size = (offset - std::mem::size_of::<LV2_Atom>()) as u32;
+    bytes[0..4].copy_from_slice(&size.to_ne_bytes());
+    bytes[4..8].copy_from_slice(&sequence_type.to_ne_bytes());
+    bytes[8..16].fill(0); // unit and pad, unit 0 means frames
+}
+
+// Output atom ports get the available capacity, the plugin replaces it with what it wrote
+fn prepare_output_atom(buffer: &mut [u64], chunk_type: LV2_URID) {
+    let bytes = atom_bytes(buffer);
+    let capacity = (bytes.len() - std::mem::size_of::<LV2_Atom>()) as u32;
+    bytes[0..4].copy_from_slice(&capacity.to_ne_bytes());
+    bytes[4..8].copy_from_slice(&chunk_type.to_ne_bytes());
+}
+
+fn midi_message(event: &TimedEvent) -> [u8; 3] {
+    // TODO channels, everything goes to channel 1 for now
+    match event.event {
+        Event::NoteOff { key, vel, .. } => [0x80, key, vel],
+        Event::NoteOn { key, vel, .. } => [0x90, key, vel],
+        Event::ControlChange { index, value } => [0xB0, index, value],
+        // LV2 has no per-note expressions, so they're applied to the whole channel.
+        // The default bend range is 2 semitones.
+        Event::ExprPitch { target_pitch, .. } => {
+            let bend = (8192.0 + target_pitch / 2.0 * 8192.0).round().clamp(0.0, 16383.0) as u16;
+            [0xE0, (bend & 0x7F) as u8, (bend >> 7) as u8]
+        },
+        // CC #7 is volume
+        Event::ExprVolume { target_vol, .. } => [0xB0, 7, target_vol.min(127)],
+        // Channel pressure is only two bytes long, see write_midi_sequence
+        Event::ExprPressure { pressure, .. } => [0xD0, pressure.min(127), 0],
+        Event::ExprTimbre { timbre, .. } => [0xB0, 74, timbre.min(127)],
+    }
+}
+
+/*
+    PLUGIN
+*/
+
+pub(crate) struct LV2Plugin {
+    active: bool,
+    error: Option<PluginError>,
+    info: PluginInfo,
+    descriptor: *const LV2_Descriptor,
+    handle: LV2_Handle,
+    host: HostFeatures,
+
+    samplerate: u32,
+    channels: u8,
+    sample_size: u32,
+
+    // Port buffers, indexed by port index. Must not be reallocated while connected.
+    controls: Vec<f32>,
+    audio: Vec<Vec<f32>>,
+    atoms: Vec<Vec<u64>>,
+
+    midi_messages: Vec<(u32, [u8; 3])>,
+    audio_inputs: Vec<usize>, // port indices, set in configure so process doesn't have to look for them
+    audio_outputs: Vec<usize>,
+    sequence_type: LV2_URID,
+    chunk_type: LV2_URID,
+    midi_type: LV2_URID,
+
+    // Dropped last, the descriptor and the instance's code live in it
+    _lib: Library,
+}
+
+// The plugin instance is only ever touched while the DAWEngine mutex is held
+unsafe impl Send for LV2Plugin {}
+
+impl Drop for LV2Plugin {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+impl LV2Plugin {
+    fn extension<T>(&self, uri: &str) -> Option<&T> {
+        let uri = CString::new(uri).ok()?;
+        unsafe {
+            let extension_data = (*self.descriptor).extension_data?;
+            (extension_data(uri.as_ptr()) as *const T).as_ref()
+        }
+    }
+
+    fn ports(&self, kind: PortKind, is_input: bool) -> impl Iterator<Item = &PortInfo> {
+        self.info.ports.iter().filter(move |port| port.kind == kind && port.is_input == is_input)
+    }
+
+    fn instantiate(&mut self) -> Result<(), PluginError> {
+        self.cleanup();
+
+        self.host.option_values.max_block_length = self.sample_size as i32;
+        self.host.option_values.sample_rate = self.samplerate as f32;
+
+        // The bundle path must end with a slash
+        let mut bundle_path = self.info.bundle.to_string_lossy().into_owned();
+        if !bundle_path.ends_with('/') {
+            bundle_path.push('/');
+        }
+        let bundle_path = CString::new(bundle_path).unwrap_or_default();
+
+        unsafe {
+            let Some(instantiate) = (*self.descriptor).instantiate else {
+                return Err(PluginError::InitError("LV2_Descriptor.instantiate is null".to_string()))
+            };
+            self.handle = instantiate(self.descriptor, self.samplerate as f64, bundle_path.as_ptr(), self.host.pointers.as_ptr());
+        }
+        if self.handle.is_null() {
+            return Err(PluginError::InitError(format!("Unable to instantiate {}", self.info.uri)))
+        }
+
+        self.connect_ports();
+        Ok(())
+    }
+
+    fn connect_ports(&mut self) {
+        let frames = self.sample_size as usize;
+        let port_count = self.info.ports.iter().map(|port| port.index as usize + 1).max().unwrap_or(0);
+
+        // Keep control values across reinstantiation
+        if self.controls.len() != port_count {
+            self.controls = vec![0.0; port_count];
+            for port in self.info.ports.iter().filter(|port| port.kind == PortKind::Control) {
+                self.controls[port.index as usize] = port.default;
+            }
+        }
+        self.audio = vec![Vec::new(); port_count];
+        self.atoms = vec![Vec::new(); port_count];
+
+        let Some(connect_port) = (unsafe { (*self.descriptor).connect_port }) else { return };
+        for port in &self.info.ports {
+            let index = port.index as usize;
+            let data: *mut c_void = match port.kind {
+                PortKind::Control => &mut self.controls[index] as *mut f32 as *mut c_void,
+                PortKind::Audio | PortKind::Cv => {
+                    self.audio[index] = vec![0.0; frames];
+                    self.audio[index].as_mut_ptr() as *mut c_void
+                },
+                PortKind::Atom => {
+                    self.atoms[index] = vec![0; ATOM_BUFFER_SIZE / 8];
+                    if port.is_input {
+                        // An empty sequence, in case it doesn't get events
+                        write_midi_sequence(&mut self.atoms[index], self.sequence_type, self.midi_type, &[]);
+                    }
+                    self.atoms[index].as_mut_ptr() as *mut c_void
+                },
+            };
+            unsafe { connect_port(self.handle, port.index, data) };
+        }
+    }
+
+    fn cleanup(&mut self) {
+        if self.handle.is_null() {
+            return
+        }
+        self.disable();
+        unsafe {
+            if let Some(cleanup) = (*self.descriptor).cleanup {
+                cleanup(self.handle);
+            }
+        }
+        self.handle = ptr::null_mut();
+    }
+
+    // Runs the work scheduled during run() and hands the responses back to the plugin
+    fn run_worker(&mut self) {
+        let Some(interface) = self.extension::<LV2_Worker_Interface>(LV2_WORKER__interface) else {
+            self.host.worker.requests.clear();
+            return
+        };
+        let (work, work_response, end_run) = (interface.work, interface.work_response, interface.end_run);
+
+        let worker_ptr = &mut *self.host.worker as *mut Worker as *mut c_void;
+        let requests = std::mem::take(&mut self.host.worker.requests);
+        unsafe {
+            if let Some(work) = work {
+                for request in &requests {
+                    work(self.handle, worker_respond, worker_ptr, request.len() as u32, request.as_ptr() as *const c_void);
+                }
+            }
+
+            let responses = std::mem::take(&mut self.host.worker.responses);
+            if let Some(work_response) = work_response {
+                for response in &responses {
+                    work_response(self.handle, response.len() as u32, response.as_ptr() as *const c_void);
+                }
+            }
+
+            if let Some(end_run) = end_run {
+                end_run(self.handle);
+            }
+        }
+    }
+
+    fn param_from_port(&self, port: &PortInfo) -> Parameter {
+        let value = self.controls[port.index as usize];
+        let scale = if port.sample_rate { self.samplerate as f32 } else { 1.0 };
+        let (min, max) = (port.min * scale, port.max * scale);
+
+        let (kind, param_value, param_min, param_max, param_default, display) = if !port.scale_points.is_empty() {
+            // Enum values are indices into the scale points
+            let nearest = |target: f32| port.scale_points.iter().enumerate()
+                .min_by(|a, b| (a.1.0 - target).abs().total_cmp(&(b.1.0 - target).abs()))
+                .map_or(0, |(i, _)| i);
+            let labels: Vec<String> = port.scale_points.iter().map(|(_, label)| label.clone()).collect();
+            let index = nearest(value);
+
+            (ParameterKind::Enum(labels), index as f64, 0.0, (port.scale_points.len() - 1) as f64, nearest(port.default) as f64, port.scale_points[index].1.clone())
+        } else if port.toggled {
+            (ParameterKind::Stepped, value as f64, 0.0, 1.0, port.default as f64, if value > 0.0 { "On" } else { "Off" }.to_string())
+        } else if port.integer {
+            (ParameterKind::Stepped, value as f64, min as f64, max as f64, port.default as f64, format!("{}", value.round() as i64))
+        } else {
+            (ParameterKind::Continuous, value as f64, min as f64, max as f64, port.default as f64, format!("{:.3}", value))
+        };
+
+        Parameter {
+            index: port.index as usize,
+            name: port.name.clone(),
+            value: param_value,
+            min: param_min,
+            max: param_max,
+            default: param_default,
+            display,
+            kind,
+            automatable: port.is_input,
+            read_only: !port.is_input,
+        }
+    }
+}
+
+impl Plugin for LV2Plugin {
+    // path is "bundle path#plugin URI", or just the bundle path for the first plugin in it
+    fn new(path: &str) -> Result<Self, PluginError> {
+        // URIs often contain #, bundle paths hopefully don't
+        let (bundle_path, uri) = match path.split_once('#') {
+            Some((bundle_path, uri)) => (bundle_path, Some(uri)),
+            None => (path, None),
+        };
+
+        let mut plugins = read_bundle(Path::new(bundle_path))?;
+        let info = match uri {
+            Some(uri) => match plugins.iter().position(|plugin| plugin.uri == uri) {
+                Some(index) => plugins.swap_remove(index),
+                None => return Err(PluginError::NoSuchPlugin),
+            },
+            None if !plugins.is_empty() => plugins.swap_remove(0),
+            None => return Err(PluginError::NoSuchPlugin),
+        };
+
+        let unsupported: Vec<&String> = info.required_features.iter()
+            .filter(|feature| !SUPPORTED_FEATURES.contains(&feature.as_str()))
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(PluginError::InitError(format!("Unsupported LV2 features: {:?}", unsupported)))
+        }
+        if let Some(port) = info.ports.iter().find(|port| port.kind == PortKind::Atom && !port.midi && !port.optional) {
+            eprintln!("LV2Plugin: atom port {} doesn't support MIDI, it will only get empty sequences", port.name);
+        }
+
+        let lib = match unsafe { Library::new(&info.binary) } {
+            Ok(lib) => lib,
+            Err(err) => return Err(PluginError::LoadError(format!("{}", err))),
+        };
+
+        let descriptor = unsafe {
+            let descriptor_fn = match lib.get::<LV2_Descriptor_Function>(b"lv2_descriptor\0") {
+                Ok(symbol) => *symbol,
+                Err(err) => return Err(PluginError::LoadError(format!("{}", err))),
+            };
+
+            let mut found: *const LV2_Descriptor = ptr::null();
+            let mut index = 0;
+            loop {
+                let descriptor = descriptor_fn(index);
+                if descriptor.is_null() {
+                    break
+                }
+                if !(*descriptor).uri.is_null() && CStr::from_ptr((*descriptor).uri).to_bytes() == info.uri.as_bytes() {
+                    found = descriptor;
+                    break
+                }
+                index += 1;
+            }
+            found
+        };
+        if descriptor.is_null() {
+            return Err(PluginError::LoadError(format!("{} is not in {}", info.uri, info.binary.display())))
+        }
+
+        let host = HostFeatures::new();
+        let (sequence_type, chunk_type, midi_type) = (host.urids.map(LV2_ATOM__Sequence), host.urids.map(LV2_ATOM__Chunk), host.urids.map(LV2_MIDI__MidiEvent));
+
+        let mut plugin = LV2Plugin {
+            active: false,
+            error: None,
+            info,
+            descriptor,
+            handle: ptr::null_mut(),
+            host,
+
+            samplerate: 48000,
+            channels: 2,
+            sample_size: 512,
+
+            controls: Vec::new(),
+            audio: Vec::new(),
+            atoms: Vec::new(),
+
+            midi_messages: Vec::with_capacity(256),
+            audio_inputs: Vec::new(),
+            audio_outputs: Vec::new(),
+            sequence_type,
+            chunk_type,
+            midi_type,
+
+            _lib: lib,
+        };
+        plugin.instantiate()?;
+
+        Ok(plugin)
+    }
+
+    fn available() -> Result<Vec<PluginDescriptor>, PluginError> {
+        let mut descriptors = Vec::new();
+
+        for dir in lv2_search_paths() {
+            let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+            for entry in entries.flatten() {
+                let bundle = entry.path();
+                if !bundle.is_dir() || bundle.extension().map_or(true, |ext| ext != "lv2") {
+                    continue
+                }
+
+                match read_bundle(&bundle) {
+                    Ok(plugins) => descriptors.extend(plugins.iter().map(PluginInfo::descriptor)),
+                    Err(err) => eprintln!("LV2Plugin: skipping {}: {}", bundle.display(), err),
+                }
+            }
+        }
+
+        Ok(descriptors)
+    }
+
+    fn configure(&mut self, samplerate: u32, channels: u8, sample_size: u32) {
+        self.channels = channels;
+        self.audio_inputs = self.ports(PortKind::Audio, true).map(|port| port.index as usize).collect();
+        self.audio_outputs = self.ports(PortKind::Audio, false).map(|port| port.index as usize).collect();
+        if samplerate == self.samplerate && sample_size == self.sample_size {
+            return
+        }
+
+        // LV2 plugins get the sample rate when instantiated, so they have to be recreated
+        let was_active = self.active;
+        let state = self.save_state();
+
+        self.samplerate = samplerate;
+        self.sample_size = sample_size;
+        if let Err(err) = self.instantiate() {
+            eprintln!("LV2Plugin: {}", err);
+            self.error = Some(err);
+            return
+        }
+
+        if let Ok(state) = state {
+            if let Err(err) = self.load_state(&state) {
+                eprintln!("LV2Plugin: {}", err);
+            }
+        }
+        if was_active {
+            self.enable();
+        }
+    }
+
+    fn process(&mut self, events: &[TimedEvent], input: &[f32], output: &mut [f32]) {
+        output.fill(0.0);
+        if !self.active {
+            return
+        }
+
+        let channels = self.channels as usize;
+        let frames = (output.len() / channels).min(self.sample_size as usize);
+
+        // MIDI in, sequences must be sorted by time
+        self.midi_messages.clear();
+        for e in events {
+            self.midi_messages.push((e.position.min(frames.saturating_sub(1) as u32), midi_message(e)));
+        }
+        self.midi_messages.sort_by_key(|(frames, _)| *frames);
+
+        let (sequence_type, chunk_type, midi_type) = (self.sequence_type, self.chunk_type, self.midi_type);
+        for port in &self.info.ports {
+            let index = port.index as usize;
+            match (port.kind, port.is_input) {
+                (PortKind::Atom, true) => {
+                    let messages: &[(u32, [u8; 3])] = if port.midi { &self.midi_messages } else { &[] };
+                    write_midi_sequence(&mut self.atoms[index], sequence_type, midi_type, messages);
+                },
+                (PortKind::Atom, false) => prepare_output_atom(&mut self.atoms[index], chunk_type),
+                _ => (),
+            }
+        }
+
+        // Deinterleave the input, one channel per audio port
+        for (ch, &index) in self.audio_inputs.iter().enumerate() {
+            let buffer = &mut self.audio[index];
+            for frame in 0..frames {
+                buffer[frame] = if ch < channels { input.get(frame * channels + ch).copied().unwrap_or(0.0) } else { 0.0 };
+            }
+        }
+
+        unsafe {
+            if let Some(run) = (*self.descriptor).run {
+                run(self.handle, frames as u32);
+            }
+        }
+        self.run_worker();
+        // TODO MIDI out of atom output ports
+
+        // Interleave the output, mono plugins go to every channel
+        if self.audio_outputs.len() == 1 {
+            let buffer = &self.audio[self.audio_outputs[0]];
+            for frame in 0..frames {
+                for ch in 0..channels {
+                    output[frame * channels + ch] = buffer[frame];
+                }
+            }
+        } else {
+            for (ch, &index) in self.audio_outputs.iter().enumerate().take(channels) {
+                let buffer = &self.audio[index];
+                for frame in 0..frames {
+                    output[frame * channels + ch] = buffer[frame];
+                }
+            }
+        }
+    }
+
+    /* fn note_states(&self) -> &[super::interface::NoteState] {
+        todo!()
+    } */
+
+    fn show_gui(&mut self, _shown: bool) {
+        // TODO LV2 UI extension
+    }
+
+    fn get_params(&self) -> Vec<Parameter> {
+        self.info.ports.iter()
+            .filter(|port| port.kind == PortKind::Control)
+            .map(|port| self.param_from_port(port))
+            .collect()
+    }
+
+    fn set_param(&mut self, index: usize, value: f64) -> Result<(), PluginError> {
+        let port = match self.info.ports.iter().find(|port| port.index as usize == index && port.kind == PortKind::Control && port.is_input) {
+            Some(port) => port,
+            None => return Err(PluginError::NoSuchParameter(index)),
+        };
+
+        let value = if !port.scale_points.is_empty() {
+            let point = (value.round().max(0.0) as usize).min(port.scale_points.len() - 1);
+            port.scale_points[point].0
+        } else {
+            let scale = if port.sample_rate { self.samplerate as f32 } else { 1.0 };
+            let value = (value as f32).clamp(port.min * scale, port.max * scale);
+            if port.integer || port.toggled { value.round() } else { value }
+        };
+
+        // Control ports are read by the plugin in run(), which can't be happening now
+        self.controls[index] = value;
+        Ok(())
+    }
+
+    // Layout: magic, the input control values, then the properties saved through the state extension.
+    // URIDs are stored as URIs, as they're only valid for this instance.
+    fn save_state(&mut self) -> Result<Vec<u8>, PluginError> {
+        let mut state = STATE_MAGIC.to_vec();
+
+        let controls: Vec<(u32, f32)> = self.ports(PortKind::Control, true).map(|port| (port.index, self.controls[port.index as usize])).collect();
+        put_u32(&mut state, controls.len() as u32);
+        for (index, value) in controls {
+            put_u32(&mut state, index);
+            put_u32(&mut state, value.to_bits());
+        }
+
+        let mut store = StateStore { urids: &self.host.urids, properties: Vec::new() };
+        if let Some(interface) = self.extension::<LV2_State_Interface>(LV2_STATE__interface).filter(|_| !self.handle.is_null()) {
+            if let Some(save) = interface.save {
+                let status = unsafe {
+                    save(self.handle, state_store, &mut store as *mut StateStore as *mut c_void, LV2_STATE_IS_POD | LV2_STATE_IS_PORTABLE, self.host.pointers.as_ptr())
+                };
+                if status != LV2_STATE_SUCCESS {
+                    return Err(PluginError::StateError(format!("LV2_State_Interface.save() returned {status}")))
+                }
+            }
+        }
+
+        put_u32(&mut state, store.properties.len() as u32);
+        for property in &store.properties {
+            put_bytes(&mut state, property.key.as_bytes());
+            put_bytes(&mut state, property.type_.as_bytes());
+            put_u32(&mut state, property.flags);
+            put_bytes(&mut state, &property.value);
+        }
+
+        Ok(state)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), PluginError> {
+        if state.is_empty() {
+            return Ok(())
+        }
+        if !state.starts_with(STATE_MAGIC) {
+            return Err(PluginError::StateError("Unknown LV2 state".to_string()))
+        }
+
+        let mut reader = StateReader { data: &state[STATE_MAGIC.len()..] };
+        for _ in 0..reader.u32()? {
+            let index = reader.u32()? as usize;
+            let value = f32::from_bits(reader.u32()?);
+            if self.info.ports.iter().any(|port| port.index as usize == index && port.kind == PortKind::Control && port.is_input) {
+                self.controls[index] = value;
+            }
+        }
+
+        let mut retrieve = StateRetrieve { properties: Vec::new() };
+        for _ in 0..reader.u32()? {
+            let key = self.host.urids.map(&reader.string()?);
+            let type_ = self.host.urids.map(&reader.string()?);
+            let flags = reader.u32()?;
+            retrieve.properties.push((key, type_, flags, reader.sized()?.to_vec()));
+        }
+
+        if let Some(interface) = self.extension::<LV2_State_Interface>(LV2_STATE__interface).filter(|_| !self.handle.is_null()) {
+            if let Some(restore) = interface.restore {
+                let status = unsafe {
+                    restore(self.handle, state_retrieve, &mut retrieve as *mut StateRetrieve as *mut c_void, 0, self.host.pointers.as_ptr())
+                };
+                if status != LV2_STATE_SUCCESS {
+                    return Err(PluginError::StateError(format!("LV2_State_Interface.restore() returned {status}")))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enable(&mut self) {
+        if self.active || self.handle.is_null() {
+            return
+        }
+
+        unsafe {
+            if let Some(activate) = (*self.descriptor).activate {
+                activate(self.handle);
+            }
+        }
+        self.active = true;
+    }
+
+    fn disable(&mut self) {
+        if !self.active {
+            return
+        }
+
+        unsafe {
+            if let Some(deactivate) = (*self.descriptor).deactivate {
+                deactivate(self.handle);
+            }
+        }
+        self.active = false;
+    }
+
+    fn active(&self) -> bool {
+        self.active
+    }
+
+    fn error(&self) -> Option<&PluginError> {
+        self.error.as_ref()
+    }
+}
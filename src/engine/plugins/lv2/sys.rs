@@ -0,0 +1,126 @@
+// Hand-written bindings for the parts of the LV2 C API we use
+#![allow(non_camel_case_types, non_upper_case_globals)]
+
+use std::ffi::c_void;
+use std::os::raw::c_char;
+
+pub type LV2_Handle = *mut c_void;
+pub type LV2_URID = u32;
+
+pub const LV2_URID__map: &str = "http://lv2plug.in/ns/ext/urid#map";
+pub const LV2_URID__unmap: &str = "http://lv2plug.in/ns/ext/urid#unmap";
+pub const LV2_WORKER__schedule: &str = "http://lv2plug.in/ns/ext/worker#schedule";
+pub const LV2_WORKER__interface: &str = "http://lv2plug.in/ns/ext/worker#interface";
+pub const LV2_STATE__interface: &str = "http://lv2plug.in/ns/ext/state#interface";
+pub const LV2_OPTIONS__options: &str = "http://lv2plug.in/ns/ext/options#options";
+pub const LV2_BUF_SIZE__boundedBlockLength: &str = "http://lv2plug.in/ns/ext/buf-size#boundedBlockLength";
+pub const LV2_BUF_SIZE__minBlockLength: &str = "http://lv2plug.in/ns/ext/buf-size#minBlockLength";
+pub const LV2_BUF_SIZE__maxBlockLength: &str = "http://lv2plug.in/ns/ext/buf-size#maxBlockLength";
+pub const LV2_PARAMETERS__sampleRate: &str = "http://lv2plug.in/ns/ext/parameters#sampleRate";
+pub const LV2_CORE__inPlaceBroken: &str = "http://lv2plug.in/ns/lv2core#inPlaceBroken";
+pub const LV2_CORE__isLive: &str = "http://lv2plug.in/ns/lv2core#isLive";
+
+pub const LV2_ATOM__Sequence: &str = "http://lv2plug.in/ns/ext/atom#Sequence";
+pub const LV2_ATOM__Chunk: &str = "http://lv2plug.in/ns/ext/atom#Chunk";
+pub const LV2_ATOM__Int: &str = "http://lv2plug.in/ns/ext/atom#Int";
+pub const LV2_ATOM__Float: &str = "http://lv2plug.in/ns/ext/atom#Float";
+pub const LV2_MIDI__MidiEvent: &str = "http://lv2plug.in/ns/ext/midi#MidiEvent";
+
+pub const LV2_WORKER_SUCCESS: u32 = 0;
+pub const LV2_WORKER_ERR_NO_SPACE: u32 = 2;
+pub const LV2_STATE_SUCCESS: u32 = 0;
+pub const LV2_STATE_ERR_NO_PROPERTY: u32 = 5;
+pub const LV2_STATE_IS_POD: u32 = 1;
+pub const LV2_STATE_IS_PORTABLE: u32 = 2;
+pub const LV2_OPTIONS_INSTANCE: u32 = 0;
+
+#[repr(C)]
+pub struct LV2_Feature {
+    pub uri: *const c_char,
+    pub data: *mut c_void,
+}
+
+#[repr(C)]
+pub struct LV2_Descriptor {
+    pub uri: *const c_char,
+    pub instantiate: Option<unsafe extern "C" fn(descriptor: *const LV2_Descriptor, sample_rate: f64, bundle_path: *const c_char, features: *const *const LV2_Feature) -> LV2_Handle>,
+    pub connect_port: Option<unsafe extern "C" fn(instance: LV2_Handle, port: u32, data: *mut c_void)>,
+    pub activate: Option<unsafe extern "C" fn(instance: LV2_Handle)>,
+    pub run: Option<unsafe extern "C" fn(instance: LV2_Handle, sample_count: u32)>,
+    pub deactivate: Option<unsafe extern "C" fn(instance: LV2_Handle)>,
+    pub cleanup: Option<unsafe extern "C" fn(instance: LV2_Handle)>,
+    pub extension_data: Option<unsafe extern "C" fn(uri: *const c_char) -> *const c_void>,
+}
+
+pub type LV2_Descriptor_Function = unsafe extern "C" fn(index: u32) -> *const LV2_Descriptor;
+
+#[repr(C)]
+pub struct LV2_URID_Map {
+    pub handle: *mut c_void,
+    pub map: unsafe extern "C" fn(handle: *mut c_void, uri: *const c_char) -> LV2_URID,
+}
+
+#[repr(C)]
+pub struct LV2_URID_Unmap {
+    pub handle: *mut c_void,
+    pub unmap: unsafe extern "C" fn(handle: *mut c_void, urid: LV2_URID) -> *const c_char,
+}
+
+#[repr(C)]
+pub struct LV2_Atom {
+    pub size: u32,
+    pub type_: u32,
+}
+
+#[repr(C)]
+pub struct LV2_Atom_Sequence_Body {
+    pub unit: u32,
+    pub pad: u32,
+}
+
+#[repr(C)]
+pub struct LV2_Atom_Sequence {
+    pub atom: LV2_Atom,
+    pub body: LV2_Atom_Sequence_Body,
+}
+
+// Followed by body.size bytes of data, padded to 8 bytes
+#[repr(C)]
+pub struct LV2_Atom_Event {
+    pub frames: i64,
+    pub body: LV2_Atom,
+}
+
+pub type LV2_Worker_Respond_Function = unsafe extern "C" fn(handle: *mut c_void, size: u32, data: *const c_void) -> u32;
+
+#[repr(C)]
+pub struct LV2_Worker_Schedule {
+    pub handle: *mut c_void,
+    pub schedule_work: unsafe extern "C" fn(handle: *mut c_void, size: u32, data: *const c_void) -> u32,
+}
+
+#[repr(C)]
+pub struct LV2_Worker_Interface {
+    pub work: Option<unsafe extern "C" fn(instance: LV2_Handle, respond: LV2_Worker_Respond_Function, handle: *mut c_void, size: u32, data: *const c_void) -> u32>,
+    pub work_response: Option<unsafe extern "C" fn(instance: LV2_Handle, size: u32, body: *const c_void) -> u32>,
+    pub end_run: Option<unsafe extern "C" fn(instance: LV2_Handle) -> u32>,
+}
+
+pub type LV2_State_Store_Function = unsafe extern "C" fn(handle: *mut c_void, key: u32, value: *const c_void, size: usize, type_: u32, flags: u32) -> u32;
+pub type LV2_State_Retrieve_Function = unsafe extern "C" fn(handle: *mut c_void, key: u32, size: *mut usize, type_: *mut u32, flags: *mut u32) -> *const c_void;
+
+#[repr(C)]
+pub struct LV2_State_Interface {
+    pub save: Option<unsafe extern "C" fn(instance: LV2_Handle, store: LV2_State_Store_Function, handle: *mut c_void, flags: u32, features: *const *const LV2_Feature) -> u32>,
+    pub restore: Option<unsafe extern "C" fn(instance: LV2_Handle, retrieve: LV2_State_Retrieve_Function, handle: *mut c_void, flags: u32, features: *const *const LV2_Feature) -> u32>,
+}
+
+#[repr(C)]
+pub struct LV2_Options_Option {
+    pub context: u32,
+    pub subject: u32,
+    pub key: LV2_URID,
+    pub size: u32,
+    pub type_: LV2_URID,
+    pub value: *const c_void,
+}
@@ -0,0 +1,535 @@
+// Just enough of a Turtle parser to read LV2 bundles (manifest.ttl and the plugin descriptions).
+// Literal datatypes and language tags are thrown away, as nothing we read needs them.
+
+use std::{fs, path::Path};
+
+pub const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+pub const RDFS: &str = "http://www.w3.org/2000/01/rdf-schema#";
+pub const LV2: &str = "http://lv2plug.in/ns/lv2core#";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    Iri(String),
+    Blank(usize),
+    Literal(String),
+}
+
+impl Node {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Node::Iri(iri) | Node::Literal(iri) => Some(iri),
+            Node::Blank(_) => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Node::Literal(literal) => literal.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+pub struct Triple {
+    pub subject: Node,
+    pub predicate: String,
+    pub object: Node,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Iri(String),
+    PrefixedName(String, String),
+    Blank(String),
+    Literal(String),
+    A,
+    Punct(char),
+    PrefixDirective,
+    BaseDirective,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '%')
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            },
+            '<' => {
+                let start = i + 1;
+                while i < chars.len() && chars[i] != '>' {
+                    i += 1;
+                }
+                tokens.push(Token::Iri(chars[start..i].iter().collect()));
+                i += 1;
+            },
+            '"' | '\'' => {
+                let long = i + 2 < chars.len() && chars[i + 1] == c && chars[i + 2] == c;
+                i += if long { 3 } else { 1 };
+
+                let mut literal = String::new();
+                loop {
+                    if i >= chars.len() {
+                        return Err("Unterminated string".to_string())
+                    }
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        literal.push(match chars[i + 1] {
+                            'n' => '\n',
+                            't' => '\t',
+                            other => other,
+                        });
+                        i += 2;
+                        continue
+                    }
+                    if long {
+                        if i + 2 < chars.len() && chars[i] == c && chars[i + 1] == c && chars[i + 2] == c {
+                            i += 3;
+                            break
+                        }
+                    } else if chars[i] == c {
+                        i += 1;
+                        break
+                    }
+                    literal.push(chars[i]);
+                    i += 1;
+                }
+
+                // Skip the language tag or datatype
+                if i < chars.len() && chars[i] == '@' {
+                    while i < chars.len() && (chars[i] == '@' || chars[i].is_alphanumeric() || chars[i] == '-') {
+                        i += 1;
+                    }
+                } else if i + 1 < chars.len() && chars[i] == '^' && chars[i + 1] == '^' {
+                    i += 2;
+                    if i < chars.len() && chars[i] == '<' {
+                        while i < chars.len() && chars[i] != '>' {
+                            i += 1;
+                        }
+                        i += 1;
+                    } else {
+                        while i < chars.len() && is_name_char(chars[i]) {
+                            i += 1;
+                        }
+                    }
+                }
+
+                tokens.push(Token::Literal(literal));
+            },
+            ';' | ',' | '[' | ']' | '(' | ')' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            },
+            '.' if !(i + 1 < chars.len() && chars[i + 1].is_ascii_digit()) => {
+                tokens.push(Token::Punct('.'));
+                i += 1;
+            },
+            '@' => {
+                let start = i + 1;
+                i += 1;
+                while i < chars.len() && chars[i].is_alphabetic() {
+                    i += 1;
+                }
+                match chars[start..i].iter().collect::<String>().as_str() {
+                    "prefix" => tokens.push(Token::PrefixDirective),
+                    "base" => tokens.push(Token::BaseDirective),
+                    other => return Err(format!("Unknown directive @{other}")),
+                }
+            },
+            _ => {
+                let start = i;
+                while i < chars.len() && is_name_char(chars[i]) {
+                    i += 1;
+                }
+                if i == start {
+                    return Err(format!("Unexpected character '{c}'"))
+                }
+                // A name can't end with a dot, that's the end of the statement
+                while i > start + 1 && chars[i - 1] == '.' {
+                    i -= 1;
+                }
+
+                let word: String = chars[start..i].iter().collect();
+                if word == "a" {
+                    tokens.push(Token::A);
+                } else if word.eq_ignore_ascii_case("prefix") {
+                    tokens.push(Token::PrefixDirective);
+                } else if word.eq_ignore_ascii_case("base") {
+                    tokens.push(Token::BaseDirective);
+                } else if word == "true" || word == "false" || word.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+' || c == '.') {
+                    tokens.push(Token::Literal(word));
+                } else if let Some(label) = word.strip_prefix("_:") {
+                    tokens.push(Token::Blank(label.to_string()));
+                } else if let Some((prefix, local)) = word.split_once(':') {
+                    tokens.push(Token::PrefixedName(prefix.to_string(), local.to_string()));
+                } else {
+                    return Err(format!("Unexpected word '{word}'"))
+                }
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Resolves a relative IRI against the base, good enough for bundle-relative paths
+fn resolve(base: &str, iri: &str) -> String {
+    if iri.contains(':') {
+        return iri.to_string()
+    }
+    if iri.is_empty() {
+        return base.to_string()
+    }
+
+    match base.rfind('/') {
+        Some(slash) => format!("{}{}", &base[..=slash], iri),
+        None => iri.to_string(),
+    }
+}
+
+pub struct Graph {
+    pub triples: Vec<Triple>,
+    blanks: usize,
+}
+
+struct Parser<'a> {
+    graph: &'a mut Graph,
+    tokens: Vec<Token>,
+    pos: usize,
+    base: String,
+    prefixes: Vec<(String, String)>,
+    blank_labels: Vec<(String, usize)>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self.tokens.get(self.pos).cloned().ok_or("Unexpected end of file")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        match self.next()? {
+            Token::Punct(c) if c == punct => Ok(()),
+            other => Err(format!("Expected '{punct}', found {:?}", other)),
+        }
+    }
+
+    fn new_blank(&mut self) -> Node {
+        self.graph.blanks += 1;
+        Node::Blank(self.graph.blanks)
+    }
+
+    fn iri(&self, token: &Token) -> Result<String, String> {
+        match token {
+            Token::Iri(iri) => Ok(resolve(&self.base, iri)),
+            Token::PrefixedName(prefix, local) => {
+                match self.prefixes.iter().rev().find(|(name, _)| name == prefix) {
+                    Some((_, namespace)) => Ok(format!("{namespace}{local}")),
+                    None => Err(format!("Unknown prefix {prefix}:")),
+                }
+            },
+            Token::A => Ok(format!("{RDF}type")),
+            other => Err(format!("Expected an IRI, found {:?}", other)),
+        }
+    }
+
+    fn parse(&mut self) -> Result<(), String> {
+        while let Some(token) = self.peek().cloned() {
+            match token {
+                Token::PrefixDirective => {
+                    self.pos += 1;
+                    let prefix = match self.next()? {
+                        Token::PrefixedName(prefix, local) if local.is_empty() => prefix,
+                        other => return Err(format!("Expected a prefix, found {:?}", other)),
+                    };
+                    let namespace = match self.next()? {
+                        Token::Iri(iri) => resolve(&self.base, &iri),
+                        other => return Err(format!("Expected an IRI, found {:?}", other)),
+                    };
+                    self.prefixes.push((prefix, namespace));
+                    // SPARQL-style directives have no dot
+                    if self.peek() == Some(&Token::Punct('.')) {
+                        self.pos += 1;
+                    }
+                },
+                Token::BaseDirective => {
+                    self.pos += 1;
+                    self.base = match self.next()? {
+                        Token::Iri(iri) => resolve(&self.base, &iri),
+                        other => return Err(format!("Expected an IRI, found {:?}", other)),
+                    };
+                    if self.peek() == Some(&Token::Punct('.')) {
+                        self.pos += 1;
+                    }
+                },
+                _ => {
+                    let subject = self.subject()?;
+                    if self.peek() != Some(&Token::Punct('.')) {
+                        self.predicate_object_list(&subject)?;
+                    }
+                    self.expect('.')?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn subject(&mut self) -> Result<Node, String> {
+        match self.next()? {
+            Token::Punct('[') => {
+                let node = self.new_blank();
+                if self.peek() != Some(&Token::Punct(']')) {
+                    self.predicate_object_list(&node)?;
+                }
+                self.expect(']')?;
+                Ok(node)
+            },
+            Token::Blank(label) => Ok(self.labeled_blank(label)),
+            token => Ok(Node::Iri(self.iri(&token)?)),
+        }
+    }
+
+    fn labeled_blank(&mut self, label: String) -> Node {
+        if let Some((_, id)) = self.blank_labels.iter().find(|(name, _)| *name == label) {
+            return Node::Blank(*id)
+        }
+        let node = self.new_blank();
+        if let Node::Blank(id) = node {
+            self.blank_labels.push((label, id));
+        }
+        node
+    }
+
+    fn predicate_object_list(&mut self, subject: &Node) -> Result<(), String> {
+        loop {
+            let token = self.next()?;
+            let predicate = self.iri(&token)?;
+
+            loop {
+                let object = self.object()?;
+                self.graph.triples.push(Triple { subject: subject.clone(), predicate: predicate.clone(), object });
+
+                if self.peek() == Some(&Token::Punct(',')) {
+                    self.pos += 1;
+                } else {
+                    break
+                }
+            }
+
+            if self.peek() != Some(&Token::Punct(';')) {
+                return Ok(())
+            }
+            // Any number of semicolons, possibly trailing
+            while self.peek() == Some(&Token::Punct(';')) {
+                self.pos += 1;
+            }
+            if matches!(self.peek(), Some(Token::Punct('.')) | Some(Token::Punct(']')) | None) {
+                return Ok(())
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Node, String> {
+        match self.next()? {
+            Token::Literal(literal) => Ok(Node::Literal(literal)),
+            Token::Blank(label) => Ok(self.labeled_blank(label)),
+            Token::Punct('[') => {
+                let node = self.new_blank();
+                if self.peek() != Some(&Token::Punct(']')) {
+                    self.predicate_object_list(&node)?;
+                }
+                self.expect(']')?;
+                Ok(node)
+            },
+            Token::Punct('(') => {
+                // Collections become rdf:first/rdf:rest chains
+                let mut items = Vec::new();
+                while self.peek() != Some(&Token::Punct(')')) {
+                    items.push(self.object()?);
+                }
+                self.pos += 1;
+
+                let mut list = Node::Iri(format!("{RDF}nil"));
+                for item in items.into_iter().rev() {
+                    let node = self.new_blank();
+                    self.graph.triples.push(Triple { subject: node.clone(), predicate: format!("{RDF}first"), object: item });
+                    self.graph.triples.push(Triple { subject: node.clone(), predicate: format!("{RDF}rest"), object: list });
+                    list = node;
+                }
+                Ok(list)
+            },
+            token => Ok(Node::Iri(self.iri(&token)?)),
+        }
+    }
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph { triples: Vec::new(), blanks: 0 }
+    }
+
+    /// Parses a Turtle file and adds its triples to the graph
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        self.parse(&source, format!("file://{}", path.display())).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // Relative IRIs in the source are resolved against base
+    fn parse(&mut self, source: &str, base: String) -> Result<(), String> {
+        let mut parser = Parser {
+            graph: self,
+            tokens: tokenize(source)?,
+            pos: 0,
+            base,
+            prefixes: Vec::new(),
+            blank_labels: Vec::new(),
+        };
+        parser.parse()
+    }
+
+    pub fn objects<'a>(&'a self, subject: &'a Node, predicate: &'a str) -> impl Iterator<Item = &'a Node> + 'a {
+        self.triples.iter()
+            .filter(move |triple| triple.subject == *subject && triple.predicate == predicate)
+            .map(|triple| &triple.object)
+    }
+
+    pub fn object(&self, subject: &Node, predicate: &str) -> Option<&Node> {
+        self.triples.iter()
+            .find(|triple| triple.subject == *subject && triple.predicate == predicate)
+            .map(|triple| &triple.object)
+    }
+
+    pub fn subjects<'a>(&'a self, predicate: &'a str, object: &'a Node) -> impl Iterator<Item = &'a Node> + 'a {
+        self.triples.iter()
+            .filter(move |triple| triple.predicate == predicate && triple.object == *object)
+            .map(|triple| &triple.subject)
+    }
+
+    pub fn has_type(&self, subject: &Node, rdf_type: &str) -> bool {
+        self.objects(subject, &format!("{RDF}type")).any(|node| node.as_str() == Some(rdf_type))
+    }
+}
+
+/// Converts a file:// IRI back into a path
+pub fn iri_to_path(iri: &str) -> Option<String> {
+    let path = iri.strip_prefix("file://")?;
+
+    // Percent-decode, bundles with spaces in their names are a thing
+    let bytes = path.as_bytes();
+    let hex = |byte: u8| (byte as char).to_digit(16);
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        // The escape may be the last thing in the path
+        if let (b'%', Some(&[high, low])) = (bytes[i], bytes.get(i + 1..i + 3)) {
+            if let (Some(high), Some(low)) = (hex(high), hex(low)) {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Graph {
+        let mut graph = Graph::new();
+        graph.parse(source, "file:///plugins/test.lv2/manifest.ttl".to_string()).unwrap();
+        graph
+    }
+
+    fn iri(iri: &str) -> Node {
+        Node::Iri(iri.to_string())
+    }
+
+    #[test]
+    fn prefixes_and_relative_iris() {
+        let graph = parse(r#"
+            @prefix lv2: <http://lv2plug.in/ns/lv2core#> .
+            @prefix rdfs: <http://www.w3.org/2000/01/rdf-schema#> .
+            <http://example.org/synth> a lv2:Plugin , lv2:InstrumentPlugin ;
+                rdfs:seeAlso <synth.ttl> ;
+                lv2:binary <synth.so> .
+        "#);
+
+        let plugin = iri("http://example.org/synth");
+        assert!(graph.has_type(&plugin, &format!("{LV2}Plugin")));
+        assert!(graph.has_type(&plugin, &format!("{LV2}InstrumentPlugin")));
+        assert_eq!(graph.object(&plugin, &format!("{RDFS}seeAlso")), Some(&iri("file:///plugins/test.lv2/synth.ttl")));
+        assert_eq!(graph.object(&plugin, &format!("{LV2}binary")), Some(&iri("file:///plugins/test.lv2/synth.so")));
+    }
+
+    #[test]
+    fn blank_nodes_and_literals() {
+        let graph = parse(r#"
+            @prefix lv2: <http://lv2plug.in/ns/lv2core#> .
+            <http://example.org/synth> lv2:port [
+                a lv2:ControlPort ;
+                lv2:index 3 ;
+                lv2:name "Cutoff" ;
+                lv2:default 0.5 ;
+            ] , _:out .
+            _:out lv2:index 4 .
+        "#);
+
+        let (plugin, port) = (iri("http://example.org/synth"), format!("{LV2}port"));
+        let ports: Vec<&Node> = graph.objects(&plugin, &port).collect();
+        assert_eq!(ports.len(), 2);
+        assert!(graph.has_type(ports[0], &format!("{LV2}ControlPort")));
+        assert_eq!(graph.object(ports[0], &format!("{LV2}index")).and_then(Node::as_f32), Some(3.0));
+        assert_eq!(graph.object(ports[0], &format!("{LV2}name")).and_then(Node::as_str), Some("Cutoff"));
+        assert_eq!(graph.object(ports[0], &format!("{LV2}default")).and_then(Node::as_f32), Some(0.5));
+        assert_eq!(graph.object(ports[1], &format!("{LV2}index")).and_then(Node::as_f32), Some(4.0));
+    }
+
+    #[test]
+    fn collections() {
+        let graph = parse(r#"<http://example.org/a> <http://example.org/list> ( "x" "y" ) ."#);
+
+        let list = graph.object(&iri("http://example.org/a"), "http://example.org/list").unwrap();
+        assert_eq!(graph.object(list, &format!("{RDF}first")), Some(&Node::Literal("x".to_string())));
+        let rest = graph.object(list, &format!("{RDF}rest")).unwrap();
+        assert_eq!(graph.object(rest, &format!("{RDF}first")), Some(&Node::Literal("y".to_string())));
+        assert_eq!(graph.object(rest, &format!("{RDF}rest")), Some(&iri(&format!("{RDF}nil"))));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let mut graph = Graph::new();
+        assert!(graph.parse("<http://example.org/a> <http://example.org/b>", String::new()).is_err());
+        assert!(graph.parse("foo:a foo:b foo:c .", String::new()).is_err());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(iri_to_path("file:///plugins/My%20Synth.lv2/").as_deref(), Some("/plugins/My Synth.lv2/"));
+        assert_eq!(iri_to_path("file:///plugins/synth%2F").as_deref(), Some("/plugins/synth/"));
+        assert_eq!(iri_to_path("file:///plugins/100%").as_deref(), Some("/plugins/100%"));
+        assert_eq!(iri_to_path("file:///plugins/%2").as_deref(), Some("/plugins/%2"));
+        assert_eq!(iri_to_path("file:///plugins/%é1").as_deref(), Some("/plugins/%é1"));
+        assert_eq!(iri_to_path("http://example.org/"), None);
+    }
+}
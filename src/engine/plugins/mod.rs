@@ -1,3 +1,6 @@
 pub(crate) mod interface;
-mod builtin;
-mod clap;
\ No newline at end of file
+pub(crate) mod builtin;
+mod clap;
+pub(crate) mod lv2;
+pub(crate) mod scanner;
+pub(crate) mod bridge;
\ No newline at end of file
@@ -0,0 +1,346 @@
+// CLAP plugin scanner
+// Every bundle is loaded in a child process (this executable, started with SCAN_ARG), so a crashing plugin can't take us down with it.
+// Results are cached in a plain text database, bundles that crash are blacklisted until they are modified.
+
+use std::{
+    env,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use super::{clap::ClapBundle, interface::{PluginDescriptor, PluginError}};
+
+pub const SCAN_ARG: &str = "--scan-clap";
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+const DATABASE_HEADER: &str = "# Project Corrosion plugin database v1";
+
+pub struct BlacklistEntry {
+    pub path: String,
+    pub modified: u64,
+    pub reason: String,
+}
+
+pub struct PluginDatabase {
+    pub plugins: Vec<PluginDescriptor>,
+    pub blacklist: Vec<BlacklistEntry>,
+    bundles: Vec<(String, u64)>, // scanned bundles and their modification time
+}
+
+/// Standard CLAP search paths, as described in clap/entry.h
+pub fn clap_search_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+
+    if let Some(clap_path) = env::var_os("CLAP_PATH") {
+        paths.extend(env::split_paths(&clap_path));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(home) = env::var_os("HOME") {
+            paths.push(Path::new(&home).join(".clap"));
+        }
+        paths.push(PathBuf::from("/usr/lib/clap"));
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if let Some(home) = env::var_os("HOME") {
+            paths.push(Path::new(&home).join("Library/Audio/Plug-Ins/CLAP"));
+        }
+        paths.push(PathBuf::from("/Library/Audio/Plug-Ins/CLAP"));
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        if let Some(common) = env::var_os("COMMONPROGRAMFILES") {
+            paths.push(Path::new(&common).join("CLAP"));
+        }
+        if let Some(local) = env::var_os("LOCALAPPDATA") {
+            paths.push(Path::new(&local).join("Programs\\Common\\CLAP"));
+        }
+    }
+
+    paths
+}
+
+fn database_path() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    let base = env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    let base = env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")));
+
+    base.unwrap_or_else(env::temp_dir)
+        .join("project-corrosion")
+        .join("plugins.db")
+}
+
+fn find_bundles(dir: &Path, bundles: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().map_or(false, |ext| ext == "clap") {
+            // On macOS, bundles are directories
+            bundles.push(path);
+        } else if path.is_dir() {
+            find_bundles(&path, bundles);
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs())
+}
+
+// Fields are tab separated, so get rid of any tabs and newlines a plugin might put in its strings
+fn sanitize(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+fn descriptor_to_line(desc: &PluginDescriptor) -> String {
+    let ports = |ports: &[u32]| ports.iter().map(|ch| ch.to_string()).collect::<Vec<String>>().join(",");
+
+    format!(
+        "plugin\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        sanitize(&desc.path),
+        sanitize(&desc.id),
+        sanitize(&desc.name),
+        sanitize(&desc.vendor),
+        sanitize(&desc.version),
+        desc.features.iter().map(|f| sanitize(f).replace(',', " ")).collect::<Vec<String>>().join(","),
+        ports(&desc.audio_inputs),
+        ports(&desc.audio_outputs),
+        desc.note_inputs,
+        desc.note_outputs,
+    )
+}
+
+fn descriptor_from_fields(fields: &[&str]) -> Option<PluginDescriptor> {
+    if fields.len() != 10 {
+        return None
+    }
+
+    let list = |field: &str| field.split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect::<Vec<String>>();
+    let ports = |field: &str| field.split(',').filter_map(|s| s.parse::<u32>().ok()).collect::<Vec<u32>>();
+
+    Some(PluginDescriptor {
+        path: fields[0].to_string(),
+        id: fields[1].to_string(),
+        name: fields[2].to_string(),
+        vendor: fields[3].to_string(),
+        version: fields[4].to_string(),
+        features: list(fields[5]),
+        audio_inputs: ports(fields[6]),
+        audio_outputs: ports(fields[7]),
+        note_inputs: fields[8].parse().ok()?,
+        note_outputs: fields[9].parse().ok()?,
+    })
+}
+
+impl PluginDatabase {
+    /// Loads the database from disk. A missing or corrupted database is treated as empty.
+    pub fn load() -> PluginDatabase {
+        let mut database = PluginDatabase {
+            plugins: Vec::new(),
+            blacklist: Vec::new(),
+            bundles: Vec::new(),
+        };
+
+        let contents = match fs::read_to_string(database_path()) {
+            Ok(contents) => contents,
+            Err(_) => return database,
+        };
+
+        let mut lines = contents.lines();
+        if lines.next() != Some(DATABASE_HEADER) {
+            return database
+        }
+
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields[0] {
+                "bundle" if fields.len() == 3 => {
+                    database.bundles.push((fields[1].to_string(), fields[2].parse().unwrap_or(0)));
+                },
+                "plugin" => {
+                    if let Some(desc) = descriptor_from_fields(&fields[1..]) {
+                        database.plugins.push(desc);
+                    }
+                },
+                "blacklist" if fields.len() == 4 => {
+                    database.blacklist.push(BlacklistEntry {
+                        path: fields[1].to_string(),
+                        modified: fields[2].parse().unwrap_or(0),
+                        reason: fields[3].to_string(),
+                    });
+                },
+                _ => {}
+            }
+        }
+
+        database
+    }
+
+    pub fn save(&self) -> Result<(), PluginError> {
+        let path = database_path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|err| PluginError::ScanError(format!("{}", err)))?;
+        }
+
+        let mut contents = String::new();
+        contents.push_str(DATABASE_HEADER);
+        contents.push('\n');
+        for (bundle, modified) in &self.bundles {
+            contents.push_str(&format!("bundle\t{}\t{}\n", sanitize(bundle), modified));
+        }
+        for desc in &self.plugins {
+            contents.push_str(&descriptor_to_line(desc));
+            contents.push('\n');
+        }
+        for entry in &self.blacklist {
+            contents.push_str(&format!("blacklist\t{}\t{}\t{}\n", sanitize(&entry.path), entry.modified, sanitize(&entry.reason)));
+        }
+
+        fs::write(path, contents).map_err(|err| PluginError::ScanError(format!("{}", err)))
+    }
+
+    pub fn is_blacklisted(&self, bundle: &str) -> bool {
+        self.blacklist.iter().any(|entry| entry.path == bundle)
+    }
+
+    /// Plugins which can be inserted as instruments
+    pub fn instruments(&self) -> impl Iterator<Item = &PluginDescriptor> {
+        self.plugins.iter().filter(|desc| desc.is_instrument())
+    }
+
+    /// Scans all search paths for new or modified bundles, forgets removed ones, and saves the database.
+    pub fn scan(&mut self) -> Result<(), PluginError> {
+        let mut found: Vec<PathBuf> = Vec::new();
+        for dir in clap_search_paths() {
+            find_bundles(&dir, &mut found);
+        }
+
+        let found: Vec<(String, u64)> = found.iter()
+            .map(|path| (path.to_string_lossy().into_owned(), modified_time(path)))
+            .collect();
+
+        // Forget bundles that have been removed or modified
+        self.bundles.retain(|bundle| found.contains(bundle));
+        self.blacklist.retain(|entry| found.contains(&(entry.path.clone(), entry.modified)));
+        let bundles = &self.bundles;
+        self.plugins.retain(|desc| {
+            let bundle = desc.path.rsplit_once('#').map_or(desc.path.as_str(), |(bundle, _)| bundle);
+            bundles.iter().any(|(path, _)| path == bundle)
+        });
+
+        for (path, modified) in found {
+            if self.bundles.iter().any(|(known, _)| *known == path) || self.is_blacklisted(&path) {
+                continue
+            }
+
+            match scan_bundle(&path) {
+                Ok(mut descriptors) => {
+                    self.plugins.append(&mut descriptors);
+                    self.bundles.push((path, modified));
+                },
+                Err(reason) => {
+                    eprintln!("PluginDatabase: blacklisting {path}: {reason}");
+                    self.blacklist.push(BlacklistEntry { path, modified, reason });
+                }
+            }
+        }
+
+        self.save()
+    }
+}
+
+// Runs the scanner child process for a single bundle. Any failure is a reason to blacklist it.
+fn scan_bundle(path: &str) -> Result<Vec<PluginDescriptor>, String> {
+    let exe = env::current_exe().map_err(|err| format!("{}", err))?;
+    let mut child = Command::new(exe)
+        .arg(SCAN_ARG)
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| format!("Unable to start scanner: {}", err))?;
+
+    // Read the output on another thread, so a plugin filling the pipe can't stall us
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        io::Read::read_to_string(&mut stdout, &mut output).map(|_| output)
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => {
+                if started.elapsed() > SCAN_TIMEOUT {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err("Timed out".to_string())
+                }
+                thread::sleep(Duration::from_millis(10));
+            },
+            Err(err) => return Err(format!("{}", err)),
+        }
+    };
+
+    if !status.success() {
+        return Err(format!("Scanner exited with {}", status))
+    }
+
+    let output = match reader.join() {
+        Ok(Ok(output)) => output,
+        _ => return Err("Unable to read scanner output".to_string()),
+    };
+
+    let mut descriptors = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields[0] == "plugin" {
+            match descriptor_from_fields(&fields[1..]) {
+                Some(desc) => descriptors.push(desc),
+                None => return Err(format!("Malformed scanner output: {line}")),
+            }
+        }
+    }
+
+    Ok(descriptors)
+}
+
+/// Entry point of the scanner child process. Prints the bundle's descriptors to stdout and returns the exit code.
+pub fn scan_child(path: &str) -> i32 {
+    let bundle = match ClapBundle::load(path) {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1
+        }
+    };
+
+    let descriptors = bundle.describe();
+    let mut stdout = io::stdout().lock();
+    for desc in &descriptors {
+        if writeln!(stdout, "{}", descriptor_to_line(desc)).is_err() {
+            return 1
+        }
+    }
+
+    0
+}
@@ -1,4 +1,6 @@
-use super::playlist::Playlist;
+use std::sync::Arc;
+
+use super::{playlist::Playlist, instrument::{Instrument, InstrumentKind}, plugins::builtin::sampler::sample::Sample};
 use crate::engine::pattern::Pattern;
 
 pub struct Project {
@@ -7,4 +9,8 @@ pub struct Project {
 
     pub playlist: Playlist,
     pub patterns: Vec<Pattern>,
+    pub instruments: Vec<Instrument>, // see instrument.rs
+    pub samples: Vec<Arc<Sample>>, // edited in the sample editor, see samples.rs. Shared with the samplers.
+    // Each instrument's plugin state for every kind it has had, kept out of the undo history
+    pub plugin_states: Vec<Vec<(InstrumentKind, Vec<u8>)>>,
 }
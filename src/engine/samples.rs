@@ -0,0 +1,64 @@
+// The project's samples. The sample editor works on a copy and hands back the result, so everything here can be undone.
+
+use std::sync::Arc;
+
+use super::{DAWEngine, history::Edit, plugins::builtin::sampler::sample::Sample};
+
+pub const MAX_SAMPLES: usize = 99;
+
+impl DAWEngine {
+    // Returns the index, None if there's no room
+    pub fn add_sample(&mut self, sample: Sample) -> Option<usize> {
+        let index = self.project.samples.len();
+        if index >= MAX_SAMPLES {
+            return None
+        }
+        self.apply_edit(Edit::Sample { index, before: None, after: Some(Arc::new(sample)) });
+        Some(index)
+    }
+
+    // Only the frames that changed are kept for undo
+    pub fn set_sample(&mut self, index: usize, mut sample: Sample) {
+        let Some(before) = self.project.samples.get(index) else {
+            return
+        };
+        sample.validate_loops();
+
+        let (start, same) = changed_frames(before, &sample);
+        let part = |sample: &Sample| Sample {
+            data: sample.data.iter().map(|channel| channel[start..channel.len() - same].to_vec()).collect(),
+            ..sample.settings()
+        };
+        let (before, after) = (part(before), part(&sample));
+        self.apply_edit(Edit::SampleFrames { index, start, before, after });
+    }
+
+    // Instruments play samples by number, so like instruments only the last one is actually removed.
+    // The rest are left as empty slots, which a new sample can be loaded into.
+    pub fn remove_sample(&mut self, index: usize) {
+        let Some(before) = self.project.samples.get(index).cloned() else {
+            return
+        };
+        let last = index + 1 == self.project.samples.len();
+        if before.is_empty() && !last {
+            return
+        }
+        let after = if last { None } else { Some(Arc::new(Sample::default())) };
+        self.apply_edit(Edit::Sample { index, before: Some(before), after });
+    }
+}
+
+// Where two versions of a sample differ, as the first changed frame and how many frames at the end are the same
+fn changed_frames(before: &Sample, after: &Sample) -> (usize, usize) {
+    if before.data.len() != after.data.len() {
+        return (0, 0)
+    }
+    let same = |a: usize, b: usize| before.data.iter().zip(&after.data).all(|(x, y)| x[a].to_bits() == y[b].to_bits());
+
+    let shortest = before.frames().min(after.frames());
+    let start = (0..shortest).find(|&frame| !same(frame, frame)).unwrap_or(shortest);
+    let end = (0..shortest - start)
+        .find(|&back| !same(before.frames() - 1 - back, after.frames() - 1 - back))
+        .unwrap_or(shortest - start);
+    (start, end)
+}
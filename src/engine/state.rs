@@ -1,5 +1,5 @@
 use std::io::Write;
-use super::{pattern::Note, project::Project, DAWEngine, plugins::interface::{TimedEvent, Event, NoteState}, allocate_note, free_note};
+use super::{pattern::Note, playlist::Clip, project::Project, DAWEngine, plugins::interface::{TimedEvent, Event, NoteState}, allocate_note, free_note};
 
 pub struct State {
     pub playing: bool,
@@ -40,7 +40,7 @@ pub struct PatternState {
     // TODO set_rpb method for DAWEngine mutating Pattern's rpb and PatternState's row_length.
     // 2022-04-07: row_length has been moved from DAWEngine to here. See the comment in the Pattern struct (same reason)
     pub(crate) row_length: u32, // in ticks
-    pub(crate) note_ids: Vec<usize>,
+    pub(crate) note_ids: Vec<Option<usize>>, // note slot each track is playing
     pub(crate) last_instrument: u8,
 
     pub position: u32,          // in ticks
@@ -61,6 +61,70 @@ impl DAWEngine {
         state.playing = true;
     }
 
+    // In ticks
+    pub fn pattern_length(&self, index: usize) -> u32 {
+        self.project.patterns[index].rows.len() as u32 * self.state.patterns[index].row_length
+    }
+
+    // Moves the playhead, picking up clips it lands in the middle of
+    pub fn playlist_seek(&mut self, position: u32) {
+        for i in 0..self.state.patterns.len() {
+            self.pattern_stop(i);
+        }
+        self.state.playlist.seek(position);
+
+        let mut starting = Vec::new();
+        for clip in &self.project.playlist.clips {
+            let Clip::Pattern(clip) = clip;
+            if (clip.begin..clip.end).contains(&position) {
+                starting.push((clip.pattern_index, clip.offset + position - clip.begin));
+            }
+        }
+        for (index, offset) in starting {
+            // Clips can be longer than their pattern
+            if index < self.state.patterns.len() && offset < self.pattern_length(index) {
+                self.pattern_play(index, offset);
+            }
+        }
+        self.sync_seek(position);
+    }
+
+    // Starts and stops the patterns of clips reaching the playhead
+    pub(crate) fn playlist_tick(&mut self) {
+        let position = self.state.playlist.position;
+        if let Some((start, end)) = self.project.playlist.loop_range {
+            if start < end && position == end {
+                self.playlist_seek(start);
+                return
+            }
+        }
+
+        let mut starting = Vec::new();
+        let mut stopping = Vec::new();
+
+        for clip in &self.project.playlist.clips {
+            let Clip::Pattern(clip) = clip;
+            if clip.begin == position {
+                starting.push((clip.pattern_index, clip.offset));
+            } else if clip.end == position {
+                stopping.push(clip.pattern_index);
+            }
+        }
+
+        // Stop first, the same pattern might be starting again right away
+        for index in stopping {
+            if index < self.state.patterns.len() {
+                self.pattern_stop(index);
+            }
+        }
+        for (index, offset) in starting {
+            // Clips can be longer than their pattern
+            if index < self.state.patterns.len() && offset < self.pattern_length(index) {
+                self.pattern_play(index, offset);
+            }
+        }
+    }
+
     pub fn pattern_stop(&mut self, index: usize) {
         self.state.patterns[index].playing = false
         // TODO note off to all active voices
@@ -97,10 +161,9 @@ impl DAWEngine {
     }
 
     fn pattern_play_row(&mut self, index: usize, sample_index: usize) {
-        let pat = &self.project.patterns[index];
-
         #[cfg(debug_assertions)]
         {
+            let pat = &self.project.patterns[index];
             print!("{:0>2} | ", self.state.patterns[index].row);
             for track in &pat.rows[self.state.patterns[index].row as usize] {
                 print!(
@@ -121,24 +184,12 @@ impl DAWEngine {
             print!("\n");
         }
 
-        for (track, event) in pat.rows[self.state.patterns[index].row as usize].iter().enumerate() {
+        let row = self.state.patterns[index].row as usize;
+        for track in 0..self.project.patterns[index].rows[row].len() {
+            let event = self.project.patterns[index].rows[row][track];
             match event.note {
                 Note::PreviousTrack => todo!(),
-                Note::Off => {
-                    let note_state = self.state.notes[self.state.patterns[index].note_ids[track]];
-
-                    self.state.event_list.push(TimedEvent {
-                        module_index: note_state.instrument,
-                        position: sample_index as u32,
-                        event: Event::NoteOff {
-                            id: self.state.patterns[index].note_ids[track],
-                            key: note_state.key,
-                            vel: note_state.vel
-                        }
-                    });
-
-                    free_note(&mut self.state.notes, note_state.id);
-                },
+                Note::Off => self.track_note_off(index, track, sample_index as u32),
                 // TODO events for these
                 // I'll probably end up removing them or something
                 Note::Cut => todo!(),
@@ -146,46 +197,60 @@ impl DAWEngine {
 
                 Note::None => {},
 
+                // Muted, so nothing new starts. What was playing on the track still gets its note off.
+                _ if !self.project.patterns[index].track_audible(track) => self.track_note_off(index, track, sample_index as u32),
+
                 // rest of the notes
                 _ => {
                     let note = event.note as u8;
                     let instrument = if event.instrument == 0 { self.state.patterns[index].last_instrument as usize } else { (event.instrument-1) as usize };
                     self.state.patterns[index].last_instrument = instrument as u8;
+                    let vel = if event.volume > 127 { self.default_velocity(instrument) } else { event.volume };
+
+                    self.track_note_off(index, track, sample_index as u32);
 
-                    let old_state = self.state.notes[self.state.patterns[index].note_ids[track]];
                     let id = allocate_note(&mut self.state.notes, note, instrument, self.state.next_note_id);
+                    self.state.notes[id].vel = vel;
+                    self.state.notes[id].is_on = true;
                     // DAW will allocate on the next ID (if free). We don't want to be using the same ID all over again.
-                    self.state.next_note_id = id+1;
-
-                    self.state.patterns[index].note_ids[track] = id;
-
-                    // TODO: replace this with is_free so that plugin APIs like CLAP can notify whenever it's free
-                    if self.state.notes[self.state.patterns[index].note_ids[track]].is_on {
-                        self.state.event_list.push(TimedEvent {
-                            module_index: instrument,
-                            position: sample_index as u32,
-                            event: Event::NoteOff {
-                                id: self.state.patterns[index].note_ids[track],
-                                key: old_state.key,
-                                vel: old_state.vel
-                            }
-                        });
-                    }
+                    self.state.next_note_id = (id + 1) % self.state.notes.len();
+
+                    self.state.patterns[index].note_ids[track] = Some(id);
 
                     self.state.event_list.push(TimedEvent {
                         // remember, 0 is none
                         module_index: instrument,
                         position: sample_index as u32,
-                        event: Event::NoteOn {
-                            id,
-                            key: note,
-                            vel: if event.volume > 127 { 127 } else { event.volume },
-                        },
+                        event: Event::NoteOn { id, key: note, vel },
                     })
                 },
             }
         }
     }
+
+    // Ends the note a track is playing, if it still has one. The track holds on to the slot until then, so nothing else can have it.
+    // TODO: replace is_on with is_free so that plugin APIs like CLAP can notify whenever it's free
+    fn track_note_off(&mut self, index: usize, track: usize, position: u32) {
+        let Some(id) = self.state.patterns[index].note_ids[track].take() else {
+            return
+        };
+        let note_state = self.state.notes[id];
+        if !note_state.is_on {
+            return
+        }
+
+        self.state.event_list.push(TimedEvent {
+            module_index: note_state.instrument,
+            position,
+            event: Event::NoteOff {
+                id,
+                key: note_state.key,
+                vel: note_state.vel
+            }
+        });
+
+        free_note(&mut self.state.notes, id);
+    }
 }
 
 fn format_note(note: Note) -> String {
@@ -0,0 +1,396 @@
+// MIDI clock and MIDI Time Code. As master the engine sends them as it ticks; as slave, incoming clock
+// sets the tempo and transport, and incoming MTC moves the playhead.
+
+use std::sync::mpsc::{Sender, Receiver};
+use std::time::{Duration, Instant};
+
+use super::DAWEngine;
+use super::plugins::builtin::midi::output::{self, Command, Message, Status};
+
+const CLOCKS_PER_BEAT: u32 = 24;
+const MTC_FPS: u32 = 25;
+const MTC_RATE_BITS: u8 = 1; // 25 fps in the MTC rate field
+const CLOCK_SMOOTHING: f64 = 0.05; // how much each clock interval moves the tempo estimate
+const PHASE_CORRECTION: f64 = 0.5; // speed change per beat the transport is off by, as slave
+const MAX_CORRECTION: f64 = 0.02; // so it's never heard as a tempo change
+const RELOCATE_BEATS: f64 = 0.5; // further off than this and the transport jumps instead
+const MAX_CLOCK_INTERVAL: u64 = 200_000; // µs, longer gaps mean the clock stopped (under 12.5 BPM)
+const MTC_TIMEOUT: Duration = Duration::from_millis(250); // stop when quarter frames stop coming in
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncMode {
+    Internal,
+    Master, // send clock (and MTC if enabled)
+    Slave, // follow clock and MTC from the MIDI input
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SyncMessage {
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    SongPosition(u16), // in 16th notes
+    QuarterFrame(u8),
+    FullFrame { hours: u8, minutes: u8, seconds: u8, frames: u8 },
+}
+
+impl SyncMessage {
+    pub fn from_midi(message: &[u8]) -> Option<SyncMessage> {
+        match message {
+            [0xF8] => Some(SyncMessage::Clock),
+            [0xFA] => Some(SyncMessage::Start),
+            [0xFB] => Some(SyncMessage::Continue),
+            [0xFC] => Some(SyncMessage::Stop),
+            [0xF2, lsb, msb] => Some(SyncMessage::SongPosition(*lsb as u16 | (*msb as u16) << 7)),
+            [0xF1, data] => Some(SyncMessage::QuarterFrame(*data)),
+            [0xF0, 0x7F, _, 0x01, 0x01, hours, minutes, seconds, frames, 0xF7] => Some(SyncMessage::FullFrame {
+                hours: hours & 0x1F,
+                minutes: *minutes,
+                seconds: *seconds,
+                frames: *frames,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct SyncState {
+    pub mode: SyncMode,
+    pub send_mtc: bool,
+
+    output: Option<(Sender<Command>, Receiver<Status>)>,
+    block_start: Instant,
+    sample_index: usize, // of the last tick, for messages sent from elsewhere
+    was_playing: bool,
+
+    // Clock out
+    clock_phase: u32, // until the next clock, a tick is CLOCKS_PER_BEAT and a clock is ppq
+
+    // MTC out
+    mtc_samples: u32,
+    mtc_piece: u8,
+    mtc_time: [u8; 4], // hours, minutes, seconds, frames, taken when piece 0 goes out
+
+    // Clock in
+    last_clock: Option<u64>, // µs, from the MIDI input
+    clock_interval: f64, // smoothed, µs
+    clock_start: u32, // position (in ticks) counting started from
+    clocks: u32, // since then
+
+    // MTC in
+    quarter_frames: [u8; 8],
+    last_quarter_frame: Option<Instant>,
+}
+
+impl SyncState {
+    pub fn new() -> Self {
+        Self {
+            mode: SyncMode::Internal,
+            send_mtc: false,
+            output: None,
+            block_start: Instant::now(),
+            sample_index: 0,
+            was_playing: false,
+            clock_phase: 0,
+            mtc_samples: 0,
+            mtc_piece: 0,
+            mtc_time: [0; 4],
+            last_clock: None,
+            clock_interval: 0.0,
+            clock_start: 0,
+            clocks: 0,
+            quarter_frames: [0; 8],
+            last_quarter_frame: None,
+        }
+    }
+}
+
+fn ticks_to_timecode(ticks: u32, tempo: u16, ppq: u16) -> [u8; 4] {
+    let seconds = ticks as f64 * 60.0 / (tempo.max(1) as f64 * ppq.max(1) as f64);
+    let frames = (seconds * MTC_FPS as f64) as u32;
+    [
+        ((frames / (MTC_FPS * 3600)) % 24) as u8,
+        ((frames / (MTC_FPS * 60)) % 60) as u8,
+        ((frames / MTC_FPS) % 60) as u8,
+        (frames % MTC_FPS) as u8,
+    ]
+}
+
+// Where the next clock is from a position, see SyncState::clock_phase
+fn clock_phase(ticks: u32, ppq: u16) -> u32 {
+    let ppq = ppq.max(1) as u64;
+    ((ppq - ticks as u64 * CLOCKS_PER_BEAT as u64 % ppq) % ppq) as u32
+}
+
+fn timecode_to_ticks(time: [u8; 4], tempo: u16, ppq: u16) -> u32 {
+    let seconds = time[0] as f64 * 3600.0 + time[1] as f64 * 60.0 + time[2] as f64 + time[3] as f64 / MTC_FPS as f64;
+    (seconds * tempo as f64 / 60.0 * ppq as f64) as u32
+}
+
+impl DAWEngine {
+    // Port clock and MTC are sent to as master, None for the first one available
+    pub fn set_sync_output(&mut self, port: Option<&str>) -> Result<(), String> {
+        self.sync.output = Some(output::spawn(port.map(str::to_string))?);
+        Ok(())
+    }
+
+    // Where the transport is, in ticks
+    pub fn transport_position(&self) -> u32 {
+        if self.song_mode {
+            self.state.playlist.position
+        } else {
+            self.state.patterns.get(self.current_pattern).map_or(0, |state| state.position)
+        }
+    }
+
+    // In pattern mode the transport loops the current pattern, this is its length in ticks
+    fn transport_loop(&self) -> Option<u32> {
+        if self.song_mode || self.current_pattern >= self.project.patterns.len() {
+            return None
+        }
+        Some((self.project.patterns[self.current_pattern].rows.len() as u32 * self.state.patterns[self.current_pattern].row_length).max(1))
+    }
+
+    pub fn locate(&mut self, ticks: u32) {
+        if self.song_mode {
+            self.playlist_seek(ticks);
+        } else if let Some(length) = self.transport_loop() {
+            self.pattern_play(self.current_pattern, ticks % length);
+        }
+    }
+
+    // Where the transport is between ticks. The position has already moved past the tick being played.
+    fn transport_phase(&self) -> f64 {
+        self.transport_position() as f64 - 1.0 + self.samples_passed as f64 / self.tick_length.max(1) as f64
+    }
+
+    // Keeps the transport in line with where the master says it is, expected in ticks.
+    // Small errors are made up by playing slightly faster or slower, relocating would retrigger notes.
+    fn sync_follow(&mut self, expected: f64) {
+        let ppq = self.project.ppq.max(1) as f64;
+        let tempo = if self.sync.clock_interval > 0.0 {
+            60_000_000.0 / (self.sync.clock_interval * CLOCKS_PER_BEAT as f64)
+        } else {
+            self.project.tempo as f64
+        };
+        self.set_exact_tempo(tempo);
+
+        let mut error = expected - self.transport_phase();
+        if let Some(length) = self.transport_loop() {
+            let length = length as f64;
+            error = (error + length / 2.0).rem_euclid(length) - length / 2.0;
+        }
+        if error.abs() > ppq * RELOCATE_BEATS {
+            self.locate(expected.round() as u32);
+        } else {
+            let correction = (error / ppq * PHASE_CORRECTION).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+            self.set_tick_rate(tempo * (1.0 + correction));
+        }
+    }
+
+    fn sync_send(&mut self, sample_index: usize, message: &[u8]) {
+        // Like MIDI Out, audio rendered now is heard after the current buffer
+        let offset = (self.sample_size as usize + sample_index) as f64 / self.samplerate as f64;
+        let time = self.sync.block_start + Duration::from_secs_f64(offset);
+        if let Some((sender, _)) = &self.sync.output {
+            let _ = sender.send(Command::Send(time, Message::new(message)));
+        }
+    }
+
+    // Song position is in 16th notes
+    fn sync_send_song_position(&mut self, sample_index: usize, position: u32) {
+        let sixteenths = (position as u64 * 4 / self.project.ppq.max(1) as u64).min(0x3FFF) as u16;
+        self.sync_send(sample_index, &[0xF2, (sixteenths & 0x7F) as u8, (sixteenths >> 7) as u8]);
+    }
+
+    // Lines clock and MTC up with a new position
+    fn sync_restart(&mut self, sample_index: usize, position: u32) {
+        self.sync.clock_phase = clock_phase(position, self.project.ppq);
+        if self.sync.send_mtc {
+            let [hours, minutes, seconds, frames] = ticks_to_timecode(position, self.project.tempo, self.project.ppq);
+            self.sync_send(sample_index, &[0xF0, 0x7F, 0x7F, 0x01, 0x01, hours | MTC_RATE_BITS << 5, minutes, seconds, frames, 0xF7]);
+            self.sync.mtc_piece = 0;
+            self.sync.mtc_samples = 0;
+        }
+    }
+
+    // After the playhead jumped, as master. Slaves ignore song position while running, so they're stopped around it.
+    pub(crate) fn sync_seek(&mut self, position: u32) {
+        if self.sync.mode != SyncMode::Master {
+            return
+        }
+        let sample_index = self.sync.sample_index;
+        let running = self.sync.was_playing;
+        if running {
+            self.sync_send(sample_index, &[0xFC]);
+        }
+        self.sync_send_song_position(sample_index, position);
+        if running {
+            self.sync_send(sample_index, &[0xFB]);
+        }
+        self.sync_restart(sample_index, position);
+    }
+
+    // Called at the start of every buffer. Sends start/stop as master, and stops when MTC goes quiet as slave.
+    pub(crate) fn sync_begin_block(&mut self) {
+        self.sync.block_start = Instant::now();
+        if let Some((_, status)) = &self.sync.output {
+            while let Ok(status) = status.try_recv() {
+                match status {
+                    Status::Disconnected(name) => eprintln!("Sync output {name} has been disconnected"),
+                    Status::Error(err) => eprintln!("Sync output error: {err}"),
+                    _ => (),
+                }
+            }
+        }
+
+        match self.sync.mode {
+            SyncMode::Master => {
+                let playing = self.state.playing;
+                if playing && !self.sync.was_playing {
+                    let position = self.transport_position();
+                    if position == 0 {
+                        self.sync_send(0, &[0xFA]);
+                    } else {
+                        self.sync_send_song_position(0, position);
+                        self.sync_send(0, &[0xFB]);
+                    }
+                    self.sync_restart(0, position);
+                } else if !playing && self.sync.was_playing {
+                    self.sync_send(0, &[0xFC]);
+                }
+                self.sync.was_playing = playing;
+            },
+            SyncMode::Slave => {
+                if self.sync.last_quarter_frame.map_or(false, |time| time.elapsed() > MTC_TIMEOUT) {
+                    self.sync.last_quarter_frame = None;
+                    self.state.playing = false;
+                }
+            },
+            SyncMode::Internal => (),
+        }
+    }
+
+    // Called from tick() while playing, every sample
+    pub(crate) fn sync_tick(&mut self, sample_index: usize) {
+        if self.sync.mode != SyncMode::Master {
+            return
+        }
+        self.sync.sample_index = sample_index;
+
+        // On a tick boundary, the clocks falling within the tick. Any ppq works, even under 24.
+        if self.samples_passed == 0 {
+            let ppq = self.project.ppq.max(1) as u32;
+            while self.sync.clock_phase < CLOCKS_PER_BEAT {
+                self.sync_send(sample_index, &[0xF8]);
+                self.sync.clock_phase += ppq;
+            }
+            self.sync.clock_phase -= CLOCKS_PER_BEAT;
+        }
+
+        // Quarter frames, 4 per frame
+        if self.sync.send_mtc {
+            if self.sync.mtc_samples == 0 {
+                let piece = self.sync.mtc_piece;
+                if piece == 0 {
+                    self.sync.mtc_time = ticks_to_timecode(self.transport_position(), self.project.tempo, self.project.ppq);
+                }
+                let [hours, minutes, seconds, frames] = self.sync.mtc_time;
+                let nibble = match piece {
+                    0 => frames & 0xF,
+                    1 => frames >> 4,
+                    2 => seconds & 0xF,
+                    3 => seconds >> 4,
+                    4 => minutes & 0xF,
+                    5 => minutes >> 4,
+                    6 => hours & 0xF,
+                    _ => (hours >> 4) | MTC_RATE_BITS << 1,
+                };
+                self.sync_send(sample_index, &[0xF1, piece << 4 | nibble]);
+                self.sync.mtc_piece = (piece + 1) % 8;
+            }
+            self.sync.mtc_samples += 1;
+            if self.sync.mtc_samples >= self.samplerate / (MTC_FPS * 4) {
+                self.sync.mtc_samples = 0;
+            }
+        }
+    }
+
+    // Clock and MTC from the MIDI input, stamp is in µs
+    pub(crate) fn sync_receive(&mut self, message: SyncMessage, stamp: u64) {
+        if self.sync.mode != SyncMode::Slave {
+            return
+        }
+        let ppq = self.project.ppq as u32;
+
+        match message {
+            SyncMessage::Clock => {
+                // Tempo from the average time between clocks, so jitter doesn't make it wobble
+                if let Some(last) = self.sync.last_clock {
+                    let interval = stamp.saturating_sub(last);
+                    if interval > 0 && interval < MAX_CLOCK_INTERVAL {
+                        let interval = interval as f64;
+                        self.sync.clock_interval = if self.sync.clock_interval == 0.0 {
+                            interval
+                        } else {
+                            self.sync.clock_interval + (interval - self.sync.clock_interval) * CLOCK_SMOOTHING
+                        };
+
+                        if !self.state.playing {
+                            self.set_exact_tempo(60_000_000.0 / (self.sync.clock_interval * CLOCKS_PER_BEAT as f64));
+                        }
+                    } else {
+                        self.sync.clock_interval = 0.0;
+                    }
+                }
+                self.sync.last_clock = Some(stamp);
+
+                if self.state.playing {
+                    self.sync.clocks += 1;
+                    self.sync_follow(self.sync.clock_start as f64 + self.sync.clocks as f64 * ppq as f64 / CLOCKS_PER_BEAT as f64);
+                }
+            },
+            SyncMessage::Start => {
+                self.locate(0);
+                self.sync.clock_start = 0;
+                self.sync.clocks = 0;
+                self.state.playing = true;
+            },
+            SyncMessage::Continue => {
+                self.sync.clock_start = self.transport_position();
+                self.sync.clocks = 0;
+                self.state.playing = true;
+            },
+            SyncMessage::Stop => self.state.playing = false,
+            SyncMessage::SongPosition(sixteenths) => {
+                let position = sixteenths as u32 * ppq / 4;
+                self.locate(position);
+                self.sync.clock_start = position;
+                self.sync.clocks = 0;
+            },
+            SyncMessage::QuarterFrame(data) => {
+                let piece = (data >> 4) as usize & 7;
+                self.sync.quarter_frames[piece] = data & 0xF;
+                self.sync.last_quarter_frame = Some(Instant::now());
+
+                // A whole time code every 8 pieces, which took 2 frames to arrive
+                if piece == 7 {
+                    let q = self.sync.quarter_frames;
+                    let time = [(q[6] | (q[7] & 1) << 4), q[4] | q[5] << 4, q[2] | q[3] << 4, q[0] | q[1] << 4];
+                    let position = timecode_to_ticks(time, self.project.tempo, self.project.ppq)
+                        + timecode_to_ticks([0, 0, 0, 2], self.project.tempo, self.project.ppq);
+                    if self.state.playing {
+                        self.sync_follow(position as f64);
+                    } else {
+                        self.locate(position);
+                        self.state.playing = true;
+                    }
+                }
+            },
+            SyncMessage::FullFrame { hours, minutes, seconds, frames } => {
+                self.locate(timecode_to_ticks([hours, minutes, seconds, frames], self.project.tempo, self.project.ppq));
+            },
+        }
+    }
+}
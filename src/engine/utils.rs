@@ -1,25 +1,189 @@
 use super::{
-    pattern::Pattern,
+    history::Edit,
+    pattern::{Pattern, Note, TrackEvent, TrackInfo},
     playlist::{Clip, PatternClip},
     state::PatternState,
 };
 
 impl super::DAWEngine {
-    pub fn add_pattern(&mut self, pat: Pattern) {
-        let rpb = pat.rpb;
+    // Added at the end, can be undone. Returns its index.
+    pub fn add_pattern(&mut self, pat: Pattern) -> usize {
+        let index = self.project.patterns.len();
+        self.apply_edit(Edit::AddPattern { pattern: index, added: pat });
+        index
+    }
 
-        let state = PatternState {
+    pub(crate) fn new_pattern_state(&self, pat: &Pattern) -> PatternState {
+        PatternState {
             // pattern_index: self.project.patterns.len() - 1,
             position: 0,
             playing: false,
             row: 0,
             ticks_passed: 0,
-            row_length: self.project.ppq as u32 / rpb as u32,
-            note_ids: vec![0; pat.rows[0].len()],
+            row_length: self.project.ppq as u32 / pat.rpb as u32,
+            note_ids: vec![None; pat.rows[0].len()],
+            last_instrument: 0,
+        }
+    }
+
+    pub fn current_pattern(&self) -> usize {
+        self.current_pattern
+    }
+
+    // The one played outside of song mode, edited and recorded into
+    pub fn select_pattern(&mut self, index: usize) {
+        if index >= self.project.patterns.len() || index == self.current_pattern {
+            return
+        }
+        if !self.song_mode {
+            self.pattern_stop(self.current_pattern);
+        }
+        self.current_pattern = index;
+    }
+
+    // Returns the index of the copy
+    pub fn clone_pattern(&mut self, index: usize) -> usize {
+        let pat = self.project.patterns[index].clone();
+        self.add_pattern(pat)
+    }
+
+    // The last pattern can't be removed. Clips of the pattern go with it, the ones after it move down.
+    // Edits before this one in the history are only ever undone after it is, so their indices still line up.
+    pub fn remove_pattern(&mut self, index: usize) {
+        if self.project.patterns.len() <= 1 || index >= self.project.patterns.len() {
+            return
+        }
+        let clips = self.project.playlist.clips.iter().enumerate()
+            .filter(|(_, clip)| matches!(clip, Clip::Pattern(clip) if clip.pattern_index == index))
+            .map(|(i, clip)| (i, clip.clone()))
+            .collect();
+        let removed = self.project.patterns[index].clone();
+        self.apply_edit(Edit::RemovePattern { pattern: index, removed, clips });
+    }
+
+    pub fn rename_pattern(&mut self, index: usize, name: String) {
+        let Some(before) = self.project.patterns.get(index).cloned() else {
+            return
+        };
+        let mut after = before.clone();
+        after.name = name;
+        self.apply_edit(Edit::Pattern { pattern: index, before, after });
+    }
+
+    // Adds or removes tracks at the right, can be undone
+    pub fn set_pattern_tracks(&mut self, index: usize, tracks: usize) {
+        let Some(before) = self.project.patterns.get(index).cloned() else {
+            return
+        };
+        if tracks == 0 || tracks == before.rows[0].len() {
+            return
+        }
+
+        let mut after = before.clone();
+        for row in &mut after.rows {
+            row.resize(tracks, TrackEvent { note: Note::None, instrument: 0, volume: 128 });
+        }
+        after.tracks.resize_with(tracks, TrackInfo::default);
+        self.apply_edit(Edit::Pattern { pattern: index, before, after });
+    }
+
+    // After patterns change shape, so playback doesn't run off the end of them
+    pub(crate) fn fit_pattern_states(&mut self) {
+        for (pattern, state) in self.project.patterns.iter_mut().zip(self.state.patterns.iter_mut()) {
+            state.note_ids.resize(pattern.rows[0].len(), None);
+            pattern.tracks.resize_with(pattern.rows[0].len(), TrackInfo::default);
+            if state.position >= pattern.rows.len() as u32 * state.row_length {
+                state.position = 0;
+                state.row = 0;
+                state.ticks_passed = 0;
+            }
+        }
+    }
+
+    // The song as IT orders: the patterns of the clips on the first playlist track, by where they start
+    pub fn orders(&self) -> Vec<usize> {
+        let mut clips: Vec<&PatternClip> = self.project.playlist.clips.iter()
+            .map(|clip| { let Clip::Pattern(clip) = clip; clip })
+            .filter(|clip| clip.track == 0)
+            .collect();
+        clips.sort_by_key(|clip| clip.begin);
+        clips.iter().map(|clip| clip.pattern_index).collect()
+    }
+
+    // Replaces the first playlist track with the patterns one after another, can be undone
+    pub fn set_orders(&mut self, orders: &[usize]) {
+        let before = self.project.playlist.clips.clone();
+        let mut after: Vec<Clip> = before.iter()
+            .filter(|clip| !matches!(clip, Clip::Pattern(clip) if clip.track == 0))
+            .cloned()
+            .collect();
+
+        let mut position = 0;
+        for &index in orders.iter().filter(|&&index| index < self.project.patterns.len()) {
+            let Clip::Pattern(mut clip) = self.pattern_to_clip(index);
+            clip.begin += position;
+            clip.end += position;
+            position = clip.end;
+            after.push(Clip::Pattern(clip));
+        }
+
+        self.apply_edit(Edit::Clips { before, after });
+    }
+
+    // Playlist edits, all can be undone
+
+    pub fn add_clip(&mut self, clip: Clip) {
+        let index = self.project.playlist.clips.len();
+        self.apply_edit(Edit::AddClip { index, clip });
+    }
+
+    pub fn remove_clip(&mut self, index: usize) {
+        let Some(clip) = self.project.playlist.clips.get(index).cloned() else {
+            return
+        };
+        self.apply_edit(Edit::RemoveClip { index, clip });
+    }
+
+    pub fn replace_clip(&mut self, index: usize, clip: Clip) {
+        let Some(before) = self.project.playlist.clips.get(index).cloned() else {
+            return
+        };
+        self.apply_edit(Edit::ReplaceClip { index, before, after: clip });
+    }
+
+    // Cuts a clip in two at a position inside of it, the second half carries on where the first one stopped
+    pub fn split_clip(&mut self, index: usize, at: u32) {
+        let Some(Clip::Pattern(clip)) = self.project.playlist.clips.get(index).cloned() else {
+            return
+        };
+        if at <= clip.begin || at >= clip.end {
+            return
+        }
+
+        let before = self.project.playlist.clips.clone();
+        let mut after = before.clone();
+        after[index] = Clip::Pattern(PatternClip { end: at, ..clip.clone() });
+        after.insert(index + 1, Clip::Pattern(PatternClip { begin: at, offset: clip.offset + (at - clip.begin), ..clip }));
+        self.apply_edit(Edit::Clips { before, after });
+    }
+
+    pub fn set_loop(&mut self, loop_range: Option<(u32, u32)>) {
+        self.project.playlist.loop_range = loop_range.filter(|(start, end)| start < end);
+    }
+
+    // Adds or removes rows at the end, can be undone
+    pub fn resize_pattern(&mut self, index: usize, rows: usize) {
+        let Some(before) = self.project.patterns.get(index).cloned() else {
+            return
         };
+        if rows == 0 || rows == before.rows.len() {
+            return
+        }
 
-        self.project.patterns.push(pat);
-        self.state.patterns.push(state);
+        let mut after = before.clone();
+        let tracks = before.rows[0].len();
+        after.rows.resize_with(rows, || vec![TrackEvent { note: Note::None, instrument: 0, volume: 128 }; tracks]);
+        self.apply_edit(Edit::Pattern { pattern: index, before, after });
     }
 
     pub fn pattern_to_clip(&self, index: usize) -> Clip {
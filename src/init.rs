@@ -28,7 +28,10 @@ pub fn main_menu() -> Menu {
     pages.push(vec![
         MenuItem::new("Play"),
         MenuItem::new("Pause"),
-        MenuItem::new("Stop")
+        MenuItem::new("Stop"),
+        MenuItem::new("Record"),
+        MenuItem::new("Sync: Internal"),
+        MenuItem::new("Mode: Pattern")
     ]);
 
     // Settings menu
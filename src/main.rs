@@ -3,8 +3,10 @@ use std::mem;
 use std::panic::catch_unwind;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
+use std::thread;
 use cpal::{SampleRate, BufferSize};
 use engine::pattern::Pattern;
+use engine::sync::SyncMode;
 use sdl2::keyboard::Keycode;
 use ui::widgets::pattern_editor::PatternEditor;
 use ui::widgets::{Position, fill_region};
@@ -17,6 +19,12 @@ use crate::ui::widgets::{draw_borders_inner_triangles_thin, Widget};
 use crate::ui::widgets::eventhook::EventHook;
 use crate::ui::widgets::menu::Menu;
 use crate::ui::widgets::rulers::LabelRuler;
+use crate::ui::widgets::order_list::{OrderList, PatternAction, PatternInfo};
+use crate::ui::widgets::playlist_view::{PlaylistView, PlaylistAction};
+use crate::ui::widgets::instrument_list::{InstrumentList, InstrumentAction};
+use crate::ui::widgets::instrument_editor::InstrumentEditor;
+use crate::ui::widgets::sample_editor::{SampleEditor, SampleAction};
+use crate::engine::instrument::{Instrument, InstrumentKind};
 
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::BufferSize::Fixed;
@@ -34,6 +42,16 @@ const HEIGHT: usize = 768;
 
 #[allow(unused_must_use)]
 fn main() {
+    // Are we a plugin scanner process?
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == engine::plugins::scanner::SCAN_ARG {
+        std::process::exit(engine::plugins::scanner::scan_child(&args[2]));
+    }
+    // ... or a plugin bridge process?
+    if args.len() == 4 && args[1] == engine::plugins::bridge::BRIDGE_ARG {
+        std::process::exit(engine::plugins::bridge::bridge_child(&args[2], &args[3]));
+    }
+
     // Set panic to display a native dialog
     std::panic::set_hook(Box::new(|panic_data| {
         let payload_raw = panic_data.payload();
@@ -128,9 +146,38 @@ fn main() {
         .expect("Cannot set up output stream");
     stream.play().unwrap();
     {
-        daw.lock().unwrap().add_pattern(Pattern::new(8, 64));
+        // A new project starts out with a pattern, not with something to undo
+        let mut locked_daw = daw.lock().unwrap();
+        locked_daw.add_pattern(Pattern::new(8, 64));
+        locked_daw.history.clear();
     }
 
+    // MIDI input, the first port if there is one
+    // TODO let the user pick the port in the settings
+    let _midi_input = match engine::live::midi_input_ports() {
+        Ok(ports) if !ports.is_empty() => {
+            match engine::live::MidiInputDevice::open(0, daw.lock().unwrap().live_sender()) {
+                Ok(device) => {
+                    println!("MIDI input: {}", device.name);
+                    Some(device)
+                },
+                Err(err) => {
+                    eprintln!("Unable to open MIDI input: {}", err);
+                    None
+                },
+            }
+        },
+        Ok(_) => None,
+        Err(err) => {
+            eprintln!("Unable to list MIDI inputs: {}", err);
+            None
+        },
+    };
+
+    // Plugins are scanned in the background, the instrument editor lists them once that's done
+    let (plugins_tx, plugins_rx) = channel();
+    thread::spawn(move || plugins_tx.send(engine::instrument::scan_instrument_plugins()));
+
 
 
     // UI
@@ -248,6 +295,7 @@ fn main() {
     pattern_view.top_rim = RIM_DARK;
     pattern_view.bottom_rim = RIM_LIGHT;
     pattern_view.key_mapping = default_kbd_mapping();
+    pattern_view.preview = Some(daw.lock().unwrap().live_sender());
     // pview.pattern = Some(pat);
 
     let pattern_ruler = Box::new(LabelRuler {
@@ -278,8 +326,128 @@ fn main() {
         playing: false,
     });
 
+    let mut order_view = Box::new(OrderList::new(
+        Position { x: 2, y: 6 }, Position { x: (WIDTH/8)-2, y: (HEIGHT/8)-1 }
+    ));
+    order_view.outer_bg = MAIN_COLOR;
+    order_view.text_color = 0xffffff;
+    order_view.top_rim = RIM_DARK;
+    order_view.bottom_rim = RIM_LIGHT;
+
+    let order_ruler = Box::new(LabelRuler {
+        label: "Order List".to_string(),
+        bg_color: MAIN_COLOR,
+        ruler_color: RIM_DARK,
+        label_color: 0,
+        start_x: 1,
+        end_x: (WIDTH/8)-1,
+        y: 3,
+    });
+
+    let order_container = Box::new(Container {
+        widgets: vec![order_ruler, order_view],
+        handles_events: true,
+    });
+
+    let mut playlist_view = Box::new(PlaylistView::new(
+        Position { x: 2, y: 6 }, Position { x: (WIDTH/8)-2, y: (HEIGHT/8)-1 }, daw.lock().unwrap().project.ppq
+    ));
+    playlist_view.outer_bg = MAIN_COLOR;
+    playlist_view.text_color = 0xffffff;
+    playlist_view.top_rim = RIM_DARK;
+    playlist_view.bottom_rim = RIM_LIGHT;
+
+    let playlist_ruler = Box::new(LabelRuler {
+        label: "Playlist".to_string(),
+        bg_color: MAIN_COLOR,
+        ruler_color: RIM_DARK,
+        label_color: 0,
+        start_x: 1,
+        end_x: (WIDTH/8)-1,
+        y: 3,
+    });
+
+    let playlist_container = Box::new(Container {
+        widgets: vec![playlist_ruler, playlist_view],
+        handles_events: true,
+    });
+
     pager.widgets.push(test);
     pager.widgets.push(pattern_container);
+    pager.widgets.push(order_container);
+    let mut instrument_list = Box::new(InstrumentList::new(
+        Position { x: 2, y: 6 }, Position { x: (WIDTH/8)-2, y: (HEIGHT/8)-1 }
+    ));
+    instrument_list.outer_bg = MAIN_COLOR;
+    instrument_list.text_color = 0xffffff;
+    instrument_list.top_rim = RIM_DARK;
+    instrument_list.bottom_rim = RIM_LIGHT;
+
+    let instrument_list_ruler = Box::new(LabelRuler {
+        label: "Instrument List".to_string(),
+        bg_color: MAIN_COLOR,
+        ruler_color: RIM_DARK,
+        label_color: 0,
+        start_x: 1,
+        end_x: (WIDTH/8)-1,
+        y: 3,
+    });
+
+    let instrument_list_container = Box::new(Container {
+        widgets: vec![instrument_list_ruler, instrument_list],
+        handles_events: true,
+    });
+
+    let mut instrument_editor = Box::new(InstrumentEditor::new(
+        Position { x: 2, y: 6 }, Position { x: (WIDTH/8)-2, y: (HEIGHT/8)-1 }
+    ));
+    instrument_editor.outer_bg = MAIN_COLOR;
+    instrument_editor.text_color = 0xffffff;
+    instrument_editor.top_rim = RIM_DARK;
+    instrument_editor.bottom_rim = RIM_LIGHT;
+
+    let instrument_editor_ruler = Box::new(LabelRuler {
+        label: "Instrument Editor".to_string(),
+        bg_color: MAIN_COLOR,
+        ruler_color: RIM_DARK,
+        label_color: 0,
+        start_x: 1,
+        end_x: (WIDTH/8)-1,
+        y: 3,
+    });
+
+    let instrument_editor_container = Box::new(Container {
+        widgets: vec![instrument_editor_ruler, instrument_editor],
+        handles_events: true,
+    });
+
+    pager.widgets.push(playlist_container);
+    pager.widgets.push(instrument_list_container);
+    let mut sample_editor = Box::new(SampleEditor::new(
+        Position { x: 2, y: 6 }, Position { x: (WIDTH/8)-2, y: (HEIGHT/8)-1 }
+    ));
+    sample_editor.outer_bg = MAIN_COLOR;
+    sample_editor.text_color = 0xffffff;
+    sample_editor.top_rim = RIM_DARK;
+    sample_editor.bottom_rim = RIM_LIGHT;
+
+    let sample_ruler = Box::new(LabelRuler {
+        label: "Sample Editor".to_string(),
+        bg_color: MAIN_COLOR,
+        ruler_color: RIM_DARK,
+        label_color: 0,
+        start_x: 1,
+        end_x: (WIDTH/8)-1,
+        y: 3,
+    });
+
+    let sample_container = Box::new(Container {
+        widgets: vec![sample_ruler, sample_editor],
+        handles_events: true,
+    });
+
+    pager.widgets.push(instrument_editor_container);
+    pager.widgets.push(sample_container);
     ui.widgets.push(Box::new(pager));
     ui.widgets.push(menu);
     ui.widgets.push(clock);
@@ -296,22 +464,189 @@ fn main() {
     #[cfg(debug_assertions)]
     ui_channel.send(Command::Text(0, 0, 0xffffff, 0xff0000, " DEBUG BUILD. Not for daily use. ".to_string()));
 
+    let mut ctrl_held = false;
+
     // Main UI loop
     while !ui.wants_to_quit() {
         let mut locked_daw = daw.lock().expect("UI thread: Unable to lock mutex on DAWEngine");
 
+        // Order List
+        {
+            let pager = get_widget_mut!(ui.widgets[0], PagerContainer);
+            let container = get_widget_mut!(pager.widgets[2], Container);
+            let orderview = get_widget_mut!(container.widgets[1], OrderList);
+
+            for action in orderview.take_actions() {
+                match action {
+                    PatternAction::New => {
+                        let tracks = locked_daw.project.patterns[locked_daw.current_pattern()].rows[0].len();
+                        locked_daw.add_pattern(Pattern::new(tracks as u8, 64));
+                    },
+                    PatternAction::Clone(index) => { locked_daw.clone_pattern(index); },
+                    PatternAction::Delete(index) => locked_daw.remove_pattern(index),
+                    PatternAction::Rename(index, name) => locked_daw.rename_pattern(index, name),
+                    PatternAction::Resize(index, rows) => locked_daw.resize_pattern(index, rows),
+                    PatternAction::SetTracks(index, tracks) => locked_daw.set_pattern_tracks(index, tracks),
+                    PatternAction::Orders(orders) => locked_daw.set_orders(&orders),
+                    PatternAction::Edit(index) => {
+                        locked_daw.select_pattern(index);
+                        pager.current_widget = 1;
+                        fill_region(&ui_channel, Position { x: 0, y: 2 }, Position { x: WIDTH/8, y: HEIGHT/8 }, MAIN_COLOR);
+                    },
+                }
+            }
+
+            let container = get_widget_mut!(pager.widgets[2], Container);
+            let orderview = get_widget_mut!(container.widgets[1], OrderList);
+            orderview.orders = locked_daw.orders();
+            orderview.patterns = locked_daw.project.patterns.iter()
+                .map(|pattern| PatternInfo { name: pattern.name.clone(), rows: pattern.rows.len(), tracks: pattern.rows[0].len() })
+                .collect();
+            orderview.current_pattern = locked_daw.current_pattern();
+        }
+
+        // Playlist
+        {
+            let pager = get_widget_mut!(ui.widgets[0], PagerContainer);
+            let container = get_widget_mut!(pager.widgets[3], Container);
+            let playlistview = get_widget_mut!(container.widgets[1], PlaylistView);
+
+            for action in playlistview.take_actions() {
+                match action {
+                    PlaylistAction::Add(clip) => locked_daw.add_clip(clip),
+                    PlaylistAction::Remove(index) => locked_daw.remove_clip(index),
+                    PlaylistAction::Replace(index, clip) => locked_daw.replace_clip(index, clip),
+                    PlaylistAction::Split(index, at) => locked_daw.split_clip(index, at),
+                    PlaylistAction::SetLoop(loop_range) => locked_daw.set_loop(loop_range),
+                    PlaylistAction::Seek(position) => locked_daw.playlist_seek(position),
+                }
+            }
+
+            playlistview.set_clips(locked_daw.project.playlist.clips.clone());
+            playlistview.patterns = (0..locked_daw.project.patterns.len())
+                .map(|index| (locked_daw.project.patterns[index].name.clone(), locked_daw.pattern_length(index)))
+                .collect();
+            playlistview.position = locked_daw.state.playlist.position;
+            playlistview.ppq = locked_daw.project.ppq;
+            playlistview.loop_range = locked_daw.project.playlist.loop_range;
+        }
+
+        // Instruments
+        {
+            let pager = get_widget_mut!(ui.widgets[0], PagerContainer);
+            let container = get_widget_mut!(pager.widgets[5], Container);
+            let editor = get_widget_mut!(container.widgets[1], InstrumentEditor);
+            let mut actions = editor.take_actions();
+
+            let container = get_widget_mut!(pager.widgets[4], Container);
+            let list = get_widget_mut!(container.widgets[1], InstrumentList);
+            actions.append(&mut list.take_actions());
+
+            for action in actions {
+                match action {
+                    InstrumentAction::New => {
+                        let number = locked_daw.project.instruments.len() + 1;
+                        if let Some(index) = locked_daw.add_instrument(Instrument::new(format!("Instrument {number}"), InstrumentKind::Sampler)) {
+                            list.current = index;
+                        }
+                    },
+                    InstrumentAction::Clone(index) => {
+                        if let Some(index) = locked_daw.clone_instrument(index) {
+                            list.current = index;
+                        }
+                    },
+                    InstrumentAction::Remove(index) => locked_daw.remove_instrument(index),
+                    InstrumentAction::Set(index, instrument) => locked_daw.set_instrument(index, instrument),
+                    InstrumentAction::Edit(index) => {
+                        list.current = index;
+                        pager.current_widget = 5;
+                        fill_region(&ui_channel, Position { x: 0, y: 2 }, Position { x: WIDTH/8, y: HEIGHT/8 }, MAIN_COLOR);
+                    },
+                }
+            }
+
+            let container = get_widget_mut!(pager.widgets[4], Container);
+            let list = get_widget_mut!(container.widgets[1], InstrumentList);
+            list.instruments = locked_daw.project.instruments.clone();
+            let current = list.current.min(list.instruments.len().saturating_sub(1));
+
+            // The selected instrument is what live notes go to and what new notes get
+            locked_daw.live_instrument = current;
+
+            let container = get_widget_mut!(pager.widgets[5], Container);
+            let editor = get_widget_mut!(container.widgets[1], InstrumentEditor);
+            if !editor.typing() || editor.index != current {
+                editor.instrument = locked_daw.project.instruments.get(current).cloned();
+            }
+            editor.index = current;
+            if let Ok(plugins) = plugins_rx.try_recv() {
+                editor.plugins = plugins;
+            }
+            (editor.midi_ports, editor.midi_port) = locked_daw.midi_out_ports(current);
+
+            let container = get_widget_mut!(pager.widgets[1], Container);
+            let patview = get_widget_mut!(container.widgets[1], PatternEditor);
+            patview.instrument = if locked_daw.project.instruments.is_empty() { 0 } else { current as u8 + 1 };
+        }
+
+        // Samples
+        {
+            let pager = get_widget_mut!(ui.widgets[0], PagerContainer);
+            let container = get_widget_mut!(pager.widgets[6], Container);
+            let editor = get_widget_mut!(container.widgets[1], SampleEditor);
+
+            for action in editor.take_actions() {
+                match action {
+                    SampleAction::Add(sample) => { locked_daw.add_sample(sample); },
+                    SampleAction::Remove(index) => locked_daw.remove_sample(index),
+                    SampleAction::Set(index, sample) => locked_daw.set_sample(index, sample),
+                }
+            }
+
+            editor.names = locked_daw.project.samples.iter().map(|sample| (!sample.is_empty()).then(|| sample.name.clone())).collect();
+            editor.update_sample(locked_daw.history.changes(), |index| locked_daw.project.samples.get(index).map(|sample| (**sample).clone()));
+        }
+
         // Pattern Editor
         {
             let pager = get_widget_mut!(ui.widgets[0], PagerContainer);
             let container = get_widget_mut!(pager.widgets[1], Container);
             let mut patview = get_widget_mut!(container.widgets[1], PatternEditor);
 
-            if patview.changed() {
-                locked_daw.project.patterns[0] = patview.pattern.as_ref().unwrap().clone();
+            // Edits to a pattern that's gone since are dropped, they'd refer to the wrong one
+            let edits = patview.take_edits();
+            let exists = patview.pattern_index < locked_daw.project.patterns.len();
+            // Recorded since the last frame, which the editor's copy doesn't have yet
+            let recorded = locked_daw.take_recorded();
+            for edit in &recorded {
+                patview.merge(edit);
+            }
+            if patview.changed() && exists {
+                locked_daw.project.patterns[patview.pattern_index] = patview.pattern.as_ref().unwrap().clone();
+                locked_daw.fit_pattern_states();
+            }
+            if exists {
+                for (edit, typing) in edits {
+                    locked_daw.history.push(edit, typing);
+                }
+            }
+            // A take is one undo step, as long as notes keep coming in
+            for edit in recorded {
+                locked_daw.history.push(edit, true);
             }
 
-            patview.pattern = Some(locked_daw.project.patterns[0].clone());
-            patview.state = Some(locked_daw.state.patterns[0].clone());
+            // Switched to another pattern, or this one changed size
+            let current = locked_daw.current_pattern();
+            let pattern = locked_daw.project.patterns[current].clone();
+            let reshaped = patview.pattern.as_ref().map_or(true, |old| old.rows.len() != pattern.rows.len() || old.rows[0].len() != pattern.rows[0].len());
+            if patview.pattern_index != current || reshaped {
+                patview.pattern_index = current;
+                patview.reload(pattern);
+            } else {
+                patview.pattern = Some(pattern);
+            }
+            patview.state = Some(locked_daw.state.patterns[current].clone());
+            locked_daw.record_track = patview.current_track();
 
             /* if !locked_daw.state.playing {
                 patview.state.as_mut().unwrap().playing = false;
@@ -322,13 +657,21 @@ fn main() {
         {
             let clock = get_widget_mut!(ui.widgets[2], Clock);
 
-            clock.playing = locked_daw.state.patterns[0].playing;
-            clock.ticks = locked_daw.state.patterns[0].position;
+            clock.playing = locked_daw.state.playing && (locked_daw.song_mode() || locked_daw.state.patterns[locked_daw.current_pattern()].playing);
+            clock.ticks = locked_daw.transport_position();
             clock.ppq = locked_daw.project.ppq;
         }
+        let mut builds = locked_daw.take_instrument_builds();
         // We updated the widgets with necessary data, unlock the mutex
         mem::drop(locked_daw);
 
+        // Plugins are loaded while the audio thread keeps playing, then swapped in
+        if builds.needs_install() {
+            builds.build();
+            daw.lock().expect("UI thread: Unable to lock mutex on DAWEngine").install_instruments(&mut builds);
+        }
+        mem::drop(builds);
+
         // Global event handler
         loop {
             match events_rx.try_recv() {
@@ -358,11 +701,26 @@ fn main() {
                             match key {
                                 Keycode::F1 => pager.current_widget = 0,
                                 Keycode::F2 => pager.current_widget = 1,
+                                Keycode::F3 => pager.current_widget = 6,
+                                Keycode::F4 => pager.current_widget = if pager.current_widget == 4 { 5 } else { 4 },
+                                Keycode::F11 => pager.current_widget = 2,
+                                Keycode::F12 => pager.current_widget = 3,
                                 // Keycode::Fx => ...
                                 _ => {}
                             }
 
                             match key {
+                                Keycode::LCtrl | Keycode::RCtrl => ctrl_held = true,
+                                Keycode::Z | Keycode::Y if ctrl_held => {
+                                    let mut locked_daw = daw.lock().expect("UI thread: Unable to lock mutex on DAWEngine");
+                                    let done = if key == Keycode::Z { locked_daw.undo() } else { locked_daw.redo() };
+                                    if done {
+                                        let container = get_widget_mut!(pager.widgets[1], Container);
+                                        let patview = get_widget_mut!(container.widgets[1], PatternEditor);
+                                        let current = locked_daw.current_pattern();
+                                        patview.reload(locked_daw.project.patterns[current].clone());
+                                    }
+                                },
                                 Keycode::Escape => {
                                     let menu = get_widget_mut!(ui.widgets[1], Menu);
                                     menu.visible = !menu.visible;
@@ -377,6 +735,7 @@ fn main() {
                                 _ => {}
                             }
                         },
+                        ui::events::Event::KeyUp(Keycode::LCtrl | Keycode::RCtrl) => ctrl_held = false,
                         _ => {}
                     }
                 },
@@ -409,8 +768,45 @@ fn main() {
                 state.playing = false;
                 state.position = 0;
             }
+            locked_daw.state.playlist.position = 0;
             menu.close();
         });
+        handle_menu!(menu.pages[init::MENU_PLAYBACK][3], { // Main -> Playback -> Record
+            let mut locked_daw = daw.lock().expect("UI thread: Unable to lock mutex on DAWEngine");
+            locked_daw.recording = !locked_daw.recording;
+            menu.pages[init::MENU_PLAYBACK][3].label = if locked_daw.recording { "Record (on)" } else { "Record" }.to_string();
+            menu.close();
+        });
+        handle_menu!(menu.pages[init::MENU_PLAYBACK][5], { // Main -> Playback -> Mode
+            let mut locked_daw = daw.lock().expect("UI thread: Unable to lock mutex on DAWEngine");
+            let song_mode = !locked_daw.song_mode();
+            locked_daw.switch_song_mode(song_mode);
+            menu.pages[init::MENU_PLAYBACK][5].label = if song_mode { "Mode: Song" } else { "Mode: Pattern" }.to_string();
+        });
+        handle_menu!(menu.pages[init::MENU_PLAYBACK][4], { // Main -> Playback -> Sync
+            // Internal -> Master -> Master + MTC -> Slave
+            let mut locked_daw = daw.lock().expect("UI thread: Unable to lock mutex on DAWEngine");
+            let (mode, send_mtc) = match (locked_daw.sync.mode, locked_daw.sync.send_mtc) {
+                (SyncMode::Internal, _) => (SyncMode::Master, false),
+                (SyncMode::Master, false) => (SyncMode::Master, true),
+                (SyncMode::Master, true) => (SyncMode::Slave, false),
+                (SyncMode::Slave, _) => (SyncMode::Internal, false),
+            };
+            if mode == SyncMode::Master && locked_daw.sync.mode != SyncMode::Master {
+                // TODO let the user pick the port in the settings
+                if let Err(err) = locked_daw.set_sync_output(None) {
+                    eprintln!("Unable to open MIDI output for sync: {}", err);
+                }
+            }
+            locked_daw.sync.mode = mode;
+            locked_daw.sync.send_mtc = send_mtc;
+            menu.pages[init::MENU_PLAYBACK][4].label = match (mode, send_mtc) {
+                (SyncMode::Internal, _) => "Sync: Internal",
+                (SyncMode::Master, false) => "Sync: Master",
+                (SyncMode::Master, true) => "Sync: Master + MTC",
+                (SyncMode::Slave, _) => "Sync: Slave",
+            }.to_string();
+        });
 
         // When menu is closed, let the pager handle events
         if !menu.visible && !ui.widgets[0].handles_events() {
@@ -22,6 +22,7 @@ type Glyph = u64;
 pub enum Command {
     Char(usize, usize, u32, u32, char),
     Text(usize, usize, u32, u32, String),
+    Pixels(usize, usize, usize, Vec<u32>), // x, y and width in pixels, then the rows one after another
     // Rectangle(usize, usize, usize, usize, u32)
 }
 
@@ -151,6 +152,7 @@ impl TextCanvas {
                     match cmd {
                         Command::Char(x, y, fg, bg, char) => self.char(x, y, fg, bg, char),
                         Command::Text(x, y, fg, bg, string) => self.text(x, y, fg, bg, &string),
+                        Command::Pixels(x, y, width, pixels) => self.pixels(x, y, width, &pixels),
                         /* Command::Rectangle(x1, y1, x2, y2, _) => {
 
                         } */
@@ -181,6 +183,23 @@ impl TextCanvas {
         }
     }
 
+    /// Copy raw pixels in, for things that don't fit in 8x8 cells. Anything off the canvas is cut off.
+    pub fn pixels(&mut self, x: usize, y: usize, width: usize, pixels: &[u32]) {
+        if width == 0 || x >= self.width {
+            return
+        }
+        let visible = width.min(self.width - x);
+
+        for (row, line) in pixels.chunks(width).enumerate() {
+            if y + row >= self.height {
+                break
+            }
+            let start = (y + row) * self.width + x;
+            let length = visible.min(line.len());
+            self.buffer[start..start + length].copy_from_slice(&line[..length]);
+        }
+    }
+
     /// Draw text in character space
     pub fn text(&mut self, mut x: usize, mut y: usize, fg: u32, bg: u32, string: &str) {
         let x_initial = x;
@@ -0,0 +1,666 @@
+use std::{sync::mpsc, mem};
+use sdl2::keyboard::Keycode;
+
+use crate::{
+    ui::{Command, events::Event},
+    engine::{
+        instrument::{Instrument, InstrumentKind, Envelope, EnvelopeNode, ENVELOPE_NAMES, ENVELOPE_MAX_NODES},
+        plugins::builtin::sampler::keymap::Zone,
+        samples::MAX_SAMPLES,
+    },
+    any_impl
+};
+
+use super::{Widget, Position, draw_borders_thick, instrument_list::InstrumentAction};
+
+const WIDGET_ID_INSTRUMENTEDITOR: u8 = 11;
+
+const LABEL_WIDTH: usize = 12;
+
+const FIELD_NAME: usize = 0;
+const FIELD_KIND: usize = 1;
+const FIELD_PATH: usize = 2;
+const FIELD_BRIDGED: usize = 3;
+const FIELD_VOLUME: usize = 4;
+const FIELD_CHANNEL: usize = 5;
+const FIELD_PORT: usize = 6;
+const FIELD_ENVELOPE: usize = 7;
+const FIELD_ENABLED: usize = 8;
+// The keymap's zones, Insert and Delete add and remove them
+const FIELD_ZONE: usize = 9;
+const FIELD_SAMPLE: usize = 10;
+const FIELD_KEY_LOW: usize = 11;
+const FIELD_KEY_HIGH: usize = 12;
+const FIELD_VEL_LOW: usize = 13;
+const FIELD_VEL_HIGH: usize = 14;
+const FIELD_GROUP: usize = 15;
+const FIELD_TRANSPOSE: usize = 16;
+const FIELD_COUNT: usize = 17;
+
+const KINDS: [&str; 6] = ["None", "Sampler", "SubSynth", "MIDI Out", "CLAP", "LV2"];
+
+fn kind_index(kind: &InstrumentKind) -> usize {
+    match kind {
+        InstrumentKind::None => 0,
+        InstrumentKind::Sampler => 1,
+        InstrumentKind::SubSynth => 2,
+        InstrumentKind::MidiOut => 3,
+        InstrumentKind::Clap(_) => 4,
+        InstrumentKind::Lv2(_) => 5,
+    }
+}
+
+fn kind_from_index(index: usize, path: String) -> InstrumentKind {
+    match index {
+        1 => InstrumentKind::Sampler,
+        2 => InstrumentKind::SubSynth,
+        3 => InstrumentKind::MidiOut,
+        4 => InstrumentKind::Clap(path),
+        5 => InstrumentKind::Lv2(path),
+        _ => InstrumentKind::None,
+    }
+}
+
+fn key_name(key: u8) -> String {
+    const NAMES: [&str; 12] = ["C-", "C#", "D-", "D#", "E-", "F-", "F#", "G-", "G#", "A-", "A#", "B-"];
+    format!("{}{}", NAMES[key as usize % 12], key / 12)
+}
+
+// Envelope value at any tick, straight lines between the nodes
+fn envelope_value(envelope: &Envelope, tick: u16) -> i8 {
+    let nodes = &envelope.nodes;
+    match nodes.iter().position(|node| node.tick >= tick) {
+        None => nodes.last().map_or(0, |node| node.value),
+        Some(0) => nodes[0].value,
+        Some(index) => {
+            let (a, b) = (nodes[index-1], nodes[index]);
+            let t = (tick - a.tick) as f32 / (b.tick - a.tick).max(1) as f32;
+            (a.value as f32 + (b.value - a.value) as f32 * t).round() as i8
+        },
+    }
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Fields,
+    Envelope,
+}
+
+// The other half of F4, everything about one instrument. Tab switches between the fields and the envelope graph.
+pub struct InstrumentEditor {
+    pub pos1: Position,
+    pub pos2: Position,
+
+    pub index: usize,
+    pub instrument: Option<Instrument>,
+    pub plugins: Vec<(InstrumentKind, String)>, // scanned CLAP and LV2 instruments, picked from in the path field
+    pub midi_ports: Vec<String>, // what a MIDI Out instrument can reach, picked from in the port field
+    pub midi_port: Option<String>, // what it's connected to
+
+    pub text_color: u32,
+    pub outer_bg: u32,
+    pub inner_bg: u32,
+    pub top_rim: u32,
+    pub bottom_rim: u32,
+    pub cursor_color: u32,
+    pub line_color: u32,
+    pub loop_color: u32,
+    pub sustain_color: u32,
+
+    actions: Vec<InstrumentAction>,
+    shift_held: bool,
+    ctrl_held: bool,
+
+    focus: Focus,
+    field: usize,
+    typing: Option<String>, // name or path
+    envelope: usize, // see ENVELOPE_*
+    node: usize,
+    zone: usize,
+}
+
+impl InstrumentEditor {
+    pub fn new(pos1: Position, pos2: Position) -> Self {
+        Self {
+            pos1,
+            pos2,
+
+            index: 0,
+            instrument: None,
+            plugins: Vec::new(),
+            midi_ports: Vec::new(),
+            midi_port: None,
+
+            text_color: 0,
+            outer_bg: 0,
+            inner_bg: 0,
+            top_rim: 0,
+            bottom_rim: 0,
+            cursor_color: 0x7f7f7f,
+            line_color: 0x7fcf7f,
+            loop_color: 0x082838,
+            sustain_color: 0x382808,
+
+            actions: Vec::new(),
+            shift_held: false,
+            ctrl_held: false,
+
+            focus: Focus::Fields,
+            field: FIELD_NAME,
+            typing: None,
+            envelope: 0,
+            node: 0,
+            zone: 0,
+        }
+    }
+
+    pub fn take_actions(&mut self) -> Vec<InstrumentAction> {
+        mem::take(&mut self.actions)
+    }
+
+    // Name or path being typed, the engine's copy shouldn't replace it until it's done
+    pub fn typing(&self) -> bool {
+        self.typing.is_some()
+    }
+
+    // Changes the instrument and sends it off
+    fn change(&mut self, change: impl FnOnce(&mut Instrument)) {
+        let Some(instrument) = &mut self.instrument else {
+            return
+        };
+        change(instrument);
+        self.actions.push(InstrumentAction::Set(self.index, instrument.clone()));
+    }
+
+    // Changes the selected zone of the keymap, if there is one
+    fn change_zone(&mut self, change: impl FnOnce(&mut Zone)) {
+        let zone = self.zone;
+        if self.instrument.as_ref().map_or(true, |instrument| zone >= instrument.keymap.zones.len()) {
+            return
+        }
+        self.change(|instrument| change(&mut instrument.keymap.zones[zone]));
+    }
+
+    fn finish_typing(&mut self) {
+        let Some(text) = self.typing.take() else {
+            return
+        };
+        let field = self.field;
+        self.change(|instrument| {
+            if field == FIELD_NAME {
+                instrument.name = text;
+            } else {
+                instrument.kind = kind_from_index(kind_index(&instrument.kind), text);
+            }
+        });
+    }
+
+    fn field_text(&self, instrument: &Instrument, field: usize) -> String {
+        let typed = |text: &String| if self.field == field { self.typing.as_ref().map(|typed| format!("{typed}_")) } else { None }.unwrap_or(text.clone());
+        let envelope = &instrument.envelopes[self.envelope];
+        let zones = &instrument.keymap.zones;
+        let zone = zones.get(self.zone);
+        let zone_text = |text: fn(&Zone) -> String| zone.map_or("-".to_string(), text);
+        match field {
+            FIELD_NAME => typed(&instrument.name),
+            FIELD_KIND => instrument.kind.name().to_string(),
+            FIELD_PATH => match instrument.kind.path() {
+                Some(path) if self.typing.is_some() && self.field == field => typed(&path.to_string()),
+                Some(path) => match self.plugins.iter().find(|(kind, _)| *kind == instrument.kind) {
+                    Some((_, name)) => format!("{name}  {path}"),
+                    None if path.is_empty() && self.plugins.iter().any(|(kind, _)| kind_index(kind) == kind_index(&instrument.kind)) => "(Left/Right to pick)".to_string(),
+                    None => path.to_string(),
+                },
+                None => "(built-in)".to_string(),
+            },
+            FIELD_BRIDGED => match instrument.kind.path() {
+                Some(_) => if instrument.bridged { "on" } else { "off" }.to_string(),
+                None => "-".to_string(),
+            },
+            FIELD_VOLUME => instrument.volume.to_string(),
+            FIELD_CHANNEL => instrument.midi_channel.map_or("default".to_string(), |channel| (channel + 1).to_string()),
+            FIELD_PORT if instrument.kind != InstrumentKind::MidiOut => "-".to_string(),
+            FIELD_PORT => {
+                let connected = self.midi_port.as_deref().unwrap_or("not connected");
+                match &instrument.midi_port {
+                    Some(port) if self.midi_port.as_ref() == Some(port) => port.clone(),
+                    Some(port) => format!("{port}  ({connected})"),
+                    None => format!("first available  ({connected})"),
+                }
+            },
+            FIELD_ENVELOPE => ENVELOPE_NAMES[self.envelope].to_string(),
+            FIELD_ENABLED => if envelope.enabled { "on" } else { "off" }.to_string(),
+            FIELD_ZONE => format!(
+                "{}{}",
+                if zones.is_empty() { format!("none, plays sample {:0>2}", self.index + 1) } else { format!("{:0>2}/{:0>2}", self.zone + 1, zones.len()) },
+                if instrument.kind == InstrumentKind::Sampler { "" } else { "  (Sampler only)" }
+            ),
+            FIELD_SAMPLE => zone_text(|zone| format!("{:0>2}", zone.sample + 1)),
+            FIELD_KEY_LOW => zone_text(|zone| key_name(zone.key_low)),
+            FIELD_KEY_HIGH => zone_text(|zone| key_name(zone.key_high)),
+            FIELD_VEL_LOW => zone_text(|zone| zone.vel_low.to_string()),
+            FIELD_VEL_HIGH => zone_text(|zone| zone.vel_high.to_string()),
+            FIELD_GROUP => zone_text(|zone| if zone.group == 0 { "none".to_string() } else { zone.group.to_string() }),
+            FIELD_TRANSPOSE => zone_text(|zone| format!("{:+}", zone.transpose)),
+            _ => String::new(),
+        }
+    }
+
+    fn change_field(&mut self, up: bool) {
+        let step = if self.shift_held { 16 } else { 1 };
+        match self.field {
+            FIELD_KIND => self.change(|instrument| {
+                let index = kind_index(&instrument.kind);
+                let index = if up { (index + 1) % KINDS.len() } else { (index + KINDS.len() - 1) % KINDS.len() };
+                let path = instrument.kind.path().unwrap_or("").to_string();
+                instrument.kind = kind_from_index(index, path);
+            }),
+            // Steps through the scanned plugins of the same kind
+            FIELD_PATH => {
+                let Some(instrument) = &self.instrument else {
+                    return
+                };
+                let kind = kind_index(&instrument.kind);
+                let choices: Vec<&InstrumentKind> = self.plugins.iter().map(|(plugin, _)| plugin).filter(|plugin| kind_index(plugin) == kind).collect();
+                if choices.is_empty() {
+                    return
+                }
+                let index = match choices.iter().position(|plugin| **plugin == instrument.kind) {
+                    Some(index) if up => (index + 1) % choices.len(),
+                    Some(index) => (index + choices.len() - 1) % choices.len(),
+                    None => 0,
+                };
+                let picked = choices[index].clone();
+                self.change(|instrument| instrument.kind = picked);
+            },
+            FIELD_BRIDGED => self.change(|instrument| {
+                if instrument.kind.path().is_some() {
+                    instrument.bridged = !instrument.bridged;
+                }
+            }),
+            FIELD_VOLUME => self.change(|instrument| {
+                instrument.volume = if up { (instrument.volume + step).min(127) } else { instrument.volume.saturating_sub(step) };
+            }),
+            FIELD_CHANNEL => self.change(|instrument| {
+                instrument.midi_channel = match (instrument.midi_channel, up) {
+                    (None, true) => Some(0),
+                    (None, false) | (Some(0), false) => None,
+                    (Some(channel), true) => Some((channel + 1).min(15)),
+                    (Some(channel), false) => Some(channel - 1),
+                };
+            }),
+            // Steps through the ports that are plugged in, before the first is the first available
+            FIELD_PORT => {
+                let ports = self.midi_ports.clone();
+                self.change(|instrument| {
+                    if instrument.kind != InstrumentKind::MidiOut {
+                        return
+                    }
+                    let index = instrument.midi_port.as_ref().and_then(|port| ports.iter().position(|name| name == port));
+                    let index = match (index, up) {
+                        (None, true) => Some(0),
+                        (None, false) | (Some(0), false) => None,
+                        (Some(index), true) => Some((index + 1).min(ports.len().saturating_sub(1))),
+                        (Some(index), false) => Some(index - 1),
+                    };
+                    instrument.midi_port = index.and_then(|index| ports.get(index).cloned());
+                });
+            },
+            FIELD_ENVELOPE => {
+                self.envelope = if up { (self.envelope + 1) % ENVELOPE_NAMES.len() } else { (self.envelope + ENVELOPE_NAMES.len() - 1) % ENVELOPE_NAMES.len() };
+                self.node = 0;
+            },
+            FIELD_ENABLED => {
+                let which = self.envelope;
+                self.change(|instrument| instrument.envelopes[which].enabled = !instrument.envelopes[which].enabled);
+            },
+            FIELD_ZONE => {
+                let count = self.instrument.as_ref().map_or(0, |instrument| instrument.keymap.zones.len());
+                self.zone = if up { (self.zone + 1).min(count.saturating_sub(1)) } else { self.zone.saturating_sub(1) };
+            },
+            FIELD_SAMPLE => self.change_zone(|zone| {
+                zone.sample = if up { (zone.sample + step as usize).min(MAX_SAMPLES - 1) } else { zone.sample.saturating_sub(step as usize) };
+            }),
+            // Shift moves keys by an octave. The range stays at least one key or velocity wide.
+            FIELD_KEY_LOW | FIELD_KEY_HIGH | FIELD_VEL_LOW | FIELD_VEL_HIGH => {
+                let field = self.field;
+                let step = if self.shift_held && (field == FIELD_KEY_LOW || field == FIELD_KEY_HIGH) { 12 } else { step };
+                self.change_zone(|zone| {
+                    let (low, high) = if field == FIELD_KEY_LOW || field == FIELD_KEY_HIGH {
+                        (&mut zone.key_low, &mut zone.key_high)
+                    } else {
+                        (&mut zone.vel_low, &mut zone.vel_high)
+                    };
+                    let value = if field == FIELD_KEY_LOW || field == FIELD_VEL_LOW { &mut *low } else { &mut *high };
+                    *value = if up { value.saturating_add(step).min(127) } else { value.saturating_sub(step) };
+                    if *low > *high {
+                        if field == FIELD_KEY_LOW || field == FIELD_VEL_LOW { *high = *low } else { *low = *high }
+                    }
+                });
+            },
+            FIELD_GROUP => self.change_zone(|zone| {
+                zone.group = if up { zone.group.saturating_add(1) } else { zone.group.saturating_sub(1) };
+            }),
+            FIELD_TRANSPOSE => {
+                let step = if self.shift_held { 12 } else { 1 };
+                self.change_zone(|zone| {
+                    zone.transpose = if up { zone.transpose.saturating_add(step) } else { zone.transpose.saturating_sub(step) };
+                });
+            },
+            _ => {}
+        }
+    }
+
+    fn handle_fields_key(&mut self, key: Keycode) {
+        if self.typing.is_some() {
+            match key {
+                Keycode::Backspace => { self.typing.as_mut().unwrap().pop(); },
+                Keycode::Return | Keycode::Up | Keycode::Down => self.finish_typing(),
+                Keycode::Escape => self.typing = None,
+                _ => {}
+            }
+            if key != Keycode::Up && key != Keycode::Down {
+                return
+            }
+        }
+
+        match key {
+            Keycode::Up => self.field = self.field.saturating_sub(1),
+            Keycode::Down => self.field = (self.field + 1).min(FIELD_COUNT - 1),
+            Keycode::Equals | Keycode::KpPlus | Keycode::Right => self.change_field(true),
+            Keycode::Minus | Keycode::KpMinus | Keycode::Left => self.change_field(false),
+            Keycode::Space if self.field == FIELD_ENABLED || self.field == FIELD_BRIDGED => self.change_field(true),
+            // The new zone starts out like the selected one, or covering everything with the instrument's sample
+            Keycode::Insert if self.field >= FIELD_ZONE => {
+                let (zone, sample) = (self.zone, self.index);
+                let mut added = 0;
+                self.change(|instrument| {
+                    let keymap = &mut instrument.keymap;
+                    let copy = keymap.zones.get(zone).cloned();
+                    added = keymap.add_zone(sample);
+                    if let Some(copy) = copy {
+                        keymap.zones[added] = copy;
+                    }
+                });
+                self.zone = added;
+            },
+            Keycode::Delete if self.field >= FIELD_ZONE => {
+                let zone = self.zone;
+                self.change(|instrument| instrument.keymap.remove_zone(zone));
+                self.zone = self.zone.saturating_sub(1);
+            },
+            Keycode::Backspace => {
+                let Some(instrument) = &self.instrument else {
+                    return
+                };
+                let mut text = match self.field {
+                    FIELD_NAME => instrument.name.clone(),
+                    FIELD_PATH if instrument.kind.path().is_some() => instrument.kind.path().unwrap().to_string(),
+                    _ => return,
+                };
+                text.pop();
+                self.typing = Some(text);
+            },
+            _ => {}
+        }
+    }
+
+    fn handle_envelope_key(&mut self, key: Keycode) {
+        let which = self.envelope;
+        let node = self.node;
+        let (min, max) = Envelope::range(which);
+        let Some(nodes) = self.instrument.as_ref().map(|instrument| instrument.envelopes[which].nodes.clone()) else {
+            return
+        };
+        let count = nodes.len();
+
+        match key {
+            Keycode::Left | Keycode::Right if self.shift_held || self.ctrl_held => {
+                // Move the node, between its neighbours. The first one stays at 0.
+                if node == 0 {
+                    return
+                }
+                let step: i32 = if self.ctrl_held { 10 } else { 1 };
+                let step = if key == Keycode::Left { -step } else { step };
+                self.change(|instrument| {
+                    let nodes = &mut instrument.envelopes[which].nodes;
+                    let low = nodes[node-1].tick as i32 + 1;
+                    let high = nodes.get(node+1).map_or(u16::MAX as i32, |next| next.tick as i32 - 1);
+                    nodes[node].tick = (nodes[node].tick as i32 + step).clamp(low, high) as u16;
+                });
+            },
+            Keycode::Left => self.node = self.node.saturating_sub(1),
+            Keycode::Right => self.node = (self.node + 1).min(count.saturating_sub(1)),
+            Keycode::Up | Keycode::Down | Keycode::PageUp | Keycode::PageDown => {
+                let step: i16 = if matches!(key, Keycode::PageUp | Keycode::PageDown) { 8 } else { 1 };
+                let step = if matches!(key, Keycode::Down | Keycode::PageDown) { -step } else { step };
+                self.change(|instrument| {
+                    let value = &mut instrument.envelopes[which].nodes[node].value;
+                    *value = (*value as i16 + step).clamp(min as i16, max as i16) as i8;
+                });
+            },
+            Keycode::Insert => {
+                // Halfway to the next one, or a bit past the last
+                let tick = match nodes.get(node+1) {
+                    Some(next) => (nodes[node].tick + next.tick) / 2,
+                    None => nodes[node].tick.saturating_add(10),
+                };
+                if count >= ENVELOPE_MAX_NODES || tick == nodes[node].tick {
+                    return
+                }
+                self.change(|instrument| {
+                    let envelope = &mut instrument.envelopes[which];
+                    let value = envelope_value(envelope, tick);
+                    envelope.nodes.insert(node+1, EnvelopeNode { tick, value });
+
+                    let shift = |range: &mut Option<(usize, usize)>| if let Some((start, end)) = range {
+                        if *start > node { *start += 1; }
+                        if *end > node { *end += 1; }
+                    };
+                    shift(&mut envelope.loop_range);
+                    shift(&mut envelope.sustain);
+                });
+                self.node += 1;
+            },
+            Keycode::Delete => {
+                if node == 0 || count <= 2 {
+                    return
+                }
+                self.change(|instrument| {
+                    let envelope = &mut instrument.envelopes[which];
+                    envelope.nodes.remove(node);
+
+                    // A range can be a single node, like B and E make it, which goes away with that node
+                    let shift = |range: &mut Option<(usize, usize)>| if let Some((start, end)) = *range {
+                        let fix = |index: usize| if index >= node { index.saturating_sub(1) } else { index };
+                        *range = Some((fix(start), fix(end))).filter(|_| start != node || end != node);
+                    };
+                    shift(&mut envelope.loop_range);
+                    shift(&mut envelope.sustain);
+                });
+                self.node -= 1;
+            },
+
+            // Loop points, Shift for the sustain loop
+            Keycode::B | Keycode::E => {
+                let sustain = self.shift_held;
+                self.change(|instrument| {
+                    let envelope = &mut instrument.envelopes[which];
+                    let range = if sustain { &mut envelope.sustain } else { &mut envelope.loop_range };
+                    let (start, end) = range.unwrap_or((0, envelope.nodes.len() - 1));
+                    *range = Some(if key == Keycode::B { (node, end.max(node)) } else { (start.min(node), node) });
+                });
+            },
+            Keycode::L => {
+                let sustain = self.shift_held;
+                self.change(|instrument| {
+                    let envelope = &mut instrument.envelopes[which];
+                    if sustain { envelope.sustain = None; } else { envelope.loop_range = None; }
+                });
+            },
+            Keycode::Space => self.change(|instrument| instrument.envelopes[which].enabled = !instrument.envelopes[which].enabled),
+            _ => {}
+        }
+    }
+
+    #[allow(unused_must_use)]
+    fn draw_envelope(&self, canvas_channel: &mpsc::Sender<Command>, envelope: &Envelope, pos1: Position, pos2: Position) {
+        let width = pos2.x - pos1.x;
+        let height = pos2.y - pos1.y;
+        let (min, max) = Envelope::range(self.envelope);
+        let last_tick = envelope.nodes.last().map_or(0, |node| node.tick) as usize;
+        let scale = (last_tick + width) / width; // ticks per column, enough to fit every node
+        let row_of = |value: i8| (max as i32 - value as i32) as usize * (height - 1) / (max as i32 - min as i32) as usize;
+        let column_of = |tick: u16| tick as usize / scale;
+
+        let in_range = |range: Option<(usize, usize)>, x: usize| range.map_or(false, |(start, end)| {
+            (column_of(envelope.nodes[start].tick)..=column_of(envelope.nodes[end].tick)).contains(&x)
+        });
+
+        for y in 0..height {
+            for x in 0..width {
+                let tick = x * scale;
+                let mut char = ' ';
+                let mut fg = self.line_color;
+                let mut bg = if in_range(envelope.sustain, x) {
+                    self.sustain_color
+                } else if in_range(envelope.loop_range, x) {
+                    self.loop_color
+                } else {
+                    self.inner_bg
+                };
+
+                if tick <= last_tick {
+                    if row_of(envelope_value(envelope, tick as u16)) == y {
+                        char = if envelope.enabled { '*' } else { '.' };
+                    } else if min < 0 && row_of(0) == y {
+                        char = '-';
+                        fg = self.text_color;
+                    }
+                }
+                if let Some(index) = envelope.nodes.iter().position(|node| column_of(node.tick) == x && row_of(node.value) == y) {
+                    char = 'o';
+                    if index == self.node && self.focus == Focus::Envelope {
+                        bg = self.cursor_color;
+                    }
+                }
+                canvas_channel.send(Command::Char(pos1.x + x, pos1.y + y, fg, bg, char));
+            }
+        }
+    }
+}
+
+#[allow(unused_must_use)]
+impl Widget for InstrumentEditor {
+    fn type_id(&self) -> u8 {
+        WIDGET_ID_INSTRUMENTEDITOR
+    }
+
+    fn draw(&mut self, canvas_channel: &mpsc::Sender<Command>) {
+        let width = self.pos2.x - self.pos1.x;
+        let fields_end = Position { x: self.pos2.x, y: self.pos1.y + FIELD_COUNT };
+        let graph_pos1 = Position { x: self.pos1.x, y: fields_end.y + 4 };
+
+        draw_borders_thick(canvas_channel, self.pos1, fields_end, self.top_rim, self.bottom_rim, self.outer_bg);
+        draw_borders_thick(canvas_channel, graph_pos1, self.pos2, self.top_rim, self.bottom_rim, self.outer_bg);
+
+        let Some(instrument) = self.instrument.clone() else {
+            canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y-2, self.text_color, self.outer_bg, format!("{:<width$}", "No instrument")));
+            return
+        };
+        canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y-2, self.text_color, self.outer_bg,
+            format!("{:<width$}", format!("Instrument {:0>2}", self.index + 1))));
+
+        const LABELS: [&str; FIELD_COUNT] = [
+            "Name", "Type", "Path", "Bridged", "Volume", "MIDI Channel", "MIDI Port", "Envelope", "Enabled",
+            "Zone", "Sample", "Lowest key", "Highest key", "Lowest vel", "Highest vel", "RR group", "Transpose",
+        ];
+        self.zone = self.zone.min(instrument.keymap.zones.len().saturating_sub(1));
+        for (field, label) in LABELS.iter().enumerate() {
+            let y = self.pos1.y + field;
+            let bg = if field == self.field && self.focus == Focus::Fields { self.cursor_color } else { self.inner_bg };
+            let text: String = self.field_text(&instrument, field).chars().take(width - LABEL_WIDTH - 1).collect();
+            canvas_channel.send(Command::Text(self.pos1.x, y, self.text_color, self.inner_bg, format!("{:<LABEL_WIDTH$} ", label)));
+            canvas_channel.send(Command::Text(self.pos1.x + LABEL_WIDTH + 1, y, self.text_color, bg, format!("{:<w$}", text, w = width - LABEL_WIDTH - 1)));
+        }
+
+        // Envelope graph
+        let envelope = &instrument.envelopes[self.envelope];
+        self.node = self.node.min(envelope.nodes.len().saturating_sub(1));
+        let node = envelope.nodes[self.node];
+        let range = |range: Option<(usize, usize)>| range.map_or("off".to_string(), |(start, end)| format!("{:0>2}-{:0>2}", start + 1, end + 1));
+        canvas_channel.send(Command::Text(graph_pos1.x, graph_pos1.y-2, self.text_color, self.outer_bg, format!(
+            "{:<w$}",
+            format!("{} envelope  Node {:0>2}/{:0>2}  Tick {:<5}  Value {:<3}  Loop {}  Sustain {}{}",
+                ENVELOPE_NAMES[self.envelope], self.node + 1, envelope.nodes.len(), node.tick, node.value, range(envelope.loop_range), range(envelope.sustain),
+                if instrument.kind == InstrumentKind::Sampler { "" } else { "  (Sampler only)" }),
+            w = width
+        )));
+        self.draw_envelope(canvas_channel, envelope, graph_pos1, self.pos2);
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::KeyDown(key) | Event::KeyRepeat(key) => {
+                match key {
+                    Keycode::LShift | Keycode::RShift => self.shift_held = true,
+                    Keycode::LCtrl | Keycode::RCtrl => self.ctrl_held = true,
+                    Keycode::Tab => {
+                        self.finish_typing();
+                        self.focus = if self.focus == Focus::Fields { Focus::Envelope } else { Focus::Fields };
+                    },
+                    _ if self.focus == Focus::Fields => self.handle_fields_key(key),
+                    _ => self.handle_envelope_key(key),
+                }
+            },
+            Event::KeyUp(key) => {
+                match key {
+                    Keycode::LShift | Keycode::RShift => self.shift_held = false,
+                    Keycode::LCtrl | Keycode::RCtrl => self.ctrl_held = false,
+                    _ => {}
+                }
+            },
+            Event::TextInput(text) => {
+                if self.focus != Focus::Fields || self.ctrl_held {
+                    return
+                }
+                let Some(instrument) = &self.instrument else {
+                    return
+                };
+                let current = match self.field {
+                    FIELD_NAME => instrument.name.clone(),
+                    FIELD_PATH if instrument.kind.path().is_some() => instrument.kind.path().unwrap().to_string(),
+                    _ => return,
+                };
+                self.typing.get_or_insert(current).push_str(&text);
+            },
+            _ => {}
+        }
+    }
+
+    fn clicked(&mut self) -> bool {
+        false
+    }
+
+    any_impl!{}
+
+    fn set_visiblity(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn visible(&self) -> bool {
+        true
+    }
+
+    fn changed(&mut self) -> bool {
+        !self.actions.is_empty()
+    }
+
+    fn set_handles_events(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn handles_events(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,268 @@
+use std::{sync::mpsc, mem};
+use sdl2::keyboard::Keycode;
+
+use crate::{ui::{Command, events::Event}, engine::instrument::{Instrument, InstrumentKind, MAX_INSTRUMENTS}, any_impl};
+
+use super::{Widget, Position, draw_borders_thick};
+
+const WIDGET_ID_INSTRUMENTLIST: u8 = 10;
+
+const NAME_WIDTH: usize = 24;
+
+const COLUMN_NAME: u8 = 0;
+const COLUMN_KIND: u8 = 1;
+const COLUMN_VOLUME: u8 = 2;
+const COLUMN_CHANNEL: u8 = 3;
+
+// Ones that can be picked without a path, the rest are set up in the instrument editor
+const SIMPLE_KINDS: [InstrumentKind; 4] = [InstrumentKind::None, InstrumentKind::Sampler, InstrumentKind::SubSynth, InstrumentKind::MidiOut];
+
+// What the user asked for, done by whoever owns the engine. See InstrumentList::take_actions().
+pub enum InstrumentAction {
+    New,
+    Clone(usize),
+    Remove(usize),
+    Set(usize, Instrument),
+    Edit(usize), // open in the instrument editor
+}
+
+// The F4 page, IT's instrument list. The one under the cursor is what new notes get.
+pub struct InstrumentList {
+    pub pos1: Position,
+    pub pos2: Position,
+
+    pub instruments: Vec<Instrument>,
+    pub current: usize,
+
+    pub text_color: u32,
+    pub outer_bg: u32,
+    pub inner_bg: u32,
+    pub top_rim: u32,
+    pub bottom_rim: u32,
+    pub cursor_color: u32,
+    pub highlight_color: u32,
+
+    actions: Vec<InstrumentAction>,
+    shift_held: bool,
+
+    column: u8,
+    scroll: usize,
+    renaming: Option<String>,
+}
+
+impl InstrumentList {
+    pub fn new(pos1: Position, pos2: Position) -> Self {
+        Self {
+            pos1,
+            pos2,
+
+            instruments: Vec::new(),
+            current: 0,
+
+            text_color: 0,
+            outer_bg: 0,
+            inner_bg: 0,
+            top_rim: 0,
+            bottom_rim: 0,
+            cursor_color: 0x7f7f7f,
+            highlight_color: 0x3f3f3f,
+
+            actions: Vec::new(),
+            shift_held: false,
+
+            column: COLUMN_NAME,
+            scroll: 0,
+            renaming: None,
+        }
+    }
+
+    pub fn take_actions(&mut self) -> Vec<InstrumentAction> {
+        mem::take(&mut self.actions)
+    }
+
+    fn finish_rename(&mut self) {
+        let Some(name) = self.renaming.take() else {
+            return
+        };
+        if let Some(instrument) = self.instruments.get(self.current) {
+            let mut instrument = instrument.clone();
+            instrument.name = name;
+            self.actions.push(InstrumentAction::Set(self.current, instrument));
+        }
+    }
+
+    fn change(&mut self, up: bool) {
+        let Some(instrument) = self.instruments.get(self.current) else {
+            return
+        };
+        let mut instrument = instrument.clone();
+        let step = if self.shift_held { 16 } else { 1 };
+
+        match self.column {
+            COLUMN_KIND => {
+                let index = SIMPLE_KINDS.iter().position(|kind| *kind == instrument.kind).unwrap_or(0);
+                let index = if up { (index + 1) % SIMPLE_KINDS.len() } else { (index + SIMPLE_KINDS.len() - 1) % SIMPLE_KINDS.len() };
+                instrument.kind = SIMPLE_KINDS[index].clone();
+            },
+            COLUMN_VOLUME => {
+                instrument.volume = if up { (instrument.volume + step).min(127) } else { instrument.volume.saturating_sub(step) };
+            },
+            COLUMN_CHANNEL => {
+                // Below 1 is the plugin's own channel
+                instrument.midi_channel = match (instrument.midi_channel, up) {
+                    (None, true) => Some(0),
+                    (None, false) => None,
+                    (Some(0), false) => None,
+                    (Some(channel), true) => Some((channel + 1).min(15)),
+                    (Some(channel), false) => Some(channel - 1),
+                };
+            },
+            _ => return,
+        }
+        self.actions.push(InstrumentAction::Set(self.current, instrument));
+    }
+}
+
+#[allow(unused_must_use)]
+impl Widget for InstrumentList {
+    fn type_id(&self) -> u8 {
+        WIDGET_ID_INSTRUMENTLIST
+    }
+
+    fn draw(&mut self, canvas_channel: &mpsc::Sender<Command>) {
+        let height = self.pos2.y - self.pos1.y;
+        let width = self.pos2.x - self.pos1.x;
+
+        self.current = self.current.min(self.instruments.len().saturating_sub(1));
+        if self.current < self.scroll {
+            self.scroll = self.current;
+        } else if self.current >= self.scroll + height {
+            self.scroll = self.current + 1 - height;
+        }
+
+        draw_borders_thick(canvas_channel, self.pos1, self.pos2, self.top_rim, self.bottom_rim, self.outer_bg);
+        canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y-2, self.text_color, self.outer_bg,
+            format!("{:<3} {:<NAME_WIDTH$} {:<8} {:>3} {:>3}  ({}/{})", "No", "Name", "Type", "Vol", "Ch", self.instruments.len(), MAX_INSTRUMENTS)));
+
+        for line in 0..height {
+            let index = self.scroll + line;
+            let y = self.pos1.y + line;
+            let Some(instrument) = self.instruments.get(index) else {
+                let text = if index == 0 { format!("{:<width$}", "No instruments, press Insert to add one") } else { " ".repeat(width) };
+                canvas_channel.send(Command::Text(self.pos1.x, y, self.text_color, self.inner_bg, text));
+                continue
+            };
+
+            // 1-based, like the pattern editor's instrument column
+            let name = match &self.renaming {
+                Some(name) if index == self.current => format!("{name}_"),
+                _ => instrument.name.clone(),
+            };
+            let name: String = name.chars().take(NAME_WIDTH).collect();
+            let channel = instrument.midi_channel.map_or("--".to_string(), |channel| (channel + 1).to_string());
+
+            let row_bg = if index == self.current { self.highlight_color } else { self.inner_bg };
+            let bg = |column: u8| if index == self.current && self.column == column { self.cursor_color } else { row_bg };
+
+            canvas_channel.send(Command::Text(self.pos1.x, y, self.text_color, row_bg, format!("{:0>2}  ", index + 1)));
+            canvas_channel.send(Command::Text(self.pos1.x+4, y, self.text_color, bg(COLUMN_NAME), format!("{:<NAME_WIDTH$}", name)));
+            canvas_channel.send(Command::Text(self.pos1.x+5+NAME_WIDTH, y, self.text_color, bg(COLUMN_KIND), format!("{:<8}", instrument.kind.name())));
+            canvas_channel.send(Command::Text(self.pos1.x+14+NAME_WIDTH, y, self.text_color, bg(COLUMN_VOLUME), format!("{:>3}", instrument.volume)));
+            canvas_channel.send(Command::Text(self.pos1.x+18+NAME_WIDTH, y, self.text_color, bg(COLUMN_CHANNEL), format!("{:>3}", channel)));
+            let used = 21 + NAME_WIDTH;
+            if width > used {
+                let path: String = instrument.kind.path().unwrap_or("").chars().take(width - used - 1).collect();
+                canvas_channel.send(Command::Text(self.pos1.x+used, y, self.text_color, row_bg, format!(" {:<w$}", path, w = width - used - 1)));
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::KeyDown(key) | Event::KeyRepeat(key) => {
+                if self.renaming.is_some() {
+                    match key {
+                        Keycode::Backspace => { self.renaming.as_mut().unwrap().pop(); },
+                        Keycode::Return | Keycode::Up | Keycode::Down | Keycode::Tab => self.finish_rename(),
+                        _ => {}
+                    }
+                    if key != Keycode::Up && key != Keycode::Down {
+                        return
+                    }
+                }
+
+                match key {
+                    Keycode::LShift | Keycode::RShift => self.shift_held = true,
+                    Keycode::Up => self.current = self.current.saturating_sub(1),
+                    Keycode::Down => self.current = (self.current + 1).min(self.instruments.len().saturating_sub(1)),
+                    Keycode::Left => self.column = self.column.saturating_sub(1),
+                    Keycode::Right | Keycode::Tab => self.column = (self.column + 1).min(COLUMN_CHANNEL),
+                    Keycode::Insert => {
+                        if self.shift_held && self.current < self.instruments.len() {
+                            self.actions.push(InstrumentAction::Clone(self.current));
+                        } else {
+                            self.actions.push(InstrumentAction::New);
+                        }
+                    },
+                    Keycode::Delete => {
+                        if self.current < self.instruments.len() {
+                            self.actions.push(InstrumentAction::Remove(self.current));
+                        }
+                    },
+                    Keycode::Backspace if self.column == COLUMN_NAME => {
+                        if let Some(instrument) = self.instruments.get(self.current) {
+                            let mut name = instrument.name.clone();
+                            name.pop();
+                            self.renaming = Some(name);
+                        }
+                    },
+                    Keycode::Equals | Keycode::KpPlus => self.change(true),
+                    Keycode::Minus | Keycode::KpMinus => self.change(false),
+                    Keycode::Return => {
+                        if self.current < self.instruments.len() {
+                            self.actions.push(InstrumentAction::Edit(self.current));
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            Event::KeyUp(Keycode::LShift | Keycode::RShift) => self.shift_held = false,
+            Event::TextInput(text) => {
+                if self.column != COLUMN_NAME {
+                    return
+                }
+                let Some(instrument) = self.instruments.get(self.current) else {
+                    return
+                };
+                self.renaming.get_or_insert_with(|| instrument.name.clone()).push_str(&text);
+            },
+            _ => {}
+        }
+    }
+
+    fn clicked(&mut self) -> bool {
+        false
+    }
+
+    any_impl!{}
+
+    fn set_visiblity(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn visible(&self) -> bool {
+        true
+    }
+
+    fn changed(&mut self) -> bool {
+        !self.actions.is_empty()
+    }
+
+    fn set_handles_events(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn handles_events(&self) -> bool {
+        true
+    }
+}
@@ -5,6 +5,11 @@ pub mod eventhook;
 pub mod menu;
 pub mod rulers;
 pub mod clock;
+pub mod order_list;
+pub mod playlist_view;
+pub mod instrument_list;
+pub mod instrument_editor;
+pub mod sample_editor;
 
 use std::{sync::mpsc::{Sender, self}, any::Any};
 
@@ -0,0 +1,368 @@
+use std::{sync::mpsc, mem};
+use sdl2::keyboard::Keycode;
+
+use crate::{ui::{Command, events::Event}, any_impl};
+
+use super::{Widget, Position, draw_borders_thick};
+
+const WIDGET_ID_ORDERLIST: u8 = 8;
+
+const ORDERS_WIDTH: usize = 9; // "000 | 000"
+const NAME_WIDTH: usize = 20;
+const MAX_TRACKS: usize = 64;
+
+const COLUMN_NAME: u8 = 0;
+const COLUMN_ROWS: u8 = 1;
+const COLUMN_TRACKS: u8 = 2;
+
+// What the user asked for, done by whoever owns the engine. See OrderList::take_actions().
+pub enum PatternAction {
+    New,
+    Clone(usize),
+    Delete(usize),
+    Rename(usize, String),
+    Resize(usize, usize), // rows
+    SetTracks(usize, usize),
+    Edit(usize), // open in the pattern editor
+    Orders(Vec<usize>),
+}
+
+pub struct PatternInfo {
+    pub name: String,
+    pub rows: usize,
+    pub tracks: usize,
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Orders,
+    Patterns,
+}
+
+// The F11 page, IT's order list on the left and the patterns on the right
+pub struct OrderList {
+    pub pos1: Position,
+    pub pos2: Position,
+
+    pub orders: Vec<usize>,
+    pub patterns: Vec<PatternInfo>,
+    pub current_pattern: usize, // the one in the pattern editor
+
+    pub text_color: u32,
+    pub outer_bg: u32,
+    pub inner_bg: u32,
+    pub top_rim: u32,
+    pub bottom_rim: u32,
+    pub cursor_color: u32,
+    pub highlight_color: u32,
+
+    actions: Vec<PatternAction>,
+    focus: Focus,
+    shift_held: bool,
+
+    order_cursor: usize, // can be one past the end, to add orders
+    order_scroll: usize,
+    typed: Option<usize>, // pattern number being typed in
+
+    pattern_cursor: usize,
+    pattern_column: u8,
+    pattern_scroll: usize,
+    renaming: Option<String>,
+}
+
+impl OrderList {
+    pub fn new(pos1: Position, pos2: Position) -> Self {
+        Self {
+            pos1,
+            pos2,
+
+            orders: Vec::new(),
+            patterns: Vec::new(),
+            current_pattern: 0,
+
+            text_color: 0,
+            outer_bg: 0,
+            inner_bg: 0,
+            top_rim: 0,
+            bottom_rim: 0,
+            cursor_color: 0x7f7f7f,
+            highlight_color: 0x3f3f3f,
+
+            actions: Vec::new(),
+            focus: Focus::Orders,
+            shift_held: false,
+
+            order_cursor: 0,
+            order_scroll: 0,
+            typed: None,
+
+            pattern_cursor: 0,
+            pattern_column: COLUMN_NAME,
+            pattern_scroll: 0,
+            renaming: None,
+        }
+    }
+
+    pub fn take_actions(&mut self) -> Vec<PatternAction> {
+        mem::take(&mut self.actions)
+    }
+
+    fn set_orders(&mut self, orders: Vec<usize>) {
+        self.orders = orders.clone();
+        self.actions.push(PatternAction::Orders(orders));
+    }
+
+    fn finish_rename(&mut self) {
+        if let Some(name) = self.renaming.take() {
+            self.actions.push(PatternAction::Rename(self.pattern_cursor, name));
+        }
+    }
+
+    fn height(&self) -> usize {
+        self.pos2.y - self.pos1.y
+    }
+
+    fn handle_orders_key(&mut self, key: Keycode) {
+        match key {
+            Keycode::Up => self.order_cursor = self.order_cursor.saturating_sub(1),
+            Keycode::Down => self.order_cursor = (self.order_cursor + 1).min(self.orders.len()),
+            Keycode::Insert => {
+                // A copy of the order under the cursor
+                let mut orders = self.orders.clone();
+                let pattern = orders.get(self.order_cursor).copied().unwrap_or(0);
+                orders.insert(self.order_cursor.min(orders.len()), pattern);
+                self.set_orders(orders);
+            },
+            Keycode::Delete | Keycode::Backspace => {
+                if self.order_cursor < self.orders.len() {
+                    let mut orders = self.orders.clone();
+                    orders.remove(self.order_cursor);
+                    self.set_orders(orders);
+                }
+            },
+            Keycode::Equals | Keycode::KpPlus | Keycode::Minus | Keycode::KpMinus => {
+                if let Some(&pattern) = self.orders.get(self.order_cursor) {
+                    let pattern = if matches!(key, Keycode::Equals | Keycode::KpPlus) {
+                        (pattern + 1).min(self.patterns.len().saturating_sub(1))
+                    } else {
+                        pattern.saturating_sub(1)
+                    };
+                    let mut orders = self.orders.clone();
+                    orders[self.order_cursor] = pattern;
+                    self.set_orders(orders);
+                }
+            },
+            Keycode::Return => {
+                if let Some(&pattern) = self.orders.get(self.order_cursor) {
+                    self.actions.push(PatternAction::Edit(pattern));
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn handle_patterns_key(&mut self, key: Keycode) {
+        if self.renaming.is_some() {
+            match key {
+                Keycode::Backspace => { self.renaming.as_mut().unwrap().pop(); },
+                Keycode::Return | Keycode::Up | Keycode::Down | Keycode::Tab => self.finish_rename(),
+                _ => {}
+            }
+            if key != Keycode::Up && key != Keycode::Down {
+                return
+            }
+        }
+
+        match key {
+            Keycode::Up => self.pattern_cursor = self.pattern_cursor.saturating_sub(1),
+            Keycode::Down => self.pattern_cursor = (self.pattern_cursor + 1).min(self.patterns.len().saturating_sub(1)),
+            Keycode::Left => self.pattern_column = self.pattern_column.saturating_sub(1),
+            Keycode::Right => self.pattern_column = (self.pattern_column + 1).min(COLUMN_TRACKS),
+            Keycode::Insert => {
+                if self.shift_held {
+                    self.actions.push(PatternAction::Clone(self.pattern_cursor));
+                } else {
+                    self.actions.push(PatternAction::New);
+                }
+            },
+            Keycode::Delete => self.actions.push(PatternAction::Delete(self.pattern_cursor)),
+            Keycode::Backspace if self.pattern_column == COLUMN_NAME => {
+                if let Some(info) = self.patterns.get(self.pattern_cursor) {
+                    let mut name = info.name.clone();
+                    name.pop();
+                    self.renaming = Some(name);
+                }
+            },
+            Keycode::Equals | Keycode::KpPlus | Keycode::Minus | Keycode::KpMinus => {
+                let Some(info) = self.patterns.get(self.pattern_cursor) else {
+                    return
+                };
+                let step = if self.shift_held { 16 } else { 1 };
+                let up = matches!(key, Keycode::Equals | Keycode::KpPlus);
+                let change = |value: usize, max: usize| if up { (value + step).min(max) } else { value.saturating_sub(step).max(1) };
+
+                match self.pattern_column {
+                    COLUMN_ROWS => self.actions.push(PatternAction::Resize(self.pattern_cursor, change(info.rows, usize::MAX))),
+                    COLUMN_TRACKS => self.actions.push(PatternAction::SetTracks(self.pattern_cursor, change(info.tracks, MAX_TRACKS))),
+                    _ => {}
+                }
+            },
+            Keycode::Return => self.actions.push(PatternAction::Edit(self.pattern_cursor)),
+            _ => {}
+        }
+    }
+}
+
+#[allow(unused_must_use)]
+impl Widget for OrderList {
+    fn type_id(&self) -> u8 {
+        WIDGET_ID_ORDERLIST
+    }
+
+    fn draw(&mut self, canvas_channel: &mpsc::Sender<Command>) {
+        let height = self.height();
+        let patterns_x = self.pos1.x + ORDERS_WIDTH + 3;
+
+        // Keep the cursors in range, patterns might have been removed
+        self.order_cursor = self.order_cursor.min(self.orders.len());
+        self.pattern_cursor = self.pattern_cursor.min(self.patterns.len().saturating_sub(1));
+        if self.order_cursor < self.order_scroll {
+            self.order_scroll = self.order_cursor;
+        } else if self.order_cursor >= self.order_scroll + height {
+            self.order_scroll = self.order_cursor + 1 - height;
+        }
+        if self.pattern_cursor < self.pattern_scroll {
+            self.pattern_scroll = self.pattern_cursor;
+        } else if self.pattern_cursor >= self.pattern_scroll + height {
+            self.pattern_scroll = self.pattern_cursor + 1 - height;
+        }
+
+        draw_borders_thick(canvas_channel, self.pos1, Position { x: self.pos1.x + ORDERS_WIDTH, y: self.pos2.y }, self.top_rim, self.bottom_rim, self.outer_bg);
+        draw_borders_thick(canvas_channel, Position { x: patterns_x, y: self.pos1.y }, self.pos2, self.top_rim, self.bottom_rim, self.outer_bg);
+        canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y-2, self.text_color, self.outer_bg, "Orders".to_string()));
+        canvas_channel.send(Command::Text(patterns_x, self.pos1.y-2, self.text_color, self.outer_bg,
+            format!("{:<3} {:<NAME_WIDTH$} {:>4} {:>6}", "No", "Name", "Rows", "Tracks")));
+
+        // Orders
+        for line in 0..height {
+            let index = self.order_scroll + line;
+            let text = match self.orders.get(index) {
+                Some(_) if self.typed.is_some() && index == self.order_cursor => format!("{:0>3} | {:0>3}", index, self.typed.unwrap()),
+                Some(pattern) => format!("{:0>3} | {:0>3}", index, pattern),
+                None if index == self.orders.len() => format!("{:0>3} | ---", index),
+                None => " ".repeat(ORDERS_WIDTH),
+            };
+            let bg = if index == self.order_cursor && self.focus == Focus::Orders { self.cursor_color } else { self.inner_bg };
+            canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y+line, self.text_color, bg, text));
+        }
+
+        // Patterns
+        let width = self.pos2.x - patterns_x;
+        for line in 0..height {
+            let index = self.pattern_scroll + line;
+            let Some(info) = self.patterns.get(index) else {
+                canvas_channel.send(Command::Text(patterns_x, self.pos1.y+line, self.text_color, self.inner_bg, " ".repeat(width)));
+                continue
+            };
+
+            let name = match &self.renaming {
+                Some(name) if index == self.pattern_cursor => format!("{name}_"),
+                _ => info.name.clone(),
+            };
+            let name: String = name.chars().take(NAME_WIDTH).collect();
+            let row_bg = if index == self.current_pattern { self.highlight_color } else { self.inner_bg };
+            let bg = |column: u8| if index == self.pattern_cursor && self.focus == Focus::Patterns && self.pattern_column == column { self.cursor_color } else { row_bg };
+
+            canvas_channel.send(Command::Text(patterns_x, self.pos1.y+line, self.text_color, row_bg, format!("{:0>3} ", index)));
+            canvas_channel.send(Command::Text(patterns_x+4, self.pos1.y+line, self.text_color, bg(COLUMN_NAME), format!("{:<NAME_WIDTH$}", name)));
+            canvas_channel.send(Command::Text(patterns_x+5+NAME_WIDTH, self.pos1.y+line, self.text_color, bg(COLUMN_ROWS), format!("{:>4}", info.rows)));
+            canvas_channel.send(Command::Text(patterns_x+10+NAME_WIDTH, self.pos1.y+line, self.text_color, bg(COLUMN_TRACKS), format!("{:>6}", info.tracks)));
+            let used = 16 + NAME_WIDTH;
+            if width > used {
+                canvas_channel.send(Command::Text(patterns_x+used, self.pos1.y+line, self.text_color, row_bg, " ".repeat(width - used)));
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::KeyDown(key) | Event::KeyRepeat(key) => {
+                match key {
+                    Keycode::LShift | Keycode::RShift => self.shift_held = true,
+                    Keycode::Tab => {
+                        self.finish_rename();
+                        self.typed = None;
+                        self.focus = if self.focus == Focus::Orders { Focus::Patterns } else { Focus::Orders };
+                    },
+                    _ if self.focus == Focus::Orders => {
+                        // Anything but a digit ends typing a pattern number
+                        if !matches!(key, Keycode::Num0 | Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4
+                            | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8 | Keycode::Num9) {
+                            self.typed = None;
+                        }
+                        self.handle_orders_key(key);
+                    },
+                    _ => self.handle_patterns_key(key),
+                }
+            },
+            Event::KeyUp(Keycode::LShift | Keycode::RShift) => self.shift_held = false,
+            Event::TextInput(text) => {
+                match self.focus {
+                    // Pattern numbers, 3 digits like IT. Takes effect once it's a pattern that exists.
+                    Focus::Orders => {
+                        for char in text.chars().filter(|char| char.is_ascii_digit()) {
+                            let typed = (self.typed.unwrap_or(0) * 10 + (char as usize - '0' as usize)) % 1000;
+                            self.typed = Some(typed);
+
+                            if typed < self.patterns.len() {
+                                let mut orders = self.orders.clone();
+                                if self.order_cursor < orders.len() {
+                                    orders[self.order_cursor] = typed;
+                                } else {
+                                    orders.push(typed);
+                                }
+                                self.set_orders(orders);
+                            }
+                        }
+                    },
+                    Focus::Patterns => {
+                        if self.pattern_column != COLUMN_NAME {
+                            return
+                        }
+                        let Some(info) = self.patterns.get(self.pattern_cursor) else {
+                            return
+                        };
+                        self.renaming.get_or_insert_with(|| info.name.clone()).push_str(&text);
+                    },
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn clicked(&mut self) -> bool {
+        false
+    }
+
+    any_impl!{}
+
+    fn set_visiblity(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn visible(&self) -> bool {
+        true
+    }
+
+    fn changed(&mut self) -> bool {
+        !self.actions.is_empty()
+    }
+
+    fn set_handles_events(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn handles_events(&self) -> bool {
+        true
+    }
+}
@@ -1,7 +1,7 @@
-use std::{sync::mpsc, collections::HashMap};
+use std::{sync::mpsc, collections::HashMap, mem, time::{SystemTime, UNIX_EPOCH}};
 use sdl2::keyboard::Keycode;
 
-use crate::{ui::{Command, events::Event, glyph_indices::{CENTERED_BORDER, CENTERED_DOT_THIN}, pixel_to_char}, engine::{pattern::{Pattern, Note}, state::PatternState}, any_impl};
+use crate::{ui::{Command, events::Event, glyph_indices::{CENTERED_BORDER, CENTERED_DOT_THIN}, pixel_to_char}, engine::{pattern::{Pattern, Note, TrackEvent, TrackInfo, TRACK_MIN_WIDTH, TRACK_MAX_WIDTH}, state::PatternState, history::{Edit, EventChange}, live::LiveEvent}, any_impl};
 
 use super::{Widget, Position, draw_borders_thick, fill_region};
 
@@ -12,6 +12,11 @@ const COLUMN_INSTRUMENT: u8 = 1;
 const COLUMN_VOLUME: u8 = 2;
 // const COLUMN_EFFECT: u8 = 3;
 // const COLUMN_EFFECTVALUE: u8 = 4;
+const COLUMNS: usize = 3; // per track
+const BASE_OCTAVE: u8 = 4; // the one key_mapping is laid out for
+const MAX_OCTAVE: u8 = 8;
+const TRACK_COLORS: [Option<u32>; 7] = [None, Some(0x7c2828), Some(0x7c5c28), Some(0x3c7c28), Some(0x287c6c), Some(0x28487c), Some(0x6c287c)];
+const MUTED_COLOR: u32 = 0x5f5f5f;
 
 fn push_digit<T: num::Integer+std::fmt::Display>(num: T, digit: u8) -> T {
     let mut num_string = num.to_string();
@@ -32,13 +37,149 @@ fn truncate_number<T: num::Integer+std::fmt::Display>(num: T, digits: usize) ->
     T::from_str_radix(&truncated_str, 10).ok().unwrap()
 }
 
+// A marked block, rows and columns inclusive. Columns are counted across tracks, track * COLUMNS + column.
+#[derive(Clone, Copy)]
+struct Block {
+    first_row: usize,
+    last_row: usize,
+    first_column: usize,
+    last_column: usize,
+}
+
+impl Block {
+    fn contains(&self, row: usize, column: usize) -> bool {
+        (self.first_row..=self.last_row).contains(&row) && (self.first_column..=self.last_column).contains(&column)
+    }
+}
+
+// What was copied. Whole events of every track the block touched, only the marked columns get pasted.
+#[derive(Clone)]
+struct Clipboard {
+    first_column: usize, // within the first track
+    columns: usize,
+    rows: Vec<Vec<TrackEvent>>,
+}
+
+fn empty_event() -> TrackEvent {
+    TrackEvent { note: Note::None, instrument: 0, volume: 128 }
+}
+
+fn same_event(a: &TrackEvent, b: &TrackEvent) -> bool {
+    a.note as u8 == b.note as u8 && a.instrument == b.instrument && a.volume == b.volume
+}
+
+fn column_empty(event: &TrackEvent, column: u8) -> bool {
+    match column {
+        COLUMN_NOTE => matches!(event.note, Note::None),
+        COLUMN_INSTRUMENT => event.instrument == 0,
+        COLUMN_VOLUME => event.volume > 127,
+        _ => true,
+    }
+}
+
+fn copy_column(event: &mut TrackEvent, column: u8, from: &TrackEvent) {
+    match column {
+        COLUMN_NOTE => event.note = from.note,
+        COLUMN_INSTRUMENT => event.instrument = from.instrument,
+        COLUMN_VOLUME => event.volume = from.volume,
+        _ => {}
+    }
+}
+
+// Block transforms, on copies of the marked rows. Only the marked columns are written back.
+
+fn transpose(rows: &mut [Vec<TrackEvent>], semitones: i32) {
+    for event in rows.iter_mut().flatten() {
+        // Commands like note off stay as they are
+        if (event.note as u8) < Note::PreviousTrack as u8 {
+            let key = (event.note as i32 + semitones).clamp(Note::C0 as i32, Note::B9 as i32);
+            event.note = Note::from_repr(key as u8).unwrap();
+        }
+    }
+}
+
+// Volumes between the first and last row, per track, if both have one
+fn interpolate_volume(rows: &mut [Vec<TrackEvent>]) {
+    let last = rows.len() - 1;
+    if last == 0 {
+        return
+    }
+
+    for track in 0..rows[0].len() {
+        let (from, to) = (rows[0][track].volume, rows[last][track].volume);
+        if from > 127 || to > 127 {
+            continue
+        }
+        for (i, row) in rows.iter_mut().enumerate() {
+            row[track].volume = (from as f32 + (to as f32 - from as f32) * i as f32 / last as f32).round() as u8;
+        }
+    }
+    // TODO effects, once there's a column for them
+}
+
+fn scale_volume(rows: &mut [Vec<TrackEvent>], percent: u32) {
+    for event in rows.iter_mut().flatten() {
+        if event.volume <= 127 {
+            event.volume = (event.volume as u32 * percent / 100).min(127) as u8;
+        }
+    }
+}
+
+// Nudges every volume by up to amount either way
+fn humanize_volume(rows: &mut [Vec<TrackEvent>], amount: u8, seed: &mut u32) {
+    for event in rows.iter_mut().flatten() {
+        if event.volume <= 127 {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 17;
+            *seed ^= *seed << 5;
+            let offset = (*seed % (amount as u32 * 2 + 1)) as i32 - amount as i32;
+            event.volume = (event.volume as i32 + offset).clamp(0, 127) as u8;
+        }
+    }
+}
+
+fn reverse(rows: &mut [Vec<TrackEvent>]) {
+    rows.reverse();
+}
+
+// Alt+F in IT, puts an empty row after every row. What doesn't fit in the block is lost.
+fn expand(rows: &mut [Vec<TrackEvent>]) {
+    for i in (0..rows.len()).rev() {
+        let row = if i % 2 == 0 { rows[i / 2].clone() } else { vec![empty_event(); rows[i].len()] };
+        rows[i] = row;
+    }
+}
+
+// Alt+G in IT, drops every other row
+fn shrink(rows: &mut [Vec<TrackEvent>]) {
+    for i in 0..rows.len() {
+        let row = if i * 2 < rows.len() { rows[i * 2].clone() } else { vec![empty_event(); rows[i].len()] };
+        rows[i] = row;
+    }
+}
+
 pub struct PatternEditor {
     pos1: Position,
     pos2: Position,
     focused: bool,
     ctrl_held: bool,
+    alt_held: bool,
+    shift_held: bool,
     changed: bool,
     temp_volume: u16,
+    edits: Vec<(Edit, bool)>, // made since the last take_edits(), for the undo history. The bool is whether it's typing.
+
+    block_start: Option<(usize, usize)>, // row, column
+    block_end: Option<(usize, usize)>,
+    clipboard: Option<Clipboard>, // kept when switching patterns
+    seed: u32, // for humanizing
+
+    pub pattern_index: usize, // the one being edited, for the undo history
+    pub edit_step: usize, // rows to move down after entering a note
+    pub octave: u8, // the lower half of the keyboard starts here
+    pub follow: bool, // keep the cursor on the playing row
+    pub instrument: u8, // written with every note entered, 0 leaves the column alone
+    pub preview: Option<mpsc::Sender<LiveEvent>>, // for hearing notes as they're entered, see DAWEngine::live_sender()
 
     pub pattern: Option<Pattern>,
     pub state: Option<PatternState>,
@@ -55,15 +196,38 @@ pub struct PatternEditor {
 
     pub row_selection_color: u32,
     pub column_selection_color: u32,
+    pub block_color: u32,
 
     current_track: usize,
     current_column: u8,
     current_row: usize,
+    naming: Option<String>, // name of the current track being typed
 
     track_scroll: usize,
     row_scroll: usize
 }
 
+macro_rules! temp_volume_get {
+    ($self:ident) => {
+        $self.temp_volume = if $self.pattern.as_ref().unwrap().rows[$self.current_row][$self.current_track].volume as u16 > 127 {
+            65535
+        } else {
+            $self.pattern.as_ref().unwrap().rows[$self.current_row][$self.current_track].volume as u16
+        };
+    };
+}
+
+macro_rules! temp_volume_set {
+    ($self:ident) => {
+        let volume = if $self.temp_volume > 999 {
+            128
+        } else {
+            $self.temp_volume.clamp(0, 127) as u8
+        };
+        $self.edit_event(|event| event.volume = volume);
+    };
+}
+
 impl PatternEditor {
     pub fn new(pos1: Position, pos2: Position) -> Self {
         Self {
@@ -71,8 +235,23 @@ impl PatternEditor {
             pos2,
             focused: false,
             ctrl_held: false,
+            alt_held: false,
+            shift_held: false,
             changed: false,
             temp_volume: 65535u16,
+            edits: Vec::new(),
+
+            block_start: None,
+            block_end: None,
+            clipboard: None,
+            seed: SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |time| time.subsec_nanos()).max(1), // xorshift gets stuck at 0
+
+            pattern_index: 0,
+            edit_step: 1,
+            octave: BASE_OCTAVE,
+            follow: false,
+            instrument: 0,
+            preview: None,
 
             text_color: 0,
             outer_bg: 0,
@@ -85,10 +264,12 @@ impl PatternEditor {
 
             row_selection_color: 0x3f3f3f,
             column_selection_color: 0x7f7f7f,
+            block_color: 0x28487c,
 
             current_track: 0,
             current_column: 0,
             current_row: 0,
+            naming: None,
 
             pattern: None,
             state: None,
@@ -98,26 +279,263 @@ impl PatternEditor {
             row_scroll: 0,
         }
     }
-}
 
-macro_rules! temp_volume_get {
-    ($self:ident) => {
-        $self.temp_volume = if $self.pattern.as_ref().unwrap().rows[$self.current_row][$self.current_track].volume as u16 > 127 {
-            65535
-        } else {
-            $self.pattern.as_ref().unwrap().rows[$self.current_row][$self.current_track].volume as u16
+    pub fn current_track(&self) -> usize {
+        self.current_track
+    }
+
+    pub fn take_edits(&mut self) -> Vec<(Edit, bool)> {
+        mem::take(&mut self.edits)
+    }
+
+    // Changes the engine made to its copy meanwhile, e.g. recorded notes, so writing this one back keeps them
+    pub fn merge(&mut self, edit: &Edit) {
+        let (Some(pattern), Edit::Events { pattern: index, changes }) = (&mut self.pattern, edit) else {
+            return
         };
-    };
-}
+        if *index != self.pattern_index {
+            return
+        }
+        for change in changes {
+            if let Some(event) = pattern.rows.get_mut(change.row).and_then(|row| row.get_mut(change.track)) {
+                *event = change.after;
+            }
+        }
+    }
 
-macro_rules! temp_volume_set {
-    ($self:ident) => {
-        $self.pattern.as_mut().unwrap().rows[$self.current_row][$self.current_track].volume = if $self.temp_volume > 999 {
-            128
-        } else {
-            $self.temp_volume.clamp(0, 127) as u8
+    // After the pattern was changed from outside, e.g. by undo
+    pub fn reload(&mut self, pattern: Pattern) {
+        // It might have gotten smaller
+        self.current_row = self.current_row.min(pattern.rows.len()-1);
+        self.current_track = self.current_track.min(pattern.rows[0].len()-1);
+        self.pattern = Some(pattern);
+        temp_volume_get!(self);
+    }
+
+    // The note a key plays, shifted to the selected octave
+    fn key_note(&self, key: Keycode) -> Option<Note> {
+        let note = *self.key_mapping.get(&key)?;
+        let key = note as i32 + (self.octave as i32 - BASE_OCTAVE as i32) * 12;
+        if (Note::C0 as i32..=Note::B9 as i32).contains(&key) { Note::from_repr(key as u8) } else { None }
+    }
+
+    // Plays an event until the key is let go
+    fn preview_event(&self, key: Keycode, event: &TrackEvent) {
+        let Some(preview) = &self.preview else {
+            return
         };
-    };
+        if (event.note as u8) < Note::PreviousTrack as u8 {
+            let _ = preview.send(LiveEvent::PreviewOn {
+                source: key as i32 as u32,
+                instrument: if event.instrument == 0 { None } else { Some(event.instrument as usize - 1) },
+                key: event.note as u8,
+                vel: if event.volume > 127 { 127 } else { event.volume },
+            });
+        }
+    }
+
+    // After something was entered
+    fn advance(&mut self) {
+        let last_row = self.pattern.as_ref().unwrap().rows.len()-1;
+        self.current_row = (self.current_row + self.edit_step).min(last_row);
+        temp_volume_get!(self);
+    }
+
+    fn scroll_to_cursor(&mut self) {
+        let row_capacity = self.pos2.y-self.pos1.y;
+        if self.current_row > self.row_scroll+row_capacity-1 {
+            self.row_scroll = self.current_row-(row_capacity-1);
+        } else if self.current_row < self.row_scroll {
+            self.row_scroll = self.current_row;
+        }
+    }
+
+    fn cursor(&self) -> (usize, usize) {
+        (self.current_row, self.current_track * COLUMNS + self.current_column as usize)
+    }
+
+    fn block(&self) -> Option<Block> {
+        let ((start_row, start_column), (end_row, end_column)) = (self.block_start?, self.block_end?);
+        let pattern = self.pattern.as_ref()?;
+        let last_column = pattern.rows[0].len() * COLUMNS - 1;
+
+        Some(Block {
+            first_row: start_row.min(end_row).min(pattern.rows.len()-1),
+            last_row: start_row.max(end_row).min(pattern.rows.len()-1),
+            first_column: start_column.min(end_column).min(last_column),
+            last_column: start_column.max(end_column).min(last_column),
+        })
+    }
+
+    // Changes several events as a single undo step, as (row, track, event)
+    fn edit_events(&mut self, events: Vec<(usize, usize, TrackEvent)>) {
+        let pattern = self.pattern.as_mut().unwrap();
+        let mut changes = Vec::new();
+
+        for (row, track, after) in events {
+            let Some(event) = pattern.rows.get_mut(row).and_then(|row| row.get_mut(track)) else {
+                continue
+            };
+            if !same_event(event, &after) {
+                changes.push(EventChange { row, track, before: *event, after });
+                *event = after;
+            }
+        }
+
+        if !changes.is_empty() {
+            self.edits.push((Edit::Events { pattern: self.pattern_index, changes }, false));
+            self.changed = true;
+        }
+    }
+
+    fn copy_block(&mut self) -> bool {
+        let Some(block) = self.block() else {
+            return false
+        };
+        let pattern = self.pattern.as_ref().unwrap();
+        let tracks = (block.first_column / COLUMNS)..=(block.last_column / COLUMNS);
+
+        self.clipboard = Some(Clipboard {
+            first_column: block.first_column % COLUMNS,
+            columns: block.last_column - block.first_column + 1,
+            rows: pattern.rows[block.first_row..=block.last_row].iter()
+                .map(|row| row[tracks.clone()].to_vec())
+                .collect(),
+        });
+        true
+    }
+
+    fn cut_block(&mut self) {
+        if !self.copy_block() {
+            return
+        }
+        let block = self.block().unwrap();
+        let pattern = self.pattern.as_ref().unwrap();
+        let empty = empty_event();
+
+        let mut events = Vec::new();
+        for row in block.first_row..=block.last_row {
+            for track in (block.first_column / COLUMNS)..=(block.last_column / COLUMNS) {
+                let mut event = pattern.rows[row][track];
+                for column in 0..COLUMNS {
+                    if block.contains(row, track * COLUMNS + column) {
+                        copy_column(&mut event, column as u8, &empty);
+                    }
+                }
+                events.push((row, track, event));
+            }
+        }
+        self.edit_events(events);
+    }
+
+    // Runs a transform on a copy of the marked block, then writes back the marked columns as one undo step
+    fn transform_block(&mut self, transform: impl FnOnce(&mut [Vec<TrackEvent>])) {
+        let Some(block) = self.block() else {
+            return
+        };
+        let pattern = self.pattern.as_ref().unwrap();
+        let first_track = block.first_column / COLUMNS;
+
+        let mut rows: Vec<Vec<TrackEvent>> = pattern.rows[block.first_row..=block.last_row].iter()
+            .map(|row| row[first_track..=(block.last_column / COLUMNS)].to_vec())
+            .collect();
+        transform(&mut rows);
+
+        let mut events = Vec::new();
+        for (row_offset, row) in rows.iter().enumerate() {
+            let row_index = block.first_row + row_offset;
+            for (track_offset, source) in row.iter().enumerate() {
+                let track = first_track + track_offset;
+                let mut event = pattern.rows[row_index][track];
+                for column in 0..COLUMNS {
+                    if block.contains(row_index, track * COLUMNS + column) {
+                        copy_column(&mut event, column as u8, source);
+                    }
+                }
+                events.push((row_index, track, event));
+            }
+        }
+        self.edit_events(events);
+        temp_volume_get!(self);
+    }
+
+    // At the cursor. Mixing only fills in empty columns.
+    // What runs past the end of the pattern is dropped, like IT
+    fn paste(&mut self, mix: bool) {
+        let Some(clipboard) = self.clipboard.clone() else {
+            return
+        };
+        let pattern = self.pattern.as_ref().unwrap();
+        let tracks = pattern.rows[0].len();
+
+        let mut events = Vec::new();
+        for (offset, source_row) in clipboard.rows.iter().enumerate() {
+            let row = self.current_row + offset;
+            if row >= pattern.rows.len() {
+                break
+            }
+
+            for (track_offset, source) in source_row.iter().enumerate() {
+                let track = self.current_track + track_offset;
+                if track >= tracks {
+                    break
+                }
+
+                let mut event = pattern.rows[row][track];
+                for column in 0..COLUMNS {
+                    let index = track_offset * COLUMNS + column;
+                    if index < clipboard.first_column || index >= clipboard.first_column + clipboard.columns {
+                        continue
+                    }
+                    if mix && !column_empty(&event, column as u8) {
+                        continue
+                    }
+                    copy_column(&mut event, column as u8, source);
+                }
+                events.push((row, track, event));
+            }
+        }
+
+        self.edit_events(events);
+        temp_volume_get!(self);
+    }
+
+    // Changes how the current track looks or plays. Mute and solo aren't something to undo, like in IT.
+    fn edit_track(&mut self, undoable: bool, edit: impl FnOnce(&mut TrackInfo)) {
+        let track = self.current_track;
+        let pattern = self.pattern.as_mut().unwrap();
+        let before = pattern.clone();
+        let Some(info) = pattern.tracks.get_mut(track) else {
+            return
+        };
+        edit(info);
+
+        if undoable {
+            let after = pattern.clone();
+            self.edits.push((Edit::Pattern { pattern: self.pattern_index, before, after }, false));
+        }
+        self.changed = true;
+    }
+
+    fn finish_naming(&mut self) {
+        if let Some(name) = self.naming.take() {
+            self.edit_track(true, |info| info.name = name);
+        }
+    }
+
+    // Changes the event under the cursor and remembers it for undo
+    fn edit_event(&mut self, edit: impl FnOnce(&mut TrackEvent)) {
+        let (row, track) = (self.current_row, self.current_track);
+        let event = &mut self.pattern.as_mut().unwrap().rows[row][track];
+        let before = *event;
+        edit(event);
+        let after = *event;
+
+        if !same_event(&before, &after) {
+            self.edits.push((Edit::Events { pattern: self.pattern_index, changes: vec![EventChange { row, track, before, after }] }, true));
+            self.changed = true;
+        }
+    }
 }
 
 #[allow(unused_must_use)]
@@ -142,12 +560,29 @@ impl Widget for PatternEditor {
             return;
         }
 
+        // Follow song
+        if let Some(state) = self.state.as_ref().filter(|state| self.follow && state.playing) {
+            let row = (state.row as usize).min(self.pattern.as_ref().unwrap().rows.len()-1);
+            if row != self.current_row {
+                self.current_row = row;
+                temp_volume_get!(self);
+                self.scroll_to_cursor();
+            }
+        }
+
+        let name: String = self.pattern.as_ref().unwrap().name.chars().take(20).collect();
+        canvas_channel.send(Command::Text(self.pos1.x-4, self.pos1.y-2, self.text_color, self.outer_bg, format!(
+            "Pattern {:0>3} {:<20}  Octave {}  Step {}  {}",
+            self.pattern_index, name, self.octave, self.edit_step, if self.follow { "Follow" } else { "      " }
+        )));
+
         for xr in self.pos1.x..self.pos2.x {
             if (self.pos1.y..self.pos2.y).contains(&(self.pos1.y+(self.current_row.saturating_sub(self.row_scroll)))) {
                 canvas_channel.send(Command::Char(xr, self.pos1.y+(self.current_row.saturating_sub(self.row_scroll)), 0, self.row_selection_color, ' '));
             }
         }
 
+        let block = self.block();
         for i in self.row_scroll..self.pattern.as_ref().unwrap().rows.len() {
             let row = &self.pattern.as_ref().unwrap().rows[i];
 
@@ -180,35 +615,57 @@ impl Widget for PatternEditor {
                 self.inner_bg
             };
 
-            let mut track_counter = 0;
+            let pattern = self.pattern.as_ref().unwrap();
+            let width_of = |track: usize| pattern.tracks.get(track).map_or(TRACK_MIN_WIDTH, |info| info.width.max(TRACK_MIN_WIDTH)) as usize;
             for j in self.track_scroll..row.len() {
                 let track = &row[j];
+                let width = width_of(j);
+                let text_color = if pattern.track_audible(j) { self.text_color } else { MUTED_COLOR };
 
                 if i == self.row_scroll {
-                    canvas_channel.send(Command::Text(self.pos1.x+(track_counter*11), self.pos1.y-1, 0xffffff, self.top_rim, format!(" Track {:0>2} ", j+1)));
-                    track_counter += 1;
+                    let info = pattern.tracks.get(j).cloned().unwrap_or_default();
+                    let name = match &self.naming {
+                        Some(name) if j == self.current_track => format!("{name}_"),
+                        _ if info.name.is_empty() => format!("Track {:0>2}", j+1),
+                        _ => info.name.clone(),
+                    };
+                    let flag = if info.solo { "S " } else if info.muted { "M " } else { "" };
+                    let header: String = format!(" {flag}{name}").chars().take(width).collect();
+                    canvas_channel.send(Command::Text(x, self.pos1.y-1, if info.muted { MUTED_COLOR } else { 0xffffff }, info.color.unwrap_or(self.top_rim), format!("{:<width$}", header)));
                 }
 
-                let bg = if i == self.current_row && j == self.current_track.into() { self.column_selection_color } else if i == self.current_row { self.row_selection_color } else { row_bg };
+                let column_bg = |column: u8| if i == self.current_row && j == self.current_track && self.current_column == column {
+                    self.column_selection_color
+                } else if block.map_or(false, |block| block.contains(i, j * COLUMNS + column as usize)) {
+                    self.block_color
+                } else if i == self.current_row {
+                    self.row_selection_color
+                } else {
+                    row_bg
+                };
 
                 let note_string = format_note(track.note);
                 let instr_string = if track.instrument != 0 { format!("{:0>2}", track.instrument) } else { CENTERED_DOT_THIN.to_string().repeat(2) };
                 let vol_string = if track.volume <= 127 { format!("{:0>3}", track.volume) } else { CENTERED_DOT_THIN.to_string().repeat(3) };
 
-                canvas_channel.send(Command::Text(x, y, self.text_color, if self.current_column == COLUMN_NOTE { bg } else if i == self.current_row { self.row_selection_color } else { row_bg }, note_string));
-                canvas_channel.send(Command::Text(x+4, y, self.text_color, if self.current_column == COLUMN_INSTRUMENT { bg } else if i == self.current_row { self.row_selection_color } else { row_bg }, instr_string));
+                canvas_channel.send(Command::Text(x, y, text_color, column_bg(COLUMN_NOTE), note_string));
+                canvas_channel.send(Command::Text(x+4, y, text_color, column_bg(COLUMN_INSTRUMENT), instr_string));
 
                 if self.current_row == i && self.current_track == j && self.current_column == COLUMN_VOLUME {
-                    canvas_channel.send(Command::Text(x+7, y, if self.temp_volume > 999 {self.text_color} else if self.temp_volume > 127 {0xff0000} else {self.text_color}, if self.current_column == COLUMN_VOLUME { bg } else if i == self.current_row { self.row_selection_color } else { row_bg }, format!("{:0>3}", if self.temp_volume > 999 {CENTERED_DOT_THIN.to_string().repeat(3)} else {self.temp_volume.to_string()} )));
+                    canvas_channel.send(Command::Text(x+7, y, if self.temp_volume > 999 {self.text_color} else if self.temp_volume > 127 {0xff0000} else {self.text_color}, column_bg(COLUMN_VOLUME), format!("{:0>3}", if self.temp_volume > 999 {CENTERED_DOT_THIN.to_string().repeat(3)} else {self.temp_volume.to_string()} )));
                 } else {
-                    canvas_channel.send(Command::Text(x+7, y, self.text_color, if self.current_column == COLUMN_VOLUME { bg } else if i == self.current_row { self.row_selection_color } else { row_bg }, vol_string));
+                    canvas_channel.send(Command::Text(x+7, y, text_color, column_bg(COLUMN_VOLUME), vol_string));
                 }
 
-                canvas_channel.send(Command::Char(x+10, y, self.outer_bg, if i == self.current_row { self.row_selection_color } else { row_bg }, CENTERED_BORDER));
+                let line_bg = if i == self.current_row { self.row_selection_color } else { row_bg };
+                if width > TRACK_MIN_WIDTH as usize {
+                    canvas_channel.send(Command::Text(x+10, y, text_color, line_bg, " ".repeat(width - TRACK_MIN_WIDTH as usize)));
+                }
+                canvas_channel.send(Command::Char(x+width-1, y, self.outer_bg, line_bg, CENTERED_BORDER));
 
-                x += 11;
+                x += width;
                 // If out of bounds to the right
-                if (x+11) >= self.pos2.x {
+                if (x+width_of(j+1)) >= self.pos2.x {
                     // If the out of bounds track is selected, scroll to the right
                     if self.current_track == j+1 {
                         self.track_scroll += 1;
@@ -234,11 +691,21 @@ impl Widget for PatternEditor {
     }
 
     fn handle_event(&mut self, event: Event) {
+        let repeat = matches!(event, Event::KeyRepeat(_));
         match event {
+            Event::KeyDown(key) | Event::KeyRepeat(key) if self.naming.is_some() => {
+                match key {
+                    Keycode::Backspace => { self.naming.as_mut().unwrap().pop(); },
+                    Keycode::Return | Keycode::Up | Keycode::Down | Keycode::Left | Keycode::Right => self.finish_naming(),
+                    _ => {}
+                }
+            },
             Event::KeyDown(key) | Event::KeyRepeat(key) => {
-                if matches!(key as u32, 0x4000004F..=0x40000052) { // arrows
+                let arrow = matches!(key as u32, 0x4000004F..=0x40000052);
+                if arrow {
                     temp_volume_set!(self);
                 }
+                let cursor_before = self.cursor();
 
                 match key {
                     Keycode::Up => {
@@ -301,39 +768,128 @@ impl Widget for PatternEditor {
                     Keycode::LCtrl | Keycode::RCtrl => {
                             self.ctrl_held = true;
                     },
-                    Keycode::Period | Keycode::Delete => {
-                        let event = &mut self.pattern.as_mut().unwrap()
-                        .rows[self.current_row][self.current_track];
+                    Keycode::LAlt | Keycode::RAlt => self.alt_held = true,
+                    Keycode::LShift | Keycode::RShift => self.shift_held = true,
+
+                    // Blocks, the same keys as IT
+                    Keycode::B if self.alt_held => { // mark beginning
+                        self.block_start = Some(self.cursor());
+                        self.block_end = self.block_end.or(self.block_start);
+                    },
+                    Keycode::E if self.alt_held => { // mark end
+                        self.block_end = Some(self.cursor());
+                        self.block_start = self.block_start.or(self.block_end);
+                    },
+                    Keycode::U if self.alt_held => { // unmark
+                        self.block_start = None;
+                        self.block_end = None;
+                    },
+                    Keycode::C if self.alt_held => { self.copy_block(); },
+                    Keycode::Z if self.alt_held => self.cut_block(),
+                    Keycode::P if self.alt_held => self.paste(false),
+                    Keycode::M if self.alt_held => self.paste(true),
+                    Keycode::Q if self.alt_held => { // transpose up, by an octave with shift
+                        let semitones = if self.shift_held { 12 } else { 1 };
+                        self.transform_block(|rows| transpose(rows, semitones));
+                    },
+                    Keycode::A if self.alt_held => { // transpose down
+                        let semitones = if self.shift_held { -12 } else { -1 };
+                        self.transform_block(|rows| transpose(rows, semitones));
+                    },
+                    Keycode::K if self.alt_held => self.transform_block(interpolate_volume),
+                    Keycode::J if self.alt_held => { // louder, quieter with shift
+                        let percent = if self.shift_held { 90 } else { 110 };
+                        self.transform_block(|rows| scale_volume(rows, percent));
+                    },
+                    Keycode::H if self.alt_held => { // humanize
+                        let mut seed = self.seed;
+                        self.transform_block(|rows| humanize_volume(rows, 8, &mut seed));
+                        self.seed = seed;
+                    },
+                    Keycode::R if self.alt_held => self.transform_block(reverse),
+                    Keycode::F if self.alt_held => self.transform_block(expand),
+                    Keycode::G if self.alt_held => self.transform_block(shrink),
+
+                    // Tracks
+                    Keycode::F9 if self.alt_held => self.edit_track(false, |info| info.muted = !info.muted),
+                    Keycode::F10 if self.alt_held => { // solo, or unsolo everything if this is the only one
+                        let track = self.current_track;
+                        let tracks = &mut self.pattern.as_mut().unwrap().tracks;
+                        let only = tracks.iter().enumerate().all(|(index, info)| info.solo == (index == track));
+                        for (index, info) in tracks.iter_mut().enumerate() {
+                            info.solo = !only && index == track;
+                        }
+                        self.changed = true;
+                    },
+                    Keycode::N if self.alt_held => {
+                        self.naming = self.pattern.as_ref().unwrap().tracks.get(self.current_track).map(|info| info.name.clone());
+                    },
+                    Keycode::O if self.alt_held => self.edit_track(true, |info| {
+                        let index = TRACK_COLORS.iter().position(|color| *color == info.color).unwrap_or(0);
+                        info.color = TRACK_COLORS[(index + 1) % TRACK_COLORS.len()];
+                    }),
+                    Keycode::W if self.alt_held => { // wider, narrower with shift
+                        let narrower = self.shift_held;
+                        self.edit_track(true, |info| {
+                            info.width = if narrower { info.width.saturating_sub(1).max(TRACK_MIN_WIDTH) } else { (info.width + 1).min(TRACK_MAX_WIDTH) };
+                        });
+                    },
 
+                    Keycode::Num0 | Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4
+                    | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8 | Keycode::Num9 if self.alt_held => {
+                        self.edit_step = (key as i32 - Keycode::Num0 as i32) as usize;
+                    },
+                    Keycode::KpDivide => self.octave = self.octave.saturating_sub(1),
+                    Keycode::KpMultiply => self.octave = (self.octave + 1).min(MAX_OCTAVE),
+                    Keycode::ScrollLock => self.follow = !self.follow,
+
+                    Keycode::Period | Keycode::Delete => {
                         match self.current_column {
                             COLUMN_NOTE => {
-                                event.note = Note::None;
+                                self.edit_event(|event| event.note = Note::None);
                             },
                             COLUMN_INSTRUMENT => {
-                                event.instrument = 0;
+                                self.edit_event(|event| event.instrument = 0);
                             },
                             COLUMN_VOLUME => {
-                                event.volume = 128;
+                                self.edit_event(|event| event.volume = 128);
                                 self.temp_volume = 65535;
                             },
                             _ => {}
                         }
-
-                        self.changed = true;
+                        self.advance();
                     }
+                    _ if self.ctrl_held || self.alt_held => {}, // shortcuts like Ctrl+Z, handled by the app
                     _ => {
                         match self.current_column {
                             COLUMN_NOTE => {
-                                let pat = self.pattern.as_mut().unwrap();
-
-                                if let Some(note) = self.key_mapping.get(&key) {
-                                    pat.rows[self.current_row][self.current_track].note = *note;
-                                    self.changed = true;
+                                if let Some(note) = self.key_note(key) {
+                                    let instrument = self.instrument;
+                                    self.edit_event(|event| {
+                                        event.note = note;
+                                        if instrument != 0 {
+                                            event.instrument = instrument;
+                                        }
+                                    });
+                                    if !repeat {
+                                        let event = self.pattern.as_ref().unwrap().rows[self.current_row][self.current_track];
+                                        self.preview_event(key, &event);
+                                    }
+                                    self.advance();
                                 } else {
                                     match key {
                                         Keycode::Backquote => {
-                                            pat.rows[self.current_row][self.current_track].note = Note::Off;
-                                            self.changed = true;
+                                            self.edit_event(|event| event.note = Note::Off);
+                                            self.advance();
+                                        }
+                                        Keycode::Num8 => { // play the whole row, like IT
+                                            if !repeat {
+                                                let row = self.pattern.as_ref().unwrap().rows[self.current_row].clone();
+                                                for event in &row {
+                                                    self.preview_event(key, event);
+                                                }
+                                            }
+                                            self.advance();
                                         }
                                         _ => {}
                                     }
@@ -344,23 +900,32 @@ impl Widget for PatternEditor {
                     }
                 }
 
-                let row_capacity = self.pos2.y-self.pos1.y;
-                if self.current_row > self.row_scroll+row_capacity-1 {
-                    self.row_scroll = self.current_row-(row_capacity-1);
-                } else if self.current_row < self.row_scroll {
-                    self.row_scroll = self.current_row;
-                }
+                self.scroll_to_cursor();
+
+                if arrow {
+                    // Shift+arrows start a block where the cursor was, or keep stretching the one it's at the end of
+                    if self.shift_held {
+                        if self.block_end != Some(cursor_before) {
+                            self.block_start = Some(cursor_before);
+                        }
+                        self.block_end = Some(self.cursor());
+                    }
 
-                if matches!(key as u32, 0x4000004F..=0x40000052) { // arrows
                     temp_volume_get!(self);
                     self.changed = true;
                 }
             },
             Event::KeyUp(key) => {
+                if let Some(preview) = &self.preview {
+                    let _ = preview.send(LiveEvent::PreviewOff { source: key as i32 as u32 });
+                }
+
                 match key {
                     Keycode::LCtrl | Keycode::RCtrl => {
                         self.ctrl_held = false;
                     },
+                    Keycode::LAlt | Keycode::RAlt => self.alt_held = false,
+                    Keycode::LShift | Keycode::RShift => self.shift_held = false,
                     _ => {}
                 }
             }
@@ -373,15 +938,22 @@ impl Widget for PatternEditor {
             },
             Event::MouseUp(_, _, _) => {},
             Event::TextInput(text) => {
+                // Alt+digits set the edit step
+                if self.alt_held || self.ctrl_held {
+                    return
+                }
+                if let Some(name) = &mut self.naming {
+                    name.push_str(&text);
+                    return
+                }
                 for char in text.chars() {
                     if char.is_numeric() {
                         match self.current_column {
                             COLUMN_INSTRUMENT => {
-                                let event = &mut self.pattern.as_mut().unwrap().rows[self.current_row][self.current_track];
-                                let tmp: u16 = push_digit(event.instrument as u16, (char as u8)-48);
-                                event.instrument = truncate_number(tmp, 2) as u8;
-
-                                self.changed = true;
+                                self.edit_event(|event| {
+                                    let tmp: u16 = push_digit(event.instrument as u16, (char as u8)-48);
+                                    event.instrument = truncate_number(tmp, 2) as u8;
+                                });
                             },
                             COLUMN_VOLUME => {
                                 if self.temp_volume == 65535 {
@@ -392,10 +964,8 @@ impl Widget for PatternEditor {
                                 self.temp_volume = truncate_number(tmp, 3);
 
                                 if self.temp_volume <= 127 {
-                                    let event = &mut self.pattern.as_mut().unwrap().rows[self.current_row][self.current_track];
-
-                                    event.volume = self.temp_volume as u8;
-                                    self.changed = true;
+                                    let volume = self.temp_volume as u8;
+                                    self.edit_event(|event| event.volume = volume);
                                 }
                             },
                             _ => {}
@@ -0,0 +1,506 @@
+use std::{sync::mpsc, mem};
+use sdl2::{keyboard::Keycode, mouse::MouseButton};
+
+use crate::{ui::{Command, events::Event, pixel_to_char}, engine::playlist::{Clip, PatternClip}, any_impl};
+
+use super::{Widget, Position, draw_borders_thick};
+
+const WIDGET_ID_PLAYLISTVIEW: u8 = 9;
+
+const LANE_WIDTH: usize = 3; // "00 "
+const NAME_WIDTH: usize = 20;
+const CLIP_COLORS: [u32; 4] = [0x2c5f8a, 0x5f7a2c, 0x7a2c5f, 0x7a5a2c];
+
+// What the user asked for, done by whoever owns the engine. See PlaylistView::take_actions().
+pub enum PlaylistAction {
+    Add(Clip),
+    Remove(usize),
+    Replace(usize, Clip),
+    Split(usize, u32), // at
+    SetLoop(Option<(u32, u32)>),
+    Seek(u32),
+}
+
+// A clip being dragged with the mouse
+struct Drag {
+    index: usize,
+    resize: bool, // grabbed by its last cell
+    tick: u32, // where it was grabbed
+    lane: usize,
+    original: PatternClip,
+}
+
+// The F12 page. Time goes to the right in bars, every lane is a clip track.
+pub struct PlaylistView {
+    pub pos1: Position,
+    pub pos2: Position,
+
+    pub patterns: Vec<(String, u32)>, // name, length in ticks
+    pub position: u32, // playhead
+    pub ppq: u16,
+    pub loop_range: Option<(u32, u32)>,
+    clips: Vec<Clip>,
+
+    pub text_color: u32,
+    pub outer_bg: u32,
+    pub inner_bg: u32,
+    pub top_rim: u32,
+    pub bottom_rim: u32,
+    pub bar_color: u32,
+    pub loop_color: u32,
+    pub cursor_color: u32,
+    pub selected_color: u32,
+    pub playhead_color: u32,
+
+    actions: Vec<PlaylistAction>,
+    ctrl_held: bool,
+    alt_held: bool,
+    shift_held: bool,
+
+    cursor_tick: u32,
+    cursor_lane: usize,
+    cell: u32, // ticks per character
+    tick_scroll: u32,
+    lane_scroll: usize,
+    pattern: usize, // the one Insert places
+    drag: Option<Drag>,
+}
+
+impl PlaylistView {
+    pub fn new(pos1: Position, pos2: Position, ppq: u16) -> Self {
+        Self {
+            pos1,
+            pos2,
+
+            patterns: Vec::new(),
+            position: 0,
+            ppq,
+            loop_range: None,
+            clips: Vec::new(),
+
+            text_color: 0,
+            outer_bg: 0,
+            inner_bg: 0,
+            top_rim: 0,
+            bottom_rim: 0,
+            bar_color: 0x381c08,
+            loop_color: 0x082838,
+            cursor_color: 0x7f7f7f,
+            selected_color: 0x4f8fcf,
+            playhead_color: 0xcfcfcf,
+
+            actions: Vec::new(),
+            ctrl_held: false,
+            alt_held: false,
+            shift_held: false,
+
+            cursor_tick: 0,
+            cursor_lane: 0,
+            cell: ppq as u32, // a beat
+            tick_scroll: 0,
+            lane_scroll: 0,
+            pattern: 0,
+            drag: None,
+        }
+    }
+
+    pub fn take_actions(&mut self) -> Vec<PlaylistAction> {
+        mem::take(&mut self.actions)
+    }
+
+    // Ignored while dragging, the clip being dragged is only sent back when it's let go
+    pub fn set_clips(&mut self, clips: Vec<Clip>) {
+        if self.drag.is_none() {
+            self.clips = clips;
+        }
+    }
+
+    fn clip(&self, index: usize) -> &PatternClip {
+        let Clip::Pattern(clip) = &self.clips[index];
+        clip
+    }
+
+    fn clip_at(&self, lane: usize, tick: u32) -> Option<usize> {
+        (0..self.clips.len()).rev().find(|&index| {
+            let clip = self.clip(index);
+            clip.track as usize == lane && (clip.begin..clip.end).contains(&tick)
+        })
+    }
+
+    // No overlapping clips on the same lane
+    fn fits(&self, clip: &PatternClip, ignore: Option<usize>) -> bool {
+        clip.begin < clip.end && (0..self.clips.len())
+            .filter(|&index| Some(index) != ignore)
+            .map(|index| self.clip(index))
+            .all(|other| other.track != clip.track || other.end <= clip.begin || other.begin >= clip.end)
+    }
+
+    // Changes the clip under the cursor, if it still fits
+    fn change_selected(&mut self, change: impl FnOnce(&mut PatternClip) -> bool) -> bool {
+        let Some(index) = self.clip_at(self.cursor_lane, self.cursor_tick) else {
+            return false
+        };
+        let mut clip = self.clip(index).clone();
+        if !change(&mut clip) || !self.fits(&clip, Some(index)) {
+            return false
+        }
+
+        self.clips[index] = Clip::Pattern(clip.clone());
+        self.actions.push(PlaylistAction::Replace(index, Clip::Pattern(clip)));
+        true
+    }
+
+    fn set_loop(&mut self, loop_range: Option<(u32, u32)>) {
+        self.loop_range = loop_range;
+        self.actions.push(PlaylistAction::SetLoop(loop_range));
+    }
+
+    // Grid position under a mouse position, in lane and tick
+    fn grid_at(&self, x: usize, y: usize) -> Option<(usize, u32)> {
+        let (x, y) = pixel_to_char(x, y);
+        let grid_x = self.pos1.x + LANE_WIDTH;
+        if !(grid_x..self.pos2.x).contains(&x) || !(self.pos1.y..self.pos2.y).contains(&y) {
+            return None
+        }
+        Some((self.lane_scroll + y - self.pos1.y, self.tick_scroll + (x - grid_x) as u32 * self.cell))
+    }
+
+    // bars:beats, 1-based like the clock
+    fn format_time(&self, ticks: u32) -> String {
+        let ppq = self.ppq as u32;
+        format!("{}:{}", ticks / (ppq * 4) + 1, ticks / ppq % 4 + 1)
+    }
+}
+
+#[allow(unused_must_use)]
+impl Widget for PlaylistView {
+    fn type_id(&self) -> u8 {
+        WIDGET_ID_PLAYLISTVIEW
+    }
+
+    fn draw(&mut self, canvas_channel: &mpsc::Sender<Command>) {
+        let ppq = self.ppq as u32;
+        let bar = ppq * 4;
+        let grid_x = self.pos1.x + LANE_WIDTH;
+        let cells = (self.pos2.x - grid_x) as u32;
+        let lanes = self.pos2.y - self.pos1.y;
+
+        // Keep the cursor in view
+        if self.cursor_tick < self.tick_scroll {
+            self.tick_scroll = self.cursor_tick;
+        } else if self.cursor_tick >= self.tick_scroll + cells * self.cell {
+            self.tick_scroll = self.cursor_tick - (cells - 1) * self.cell;
+        }
+        if self.cursor_lane < self.lane_scroll {
+            self.lane_scroll = self.cursor_lane;
+        } else if self.cursor_lane >= self.lane_scroll + lanes {
+            self.lane_scroll = self.cursor_lane + 1 - lanes;
+        }
+
+        draw_borders_thick(canvas_channel, self.pos1, self.pos2, self.top_rim, self.bottom_rim, self.outer_bg);
+
+        // Status
+        let (name, _) = self.patterns.get(self.pattern).cloned().unwrap_or_default();
+        let name: String = name.chars().take(NAME_WIDTH).collect();
+        let zoom = if self.cell >= ppq { format!("{} beat(s)", self.cell / ppq) } else { format!("1/{} beat", ppq / self.cell) };
+        let looping = match self.loop_range {
+            Some((start, end)) => format!("{} - {}", self.format_time(start), self.format_time(end)),
+            None => "off".to_string(),
+        };
+        canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y-2, self.text_color, self.outer_bg, format!(
+            "Pattern {:0>3} {:<NAME_WIDTH$}  Zoom {:<10}  Loop {:<16}", self.pattern, name, zoom, looping
+        )));
+
+        // Bar numbers, loop markers and the playhead along the top
+        let in_cell = |tick: u32, cell_tick: u32| (cell_tick..cell_tick + self.cell).contains(&tick);
+        let mut header: Vec<char> = vec![' '; cells as usize];
+        for x in 0..cells as usize {
+            let tick = self.tick_scroll + x as u32 * self.cell;
+            let bar_start = (tick + bar - 1) / bar * bar;
+            if in_cell(bar_start, tick) {
+                for (i, digit) in (bar_start / bar + 1).to_string().chars().enumerate() {
+                    if x + i < header.len() {
+                        header[x + i] = digit;
+                    }
+                }
+            }
+        }
+        for x in 0..cells as usize {
+            let tick = self.tick_scroll + x as u32 * self.cell;
+            if let Some((start, end)) = self.loop_range {
+                if in_cell(start, tick) {
+                    header[x] = '[';
+                }
+                if in_cell(end.saturating_sub(1), tick) {
+                    header[x] = ']';
+                }
+            }
+            if in_cell(self.position, tick) {
+                header[x] = 'v';
+            }
+        }
+        canvas_channel.send(Command::Text(grid_x, self.pos1.y-1, 0xffffff, self.top_rim, header.into_iter().collect()));
+
+        let selected = self.clip_at(self.cursor_lane, self.cursor_tick);
+        for line in 0..lanes {
+            let lane = self.lane_scroll + line;
+            let y = self.pos1.y + line;
+            canvas_channel.send(Command::Text(self.pos1.x, y, self.text_color, self.outer_bg, format!("{:0>2} ", lane)));
+
+            for x in 0..cells {
+                let tick = self.tick_scroll + x * self.cell;
+
+                // Whichever clip is in this cell, the last one if there's more
+                let clip = (0..self.clips.len()).rev().find(|&index| {
+                    let clip = self.clip(index);
+                    clip.track as usize == lane && clip.begin < tick + self.cell && clip.end > tick
+                });
+
+                let mut char = ' ';
+                let mut bg = if self.loop_range.map_or(false, |(start, end)| (start..end).contains(&tick)) {
+                    self.loop_color
+                } else if tick % bar == 0 {
+                    self.bar_color
+                } else {
+                    self.inner_bg
+                };
+
+                if let Some(index) = clip {
+                    let clip = self.clip(index);
+                    bg = if selected == Some(index) { self.selected_color } else { CLIP_COLORS[index % CLIP_COLORS.len()] };
+
+                    // The pattern, where in it the clip starts, and its name
+                    let mut label = format!("{:0>2}", clip.pattern_index);
+                    if clip.offset != 0 {
+                        label.push_str(&format!("+{}.{:0>2}", clip.offset / ppq, clip.offset % ppq));
+                    }
+                    if let Some((name, _)) = self.patterns.get(clip.pattern_index) {
+                        label.push(' ');
+                        label.push_str(name);
+                    }
+                    let first_cell = clip.begin - clip.begin % self.cell;
+                    char = label.chars().nth(((tick - first_cell) / self.cell) as usize).unwrap_or(' ');
+                }
+
+                if in_cell(self.position, tick) && clip.is_none() {
+                    bg = self.playhead_color;
+                }
+                if lane == self.cursor_lane && in_cell(self.cursor_tick, tick) {
+                    bg = self.cursor_color;
+                }
+                canvas_channel.send(Command::Char(grid_x + x as usize, y, self.text_color, bg, char));
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::KeyDown(key) | Event::KeyRepeat(key) => {
+                let cell = self.cell;
+                match key {
+                    Keycode::LCtrl | Keycode::RCtrl => self.ctrl_held = true,
+                    Keycode::LAlt | Keycode::RAlt => self.alt_held = true,
+                    Keycode::LShift | Keycode::RShift => self.shift_held = true,
+
+                    Keycode::Left | Keycode::Right => {
+                        let right = key == Keycode::Right;
+                        if self.shift_held {
+                            // Move
+                            let moved = self.change_selected(|clip| {
+                                if !right && clip.begin < cell {
+                                    return false
+                                }
+                                if right { clip.begin += cell; clip.end += cell; } else { clip.begin -= cell; clip.end -= cell; }
+                                true
+                            });
+                            if !moved {
+                                return
+                            }
+                        } else if self.alt_held {
+                            // Resize from the end
+                            self.change_selected(|clip| {
+                                if right { clip.end += cell; } else { clip.end = clip.end.saturating_sub(cell); }
+                                true
+                            });
+                            return
+                        } else if self.ctrl_held {
+                            // Trim the start, what's playing stays where it is
+                            self.change_selected(|clip| {
+                                if right {
+                                    clip.begin += cell;
+                                    clip.offset += cell;
+                                } else {
+                                    if clip.begin < cell || clip.offset < cell {
+                                        return false
+                                    }
+                                    clip.begin -= cell;
+                                    clip.offset -= cell;
+                                }
+                                true
+                            });
+                            return
+                        }
+                        self.cursor_tick = if right { self.cursor_tick + cell } else { self.cursor_tick.saturating_sub(cell) };
+                    },
+                    Keycode::Up | Keycode::Down => {
+                        let down = key == Keycode::Down;
+                        if down && self.cursor_lane == u8::MAX as usize || !down && self.cursor_lane == 0 {
+                            return
+                        }
+                        if self.shift_held && !self.change_selected(|clip| {
+                            clip.track = if down { clip.track + 1 } else { clip.track - 1 };
+                            true
+                        }) {
+                            return
+                        }
+                        self.cursor_lane = if down { self.cursor_lane + 1 } else { self.cursor_lane - 1 };
+                    },
+                    Keycode::Home => self.cursor_tick = 0,
+
+                    Keycode::Insert => {
+                        let Some(&(_, length)) = self.patterns.get(self.pattern) else {
+                            return
+                        };
+                        let clip = PatternClip {
+                            pattern_index: self.pattern,
+                            begin: self.cursor_tick,
+                            end: self.cursor_tick + length,
+                            offset: 0,
+                            track: self.cursor_lane as u8,
+                        };
+                        if self.fits(&clip, None) {
+                            self.clips.push(Clip::Pattern(clip.clone()));
+                            self.actions.push(PlaylistAction::Add(Clip::Pattern(clip)));
+                        }
+                    },
+                    Keycode::Delete => {
+                        if let Some(index) = self.clip_at(self.cursor_lane, self.cursor_tick) {
+                            self.clips.remove(index);
+                            self.actions.push(PlaylistAction::Remove(index));
+                        }
+                    },
+                    Keycode::S => {
+                        if let Some(index) = self.clip_at(self.cursor_lane, self.cursor_tick) {
+                            self.actions.push(PlaylistAction::Split(index, self.cursor_tick));
+                        }
+                    },
+
+                    // Loop markers
+                    Keycode::B => {
+                        let end = self.loop_range.map_or(self.cursor_tick + bar_length(self.ppq), |(_, end)| end);
+                        self.set_loop(Some((self.cursor_tick, end.max(self.cursor_tick + cell))));
+                    },
+                    Keycode::E => {
+                        let start = self.loop_range.map_or(0, |(start, _)| start);
+                        let end = self.cursor_tick + cell;
+                        self.set_loop(Some((start.min(self.cursor_tick), end)));
+                    },
+                    Keycode::L => self.set_loop(None),
+
+                    Keycode::Return => self.actions.push(PlaylistAction::Seek(self.cursor_tick)),
+                    Keycode::LeftBracket => self.pattern = self.pattern.saturating_sub(1),
+                    Keycode::RightBracket => self.pattern = (self.pattern + 1).min(self.patterns.len().saturating_sub(1)),
+
+                    // Zoom, from an 8th of a beat up to a bar
+                    Keycode::Equals | Keycode::KpPlus => self.cell = (self.cell / 2).max(self.ppq as u32 / 8).max(1),
+                    Keycode::Minus | Keycode::KpMinus => self.cell = (self.cell * 2).min(bar_length(self.ppq)),
+                    _ => {}
+                }
+                self.cursor_tick -= self.cursor_tick % self.cell;
+            },
+            Event::KeyUp(key) => {
+                match key {
+                    Keycode::LCtrl | Keycode::RCtrl => self.ctrl_held = false,
+                    Keycode::LAlt | Keycode::RAlt => self.alt_held = false,
+                    Keycode::LShift | Keycode::RShift => self.shift_held = false,
+                    _ => {}
+                }
+            },
+            Event::MouseDown(x, y, button) => {
+                let Some((lane, tick)) = self.grid_at(x, y) else {
+                    return
+                };
+                self.cursor_lane = lane.min(u8::MAX as usize);
+                self.cursor_tick = tick;
+
+                let clip = self.clip_at(lane, tick);
+                match (button, clip) {
+                    // Grab it, by the last cell to resize
+                    (MouseButton::Left, Some(index)) => {
+                        let original = self.clip(index).clone();
+                        let resize = tick + self.cell >= original.end;
+                        self.drag = Some(Drag { index, resize, tick, lane, original });
+                    },
+                    // Right click places and deletes, like the keys
+                    (MouseButton::Right, Some(_)) => self.handle_event(Event::KeyDown(Keycode::Delete)),
+                    (MouseButton::Right, None) => self.handle_event(Event::KeyDown(Keycode::Insert)),
+                    _ => {}
+                }
+            },
+            Event::MouseMove(x, y) => {
+                let Some(drag) = &self.drag else {
+                    return
+                };
+                let Some((lane, tick)) = self.grid_at(x, y) else {
+                    return
+                };
+
+                let mut clip = drag.original.clone();
+                let delta = tick as i64 - drag.tick as i64;
+                if drag.resize {
+                    clip.end = (clip.end as i64 + delta).max(clip.begin as i64 + self.cell as i64) as u32;
+                } else {
+                    let delta = delta.max(-(clip.begin as i64));
+                    clip.begin = (clip.begin as i64 + delta) as u32;
+                    clip.end = (clip.end as i64 + delta) as u32;
+                    clip.track = (clip.track as i64 + lane as i64 - drag.lane as i64).clamp(0, u8::MAX as i64) as u8;
+                }
+
+                let index = drag.index;
+                if self.fits(&clip, Some(index)) {
+                    self.clips[index] = Clip::Pattern(clip);
+                }
+            },
+            Event::MouseUp(_, _, _) => {
+                let Some(drag) = self.drag.take() else {
+                    return
+                };
+                let clip = self.clip(drag.index).clone();
+                if clip != drag.original {
+                    self.actions.push(PlaylistAction::Replace(drag.index, Clip::Pattern(clip)));
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn clicked(&mut self) -> bool {
+        false
+    }
+
+    any_impl!{}
+
+    fn set_visiblity(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn visible(&self) -> bool {
+        true
+    }
+
+    fn changed(&mut self) -> bool {
+        !self.actions.is_empty()
+    }
+
+    fn set_handles_events(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn handles_events(&self) -> bool {
+        true
+    }
+}
+
+fn bar_length(ppq: u16) -> u32 {
+    ppq as u32 * 4
+}
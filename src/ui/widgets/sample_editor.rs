@@ -0,0 +1,534 @@
+use std::{sync::mpsc, mem};
+use sdl2::keyboard::Keycode;
+
+use crate::{
+    ui::{Command, events::Event, pixel_to_char},
+    engine::plugins::builtin::sampler::{sample::{Sample, LoopMode, Interpolation, SincTable}, decoder::load_sample},
+    any_impl
+};
+
+use super::{Widget, Position, draw_borders_thick};
+
+const WIDGET_ID_SAMPLEEDITOR: u8 = 12;
+
+const LIST_WIDTH: usize = 24; // "01 " and the name
+
+// What the user asked for, done by whoever owns the engine. See SampleEditor::take_actions().
+pub enum SampleAction {
+    Add(Sample),
+    Remove(usize),
+    Set(usize, Sample), // after an edit
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    List,
+    Wave,
+}
+
+// The F3 page, the project's samples on the left and the waveform of the selected one on the right.
+// Edits are done on a copy here and sent back whole, so they can be undone.
+pub struct SampleEditor {
+    pub pos1: Position,
+    pub pos2: Position,
+
+    pub names: Vec<Option<String>>, // None for an empty slot
+    pub current: usize,
+    sample: Option<Sample>,
+    shown: Option<(usize, u64)>, // index and history changes of the copy we have
+
+    pub text_color: u32,
+    pub outer_bg: u32,
+    pub inner_bg: u32,
+    pub top_rim: u32,
+    pub bottom_rim: u32,
+    pub cursor_color: u32,
+    pub highlight_color: u32,
+    pub wave_color: u32,
+    pub center_color: u32,
+    pub selection_color: u32,
+    pub loop_color: u32,
+    pub sustain_color: u32,
+
+    actions: Vec<SampleAction>,
+    shift_held: bool,
+    alt_held: bool,
+
+    focus: Focus,
+    list_scroll: usize,
+    cursor: usize, // in frames
+    anchor: Option<usize>, // where the selection started
+    selection: Option<(usize, usize)>,
+    zoom: usize, // frames per pixel, 0 fits the whole sample
+    scroll: usize, // first frame shown
+    dragging: bool,
+    resampling: Option<String>, // rate being typed in
+    message: Option<String>, // last error
+
+    peaks: Vec<Vec<(f32, f32)>>, // min and max of every pixel column, per channel
+    peaks_key: Option<(usize, u64, usize, usize, usize)>, // what they were made from: shown, zoom, scroll, width
+    sinc: SincTable,
+}
+
+impl SampleEditor {
+    pub fn new(pos1: Position, pos2: Position) -> Self {
+        Self {
+            pos1,
+            pos2,
+
+            names: Vec::new(),
+            current: 0,
+            sample: None,
+            shown: None,
+
+            text_color: 0,
+            outer_bg: 0,
+            inner_bg: 0,
+            top_rim: 0,
+            bottom_rim: 0,
+            cursor_color: 0x7f7f7f,
+            highlight_color: 0x3f3f3f,
+            wave_color: 0x7fcf7f,
+            center_color: 0x2f4f2f,
+            selection_color: 0x2c3f5f,
+            loop_color: 0x4f8fcf,
+            sustain_color: 0xcf8f4f,
+
+            actions: Vec::new(),
+            shift_held: false,
+            alt_held: false,
+
+            focus: Focus::List,
+            list_scroll: 0,
+            cursor: 0,
+            anchor: None,
+            selection: None,
+            zoom: 0,
+            scroll: 0,
+            dragging: false,
+            resampling: None,
+            message: None,
+
+            peaks: Vec::new(),
+            peaks_key: None,
+            sinc: SincTable::new(),
+        }
+    }
+
+    pub fn take_actions(&mut self) -> Vec<SampleAction> {
+        mem::take(&mut self.actions)
+    }
+
+    // Fetches the selected sample again if it's another one or the project changed since
+    pub fn update_sample(&mut self, changes: u64, sample: impl FnOnce(usize) -> Option<Sample>) {
+        self.current = self.current.min(self.names.len().saturating_sub(1));
+        if self.shown == Some((self.current, changes)) {
+            return
+        }
+        if self.shown.map(|(index, _)| index) != Some(self.current) {
+            self.cursor = 0;
+            self.anchor = None;
+            self.selection = None;
+            self.zoom = 0;
+            self.scroll = 0;
+        }
+        self.sample = sample(self.current);
+        self.shown = Some((self.current, changes));
+        self.peaks_key = None;
+    }
+
+    fn frames(&self) -> usize {
+        self.sample.as_ref().map_or(0, |sample| sample.frames())
+    }
+
+    fn wave_x(&self) -> usize {
+        self.pos1.x + LIST_WIDTH + 2
+    }
+
+    fn wave_width(&self) -> usize {
+        (self.pos2.x - self.wave_x()) * 8
+    }
+
+    fn zoom(&self) -> usize {
+        if self.zoom == 0 {
+            (self.frames() + self.wave_width() - 1) / self.wave_width().max(1)
+        } else {
+            self.zoom
+        }.max(1)
+    }
+
+    // What edits work on, the whole sample if nothing is selected
+    fn range(&self) -> (usize, usize) {
+        self.selection.unwrap_or((0, self.frames()))
+    }
+
+    // Edits the copy and sends it off
+    fn edit(&mut self, edit: impl FnOnce(&mut Sample, &SincTable)) {
+        // Empty slots have nothing to edit
+        let Some(sample) = self.sample.as_mut().filter(|sample| !sample.is_empty()) else {
+            return
+        };
+        edit(sample, &self.sinc);
+        sample.validate_loops();
+        self.actions.push(SampleAction::Set(self.current, sample.clone()));
+        self.peaks_key = None;
+
+        let frames = sample.frames();
+        self.cursor = self.cursor.min(frames);
+        self.selection = self.selection.map(|(start, end)| (start.min(frames), end.min(frames))).filter(|(start, end)| start < end);
+    }
+
+    fn move_cursor(&mut self, frame: usize) {
+        let frame = frame.min(self.frames());
+        if self.shift_held || self.dragging {
+            let anchor = *self.anchor.get_or_insert(self.cursor);
+            self.selection = Some((anchor.min(frame), anchor.max(frame))).filter(|(start, end)| start < end);
+        } else {
+            self.anchor = None;
+        }
+        self.cursor = frame;
+
+        // Keep it in view
+        let shown = self.wave_width() * self.zoom();
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + shown {
+            self.scroll = self.cursor + 1 - shown;
+        }
+    }
+
+    fn load(&mut self) {
+        let path = native_dialog::FileDialog::new()
+            .add_filter("Audio files", &["wav", "aif", "aiff", "aifc", "flac"])
+            .show_open_single_file();
+
+        match path {
+            Ok(Some(path)) => match load_sample(&path.to_string_lossy()) {
+                Ok(sample) => {
+                    self.message = None;
+                    // Into the selected slot if it's empty
+                    if matches!(self.names.get(self.current), Some(None)) {
+                        self.actions.push(SampleAction::Set(self.current, sample));
+                    } else {
+                        self.actions.push(SampleAction::Add(sample));
+                        self.current = self.names.len();
+                    }
+                },
+                Err(err) => self.message = Some(err),
+            },
+            Ok(None) => {},
+            Err(err) => self.message = Some(err.to_string()),
+        }
+    }
+
+    fn handle_wave_key(&mut self, key: Keycode) {
+        // Typing in a sample rate
+        if let Some(rate) = &mut self.resampling {
+            match key {
+                Keycode::Backspace => { rate.pop(); },
+                Keycode::Return => {
+                    let rate = self.resampling.take().unwrap().parse::<u32>().unwrap_or(0);
+                    if (1000..=384000).contains(&rate) {
+                        self.edit(|sample, sinc| sample.resample(rate, Interpolation::Sinc, sinc));
+                    }
+                },
+                // The digits come in as text
+                Keycode::Num0 | Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4
+                    | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8 | Keycode::Num9 => {},
+                _ => self.resampling = None,
+            }
+            return
+        }
+
+        let step = self.zoom() * 8; // a character
+        let range = self.range();
+        let cursor = self.cursor;
+        let sustain = self.shift_held;
+
+        match key {
+            Keycode::Left => self.move_cursor(self.cursor.saturating_sub(step)),
+            Keycode::Right => self.move_cursor(self.cursor + step),
+            Keycode::Home => self.move_cursor(0),
+            Keycode::End => self.move_cursor(self.frames()),
+            Keycode::Equals | Keycode::KpPlus => {
+                self.zoom = (self.zoom() / 2).max(1);
+                self.move_cursor(self.cursor);
+            },
+            Keycode::Minus | Keycode::KpMinus => {
+                self.zoom = self.zoom() * 2;
+                if self.zoom * self.wave_width() >= self.frames() {
+                    self.zoom = 0;
+                    self.scroll = 0;
+                }
+            },
+
+            Keycode::N if self.alt_held => self.edit(|sample, _| sample.normalize(range)),
+            Keycode::I if self.alt_held => self.edit(|sample, _| sample.fade_in(range)),
+            Keycode::O if self.alt_held => self.edit(|sample, _| sample.fade_out(range)),
+            Keycode::R if self.alt_held => self.edit(|sample, _| sample.reverse(range)),
+            Keycode::T if self.alt_held => {
+                self.edit(|sample, _| sample.trim(range));
+                self.selection = None;
+                self.cursor = 0;
+                self.scroll = 0;
+            },
+            Keycode::S if self.alt_held => {
+                self.resampling = Some(self.sample.as_ref().map_or(String::new(), |sample| sample.samplerate.to_string()));
+            },
+
+            Keycode::A => self.selection = Some((0, self.frames())).filter(|(start, end)| start < end),
+            Keycode::Backspace => {
+                self.selection = None;
+                self.anchor = None;
+            },
+
+            // Loop points at the cursor, Shift for the sustain loop
+            Keycode::B | Keycode::E => self.edit(|sample, _| {
+                let (start, end) = if sustain { (&mut sample.sustain_start, &mut sample.sustain_end) } else { (&mut sample.loop_start, &mut sample.loop_end) };
+                if key == Keycode::B { *start = cursor; } else { *end = cursor; }
+                let mode = if sustain { &mut sample.sustain_mode } else { &mut sample.loop_mode };
+                if *mode == LoopMode::Off {
+                    *mode = LoopMode::Forward;
+                }
+            }),
+            Keycode::L => self.edit(|sample, _| {
+                let mode = if sustain { &mut sample.sustain_mode } else { &mut sample.loop_mode };
+                let index = LoopMode::ALL.iter().position(|other| other == mode).unwrap_or(0);
+                *mode = LoopMode::from_index((index + 1) % LoopMode::ALL.len());
+            }),
+            _ => {}
+        }
+    }
+
+    // Frame under a mouse position, None if it's not on the waveform
+    fn frame_at(&self, x: usize, y: usize) -> Option<usize> {
+        let (column, row) = pixel_to_char(x, y);
+        if !(self.wave_x()..self.pos2.x).contains(&column) || !(self.pos1.y..self.pos2.y).contains(&row) {
+            return None
+        }
+        Some((self.scroll + (x - self.wave_x() * 8) * self.zoom()).min(self.frames()))
+    }
+
+    // Min and max of each pixel column, redone only when the view or the sample changes
+    fn update_peaks(&mut self, width: usize) {
+        let zoom = self.zoom();
+        let key = self.shown.map(|(index, changes)| (index, changes, zoom, self.scroll, width));
+        if key.is_none() || self.peaks_key == key {
+            return
+        }
+        self.peaks_key = key;
+
+        let Some(sample) = &self.sample else {
+            self.peaks.clear();
+            return
+        };
+        self.peaks = sample.data.iter().map(|channel| {
+            (0..width).map(|x| {
+                let start = (self.scroll + x * zoom).min(channel.len());
+                let end = (start + zoom).min(channel.len());
+                channel[start..end].iter().fold((f32::MAX, f32::MIN), |(min, max), &value| (min.min(value), max.max(value)))
+            }).collect()
+        }).collect();
+    }
+}
+
+#[allow(unused_must_use)]
+impl Widget for SampleEditor {
+    fn type_id(&self) -> u8 {
+        WIDGET_ID_SAMPLEEDITOR
+    }
+
+    fn draw(&mut self, canvas_channel: &mpsc::Sender<Command>) {
+        let height = self.pos2.y - self.pos1.y;
+        let list_end = Position { x: self.pos1.x + LIST_WIDTH, y: self.pos2.y };
+        let wave_x = self.wave_x();
+
+        draw_borders_thick(canvas_channel, self.pos1, list_end, self.top_rim, self.bottom_rim, self.outer_bg);
+        draw_borders_thick(canvas_channel, Position { x: wave_x, y: self.pos1.y }, self.pos2, self.top_rim, self.bottom_rim, self.outer_bg);
+
+        // Samples
+        if self.current < self.list_scroll {
+            self.list_scroll = self.current;
+        } else if self.current >= self.list_scroll + height {
+            self.list_scroll = self.current + 1 - height;
+        }
+        for line in 0..height {
+            let index = self.list_scroll + line;
+            let text = match self.names.get(index) {
+                Some(Some(name)) => format!("{:0>2} {:<w$}", index + 1, name.chars().take(LIST_WIDTH - 3).collect::<String>(), w = LIST_WIDTH - 3),
+                Some(None) => format!("{:0>2} {:<w$}", index + 1, "(empty)", w = LIST_WIDTH - 3),
+                None if index == 0 => format!("{:<LIST_WIDTH$}", "Insert loads a sample"),
+                None => " ".repeat(LIST_WIDTH),
+            };
+            let bg = match index == self.current {
+                true if self.focus == Focus::List => self.cursor_color,
+                true => self.highlight_color,
+                false => self.inner_bg,
+            };
+            canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y + line, self.text_color, bg, text));
+        }
+
+        // Status
+        let width = self.pos2.x - self.pos1.x;
+        let status = if let Some(rate) = &self.resampling {
+            format!("Resample to: {rate}_ Hz, Enter to apply")
+        } else if let Some(message) = &self.message {
+            message.clone()
+        } else if self.sample.as_ref().is_some_and(|sample| sample.is_empty()) {
+            "Empty slot, Insert loads a sample into it".to_string()
+        } else if let Some(sample) = &self.sample {
+            let mode = |mode: LoopMode, start: usize, end: usize| if mode == LoopMode::Off { "Off".to_string() } else { format!("{} {}-{}", mode.name(), start, end) };
+            let selection = self.selection.map_or("none".to_string(), |(start, end)| format!("{start}-{end}"));
+            format!("{} Hz  {} ch  {} frames  Cursor {}  Sel {}  Loop {}  Sustain {}",
+                sample.samplerate, sample.data.len(), sample.frames(), self.cursor, selection,
+                mode(sample.loop_mode, sample.loop_start, sample.loop_end), mode(sample.sustain_mode, sample.sustain_start, sample.sustain_end))
+        } else {
+            String::new()
+        };
+        let status: String = status.chars().take(width).collect();
+        canvas_channel.send(Command::Text(self.pos1.x, self.pos1.y-2, self.text_color, self.outer_bg, format!("{:<width$}", status)));
+
+        // Waveform, drawn in pixels
+        let width = self.wave_width();
+        let pixel_height = height * 8;
+        self.update_peaks(width);
+
+        let frames = self.frames();
+        let zoom = self.zoom();
+        let mut pixels = vec![self.inner_bg; width * pixel_height];
+        let Some(sample) = &self.sample else {
+            canvas_channel.send(Command::Pixels(wave_x * 8, self.pos1.y * 8, width, pixels));
+            return
+        };
+
+        let marker = |mode: LoopMode, start: usize, end: usize, first: usize| {
+            mode != LoopMode::Off && ((first..first + zoom).contains(&start) || (first..first + zoom).contains(&end))
+        };
+        let lane_height = pixel_height / self.peaks.len().max(1);
+        for x in 0..width {
+            let first = self.scroll + x * zoom;
+            if first >= frames {
+                break
+            }
+
+            // Column background, markers take the whole height
+            let background = if (first..first + zoom).contains(&self.cursor) && self.focus == Focus::Wave {
+                self.cursor_color
+            } else if marker(sample.sustain_mode, sample.sustain_start, sample.sustain_end, first) {
+                self.sustain_color
+            } else if marker(sample.loop_mode, sample.loop_start, sample.loop_end, first) {
+                self.loop_color
+            } else if self.selection.map_or(false, |(start, end)| first < end && first + zoom > start) {
+                self.selection_color
+            } else {
+                self.inner_bg
+            };
+
+            for (channel, peaks) in self.peaks.iter().enumerate() {
+                let (min, max) = peaks[x];
+                let top = channel * lane_height;
+                let center = top + lane_height / 2;
+                let half = (lane_height / 2) as f32;
+                let to_y = |value: f32| ((center as f32 - value.clamp(-1.0, 1.0) * half) as usize).clamp(top, top + lane_height - 1);
+                let (high, low) = (to_y(max), to_y(min));
+
+                for y in top..top + lane_height {
+                    pixels[y * width + x] = if (high..=low).contains(&y) {
+                        self.wave_color
+                    } else if y == center {
+                        self.center_color
+                    } else {
+                        background
+                    };
+                }
+            }
+        }
+        canvas_channel.send(Command::Pixels(wave_x * 8, self.pos1.y * 8, width, pixels));
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::KeyDown(key) | Event::KeyRepeat(key) => {
+                match key {
+                    Keycode::LShift | Keycode::RShift => self.shift_held = true,
+                    Keycode::LAlt | Keycode::RAlt => self.alt_held = true,
+                    Keycode::Tab => {
+                        self.resampling = None;
+                        self.focus = if self.focus == Focus::List { Focus::Wave } else { Focus::List };
+                    },
+                    _ if self.focus == Focus::List => match key {
+                        Keycode::Up => self.current = self.current.saturating_sub(1),
+                        Keycode::Down => self.current = (self.current + 1).min(self.names.len().saturating_sub(1)),
+                        Keycode::Insert => self.load(),
+                        Keycode::Delete => {
+                            if self.current < self.names.len() {
+                                self.actions.push(SampleAction::Remove(self.current));
+                            }
+                        },
+                        Keycode::Return => self.focus = Focus::Wave,
+                        _ => {}
+                    },
+                    _ => self.handle_wave_key(key),
+                }
+            },
+            Event::KeyUp(key) => {
+                match key {
+                    Keycode::LShift | Keycode::RShift => self.shift_held = false,
+                    Keycode::LAlt | Keycode::RAlt => self.alt_held = false,
+                    _ => {}
+                }
+            },
+            Event::TextInput(text) => {
+                if let Some(rate) = &mut self.resampling {
+                    rate.extend(text.chars().filter(|char| char.is_ascii_digit()));
+                    rate.truncate(6);
+                }
+            },
+            Event::MouseDown(x, y, _) => {
+                let Some(frame) = self.frame_at(x, y) else {
+                    return
+                };
+                self.focus = Focus::Wave;
+                self.anchor = Some(frame);
+                self.selection = None;
+                self.dragging = true;
+                self.move_cursor(frame);
+            },
+            Event::MouseMove(x, y) => {
+                if !self.dragging {
+                    return
+                }
+                if let Some(frame) = self.frame_at(x, y) {
+                    self.move_cursor(frame);
+                }
+            },
+            Event::MouseUp(_, _, _) => self.dragging = false,
+        }
+    }
+
+    fn clicked(&mut self) -> bool {
+        false
+    }
+
+    any_impl!{}
+
+    fn set_visiblity(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn visible(&self) -> bool {
+        true
+    }
+
+    fn changed(&mut self) -> bool {
+        !self.actions.is_empty()
+    }
+
+    fn set_handles_events(&mut self, _: bool) {
+        // no-op
+    }
+
+    fn handles_events(&self) -> bool {
+        true
+    }
+}